@@ -7,7 +7,9 @@ pub mod command;
 pub mod config;
 
 use crate::io::emu::Emulator;
-use crate::io::gpio::{GpioMonitoring, GpioPin, PinMode, PullMode};
+use crate::io::gpio::{
+    GpioMonitoring, GpioPatternGenerator, GpioPin, PinMode, PinSnapshot, PullMode,
+};
 use crate::io::i2c::Bus;
 use crate::io::spi::Target;
 use crate::io::uart::Uart;
@@ -344,6 +346,11 @@ impl TransportWrapper {
         self.transport.borrow().capabilities()
     }
 
+    /// Identification strings of the USB debugger device.
+    pub fn description(&self) -> Result<crate::util::usb::UsbStrings> {
+        self.transport.borrow().description()
+    }
+
     /// Returns a SPI [`Target`] implementation.
     pub fn spi(&self, name: &str) -> Result<Rc<dyn Target>> {
         self.transport
@@ -388,6 +395,11 @@ impl TransportWrapper {
         self.transport.borrow().gpio_monitoring()
     }
 
+    /// Returns a [`GpioPatternGenerator`] implementation.
+    pub fn gpio_pattern_generator(&self) -> Result<Rc<dyn GpioPatternGenerator>> {
+        self.transport.borrow().gpio_pattern_generator()
+    }
+
     /// Returns a [`Emulator`] implementation.
     pub fn emulator(&self) -> Result<Rc<dyn Emulator>> {
         self.transport.borrow().emulator()
@@ -398,6 +410,16 @@ impl TransportWrapper {
         self.transport.borrow().proxy_ops()
     }
 
+    /// Records the mode, level and pull of all GPIO pins in use.
+    pub fn snapshot_pins(&self) -> Result<PinSnapshot> {
+        self.transport.borrow().snapshot_pins()
+    }
+
+    /// Reapplies the pin state recorded by `snapshot_pins()`.
+    pub fn restore_pins(&self, snapshot: &PinSnapshot) -> Result<()> {
+        self.transport.borrow().restore_pins(snapshot)
+    }
+
     /// Invoke non-standard functionality of some Transport implementations.
     pub fn dispatch(&self, action: &dyn Any) -> Result<Option<Box<dyn Annotate>>> {
         self.transport.borrow().dispatch(action)
@@ -3,10 +3,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::Result;
+use humantime::parse_duration;
+use std::time::Duration;
 use structopt::StructOpt;
 
 use crate::backend::BackendOpts;
-use crate::transport::cw310::CW310;
+use crate::transport::cw310::{Cw310Options, CW310};
 use crate::transport::Transport;
 
 #[derive(Debug, StructOpt)]
@@ -16,6 +18,34 @@ pub struct Cw310Opts {
         help = "Comma-separated list of CW310 UARTs for non-udev environments. List the console uart first."
     )]
     pub cw310_uarts: Option<String>,
+
+    #[structopt(
+        long,
+        help = "USB serial number of the CW310 UARTs, if provided by the FPGA design rather than the board's USB interface"
+    )]
+    pub cw310_console_serial: Option<String>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = parse_duration),
+        help = "Cache GPIO levels read from the CW310 for up to the given duration (e.g. \"5ms\"), a write to any pin invalidates the cache"
+    )]
+    pub cw310_gpio_cache_ttl: Option<Duration>,
+
+    #[structopt(long, help = "Record all operations on CW310 GPIO pins, for debugging")]
+    pub cw310_gpio_log: bool,
+
+    #[structopt(
+        long,
+        help = "Comma-separated list of CW310 GPIO pins which are inverted by board circuitry, levels on these pins are inverted when read or written"
+    )]
+    pub cw310_inverted_pins: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Comma-separated list of named CW310 SPI instances, as NAME=PIN:LEVEL, each reached by driving the control pin of an on-board mux to the given level, instance 0 releasing all control pins"
+    )]
+    pub cw310_spi_mux: Option<String>,
 }
 
 pub fn create(args: &BackendOpts) -> Result<Box<dyn Transport>> {
@@ -25,10 +55,29 @@ pub fn create(args: &BackendOpts) -> Result<Box<dyn Transport>> {
         .as_ref()
         .map(|v| v.split(',').collect::<Vec<&str>>())
         .unwrap_or_default();
+    let inverted_pins = args
+        .cw310_opts
+        .cw310_inverted_pins
+        .as_ref()
+        .map(|v| v.split(',').collect::<Vec<&str>>())
+        .unwrap_or_default();
+    let spi_mux = args
+        .cw310_opts
+        .cw310_spi_mux
+        .as_ref()
+        .map(|v| v.split(',').collect::<Vec<&str>>())
+        .unwrap_or_default();
     Ok(Box::new(CW310::new(
         args.usb_vid,
         args.usb_pid,
         args.usb_serial.as_deref(),
         &uarts,
+        &Cw310Options {
+            console_serial: args.cw310_opts.cw310_console_serial.as_deref(),
+            gpio_cache_ttl: args.cw310_opts.cw310_gpio_cache_ttl,
+            gpio_log: args.cw310_opts.cw310_gpio_log,
+            inverted_pins: &inverted_pins,
+            spi_mux: &spi_mux,
+        },
     )?))
 }
@@ -2,18 +2,69 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::transport::hyperdebug::{Flavor, Hyperdebug, HyperdebugDfu};
+use crate::transport::hyperdebug::{Flavor, Hyperdebug, HyperdebugDfu, Throttle};
 use crate::transport::Transport;
 use anyhow::Result;
+use humantime::parse_duration;
+use std::time::Duration;
+use structopt::StructOpt;
 
 use crate::backend::BackendOpts;
 
+#[derive(Debug, StructOpt)]
+pub struct HyperdebugOpts {
+    #[structopt(
+        long,
+        help = "Limit the sustained rate of USB bulk operations to HyperDebug (per second)"
+    )]
+    pub hyperdebug_max_bulk_rate: Option<u32>,
+
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "Number of USB bulk operations allowed back-to-back when rate limited"
+    )]
+    pub hyperdebug_bulk_burst: u32,
+
+    #[structopt(
+        long,
+        help = "Console prompt of the HyperDebug firmware, if different from the official one"
+    )]
+    pub hyperdebug_prompt: Option<String>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = parse_duration),
+        help = "Time allowed for each HyperDebug console command [default: 5s]"
+    )]
+    pub hyperdebug_command_timeout: Option<Duration>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = parse_duration),
+        help = "Time to wait for a HyperDebug USB interface held by another process [default: 1s]"
+    )]
+    pub hyperdebug_claim_timeout: Option<Duration>,
+}
+
 pub fn create<T: 'static + Flavor>(args: &BackendOpts) -> Result<Box<dyn Transport>> {
-    Ok(Box::new(Hyperdebug::<T>::open(
+    let opts = &args.hyperdebug_opts;
+    let hyperdebug = Hyperdebug::<T>::open(
         args.usb_vid,
         args.usb_pid,
         args.usb_serial.as_deref(),
-    )?))
+        opts.hyperdebug_max_bulk_rate
+            .map(|rate| Throttle::new(rate, opts.hyperdebug_bulk_burst))
+            .transpose()?,
+        opts.hyperdebug_prompt.as_deref(),
+    )?;
+    if let Some(timeout) = opts.hyperdebug_command_timeout {
+        hyperdebug.set_command_timeout(timeout);
+    }
+    if let Some(timeout) = opts.hyperdebug_claim_timeout {
+        hyperdebug.set_claim_timeout(timeout);
+    }
+    Ok(Box::new(hyperdebug))
 }
 
 pub fn create_dfu(args: &BackendOpts) -> Result<Box<dyn Transport>> {
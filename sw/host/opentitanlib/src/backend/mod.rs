@@ -4,11 +4,13 @@
 
 use anyhow::Result;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use structopt::StructOpt;
 use thiserror::Error;
 
 use crate::app::config::process_config_file;
 use crate::app::{TransportWrapper, TransportWrapperBuilder};
+use crate::transport::common::trace::{TraceLog, TracingTransport};
 use crate::transport::hyperdebug::{C2d2Flavor, CW310Flavor, StandardFlavor, Ti50Flavor};
 use crate::transport::{EmptyTransport, Transport};
 use crate::util::parse_int::ParseInt;
@@ -37,6 +39,9 @@ pub struct BackendOpts {
     #[structopt(flatten)]
     pub cw310_opts: cw310::Cw310Opts,
 
+    #[structopt(flatten)]
+    pub hyperdebug_opts: hyperdebug::HyperdebugOpts,
+
     #[structopt(flatten)]
     pub verilator_opts: verilator::VerilatorOpts,
 
@@ -48,6 +53,9 @@ pub struct BackendOpts {
 
     #[structopt(long, number_of_values(1), help = "Configuration files")]
     pub conf: Vec<PathBuf>,
+
+    #[structopt(long, help = "Record all transport operations to this file")]
+    pub trace_file: Option<PathBuf>,
 }
 
 #[derive(Error, Debug)]
@@ -101,6 +109,13 @@ pub fn create(args: &BackendOpts) -> Result<TransportWrapper> {
             process_config_file(&mut env, conf_file)?
         }
     }
+    let backend = match &args.trace_file {
+        Some(path) => Box::new(TracingTransport::new(
+            backend,
+            Rc::new(TraceLog::create(path)?),
+        )),
+        None => backend,
+    };
     env.build(backend)
 }
 
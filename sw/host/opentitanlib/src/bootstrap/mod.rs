@@ -24,12 +24,15 @@ mod primitive;
 mod rescue;
 
 pub use legacy::LegacyBootstrapError;
+pub(crate) use primitive::send_frames;
 pub use rescue::RescueError;
 
 #[derive(Debug, Error, Serialize, Deserialize)]
 pub enum BootstrapError {
     #[error("Invalid hash length: {0}")]
     InvalidHashLength(usize),
+    #[error("Frame {0} not acknowledged")]
+    FrameNotAcknowledged(u32),
 }
 impl_serializable_error!(BootstrapError);
 
@@ -74,6 +77,60 @@ trait UpdateProtocol {
     ) -> Result<()>;
 }
 
+/// Pin operations of the common bootstrap sequence, see `run_with_reset()`.
+pub(crate) trait BootstrapReset {
+    /// Asserts the strapping requesting bootstrap if `bootstrap`, releases it otherwise.
+    fn set_bootstrap_strapping(&self, bootstrap: bool) -> Result<()>;
+    /// Resets the chip, clearing the UART RX buffer while reset is asserted, if requested.
+    fn reset(&self, clear_uart_rx: bool) -> Result<()>;
+}
+
+/// Runs `update`, then resets the chip to run the new firmware.  If `strap`, the chip is first
+/// reset with the bootstrap strapping asserted, which is released again after `update`.  The
+/// result of `update` is returned once the chip has been reset, also if it failed.
+pub(crate) fn run_with_reset(
+    pins: &dyn BootstrapReset,
+    strap: bool,
+    clear_uart_rx: bool,
+    update: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    if strap {
+        log::info!("Asserting bootstrap pins...");
+        pins.set_bootstrap_strapping(true)?;
+        pins.reset(clear_uart_rx)?;
+        log::info!("Performing bootstrap...");
+    }
+    let result = update();
+
+    if strap {
+        log::info!("Releasing bootstrap pins...");
+        pins.set_bootstrap_strapping(false)?;
+    }
+
+    // Don't clear the UART RX buffer after bootstrap to preserve the bootstrap output.
+    pins.reset(false)?;
+    result
+}
+
+/// The common bootstrap sequence, performed through the pin strappings of the transport.
+struct TransportReset<'a> {
+    transport: &'a TransportWrapper,
+    reset_delay: Duration,
+}
+
+impl BootstrapReset for TransportReset<'_> {
+    fn set_bootstrap_strapping(&self, bootstrap: bool) -> Result<()> {
+        if bootstrap {
+            self.transport.apply_pin_strapping("ROM_BOOTSTRAP")
+        } else {
+            self.transport.remove_pin_strapping("ROM_BOOTSTRAP")
+        }
+    }
+    fn reset(&self, clear_uart_rx: bool) -> Result<()> {
+        self.transport.reset_target(self.reset_delay, clear_uart_rx)
+    }
+}
+
 /// Options which control bootstrap behavior.
 /// The meaning of each of these values depends on the specific bootstrap protocol being used.
 #[derive(Clone, Debug, StructOpt, Serialize, Deserialize)]
@@ -175,23 +232,62 @@ impl<'a> Bootstrap<'a> {
         progress: &dyn Fn(u32, u32),
     ) -> Result<()> {
         updater.verify_capabilities(self, transport)?;
-        let perform_bootstrap_reset = updater.uses_common_bootstrap_reset();
+        run_with_reset(
+            &TransportReset {
+                transport,
+                reset_delay: self.reset_delay,
+            },
+            updater.uses_common_bootstrap_reset(),
+            self.clear_uart_rx,
+            || updater.update(self, transport, payload, progress),
+        )
+    }
+}
 
-        if perform_bootstrap_reset {
-            log::info!("Asserting bootstrap pins...");
-            transport.apply_pin_strapping("ROM_BOOTSTRAP")?;
-            transport.reset_target(self.reset_delay, self.clear_uart_rx)?;
-            log::info!("Performing bootstrap...");
-        }
-        let result = updater.update(self, transport, payload, progress);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Records the operations of the bootstrap sequence.
+    #[derive(Default)]
+    struct RecordingReset(RefCell<Vec<&'static str>>);
 
-        if perform_bootstrap_reset {
-            log::info!("Releasing bootstrap pins...");
-            transport.remove_pin_strapping("ROM_BOOTSTRAP")?;
+    impl BootstrapReset for RecordingReset {
+        fn set_bootstrap_strapping(&self, bootstrap: bool) -> Result<()> {
+            self.0
+                .borrow_mut()
+                .push(if bootstrap { "strap" } else { "release" });
+            Ok(())
+        }
+        fn reset(&self, clear_uart_rx: bool) -> Result<()> {
+            self.0.borrow_mut().push(if clear_uart_rx {
+                "reset+clear"
+            } else {
+                "reset"
+            });
+            Ok(())
         }
+    }
+
+    #[test]
+    fn test_run_with_reset() {
+        let pins = RecordingReset::default();
+        let update = || {
+            pins.0.borrow_mut().push("update");
+            Ok(())
+        };
+        run_with_reset(&pins, true, true, update).unwrap();
+        assert_eq!(
+            pins.0.take(),
+            vec!["strap", "reset+clear", "update", "release", "reset"]
+        );
+        run_with_reset(&pins, false, true, update).unwrap();
+        assert_eq!(pins.0.take(), vec!["update", "reset"]);
 
-        // Don't clear the UART RX buffer after bootstrap to preserve the bootstrap output.
-        transport.reset_target(self.reset_delay, false)?;
-        result
+        // A failed update still releases the strapping and resets the chip.
+        let err = run_with_reset(&pins, true, false, || anyhow::bail!("broken")).unwrap_err();
+        assert_eq!(err.to_string(), "broken");
+        assert_eq!(pins.0.take(), vec!["strap", "reset", "release", "reset"]);
     }
 }
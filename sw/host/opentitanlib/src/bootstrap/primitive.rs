@@ -8,8 +8,8 @@ use std::time::Duration;
 use zerocopy::AsBytes;
 
 use crate::app::TransportWrapper;
-use crate::bootstrap::{Bootstrap, BootstrapOptions, UpdateProtocol};
-use crate::io::spi::Transfer;
+use crate::bootstrap::{Bootstrap, BootstrapError, BootstrapOptions, UpdateProtocol};
+use crate::io::spi::{Target, Transfer};
 use crate::transport::Capability;
 
 #[derive(AsBytes, Debug, Default)]
@@ -132,50 +132,94 @@ impl UpdateProtocol for Primitive {
         progress: &dyn Fn(u32, u32),
     ) -> Result<()> {
         let spi = container.spi_params.create(transport, "BOOTSTRAP")?;
+        send_frames(
+            &*spi,
+            payload,
+            self.inter_frame_delay,
+            self.flash_erase_delay,
+            None,
+            progress,
+        )
+    }
+}
 
-        let frames = Frame::from_payload(payload);
+/// Sends `payload` to the ROM using the primitive bootstrap protocol.  The integrity field of each
+/// frame is a SHA-256 hash of the rest of the frame, the ROM programs only frames with a matching
+/// hash.  It acknowledges each frame by returning the hash of the entire frame as received while
+/// the following frame is sent.  A frame which is not acknowledged, e.g. because it was corrupted
+/// on the way, is sent again, at most `max_attempts` times in total, if given.
+pub(crate) fn send_frames(
+    spi: &dyn Target,
+    payload: &[u8],
+    inter_frame_delay: Duration,
+    flash_erase_delay: Duration,
+    max_attempts: Option<usize>,
+    progress: &dyn Fn(u32, u32),
+) -> Result<()> {
+    let frames = Frame::from_payload(payload);
 
-        let mut i = 0;
-        while i < frames.len() {
-            let frame = &frames[i];
-            log::info!(
-                "Writing frame {} (offset {:x?})",
-                i,
-                frame.header.flash_offset
-            );
+    let mut i = 0;
+    let mut attempts = 0;
+    // Whether the data received along with frame `i` is the acknowledgement of frame `i - 1`,
+    // rather than of some frame sent before going back.
+    let mut check_ack = false;
+    while i < frames.len() {
+        let frame = &frames[i];
+        log::info!(
+            "Writing frame {} (offset {:x?})",
+            i,
+            frame.header.flash_offset
+        );
 
-            // Write the frame and read back the hash of the previous frame.
-            progress(frame.header.flash_offset, frame.data.len() as u32);
-            let mut prev_hash = [0u8; std::mem::size_of::<Frame>()];
-            spi.run_transaction(&mut [Transfer::Both(frame.as_bytes(), &mut prev_hash)])?;
-
-            if i == 0 {
-                // If its the first frame, there is no hash to check.
-                // We need to give the target some time to erase the flash.
-                std::thread::sleep(self.flash_erase_delay);
-                i += 1;
-                continue;
-            }
+        // Write the frame and read back the hash of the previous frame.
+        progress(frame.header.flash_offset, frame.data.len() as u32);
+        let mut prev_hash = [0u8; std::mem::size_of::<Frame>()];
+        spi.run_transaction(&mut [Transfer::Both(frame.as_bytes(), &mut prev_hash)])?;
 
-            std::thread::sleep(self.inter_frame_delay);
-            let want_hash = frames[i - 1].frame_hash();
-            if prev_hash[..Frame::HASH_LEN] != want_hash {
-                log::error!(
-                    "Frame hash mismatch device:{:x?} != frame:{:x?}.",
-                    &prev_hash[..Frame::HASH_LEN],
-                    want_hash
-                );
-                continue;
-            }
+        if i == 0 {
+            // If its the first frame, there is no hash to check.
+            // We need to give the target some time to erase the flash.
+            std::thread::sleep(flash_erase_delay);
+            check_ack = true;
             i += 1;
+            continue;
         }
-        Ok(())
+
+        std::thread::sleep(inter_frame_delay);
+        if !check_ack {
+            check_ack = true;
+            i += 1;
+            continue;
+        }
+        let want_hash = frames[i - 1].frame_hash();
+        if prev_hash[..Frame::HASH_LEN] != want_hash {
+            log::error!(
+                "Frame hash mismatch device:{:x?} != frame:{:x?}.",
+                &prev_hash[..Frame::HASH_LEN],
+                want_hash
+            );
+            attempts += 1;
+            if matches!(max_attempts, Some(max) if attempts >= max) {
+                return Err(BootstrapError::FrameNotAcknowledged(i as u32 - 1).into());
+            }
+            // Send the unacknowledged frame again, the ROM will ignore the following frame, if
+            // the previous one did not arrive intact.
+            check_ack = false;
+            i -= 1;
+            continue;
+        }
+        attempts = 0;
+        i += 1;
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::io::spi::{AssertChipSelect, MaxSizes, TransferMode};
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
 
     const SIMPLE_BIN: &[u8; 2048] = include_bytes!("simple.bin");
 
@@ -198,4 +242,164 @@ mod tests {
         );
         Ok(())
     }
+
+    /// Plays the part of the ROM, answering each frame with the hash of the previous one as
+    /// received, except for the frame with index `corrupt_ack`, the acknowledgement of which is
+    /// always garbled.  Like the ROM, frames are only programmed if their header hash matches.
+    struct MockRom {
+        corrupt_ack: Option<usize>,
+        /// Index of a frame to be corrupted on its first transmission, as if by line noise.
+        corrupt_frame: Cell<Option<usize>>,
+        previous: RefCell<Option<Vec<u8>>>,
+        frames_received: Cell<usize>,
+        /// Indices of the frames which passed the integrity check.
+        programmed: RefCell<Vec<u32>>,
+    }
+
+    impl MockRom {
+        fn new(corrupt_ack: Option<usize>) -> Self {
+            Self {
+                corrupt_ack,
+                corrupt_frame: Cell::new(None),
+                previous: RefCell::new(None),
+                frames_received: Cell::new(0),
+                programmed: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn frame_index(frame: &[u8]) -> u32 {
+            u32::from_le_bytes(
+                frame[Frame::HASH_LEN..Frame::HASH_LEN + 4]
+                    .try_into()
+                    .unwrap(),
+            ) & !Frame::EOF
+        }
+    }
+
+    impl Target for MockRom {
+        fn get_transfer_mode(&self) -> Result<TransferMode> {
+            Ok(TransferMode::Mode0)
+        }
+        fn set_transfer_mode(&self, _mode: TransferMode) -> Result<()> {
+            Ok(())
+        }
+        fn get_bits_per_word(&self) -> Result<u32> {
+            Ok(8)
+        }
+        fn set_bits_per_word(&self, _bits_per_word: u32) -> Result<()> {
+            Ok(())
+        }
+        fn get_max_speed(&self) -> Result<u32> {
+            Ok(1_000_000)
+        }
+        fn set_max_speed(&self, _max_speed: u32) -> Result<()> {
+            Ok(())
+        }
+        fn get_max_transfer_count(&self) -> Result<usize> {
+            Ok(usize::MAX)
+        }
+        fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+            Ok(MaxSizes {
+                read: 2048,
+                write: 2048,
+            })
+        }
+        fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+            match transaction {
+                [Transfer::Both(wbuf, rbuf)] => {
+                    rbuf.fill(0);
+                    if let Some(previous) = self.previous.borrow().as_ref() {
+                        let mut digest = Sha256::digest(previous);
+                        digest.reverse();
+                        rbuf[..Frame::HASH_LEN].copy_from_slice(&digest);
+                        if self.corrupt_ack == Some(Self::frame_index(previous) as usize) {
+                            rbuf[0] ^= 0xff;
+                        }
+                    }
+                    let mut received = wbuf.to_vec();
+                    let index = Self::frame_index(&received);
+                    if self.corrupt_frame.get() == Some(index as usize) {
+                        self.corrupt_frame.set(None);
+                        *received.last_mut().unwrap() ^= 0x01;
+                    }
+                    let mut digest = Sha256::digest(&received[Frame::HASH_LEN..]);
+                    digest.reverse();
+                    // Frames are programmed in order, the ROM ignores any out of sequence.
+                    if received[..Frame::HASH_LEN] == digest[..]
+                        && index as usize == self.programmed.borrow().len()
+                    {
+                        self.programmed.borrow_mut().push(index);
+                    }
+                    *self.previous.borrow_mut() = Some(received);
+                    self.frames_received.set(self.frames_received.get() + 1);
+                    Ok(())
+                }
+                _ => panic!("Unexpected transaction"),
+            }
+        }
+        fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+            unimplemented!();
+        }
+    }
+
+    fn payload(frames: usize) -> Vec<u8> {
+        (0..frames * Frame::DATA_LEN).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_send_frames() -> Result<()> {
+        let rom = MockRom::new(None);
+        send_frames(
+            &rom,
+            &payload(3),
+            Duration::ZERO,
+            Duration::ZERO,
+            Some(3),
+            &|_, _| {},
+        )?;
+        assert_eq!(rom.frames_received.get(), 3);
+        assert_eq!(*rom.programmed.borrow(), vec![0, 1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_frames_corrupted() -> Result<()> {
+        let rom = MockRom::new(None);
+        rom.corrupt_frame.set(Some(1));
+        send_frames(
+            &rom,
+            &payload(3),
+            Duration::ZERO,
+            Duration::ZERO,
+            Some(3),
+            &|_, _| {},
+        )?;
+        // The ROM rejects the corrupted frame 1, and acknowledges it with the hash of the data
+        // actually received, which does not match, so it is sent again and then accepted.
+        // Frame 2, sent right after the corrupted frame, is ignored and sent again.
+        assert_eq!(rom.frames_received.get(), 5);
+        assert_eq!(*rom.programmed.borrow(), vec![0, 1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_frames_unacknowledged() {
+        let rom = MockRom::new(Some(1));
+        let err = send_frames(
+            &rom,
+            &payload(3),
+            Duration::ZERO,
+            Duration::ZERO,
+            Some(3),
+            &|_, _| {},
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BootstrapError>(),
+            Some(BootstrapError::FrameNotAcknowledged(1))
+        ));
+        // Frames 0 and 1, followed by three attempts to get frame 1 acknowledged, each by
+        // sending frame 2 after it, and going back to frame 1 in between.
+        assert_eq!(rom.frames_received.get(), 7);
+    }
 }
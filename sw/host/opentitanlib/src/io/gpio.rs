@@ -2,13 +2,20 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use structopt::clap::arg_enum;
 use thiserror::Error;
 
 use crate::impl_serializable_error;
 use crate::transport::TransportError;
+use crate::util::voltage::Voltage;
 
 /// Errors related to the GPIO interface.
 #[derive(Debug, Error, Serialize, Deserialize)]
@@ -35,6 +42,19 @@ pub enum GpioError {
     PinValueUndefined(String),
     #[error("Unsupported voltage {0}V requested")]
     UnsupportedPinVoltage(f32),
+    #[error("Unsupported timestamp resolution {0}, {1}")]
+    UnsupportedTimestampResolution(u64, String),
+    #[error("Monitoring buffer overrun, more than {0} events")]
+    MonitoringOverrun(usize),
+    #[error("Pattern of {0} levels too long, at most {1} supported")]
+    PatternTooLong(usize, usize),
+    #[error("Unsupported pattern clock {0}Hz, must be between {1}Hz and {2}Hz")]
+    UnsupportedPatternClock(u32, u32, u32),
+    #[error("Pattern generation not supported on pin {0}")]
+    UnsupportedPatternPin(String),
+    /// Analog output was requested on a pin known not to be in `AnalogOutput` mode.
+    #[error("Pin {0} is not in AnalogOutput mode")]
+    NotAnalogOutput(String),
     #[error("Generic error: {0}")]
     Generic(String),
 }
@@ -63,11 +83,101 @@ arg_enum! {
     }
 }
 
+arg_enum! {
+    /// Strength of the pull resistor, for hardware offering a choice.
+    #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+    pub enum PullStrength {
+        Weak,
+        Strong,
+    }
+}
+
+/// I/O voltage of the bank to which a GPIO pin belongs.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum VoltageDomain {
+    Fixed {
+        millivolts: u32,
+    },
+    Adjustable {
+        min_millivolts: u32,
+        max_millivolts: u32,
+    },
+}
+
+impl VoltageDomain {
+    /// Tolerance when comparing a requested voltage to that of a fixed domain.
+    const TOLERANCE_MILLIVOLTS: u32 = 10;
+
+    /// Verify that the domain can be operated at the given voltage.
+    pub fn check(&self, voltage: Voltage) -> Result<()> {
+        let millivolts = voltage.as_millivolts();
+        let ok = match *self {
+            VoltageDomain::Fixed { millivolts: fixed } => {
+                millivolts.abs_diff(fixed) <= Self::TOLERANCE_MILLIVOLTS
+            }
+            VoltageDomain::Adjustable {
+                min_millivolts,
+                max_millivolts,
+            } => (min_millivolts..=max_millivolts).contains(&millivolts),
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(GpioError::UnsupportedPinVoltage(voltage.as_volts() as f32).into())
+        }
+    }
+}
+
+/// Modes and features supported by a GPIO pin, as reported by `GpioPin::capabilities()`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GpioPinCapabilities {
+    pub modes: Vec<PinMode>,
+    pub pull_modes: Vec<PullMode>,
+    pub analog_read: bool,
+    pub analog_write: bool,
+    /// Voltage domain of the pin, `None` if not known to the transport.
+    pub voltage_domain: Option<VoltageDomain>,
+}
+
+impl GpioPinCapabilities {
+    pub fn supports_mode(&self, mode: PinMode) -> bool {
+        self.modes.contains(&mode)
+    }
+
+    pub fn supports_pull_mode(&self, mode: PullMode) -> bool {
+        self.pull_modes.contains(&mode)
+    }
+
+    /// Returns `mode` if supported, otherwise the supported mode closest in behavior, or `None`
+    /// if there is no acceptable substitute.  The only substitution is `PushPull` for
+    /// `OpenDrain`: it drives low in the same way, but also actively drives high rather than
+    /// leaving that to a pull-up, so it is only safe on lines without other drivers, and callers
+    /// should warn about it.  Analog and alternate functions have no digital equivalent, and
+    /// `OpenDrain` cannot drive high without a pull-up, so other modes are never substituted.
+    pub fn closest_mode(&self, mode: PinMode) -> Option<PinMode> {
+        if self.supports_mode(mode) {
+            return Some(mode);
+        }
+        match mode {
+            PinMode::OpenDrain if self.supports_mode(PinMode::PushPull) => Some(PinMode::PushPull),
+            _ => None,
+        }
+    }
+}
+
 /// A trait which represents a single GPIO pin.
 pub trait GpioPin {
     /// Reads the value of the the GPIO pin.
     fn read(&self) -> Result<bool>;
 
+    /// Reads the values of all of `pins`, which include this one, in fewer round trips than
+    /// separate `read()` calls.  Returns `None` if the transport cannot do so, e.g. because some
+    /// of the pins belong to another transport, see `read_multiple()`.  Wrappers, such as
+    /// tracing, forward this to the wrapped pin.
+    fn read_batch(&self, _pins: &[&dyn GpioPin]) -> Result<Option<Vec<bool>>> {
+        Ok(None)
+    }
+
     /// Sets the value of the GPIO pin to `value`.
     fn write(&self, value: bool) -> Result<()>;
 
@@ -77,6 +187,19 @@ pub trait GpioPin {
     /// Sets the weak pull resistors of the GPIO pin.
     fn set_pull_mode(&self, mode: PullMode) -> Result<()>;
 
+    /// Sets the pull resistors of the GPIO pin, with the given strength if any.  Transports
+    /// knowing the available strengths return `UnsupportedPullMode` for others, the default
+    /// implementation ignores the strength.
+    fn set_pull_mode_ex(&self, mode: PullMode, _strength: Option<PullStrength>) -> Result<()> {
+        self.set_pull_mode(mode)
+    }
+
+    /// Sets the output drive strength of the GPIO pin, e.g. lowered to reduce ringing on long
+    /// cables.  Returns `UnsupportedOperation` unless implemented by the transport.
+    fn set_drive_strength(&self, _milliamps: u32) -> Result<()> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
+
     /// Reads the analog value of the the GPIO pin in Volts. `AnalogInput` mode disables digital
     /// circuitry for better results, but this method may also work in other modes.
     fn analog_read(&self) -> Result<f32> {
@@ -88,6 +211,12 @@ pub trait GpioPin {
         Err(TransportError::UnsupportedOperation.into())
     }
 
+    /// Sets the I/O voltage of the domain to which the pin belongs, which may affect other pins
+    /// in the same domain.  Transports knowing the `VoltageDomain` reject incompatible voltages.
+    fn set_voltage(&self, _voltage: Voltage) -> Result<()> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
+
     /// Simultaneously sets mode, value, and weak pull, some transports may guarantee atomicity.
     fn set(
         &self,
@@ -113,6 +242,54 @@ pub trait GpioPin {
         Ok(())
     }
 
+    /// Returns the mode of the GPIO pin, if the transport is able to tell.
+    fn get_mode(&self) -> Result<PinMode> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
+
+    /// Returns the weak pull mode of the GPIO pin, if the transport is able to tell.
+    fn get_pull_mode(&self) -> Result<PullMode> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
+
+    /// Returns the modes and features supported by the pin, such that e.g. a user interface can
+    /// offer only valid choices, if the transport is able to tell.
+    fn capabilities(&self) -> Result<GpioPinCapabilities> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
+
+    /// Reads the pin every `interval` until `stop` is set, calling `callback` with the new level
+    /// each time it differs from the previous reading, for simple tests not warranting
+    /// `GpioMonitoring`.  Each reading takes at least a USB round trip, which bounds the
+    /// resolution, pulses shorter than that (or than `interval`) may go unnoticed.
+    fn poll_changes(
+        &self,
+        interval: Duration,
+        stop: Arc<AtomicBool>,
+        callback: &mut dyn FnMut(bool),
+    ) -> Result<()> {
+        let mut level = self.read()?;
+        let mut next_poll = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            next_poll += interval;
+            std::thread::sleep(next_poll.saturating_duration_since(Instant::now()));
+            let new_level = self.read()?;
+            if new_level != level {
+                callback(new_level);
+                level = new_level;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the supported mode closest to `mode`, or `None`, allowing configuration loaders
+    /// to adapt or warn before applying settings, see `GpioPinCapabilities::closest_mode()` for
+    /// the substitution rules.  Also `None` if the transport does not report the capabilities of
+    /// the pin, callers should then apply `mode` as given.
+    fn closest_supported(&self, mode: PinMode) -> Option<PinMode> {
+        self.capabilities().ok()?.closest_mode(mode)
+    }
+
     /// Not meant for API clients, this method returns the pin name as it is known to the
     /// transport (which may have been through one or more alias mappings from the name provided
     /// by the API client.)  This method is used by implementations of `GpioMonitoring`.
@@ -121,6 +298,84 @@ pub trait GpioPin {
     }
 }
 
+/// Reads the values of the given pins, returned in the same order, in a single round trip if the
+/// transport of the pins supports it, otherwise by reading each pin in turn.
+pub fn read_multiple(pins: &[&dyn GpioPin]) -> Result<Vec<bool>> {
+    let Some(first) = pins.first() else {
+        return Ok(Vec::new());
+    };
+    if let Some(levels) = first.read_batch(pins)? {
+        ensure!(
+            levels.len() == pins.len(),
+            GpioError::Generic(format!(
+                "Batched read returned {} values for {} pins",
+                levels.len(),
+                pins.len()
+            ))
+        );
+        return Ok(levels);
+    }
+    pins.iter().map(|pin| pin.read()).collect()
+}
+
+/// State of a single pin, as recorded in a `PinSnapshot`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PinState {
+    pub name: String,
+    pub mode: PinMode,
+    /// Level driven by the pin, only recorded for output modes.
+    pub value: Option<bool>,
+    pub pull: Option<PullMode>,
+}
+
+/// State of a set of pins, allowing a test to restore the pin configuration it started with.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PinSnapshot {
+    pub pins: Vec<PinState>,
+}
+
+impl PinSnapshot {
+    /// Records the state of each of the given pins.  Pins whose mode cannot be read back are
+    /// left out of the snapshot, with a warning.
+    pub fn capture(pins: &[(String, Rc<dyn GpioPin>)]) -> Self {
+        let mut snapshot = Self::default();
+        for (name, pin) in pins {
+            let mode = match pin.get_mode() {
+                Ok(mode) => mode,
+                Err(e) => {
+                    log::warn!("Not including pin {} in snapshot: {}", name, e);
+                    continue;
+                }
+            };
+            let value = match mode {
+                PinMode::PushPull | PinMode::OpenDrain => match pin.read() {
+                    Ok(value) => Some(value),
+                    Err(e) => {
+                        log::warn!("Not including level of pin {} in snapshot: {}", name, e);
+                        None
+                    }
+                },
+                _ => None,
+            };
+            snapshot.pins.push(PinState {
+                name: name.clone(),
+                mode,
+                value,
+                pull: pin.get_pull_mode().ok(),
+            });
+        }
+        snapshot
+    }
+
+    /// Reapplies the recorded state, using `lookup` to get hold of each pin by name.
+    pub fn restore(&self, lookup: impl Fn(&str) -> Result<Rc<dyn GpioPin>>) -> Result<()> {
+        for state in &self.pins {
+            lookup(&state.name)?.set(Some(state.mode), state.value, state.pull, None)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Edge {
     Rising,
@@ -142,11 +397,32 @@ pub enum ClockNature {
     Unspecified,
 }
 
+/// Verify that timestamps from a clock running at `clock_frequency` ticks per second can be
+/// converted to the given `resolution` by integer division, and return the divisor.
+pub fn timestamp_divisor(clock_frequency: u64, resolution: u64) -> Result<u64> {
+    if resolution == 0 || resolution > clock_frequency {
+        return Err(GpioError::UnsupportedTimestampResolution(
+            resolution,
+            format!("must be between 1 and {}", clock_frequency),
+        )
+        .into());
+    }
+    let divisor = clock_frequency / resolution;
+    if divisor * resolution != clock_frequency {
+        return Err(GpioError::UnsupportedTimestampResolution(
+            resolution,
+            format!("must evenly divide {}", clock_frequency),
+        )
+        .into());
+    }
+    Ok(divisor)
+}
+
 /// Represents an edge detected on the GPIO pin.
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct MonitoringEvent {
     /// Identification of the signal that had an event, in the form of an index into the array
-    /// originally passed to `monitoring_read()`.
+    /// of pins originally passed to `monitoring_start()`.
     pub signal_index: u8,
     /// Rising or falling edge
     pub edge: Edge,
@@ -155,8 +431,74 @@ pub struct MonitoringEvent {
     pub timestamp: u64,
 }
 
+/// A `MonitoringEvent` with the transport-internal name of its pin in place of the index, see
+/// `MonitoringSession::name_events()`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NamedMonitoringEvent {
+    pub pin_name: String,
+    pub edge: Edge,
+    pub timestamp: u64,
+}
+
+/// Handle identifying a single monitoring session, as returned by `monitoring_start()`.  A
+/// session is identified by the transport-internal names of the pins it monitors, such that an
+/// equivalent handle can be re-created later, e.g. by another invocation of opentitantool, using
+/// `MonitoringSession::from_pins()`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct MonitoringSession {
+    pin_names: Vec<String>,
+}
+
+impl MonitoringSession {
+    /// Identify the session monitoring the given list of pins (in that order).
+    pub fn from_pins(pins: &[&dyn GpioPin]) -> Result<Self> {
+        let mut pin_names = Vec::new();
+        for pin in pins {
+            pin_names.push(
+                pin.get_internal_pin_name()
+                    .ok_or(TransportError::InvalidOperation)?
+                    .to_string(),
+            );
+        }
+        Ok(Self { pin_names })
+    }
+
+    /// Transport-internal names of the pins monitored by this session.  The `signal_index` of
+    /// each `MonitoringEvent` is an index into this list.
+    pub fn pin_names(&self) -> &[String] {
+        &self.pin_names
+    }
+
+    /// Resolve the `signal_index` of each of `events`, read in this session, to the name of the
+    /// pin, such that the events can be logged without the original array of pins.
+    pub fn name_events(&self, events: &[MonitoringEvent]) -> Result<Vec<NamedMonitoringEvent>> {
+        events
+            .iter()
+            .map(|event| {
+                let pin_name =
+                    self.pin_names
+                        .get(event.signal_index as usize)
+                        .ok_or_else(|| {
+                            GpioError::Generic(format!(
+                                "Event for signal {} of session monitoring {} pins",
+                                event.signal_index,
+                                self.pin_names.len()
+                            ))
+                        })?;
+                Ok(NamedMonitoringEvent {
+                    pin_name: pin_name.clone(),
+                    edge: event.edge,
+                    timestamp: event.timestamp,
+                })
+            })
+            .collect()
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MonitoringStartResponse {
+    /// Handle to pass to `monitoring_read()` in order to retrieve events of this session.
+    pub session: MonitoringSession,
     /// Transport timestamp at the time monitoring started.
     pub timestamp: u64,
     /// Initial logic level for each of the given pins.
@@ -174,19 +516,897 @@ pub struct MonitoringReadResponse {
 /// A trait implemented by transports which support advanced edge-detection on GPIO pins.  This
 /// trait allows monitoring a set of pins, and getting a stream of "events" (rising and falling
 /// edges with timestamps) for any change among the set.
+///
+/// Several sessions, each monitoring a distinct set of pins, may be active at the same time, and
+/// events are buffered separately for each session.  A pin can be part of at most one active
+/// session, attempting to start a session including a pin which is already being monitored will
+/// result in an `Err`.
 pub trait GpioMonitoring {
     fn get_clock_nature(&self) -> Result<ClockNature>;
 
+    /// Change the number of timestamp units per second in which events are reported, as
+    /// subsequently given by `get_clock_nature()`.  This merely converts the timestamps reported
+    /// by the hardware, by integer division on the host, it affects neither the precision of the
+    /// edge detection nor how long events can be buffered.  Resolutions which do not evenly
+    /// divide the hardware clock are reported as an `Err`.
+    fn set_timestamp_resolution(&self, _resolution: u64) -> Result<()> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
+
     /// Set up edge trigger detection on the given set of pins, transport will buffer the list
-    /// internally, return the initial level of each of the given pins.
+    /// internally, return the initial level of each of the given pins, as well as a handle
+    /// identifying the new session.
     fn monitoring_start(&self, pins: &[&dyn GpioPin]) -> Result<MonitoringStartResponse>;
 
-    /// Retrieve list of events detected thus far, optionally stopping the possibly expensive edge
-    /// detection.  Buffer overrun will be reported as an `Err`, and result in the stopping of the
-    /// edge detection irrespective of the parameter value.
+    /// Retrieve list of events detected thus far in the given session, optionally stopping the
+    /// possibly expensive edge detection.  Buffer overrun will be reported as an `Err`, and result
+    /// in the stopping of the edge detection irrespective of the parameter value.
+    ///
+    /// The returned `timestamp` is a watermark: each response contains exactly the events later
+    /// than the watermark of the previous response, and at or before its own.  Concatenating the
+    /// events of successive reads therefore gives each edge exactly once, see
+    /// `MonitoringWatermarks`.  The final read, stopping the session, returns all remaining
+    /// events.
     fn monitoring_read(
         &self,
-        pins: &[&dyn GpioPin],
+        session: &MonitoringSession,
         continue_monitoring: bool,
     ) -> Result<MonitoringReadResponse>;
+
+    /// Temporarily halt edge detection in the given session, without discarding events captured
+    /// thus far, they will be returned by the next `monitoring_read()`.  Edges occurring while
+    /// the session is paused are not captured at all, the levels returned by
+    /// `monitoring_resume()` can be used to detect that a pin changed during the gap.
+    fn monitoring_pause(&self, _session: &MonitoringSession) -> Result<()> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
+
+    /// Restart edge detection in a session previously paused by `monitoring_pause()`.  Event
+    /// timestamps continue to use the same epoch as before the pause, the returned timestamp
+    /// marks the end of the gap in coverage, and the levels are those of the pins at that time.
+    fn monitoring_resume(&self, _session: &MonitoringSession) -> Result<MonitoringStartResponse> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
+}
+
+/// The watermark and held back events of a monitoring session, see `MonitoringWatermarks`.
+#[derive(Default)]
+struct Watermark {
+    timestamp: Option<u64>,
+    held: Vec<MonitoringEvent>,
+}
+
+/// Host-side bookkeeping giving `monitoring_read()` its watermark semantics, for transports
+/// whose raw reads may report an edge twice, or report edges later than the timestamp they
+/// return (such that a later edge on another pin could still be reported by the next read).
+/// Events at or before the previous watermark have already been returned, and are dropped,
+/// events later than the current watermark are held back until the next read.
+#[derive(Default)]
+pub struct MonitoringWatermarks {
+    sessions: RefCell<HashMap<MonitoringSession, Watermark>>,
+}
+
+impl MonitoringWatermarks {
+    /// Forget any state of a previous session on the same pins, to be called by implementations
+    /// of `monitoring_start()`.
+    pub fn start(&self, session: &MonitoringSession) {
+        self.sessions.borrow_mut().remove(session);
+    }
+
+    /// Filter the response of a raw read of the transport.
+    pub fn apply(
+        &self,
+        session: &MonitoringSession,
+        resp: MonitoringReadResponse,
+        continue_monitoring: bool,
+    ) -> MonitoringReadResponse {
+        let mut sessions = self.sessions.borrow_mut();
+        let mut watermark = sessions.remove(session).unwrap_or_default();
+        let mut events = std::mem::take(&mut watermark.held);
+        for event in resp.events {
+            let seen = matches!(watermark.timestamp, Some(ts) if event.timestamp <= ts);
+            if seen || events.contains(&event) {
+                continue;
+            }
+            events.push(event);
+        }
+        events.sort_by_key(|event| event.timestamp);
+        if !continue_monitoring {
+            let timestamp = events
+                .last()
+                .map_or(resp.timestamp, |event| event.timestamp.max(resp.timestamp));
+            return MonitoringReadResponse { events, timestamp };
+        }
+        // The watermark never moves backwards.
+        let timestamp = watermark
+            .timestamp
+            .map_or(resp.timestamp, |ts| ts.max(resp.timestamp));
+        let split = events.partition_point(|event| event.timestamp <= timestamp);
+        let held = events.split_off(split);
+        sessions.insert(
+            session.clone(),
+            Watermark {
+                timestamp: Some(timestamp),
+                held,
+            },
+        );
+        MonitoringReadResponse { events, timestamp }
+    }
+}
+
+/// Events read out of the transport at the time a session was paused, and not yet retrieved
+/// by `monitoring_read()`.
+struct HeldEvents {
+    events: Vec<MonitoringEvent>,
+    timestamp: u64,
+    paused: bool,
+}
+
+/// Host-side bookkeeping for transports which implement `monitoring_pause()` by reading out
+/// and stopping edge detection, and `monitoring_resume()` by starting it anew.  Events read at
+/// the time of pausing are held here, and prepended to the result of the next read.
+#[derive(Default)]
+pub struct PausedMonitoring {
+    sessions: RefCell<HashMap<MonitoringSession, HeldEvents>>,
+}
+
+impl PausedMonitoring {
+    /// Verify that none of the pins of `session` is part of a paused session, intended to be
+    /// called by implementations of `monitoring_start()`, since the transport itself considers
+    /// those pins to be free.
+    pub fn check_start(&self, session: &MonitoringSession) -> Result<()> {
+        let sessions = self.sessions.borrow();
+        for (held, state) in sessions.iter() {
+            if state.paused
+                && session
+                    .pin_names()
+                    .iter()
+                    .any(|p| held.pin_names().contains(p))
+            {
+                return Err(TransportError::InvalidOperation.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Pause `session`, `stop` must read out pending events and stop edge detection in the
+    /// transport.
+    pub fn pause(
+        &self,
+        session: &MonitoringSession,
+        stop: impl FnOnce() -> Result<MonitoringReadResponse>,
+    ) -> Result<()> {
+        if self.is_paused(session) {
+            return Err(TransportError::InvalidOperation.into());
+        }
+        let resp = stop()?;
+        let mut sessions = self.sessions.borrow_mut();
+        let held = sessions.entry(session.clone()).or_insert(HeldEvents {
+            events: Vec::new(),
+            timestamp: 0,
+            paused: true,
+        });
+        held.events.extend(resp.events);
+        held.timestamp = resp.timestamp;
+        held.paused = true;
+        Ok(())
+    }
+
+    /// Resume `session`, `start` must restart edge detection in the transport.
+    pub fn resume(
+        &self,
+        session: &MonitoringSession,
+        start: impl FnOnce() -> Result<MonitoringStartResponse>,
+    ) -> Result<MonitoringStartResponse> {
+        if !self.is_paused(session) {
+            return Err(TransportError::InvalidOperation.into());
+        }
+        let resp = start()?;
+        if let Some(held) = self.sessions.borrow_mut().get_mut(session) {
+            held.paused = false;
+        }
+        Ok(resp)
+    }
+
+    /// Retrieve events of `session`, `read` is invoked to read from the transport unless the
+    /// session is paused.
+    pub fn read(
+        &self,
+        session: &MonitoringSession,
+        continue_monitoring: bool,
+        read: impl FnOnce(bool) -> Result<MonitoringReadResponse>,
+    ) -> Result<MonitoringReadResponse> {
+        let held = self.sessions.borrow_mut().remove(session);
+        match held {
+            Some(held) if held.paused => {
+                if continue_monitoring {
+                    self.sessions.borrow_mut().insert(
+                        session.clone(),
+                        HeldEvents {
+                            events: Vec::new(),
+                            timestamp: held.timestamp,
+                            paused: true,
+                        },
+                    );
+                }
+                Ok(MonitoringReadResponse {
+                    events: held.events,
+                    timestamp: held.timestamp,
+                })
+            }
+            held => {
+                let mut resp = read(continue_monitoring)?;
+                if let Some(mut held) = held {
+                    held.events.append(&mut resp.events);
+                    resp.events = held.events;
+                }
+                Ok(resp)
+            }
+        }
+    }
+
+    fn is_paused(&self, session: &MonitoringSession) -> bool {
+        self.sessions
+            .borrow()
+            .get(session)
+            .map(|held| held.paused)
+            .unwrap_or(false)
+    }
+}
+
+/// A trait implemented by transports which can emit a clocked sequence of levels on a GPIO pin
+/// in hardware.  This gives much more precise timing than repeatedly calling
+/// `GpioPin::write()`, which is subject to USB and host scheduling latency.
+pub trait GpioPatternGenerator {
+    /// Maximum number of levels which can be given to a single call to `output_pattern()`.
+    fn max_pattern_length(&self) -> Result<usize>;
+
+    /// Drive `pin` through the levels in `pattern`, holding each for one period of `clock_hz`.
+    /// Returns when the entire pattern has been emitted.
+    fn output_pattern(&self, pin: &dyn GpioPin, pattern: &[bool], clock_hz: u32) -> Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct FakePin(&'static str);
+
+    impl GpioPin for FakePin {
+        fn read(&self) -> Result<bool> {
+            Ok(false)
+        }
+        fn write(&self, _value: bool) -> Result<()> {
+            Ok(())
+        }
+        fn set_mode(&self, _mode: PinMode) -> Result<()> {
+            Ok(())
+        }
+        fn set_pull_mode(&self, _mode: PullMode) -> Result<()> {
+            Ok(())
+        }
+        fn get_internal_pin_name(&self) -> Option<&str> {
+            Some(self.0)
+        }
+    }
+
+    /// Pin reporting fixed capabilities.
+    struct CapablePin(GpioPinCapabilities);
+
+    impl GpioPin for CapablePin {
+        fn read(&self) -> Result<bool> {
+            Ok(false)
+        }
+        fn write(&self, _value: bool) -> Result<()> {
+            Ok(())
+        }
+        fn set_mode(&self, _mode: PinMode) -> Result<()> {
+            Ok(())
+        }
+        fn set_pull_mode(&self, _mode: PullMode) -> Result<()> {
+            Ok(())
+        }
+        fn capabilities(&self) -> Result<GpioPinCapabilities> {
+            Ok(self.0.clone())
+        }
+    }
+
+    /// Pin which remembers its configuration.
+    struct StatefulPin {
+        mode: Cell<PinMode>,
+        value: Cell<bool>,
+        pull: Cell<PullMode>,
+    }
+
+    impl StatefulPin {
+        fn new(mode: PinMode, value: bool, pull: PullMode) -> Rc<Self> {
+            Rc::new(Self {
+                mode: Cell::new(mode),
+                value: Cell::new(value),
+                pull: Cell::new(pull),
+            })
+        }
+    }
+
+    impl GpioPin for StatefulPin {
+        fn read(&self) -> Result<bool> {
+            Ok(self.value.get())
+        }
+        fn write(&self, value: bool) -> Result<()> {
+            self.value.set(value);
+            Ok(())
+        }
+        fn set_mode(&self, mode: PinMode) -> Result<()> {
+            self.mode.set(mode);
+            Ok(())
+        }
+        fn set_pull_mode(&self, mode: PullMode) -> Result<()> {
+            self.pull.set(mode);
+            Ok(())
+        }
+        fn get_mode(&self) -> Result<PinMode> {
+            Ok(self.mode.get())
+        }
+        fn get_pull_mode(&self) -> Result<PullMode> {
+            Ok(self.pull.get())
+        }
+    }
+
+    /// Keeps a separate event buffer for each session, events are injected by the test.
+    #[derive(Default)]
+    struct FakeMonitoring {
+        sessions: RefCell<HashMap<MonitoringSession, Vec<MonitoringEvent>>>,
+        /// Divisor applied to injected timestamps, `None` if no resolution has been set.
+        divisor: Cell<Option<u64>>,
+        /// Timestamp of the most recently injected event.
+        now: Cell<u64>,
+        paused: PausedMonitoring,
+    }
+
+    impl FakeMonitoring {
+        /// Ticks per second of the clock used for injected timestamps.
+        const CLOCK_FREQUENCY: u64 = 1_000_000;
+
+        fn inject(&self, pin: &str, edge: Edge, timestamp: u64) {
+            let timestamp = timestamp / self.divisor.get().unwrap_or(1);
+            self.now.set(timestamp);
+            for (session, events) in self.sessions.borrow_mut().iter_mut() {
+                if let Some(idx) = session.pin_names().iter().position(|p| p == pin) {
+                    events.push(MonitoringEvent {
+                        signal_index: idx as u8,
+                        edge,
+                        timestamp,
+                    });
+                }
+            }
+        }
+
+        fn start(&self, session: &MonitoringSession) -> Result<MonitoringStartResponse> {
+            let mut sessions = self.sessions.borrow_mut();
+            for active in sessions.keys() {
+                if session
+                    .pin_names()
+                    .iter()
+                    .any(|p| active.pin_names().contains(p))
+                {
+                    return Err(TransportError::InvalidOperation.into());
+                }
+            }
+            sessions.insert(session.clone(), Vec::new());
+            Ok(MonitoringStartResponse {
+                session: session.clone(),
+                timestamp: self.now.get(),
+                initial_levels: vec![false; session.pin_names().len()],
+            })
+        }
+
+        fn read(
+            &self,
+            session: &MonitoringSession,
+            continue_monitoring: bool,
+        ) -> Result<MonitoringReadResponse> {
+            let mut sessions = self.sessions.borrow_mut();
+            let events = if continue_monitoring {
+                std::mem::take(
+                    sessions
+                        .get_mut(session)
+                        .ok_or(TransportError::InvalidOperation)?,
+                )
+            } else {
+                sessions
+                    .remove(session)
+                    .ok_or(TransportError::InvalidOperation)?
+            };
+            Ok(MonitoringReadResponse {
+                events,
+                timestamp: self.now.get(),
+            })
+        }
+    }
+
+    impl GpioMonitoring for FakeMonitoring {
+        fn get_clock_nature(&self) -> Result<ClockNature> {
+            Ok(match self.divisor.get() {
+                Some(divisor) => ClockNature::Wallclock {
+                    resolution: Self::CLOCK_FREQUENCY / divisor,
+                    offset: None,
+                },
+                None => ClockNature::Unspecified,
+            })
+        }
+
+        fn set_timestamp_resolution(&self, resolution: u64) -> Result<()> {
+            self.divisor
+                .set(Some(timestamp_divisor(Self::CLOCK_FREQUENCY, resolution)?));
+            Ok(())
+        }
+
+        fn monitoring_start(&self, pins: &[&dyn GpioPin]) -> Result<MonitoringStartResponse> {
+            let session = MonitoringSession::from_pins(pins)?;
+            self.paused.check_start(&session)?;
+            self.start(&session)
+        }
+
+        fn monitoring_read(
+            &self,
+            session: &MonitoringSession,
+            continue_monitoring: bool,
+        ) -> Result<MonitoringReadResponse> {
+            self.paused.read(session, continue_monitoring, |cont| {
+                self.read(session, cont)
+            })
+        }
+
+        fn monitoring_pause(&self, session: &MonitoringSession) -> Result<()> {
+            self.paused.pause(session, || self.read(session, false))
+        }
+
+        fn monitoring_resume(
+            &self,
+            session: &MonitoringSession,
+        ) -> Result<MonitoringStartResponse> {
+            self.paused.resume(session, || self.start(session))
+        }
+    }
+
+    #[test]
+    fn test_independent_sessions() -> Result<()> {
+        let monitoring = FakeMonitoring::default();
+        let first = monitoring.monitoring_start(&[&FakePin("A"), &FakePin("B")])?;
+        let second = monitoring.monitoring_start(&[&FakePin("C")])?;
+        assert_ne!(first.session, second.session);
+        // Pin "B" is already part of the first session.
+        assert!(monitoring
+            .monitoring_start(&[&FakePin("B"), &FakePin("D")])
+            .is_err());
+
+        monitoring.inject("B", Edge::Rising, 10);
+        monitoring.inject("C", Edge::Falling, 20);
+        monitoring.inject("A", Edge::Rising, 30);
+
+        let resp = monitoring.monitoring_read(&first.session, true)?;
+        assert_eq!(
+            resp.events
+                .iter()
+                .map(|e| (e.signal_index, e.edge, e.timestamp))
+                .collect::<Vec<_>>(),
+            vec![(1, Edge::Rising, 10), (0, Edge::Rising, 30)]
+        );
+
+        // Stopping the second session leaves the first one running.
+        let resp = monitoring.monitoring_read(&second.session, false)?;
+        assert_eq!(resp.events.len(), 1);
+        assert_eq!(resp.events[0].signal_index, 0);
+        assert_eq!(resp.events[0].edge, Edge::Falling);
+        assert!(monitoring.monitoring_read(&second.session, true).is_err());
+
+        monitoring.inject("A", Edge::Falling, 40);
+        // A session handle can be re-created from the list of pins.
+        let session = MonitoringSession::from_pins(&[&FakePin("A"), &FakePin("B")])?;
+        let resp = monitoring.monitoring_read(&session, false)?;
+        assert_eq!(resp.events.len(), 1);
+        assert_eq!(resp.events[0].timestamp, 40);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_timestamp_resolution() -> Result<()> {
+        let monitoring = FakeMonitoring::default();
+        assert_eq!(monitoring.get_clock_nature()?, ClockNature::Unspecified);
+
+        monitoring.set_timestamp_resolution(1000)?;
+        assert_eq!(
+            monitoring.get_clock_nature()?,
+            ClockNature::Wallclock {
+                resolution: 1000,
+                offset: None
+            }
+        );
+        let start = monitoring.monitoring_start(&[&FakePin("A")])?;
+        // Edge 2.5ms after the epoch, reported in milliseconds.
+        monitoring.inject("A", Edge::Rising, 2_500);
+        let resp = monitoring.monitoring_read(&start.session, false)?;
+        assert_eq!(resp.events[0].timestamp, 2);
+
+        for resolution in [0, 3, 2_000_000] {
+            let err = monitoring.set_timestamp_resolution(resolution).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<GpioError>(),
+                Some(GpioError::UnsupportedTimestampResolution(r, _)) if *r == resolution
+            ));
+        }
+        // The previous resolution remains in effect.
+        assert!(matches!(
+            monitoring.get_clock_nature()?,
+            ClockNature::Wallclock {
+                resolution: 1000,
+                ..
+            }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_name_events() -> Result<()> {
+        let pins = [FakePin("IOA0"), FakePin("IOA1"), FakePin("IOB2")];
+        let session = MonitoringSession::from_pins(&[&pins[0], &pins[1], &pins[2]])?;
+        let events = [
+            MonitoringEvent {
+                signal_index: 2,
+                edge: Edge::Rising,
+                timestamp: 10,
+            },
+            MonitoringEvent {
+                signal_index: 0,
+                edge: Edge::Falling,
+                timestamp: 20,
+            },
+        ];
+        let named = session.name_events(&events)?;
+        for (event, named) in events.iter().zip(&named) {
+            assert_eq!(
+                Some(named.pin_name.as_str()),
+                pins[event.signal_index as usize].get_internal_pin_name()
+            );
+            assert_eq!(named.edge, event.edge);
+            assert_eq!(named.timestamp, event.timestamp);
+        }
+
+        let unknown = MonitoringEvent {
+            signal_index: 3,
+            edge: Edge::Rising,
+            timestamp: 30,
+        };
+        assert!(session.name_events(&[unknown]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_monitoring_watermarks() -> Result<()> {
+        let session = MonitoringSession::from_pins(&[&FakePin("A"), &FakePin("B")])?;
+        let event = |signal_index, timestamp| MonitoringEvent {
+            signal_index,
+            edge: Edge::Rising,
+            timestamp,
+        };
+        let watermarks = MonitoringWatermarks::default();
+        watermarks.start(&session);
+
+        // The raw read reports an edge later than its timestamp, it is held back.
+        let first = watermarks.apply(
+            &session,
+            MonitoringReadResponse {
+                events: vec![event(0, 10), event(1, 20), event(0, 30)],
+                timestamp: 25,
+            },
+            true,
+        );
+        assert_eq!(first.events, vec![event(0, 10), event(1, 20)]);
+        assert_eq!(first.timestamp, 25);
+
+        // The next raw read repeats edges on both sides of the previous watermark, and includes
+        // an edge which happened before the held back one.
+        let second = watermarks.apply(
+            &session,
+            MonitoringReadResponse {
+                events: vec![event(1, 20), event(1, 28), event(0, 30), event(1, 40)],
+                timestamp: 50,
+            },
+            true,
+        );
+        assert_eq!(
+            second.events,
+            vec![event(1, 28), event(0, 30), event(1, 40)]
+        );
+        assert_eq!(second.timestamp, 50);
+
+        // Stopping returns everything.
+        let last = watermarks.apply(
+            &session,
+            MonitoringReadResponse {
+                events: vec![event(0, 60)],
+                timestamp: 55,
+            },
+            false,
+        );
+        assert_eq!(last.events, vec![event(0, 60)]);
+        assert_eq!(last.timestamp, 60);
+
+        let all: Vec<u64> = [first, second, last]
+            .iter()
+            .flat_map(|resp| resp.events.iter().map(|e| e.timestamp))
+            .collect();
+        assert_eq!(all, vec![10, 20, 28, 30, 40, 60]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_monitoring_pause() -> Result<()> {
+        let monitoring = FakeMonitoring::default();
+        let start = monitoring.monitoring_start(&[&FakePin("A"), &FakePin("B")])?;
+        let session = start.session;
+        // Resuming a session which is not paused is an error.
+        assert!(monitoring.monitoring_resume(&session).is_err());
+
+        monitoring.inject("A", Edge::Rising, 10);
+        monitoring.monitoring_pause(&session)?;
+        assert!(monitoring.monitoring_pause(&session).is_err());
+        // Edges during the pause are not captured, and the pins remain reserved.
+        monitoring.inject("B", Edge::Rising, 20);
+        assert!(monitoring.monitoring_start(&[&FakePin("B")]).is_err());
+
+        // Events captured before pausing can be read without resuming.
+        let resp = monitoring.monitoring_read(&session, true)?;
+        assert_eq!(resp.events.len(), 1);
+        assert_eq!(resp.timestamp, 10);
+
+        monitoring.inject("A", Edge::Falling, 30);
+        let resume = monitoring.monitoring_resume(&session)?;
+        assert_eq!(resume.timestamp, 30);
+        monitoring.inject("A", Edge::Rising, 40);
+        monitoring.monitoring_pause(&session)?;
+        monitoring.monitoring_resume(&session)?;
+        monitoring.inject("B", Edge::Falling, 50);
+
+        // Events from before and after the second pause are returned together, using the same
+        // clock as before.
+        let resp = monitoring.monitoring_read(&session, false)?;
+        assert_eq!(
+            resp.events
+                .iter()
+                .map(|e| (e.signal_index, e.edge, e.timestamp))
+                .collect::<Vec<_>>(),
+            vec![(0, Edge::Rising, 40), (1, Edge::Falling, 50)]
+        );
+        assert!(monitoring.monitoring_read(&session, true).is_err());
+        monitoring.monitoring_start(&[&FakePin("B")])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_snapshot() -> Result<()> {
+        let reset = StatefulPin::new(PinMode::PushPull, true, PullMode::None);
+        let strap = StatefulPin::new(PinMode::OpenDrain, false, PullMode::PullUp);
+        let input = StatefulPin::new(PinMode::Input, true, PullMode::PullDown);
+        let pins: Vec<(String, Rc<dyn GpioPin>)> = vec![
+            ("RESET".to_string(), reset.clone()),
+            ("STRAP".to_string(), strap.clone()),
+            ("INPUT".to_string(), input.clone()),
+            // Mode cannot be read back, will be skipped.
+            ("OPAQUE".to_string(), Rc::new(FakePin("OPAQUE"))),
+        ];
+        let snapshot = PinSnapshot::capture(&pins);
+        assert_eq!(snapshot.pins.len(), 3);
+        assert_eq!(snapshot.pins[2].value, None);
+
+        reset.write(false)?;
+        strap.set(
+            Some(PinMode::PushPull),
+            Some(true),
+            Some(PullMode::None),
+            None,
+        )?;
+        input.set_pull_mode(PullMode::None)?;
+
+        snapshot.restore(|name| {
+            pins.iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, p)| Rc::clone(p))
+                .ok_or_else(|| GpioError::InvalidPinName(name.to_string()).into())
+        })?;
+        assert_eq!(reset.mode.get(), PinMode::PushPull);
+        assert!(reset.value.get());
+        assert_eq!(strap.mode.get(), PinMode::OpenDrain);
+        assert!(!strap.value.get());
+        assert_eq!(strap.pull.get(), PullMode::PullUp);
+        assert_eq!(input.mode.get(), PinMode::Input);
+        assert_eq!(input.pull.get(), PullMode::PullDown);
+        Ok(())
+    }
+
+    /// Pin reporting a scripted sequence of levels, requesting polling to stop after the last.
+    struct ScriptedPin {
+        levels: RefCell<Vec<bool>>,
+        stop: Arc<AtomicBool>,
+    }
+
+    impl GpioPin for ScriptedPin {
+        fn read(&self) -> Result<bool> {
+            let mut levels = self.levels.borrow_mut();
+            let level = levels.remove(0);
+            if levels.is_empty() {
+                self.stop.store(true, Ordering::Relaxed);
+            }
+            Ok(level)
+        }
+        fn write(&self, _value: bool) -> Result<()> {
+            Ok(())
+        }
+        fn set_mode(&self, _mode: PinMode) -> Result<()> {
+            Ok(())
+        }
+        fn set_pull_mode(&self, _mode: PullMode) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_poll_changes() -> Result<()> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let pin = ScriptedPin {
+            levels: RefCell::new(vec![false, false, true, true, true, false, true, true]),
+            stop: Arc::clone(&stop),
+        };
+        let mut changes = Vec::new();
+        pin.poll_changes(Duration::from_millis(1), stop, &mut |level| {
+            changes.push(level)
+        })?;
+        assert_eq!(changes, vec![true, false, true]);
+        Ok(())
+    }
+
+    /// Pin whose transport reads every pin named in a single batch, with pins named "HIGH*"
+    /// being high.  Individual reads are not expected.
+    struct BatchPin(&'static str);
+
+    impl GpioPin for BatchPin {
+        fn read(&self) -> Result<bool> {
+            panic!("Unbatched read of {}", self.0);
+        }
+        fn read_batch(&self, pins: &[&dyn GpioPin]) -> Result<Option<Vec<bool>>> {
+            Ok(pins
+                .iter()
+                .map(|pin| pin.get_internal_pin_name().map(|n| n.starts_with("HIGH")))
+                .collect())
+        }
+        fn write(&self, _value: bool) -> Result<()> {
+            Ok(())
+        }
+        fn set_mode(&self, _mode: PinMode) -> Result<()> {
+            Ok(())
+        }
+        fn set_pull_mode(&self, _mode: PullMode) -> Result<()> {
+            Ok(())
+        }
+        fn get_internal_pin_name(&self) -> Option<&str> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn test_read_multiple() -> Result<()> {
+        assert!(read_multiple(&[])?.is_empty());
+
+        let (a, b, c) = (BatchPin("HIGH_A"), BatchPin("LOW_B"), BatchPin("HIGH_C"));
+        assert_eq!(
+            read_multiple(&[&b, &a, &c, &b])?,
+            vec![false, true, true, false]
+        );
+
+        // Falls back to individual reads, keeping the order of the pins.
+        let low = StatefulPin::new(PinMode::Input, false, PullMode::None);
+        let high = StatefulPin::new(PinMode::Input, true, PullMode::None);
+        assert_eq!(
+            read_multiple(&[&*high, &*low, &*high])?,
+            vec![true, false, true]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_capabilities() -> Result<()> {
+        let analog = CapablePin(GpioPinCapabilities {
+            modes: vec![PinMode::Input, PinMode::AnalogInput],
+            pull_modes: vec![PullMode::None],
+            analog_read: true,
+            analog_write: false,
+            voltage_domain: None,
+        });
+        let caps = analog.capabilities()?;
+        assert!(caps.supports_mode(PinMode::AnalogInput));
+        assert!(caps.analog_read);
+        assert_eq!(
+            analog.closest_supported(PinMode::AnalogInput),
+            Some(PinMode::AnalogInput)
+        );
+
+        let digital = CapablePin(GpioPinCapabilities {
+            modes: vec![PinMode::Input, PinMode::PushPull],
+            pull_modes: vec![PullMode::None, PullMode::PullUp],
+            analog_read: false,
+            analog_write: false,
+            voltage_domain: None,
+        });
+        let caps = digital.capabilities()?;
+        assert!(!caps.supports_mode(PinMode::AnalogInput));
+        assert!(!caps.analog_read);
+        assert_eq!(digital.closest_supported(PinMode::AnalogInput), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_closest_supported() {
+        let digital = GpioPinCapabilities {
+            modes: vec![PinMode::Input, PinMode::PushPull],
+            pull_modes: vec![PullMode::None],
+            analog_read: false,
+            analog_write: false,
+            voltage_domain: None,
+        };
+        assert_eq!(digital.closest_mode(PinMode::Input), Some(PinMode::Input));
+        assert_eq!(
+            digital.closest_mode(PinMode::PushPull),
+            Some(PinMode::PushPull)
+        );
+        assert_eq!(
+            digital.closest_mode(PinMode::OpenDrain),
+            Some(PinMode::PushPull)
+        );
+        assert_eq!(digital.closest_mode(PinMode::AnalogInput), None);
+        assert_eq!(digital.closest_mode(PinMode::Alternate), None);
+
+        // Capabilities unknown to the transport, no mode is offered in place of another.
+        let pin = FakePin("A");
+        assert!(pin.capabilities().is_err());
+        assert_eq!(pin.closest_supported(PinMode::Input), None);
+        assert_eq!(pin.closest_supported(PinMode::OpenDrain), None);
+
+        // An input-only pin has no substitute for `OpenDrain`.
+        let caps = GpioPinCapabilities {
+            modes: vec![PinMode::Input],
+            ..digital.clone()
+        };
+        assert_eq!(caps.closest_mode(PinMode::OpenDrain), None);
+        assert_eq!(caps.closest_mode(PinMode::PushPull), None);
+
+        let caps = GpioPinCapabilities {
+            modes: vec![PinMode::Input, PinMode::OpenDrain],
+            ..digital
+        };
+        assert_eq!(
+            caps.closest_mode(PinMode::OpenDrain),
+            Some(PinMode::OpenDrain)
+        );
+        assert_eq!(caps.closest_mode(PinMode::PushPull), None);
+    }
+
+    #[test]
+    fn test_voltage_domain_check() -> Result<()> {
+        let domain = VoltageDomain::Fixed { millivolts: 1800 };
+        domain.check(Voltage(1.8))?;
+        let err = domain.check(Voltage(3.3)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GpioError>(),
+            Some(GpioError::UnsupportedPinVoltage(_))
+        ));
+
+        let domain = VoltageDomain::Adjustable {
+            min_millivolts: 1200,
+            max_millivolts: 3300,
+        };
+        domain.check(Voltage(1.8))?;
+        assert!(domain.check(Voltage(5.0)).is_err());
+        Ok(())
+    }
 }
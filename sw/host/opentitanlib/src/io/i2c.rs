@@ -33,6 +33,8 @@ pub enum I2cError {
     Timeout,
     #[error("Bus busy")]
     Busy,
+    #[error("No acknowledge from device {0:#04x}")]
+    Nack(u8),
     #[error("Generic error {0}")]
     Generic(String),
 }
@@ -2,16 +2,19 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
 use serde::{Deserialize, Serialize};
 use std::rc::Rc;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 use thiserror::Error;
 
 use super::eeprom;
+use super::gpio::GpioPin;
 use crate::app::TransportWrapper;
 use crate::impl_serializable_error;
+use crate::transport::TransportError;
 use crate::util::voltage::Voltage;
 
 #[derive(Clone, Debug, StructOpt, Serialize, Deserialize)]
@@ -27,6 +30,13 @@ pub struct SpiParams {
 
     #[structopt(long, help = "SPI polarity/phase mode", parse(try_from_str = TransferMode::from_str))]
     pub mode: Option<TransferMode>,
+
+    #[structopt(
+        long,
+        help = "Flag reads of all 0x00 or 0xFF as probable bus contention (warn or error)",
+        parse(try_from_str = FloatingDetection::from_str)
+    )]
+    pub detect_floating: Option<FloatingDetection>,
 }
 
 impl SpiParams {
@@ -45,6 +55,9 @@ impl SpiParams {
         if let Some(mode) = self.mode {
             spi.set_transfer_mode(mode)?;
         }
+        if let Some(detection) = self.detect_floating {
+            return Ok(Rc::new(FloatingDetectTarget::new(spi, detection)));
+        }
         Ok(spi)
     }
 }
@@ -64,13 +77,17 @@ pub enum SpiError {
     MismatchedDataLength(usize, usize),
     #[error("Invalid transfer mode: {0}")]
     InvalidTransferMode(String),
+    #[error("Read {0} bytes of all {1:#04x}, probable bus contention or absent device")]
+    BusContention(usize, u8),
+    #[error("SPI mux control pin {0} did not take the level selecting instance {1}")]
+    MuxNotSelected(String, String),
 }
 impl_serializable_error!(SpiError);
 
 /// Represents the SPI transfer mode.
 /// See https://en.wikipedia.org/wiki/Serial_Peripheral_Interface#Clock_polarity_and_phase
 /// for details about SPI transfer modes.
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum TransferMode {
     /// `Mode0` is CPOL=0, CPHA=0.
     Mode0,
@@ -120,6 +137,15 @@ impl TransferMode {
             TransferMode::Mode2 | TransferMode::Mode3 => ClockPolarity::IdleHigh,
         }
     }
+    /// The mode with the same clock phase as this one, and the given polarity.
+    pub fn with_polarity(&self, polarity: ClockPolarity) -> TransferMode {
+        match (self.phase(), polarity) {
+            (ClockPhase::SampleLeading, ClockPolarity::IdleLow) => TransferMode::Mode0,
+            (ClockPhase::SampleTrailing, ClockPolarity::IdleLow) => TransferMode::Mode1,
+            (ClockPhase::SampleLeading, ClockPolarity::IdleHigh) => TransferMode::Mode2,
+            (ClockPhase::SampleTrailing, ClockPolarity::IdleHigh) => TransferMode::Mode3,
+        }
+    }
 }
 
 /// Represents maximum allowed read or write operation in bytes.
@@ -134,6 +160,40 @@ pub enum Transfer<'rd, 'wr> {
     Read(&'rd mut [u8]),
     Write(&'wr [u8]),
     Both(&'wr [u8], &'rd mut [u8]),
+    /// Writes `count` bytes of `value`, without the caller having to allocate a buffer of that
+    /// size, e.g. for filling large flash regions.
+    Fill {
+        value: u8,
+        count: usize,
+    },
+    /// Changes the clock frequency for the remaining transfers, without deasserting CS.  The new
+    /// speed remains in effect after the transaction, as if set by `Target::set_max_speed()`.
+    /// Transports which cannot change speed while CS is asserted return `UnsupportedOperation`.
+    SetSpeed(u32),
+    /// Marks the point between a `Write` and a following `Read` where, in 3-wire (half-duplex)
+    /// mode, the shared data line changes direction.  A no-op on 4-wire targets, which have
+    /// separate data lines for each direction.
+    Turnaround,
+}
+
+/// Calls `write` with consecutive chunks of at most `chunk_size` bytes, together making up
+/// `count` bytes of `value`, reusing a single buffer.  Used by transports to implement
+/// `Transfer::Fill`.
+pub fn fill_chunks(
+    value: u8,
+    count: usize,
+    chunk_size: usize,
+    mut write: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    ensure!(chunk_size > 0, SpiError::InvalidDataLength(chunk_size));
+    let buf = vec![value; std::cmp::min(count, chunk_size)];
+    let mut remaining = count;
+    while remaining > 0 {
+        let len = std::cmp::min(remaining, buf.len());
+        write(&buf[..len])?;
+        remaining -= len;
+    }
+    Ok(())
 }
 
 /// A trait which represents a SPI Target.
@@ -152,6 +212,11 @@ pub trait Target {
     fn get_max_speed(&self) -> Result<u32>;
     /// Sets the maximum allowed speed of the SPI bus.
     fn set_max_speed(&self, max_speed: u32) -> Result<()>;
+    /// Gets the highest speed the hardware supports, the upper bound of valid arguments to
+    /// `set_max_speed()`, as opposed to the speed currently set.
+    fn get_hardware_max_speed(&self) -> Result<u32> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
 
     /// Returns the maximum number of transfers allowed in a single transaction.
     fn get_max_transfer_count(&self) -> Result<usize>;
@@ -163,10 +228,84 @@ pub trait Target {
         Err(SpiError::InvalidOption("This target does not support set_voltage".to_string()).into())
     }
 
+    /// Name of the GPIO pin serving as CS of this target, if the transport could also hand out
+    /// that pin as a `GpioPin`.  Driving the pin as GPIO would corrupt SPI transactions.
+    fn cs_pin_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Sets the level at which CS rests between transactions, `true` being the usual deasserted
+    /// (high) level.  The idle level does not affect transactions, nor the periods during which
+    /// an `AssertChipSelect` object is held, CS returns to the idle level only after the last
+    /// such object is dropped.  Transports which cannot control this accept only `true`.
+    fn set_cs_idle_level(&self, high: bool) -> Result<()> {
+        ensure!(
+            high,
+            SpiError::InvalidOption("This target does not support CS idle level".to_string())
+        );
+        Ok(())
+    }
+
+    /// Selects whether CS is active high, rather than the usual active low, e.g. for a device
+    /// behind an inverting level shifter.  Transports which cannot invert CS accept only `false`.
+    fn set_cs_polarity(&self, active_high: bool) -> Result<()> {
+        ensure!(!active_high, TransportError::UnsupportedOperation);
+        Ok(())
+    }
+
+    /// Sets the level at which the clock rests between transactions, overriding the polarity of
+    /// the transfer mode.  The override takes precedence over any mode given to
+    /// `set_transfer_mode()`, before or after this call, which then only selects the clock phase
+    /// (whether data is sampled on the leading or trailing edge of each clock pulse).
+    /// `get_transfer_mode()` reports the mode in effect, including the overridden polarity.
+    fn set_idle_clock_high(&self, _high: bool) -> Result<()> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
+
+    /// Sets the minimum time CS must stay deasserted between transactions, as required by some
+    /// flash parts (tSHSL).  If needed, the next transaction is delayed until this much time has
+    /// passed.  The time may be measured on the host, from the completion of the request which
+    /// deasserted CS to the next request asserting it, bus timing only adds to that.  Transports
+    /// which cannot enforce this accept only zero.
+    fn set_min_deselect_time(&self, duration: Duration) -> Result<()> {
+        ensure!(
+            duration.is_zero(),
+            SpiError::InvalidOption("This target does not support deselect time".to_string())
+        );
+        Ok(())
+    }
+
+    /// Sets the time allowed for each USB packet (or other unit of communication with the
+    /// debugger) of a transaction, after which the transaction fails with
+    /// `TransportError::CommunicationError`, rather than hanging on a stuck device.  The limit
+    /// applies to each packet separately, such that long transfers are not cut short.
+    fn set_transaction_timeout(&self, _timeout: Duration) -> Result<()> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
+
     /// Runs a SPI transaction composed from the slice of [`Transfer`] objects.  Will assert the
     /// CS for the duration of the entire transactions.
     fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()>;
 
+    /// Runs a SPI transaction at the given speed, restoring the previous speed afterwards, also
+    /// if the transaction fails.
+    fn run_transaction_at_speed(&self, transaction: &mut [Transfer], max_speed: u32) -> Result<()> {
+        let previous_speed = self.get_max_speed()?;
+        self.set_max_speed(max_speed)?;
+        let result = self.run_transaction(transaction);
+        self.set_max_speed(previous_speed)?;
+        result
+    }
+
+    /// Runs a SPI transaction like `run_transaction()`, returning the wall-clock time it took.
+    /// The time is measured on the host, and as such includes USB and other host-side overhead,
+    /// not only the time spent clocking data on the SPI bus.
+    fn run_transaction_timed(&self, transaction: &mut [Transfer]) -> Result<Duration> {
+        let start = Instant::now();
+        self.run_transaction(transaction)?;
+        Ok(start.elapsed())
+    }
+
     /// Maximum payload size of `Read` and `Write` elements for `run_eeprom_transactions()`.
     fn get_eeprom_max_transfer_sizes(&self) -> Result<MaxSizes> {
         // By default, go by the low-level SPI limits, allowing for 6 bytes of opcode+address+dummy
@@ -218,6 +357,68 @@ pub trait Target {
     fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect>;
 }
 
+/// Runs `f` with CS of `target` asserted throughout, such that the transactions performed by
+/// `f` appear to the device as a single one.  CS is deasserted when `f` returns, whether
+/// successfully, with an error, or by panicking, unless also held by an `AssertChipSelect`
+/// object outside of `f`.
+pub fn with_cs_asserted<T>(
+    target: &Rc<dyn Target>,
+    f: impl FnOnce(&dyn Target) -> Result<T>,
+) -> Result<T> {
+    let _cs_asserted = Rc::clone(target).assert_cs()?;
+    f(target.as_ref())
+}
+
+/// Sends `write`, then reads until the received data ends with `sentinel`, or `max_len` bytes
+/// have been received.  Returns the received data, including the sentinel if found, callers
+/// should check `ends_with(sentinel)` to distinguish the two cases.
+///
+/// SPI has no native notion of "read until", so this is done by polling in chunks of the maximum
+/// read size, each chunk being a separate transaction, while CS is held asserted throughout, such
+/// that the device sees a single command.  Any data clocked in after the sentinel within the last
+/// chunk is discarded.
+pub fn read_until(
+    target: &Rc<dyn Target>,
+    write: &[u8],
+    sentinel: &[u8],
+    max_len: usize,
+) -> Result<Vec<u8>> {
+    ensure!(
+        !sentinel.is_empty(),
+        SpiError::InvalidOption("Empty sentinel".to_string())
+    );
+    let chunk_size = target.get_max_transfer_sizes()?.read;
+    ensure!(
+        chunk_size > 0,
+        SpiError::InvalidOption("Target does not support reads".to_string())
+    );
+    with_cs_asserted(target, |target| {
+        let mut data = Vec::new();
+        let mut write = Some(write);
+        while data.len() < max_len {
+            let start = data.len();
+            data.resize(std::cmp::min(start + chunk_size, max_len), 0);
+            match write.take() {
+                Some(wbuf) => target.run_transaction(&mut [
+                    Transfer::Write(wbuf),
+                    Transfer::Read(&mut data[start..]),
+                ])?,
+                None => target.run_transaction(&mut [Transfer::Read(&mut data[start..])])?,
+            }
+            // The sentinel may straddle the boundary to the previous chunk.
+            let search_from = start.saturating_sub(sentinel.len().saturating_sub(1));
+            if let Some(pos) = data[search_from..]
+                .windows(sentinel.len())
+                .position(|w| w == sentinel)
+            {
+                data.truncate(search_from + pos + sentinel.len());
+                break;
+            }
+        }
+        Ok(data)
+    })
+}
+
 /// Object that keeps the CS asserted, deasserting when it goes out of scope, (unless another
 /// instance keeps CS asserted longer.)
 pub struct AssertChipSelect {
@@ -238,8 +439,1051 @@ impl Drop for AssertChipSelect {
     }
 }
 
+/// Convenience operations common to many SPI devices, built on top of `Target`.
+pub trait SpiExt {
+    /// Reads the unique ID of a flash or secure element part, by sending `opcode` followed by
+    /// `dummy_bytes` zero bytes, and then reading `id_len` bytes of ID within the same
+    /// transaction.
+    fn read_unique_id(&self, opcode: u8, dummy_bytes: u8, id_len: usize) -> Result<Vec<u8>>;
+
+    /// Sends `write`, then reads `N` bytes within the same transaction, e.g. a register of known
+    /// width.  Transports fill the entire read buffer or fail, reads exceeding the maximum read
+    /// size of the target are rejected up front rather than risking a partially filled array.
+    fn read_array<const N: usize>(&self, write: &[u8]) -> Result<[u8; N]>;
+
+    /// Reads a number of registers, sending each of `commands` followed by reading the
+    /// corresponding number of bytes of `read_lens`, and returns the values in order.  Each
+    /// command and read must fit within the maximum transfer sizes of the target, which is
+    /// checked before anything is sent.  Every register is read in a transaction of its own, as
+    /// most devices require CS to be deasserted between commands, and transports such as
+    /// HyperDebug carry out a single write followed by read as one request.
+    fn read_registers(&self, commands: &[&[u8]], read_lens: &[usize]) -> Result<Vec<Vec<u8>>>;
+}
+
+impl<T: Target + ?Sized> SpiExt for T {
+    fn read_unique_id(&self, opcode: u8, dummy_bytes: u8, id_len: usize) -> Result<Vec<u8>> {
+        let mut cmd = vec![0u8; 1 + dummy_bytes as usize];
+        cmd[0] = opcode;
+        let mut id = vec![0u8; id_len];
+        self.run_transaction(&mut [Transfer::Write(&cmd), Transfer::Read(&mut id)])?;
+        Ok(id)
+    }
+
+    fn read_array<const N: usize>(&self, write: &[u8]) -> Result<[u8; N]> {
+        ensure!(
+            N <= self.get_max_transfer_sizes()?.read,
+            SpiError::InvalidDataLength(N)
+        );
+        let mut data = [0u8; N];
+        self.run_transaction(&mut [Transfer::Write(write), Transfer::Read(&mut data)])?;
+        Ok(data)
+    }
+
+    fn read_registers(&self, commands: &[&[u8]], read_lens: &[usize]) -> Result<Vec<Vec<u8>>> {
+        ensure!(
+            commands.len() == read_lens.len(),
+            SpiError::MismatchedDataLength(commands.len(), read_lens.len())
+        );
+        let max_sizes = self.get_max_transfer_sizes()?;
+        for (command, read_len) in commands.iter().zip(read_lens) {
+            ensure!(
+                command.len() <= max_sizes.write,
+                SpiError::InvalidDataLength(command.len())
+            );
+            ensure!(
+                *read_len <= max_sizes.read,
+                SpiError::InvalidDataLength(*read_len)
+            );
+        }
+        commands
+            .iter()
+            .zip(read_lens)
+            .map(|(command, read_len)| {
+                let mut value = vec![0u8; *read_len];
+                self.run_transaction(&mut [Transfer::Write(command), Transfer::Read(&mut value)])?;
+                Ok(value)
+            })
+            .collect()
+    }
+}
+
+/// Byte order of register values on the SPI bus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Endianness {
+    Big,
+    Little,
+}
+
+/// Helper for register-oriented SPI peripherals (as opposed to command-oriented flash), where
+/// each transaction consists of a register address, with one bit indicating read or write,
+/// followed by the register value.  The address is always sent most significant byte first.
+pub struct SpiRegisterDevice {
+    spi: Rc<dyn Target>,
+    address_bytes: usize,
+    value_bytes: usize,
+    read_bit: u32,
+    endianness: Endianness,
+}
+
+impl SpiRegisterDevice {
+    /// Creates a helper for a device with addresses of `address_bytes`, and values of
+    /// `value_bytes` (each at most 4), where bit number `read_bit` of the address word is set for
+    /// reads and clear for writes.
+    pub fn new(
+        spi: Rc<dyn Target>,
+        address_bytes: usize,
+        value_bytes: usize,
+        read_bit: u32,
+        endianness: Endianness,
+    ) -> Result<Self> {
+        ensure!(
+            (1..=4).contains(&address_bytes),
+            SpiError::InvalidOption(format!("Unsupported address size {}", address_bytes))
+        );
+        ensure!(
+            (1..=4).contains(&value_bytes),
+            SpiError::InvalidOption(format!("Unsupported value size {}", value_bytes))
+        );
+        ensure!(
+            read_bit < 8 * address_bytes as u32,
+            SpiError::InvalidOption(format!("Read bit {} outside of address", read_bit))
+        );
+        Ok(Self {
+            spi,
+            address_bytes,
+            value_bytes,
+            read_bit,
+            endianness,
+        })
+    }
+
+    /// Reads the value of the register at `addr`.
+    pub fn read_reg(&self, addr: u32) -> Result<u32> {
+        let cmd = self.address(addr, true)?;
+        let mut buf = vec![0u8; self.value_bytes];
+        self.spi
+            .run_transaction(&mut [Transfer::Write(&cmd), Transfer::Read(&mut buf)])?;
+        if self.endianness == Endianness::Little {
+            buf.reverse();
+        }
+        Ok(buf.iter().fold(0, |acc, &b| (acc << 8) | b as u32))
+    }
+
+    /// Writes `value` to the register at `addr`.
+    pub fn write_reg(&self, addr: u32, value: u32) -> Result<()> {
+        ensure!(
+            self.value_bytes == 4 || value >> (8 * self.value_bytes) == 0,
+            SpiError::InvalidOption(format!("Value {:#x} too large for register", value))
+        );
+        let mut cmd = self.address(addr, false)?;
+        let mut bytes = value.to_be_bytes()[4 - self.value_bytes..].to_vec();
+        if self.endianness == Endianness::Little {
+            bytes.reverse();
+        }
+        cmd.extend_from_slice(&bytes);
+        self.spi.run_transaction(&mut [Transfer::Write(&cmd)])
+    }
+
+    /// Encodes `addr` along with the read/write flag.
+    fn address(&self, addr: u32, read: bool) -> Result<Vec<u8>> {
+        let width = 8 * self.address_bytes as u32;
+        ensure!(
+            (width == 32 || addr >> width == 0) && addr & (1 << self.read_bit) == 0,
+            SpiError::InvalidOption(format!("Invalid register address {:#x}", addr))
+        );
+        let word = if read {
+            addr | (1 << self.read_bit)
+        } else {
+            addr
+        };
+        Ok(word.to_be_bytes()[4 - self.address_bytes..].to_vec())
+    }
+}
+
+/// How `FloatingDetectTarget` reports reads which look like CIPO being stuck.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FloatingDetection {
+    /// Log a warning, and return the data as usual.
+    Warn,
+    /// Fail the read with `SpiError::BusContention`.
+    Error,
+}
+
+impl FromStr for FloatingDetection {
+    type Err = SpiError;
+    fn from_str(s: &str) -> std::result::Result<FloatingDetection, Self::Err> {
+        match s {
+            "Warn" | "warn" => Ok(FloatingDetection::Warn),
+            "Error" | "error" => Ok(FloatingDetection::Error),
+            _ => Err(SpiError::InvalidOption(s.to_string())),
+        }
+    }
+}
+
+/// Wrapper around a SPI `Target`, which checks the data received by each transaction.  If CIPO
+/// is stuck high or low, e.g. because no device is present, or because of bus contention, reads
+/// return all 0xFF or all 0x00, which is otherwise indistinguishable from actual data.
+///
+/// Single status bytes are legitimately all zero, so only transactions reading at least
+/// `MIN_READ_LEN` bytes in total are checked.  Reads which may legitimately be uniform, e.g. of
+/// erased flash, can be excluded with `with_filter()`.
+pub struct FloatingDetectTarget {
+    inner: Rc<dyn Target>,
+    detection: FloatingDetection,
+    filter: Option<Box<dyn Fn(Option<u8>) -> bool>>,
+}
+
+impl FloatingDetectTarget {
+    pub const MIN_READ_LEN: usize = 4;
+
+    pub fn new(inner: Rc<dyn Target>, detection: FloatingDetection) -> Self {
+        Self {
+            inner,
+            detection,
+            filter: None,
+        }
+    }
+
+    /// Checks only transactions for which `filter` returns true, given the first byte written
+    /// (usually the opcode), if any.
+    pub fn with_filter(mut self, filter: impl Fn(Option<u8>) -> bool + 'static) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Checks the concatenation of all data read by one transaction, which started by sending
+    /// `opcode`.
+    fn check<'a>(&self, opcode: Option<u8>, reads: impl Iterator<Item = &'a [u8]>) -> Result<()> {
+        if let Some(filter) = &self.filter {
+            if !filter(opcode) {
+                return Ok(());
+            }
+        }
+        let mut len = 0;
+        let mut uniform = None;
+        for &byte in reads.flatten() {
+            len += 1;
+            uniform = match uniform {
+                None if byte == 0x00 || byte == 0xFF => Some(Some(byte)),
+                Some(Some(value)) if byte == value => Some(Some(value)),
+                _ => Some(None),
+            };
+        }
+        if let (true, Some(Some(value))) = (len >= Self::MIN_READ_LEN, uniform) {
+            match self.detection {
+                FloatingDetection::Error => bail!(SpiError::BusContention(len, value)),
+                FloatingDetection::Warn => log::warn!("{}", SpiError::BusContention(len, value)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Target for FloatingDetectTarget {
+    fn get_transfer_mode(&self) -> Result<TransferMode> {
+        self.inner.get_transfer_mode()
+    }
+    fn set_transfer_mode(&self, mode: TransferMode) -> Result<()> {
+        self.inner.set_transfer_mode(mode)
+    }
+    fn get_bits_per_word(&self) -> Result<u32> {
+        self.inner.get_bits_per_word()
+    }
+    fn set_bits_per_word(&self, bits_per_word: u32) -> Result<()> {
+        self.inner.set_bits_per_word(bits_per_word)
+    }
+    fn get_max_speed(&self) -> Result<u32> {
+        self.inner.get_max_speed()
+    }
+    fn set_max_speed(&self, max_speed: u32) -> Result<()> {
+        self.inner.set_max_speed(max_speed)
+    }
+    fn get_hardware_max_speed(&self) -> Result<u32> {
+        self.inner.get_hardware_max_speed()
+    }
+    fn get_max_transfer_count(&self) -> Result<usize> {
+        self.inner.get_max_transfer_count()
+    }
+    fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+        self.inner.get_max_transfer_sizes()
+    }
+    fn set_voltage(&self, voltage: Voltage) -> Result<()> {
+        self.inner.set_voltage(voltage)
+    }
+    fn cs_pin_name(&self) -> Option<&str> {
+        self.inner.cs_pin_name()
+    }
+    fn set_cs_idle_level(&self, high: bool) -> Result<()> {
+        self.inner.set_cs_idle_level(high)
+    }
+    fn set_cs_polarity(&self, active_high: bool) -> Result<()> {
+        self.inner.set_cs_polarity(active_high)
+    }
+    fn set_idle_clock_high(&self, high: bool) -> Result<()> {
+        self.inner.set_idle_clock_high(high)
+    }
+    fn set_min_deselect_time(&self, duration: Duration) -> Result<()> {
+        self.inner.set_min_deselect_time(duration)
+    }
+    fn set_transaction_timeout(&self, timeout: Duration) -> Result<()> {
+        self.inner.set_transaction_timeout(timeout)
+    }
+
+    fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+        self.inner.run_transaction(transaction)?;
+        let opcode = match transaction.first() {
+            Some(Transfer::Write(wbuf)) | Some(Transfer::Both(wbuf, _)) => wbuf.first().copied(),
+            _ => None,
+        };
+        self.check(
+            opcode,
+            transaction.iter().filter_map(|t| match t {
+                Transfer::Read(rbuf) | Transfer::Both(_, rbuf) => Some(&rbuf[..]),
+                Transfer::Write(_)
+                | Transfer::Fill { .. }
+                | Transfer::SetSpeed(_)
+                | Transfer::Turnaround => None,
+            }),
+        )
+    }
+
+    fn get_eeprom_max_transfer_sizes(&self) -> Result<MaxSizes> {
+        self.inner.get_eeprom_max_transfer_sizes()
+    }
+
+    fn run_eeprom_transactions(&self, transactions: &mut [eeprom::Transaction]) -> Result<()> {
+        self.inner.run_eeprom_transactions(transactions)?;
+        for transaction in transactions.iter() {
+            if let eeprom::Transaction::Read(cmd, rbuf) = transaction {
+                self.check(
+                    cmd.get_opcode().first().copied(),
+                    std::iter::once(&rbuf[..]),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+        Rc::clone(&self.inner).assert_cs()
+    }
+}
+
+/// Variant of `Transfer` owning its data, such that a transaction can be shared between threads
+/// by `run_transaction_parallel()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OwnedTransfer {
+    /// Read the given number of bytes.
+    Read(usize),
+    Write(Vec<u8>),
+    Both(Vec<u8>),
+}
+
+/// Outcome of a transaction on one target, the data received by each `Read` or `Both` transfer.
+pub type TargetResult = Result<Vec<Vec<u8>>>;
+
+/// Opens the SPI targets of one debugger device, see `run_transaction_parallel()`.
+pub type DeviceTargetsOpener = Box<dyn FnOnce() -> Result<Vec<Rc<dyn Target>>> + Send>;
+
+/// Runs `transaction` on each of `targets` in turn, collecting the result for each target.  A
+/// failure on one target does not prevent attempting the rest.
+pub fn run_transaction_each(
+    targets: &[Rc<dyn Target>],
+    transaction: &[OwnedTransfer],
+) -> Vec<TargetResult> {
+    targets
+        .iter()
+        .map(|target| run_owned_transaction(target.as_ref(), transaction))
+        .collect()
+}
+
+/// Runs `transaction` on `target`, returning the data received by each `Read` or `Both`
+/// transfer.
+fn run_owned_transaction(target: &dyn Target, transaction: &[OwnedTransfer]) -> TargetResult {
+    let mut reads: Vec<Vec<u8>> = transaction
+        .iter()
+        .filter_map(|t| match t {
+            OwnedTransfer::Read(len) => Some(vec![0u8; *len]),
+            OwnedTransfer::Both(data) => Some(vec![0u8; data.len()]),
+            OwnedTransfer::Write(_) => None,
+        })
+        .collect();
+    let mut read_bufs = reads.iter_mut();
+    let mut transfers: Vec<Transfer> = transaction
+        .iter()
+        .map(|t| match t {
+            OwnedTransfer::Read(_) => Transfer::Read(read_bufs.next().unwrap()),
+            OwnedTransfer::Write(data) => Transfer::Write(data),
+            OwnedTransfer::Both(data) => Transfer::Both(data, read_bufs.next().unwrap()),
+        })
+        .collect();
+    target.run_transaction(&mut transfers)?;
+    Ok(reads)
+}
+
+/// Step of a device initialization sequence, see `run_init_sequence()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InitStep {
+    /// A transaction, with CS asserted throughout, and deasserted afterwards.
+    Transaction(Vec<OwnedTransfer>),
+    /// Hold the (active-low) reset pin low for the given time, then release it.  The pin is used
+    /// in whatever mode it has been configured in, e.g. open drain for a shared reset line.
+    ResetPulse(Duration),
+    /// Wait, e.g. for the device to come out of reset.
+    Delay(Duration),
+}
+
+/// Runs the steps of a device initialization sequence in order, interleaving SPI transactions
+/// with pulses of the reset pin, typically the `RESET` pin of the transport.  As each
+/// transaction deasserts CS at its end, CS is deasserted during reset pulses, provided the
+/// caller does not hold an `AssertChipSelect` across the sequence.  Returns the data received
+/// by all transactions, in order.
+pub fn run_init_sequence(
+    target: &dyn Target,
+    reset_pin: Option<&dyn GpioPin>,
+    steps: &[InitStep],
+) -> Result<Vec<Vec<u8>>> {
+    let mut reads = Vec::new();
+    for step in steps {
+        match step {
+            InitStep::Transaction(transaction) => {
+                reads.extend(run_owned_transaction(target, transaction)?);
+            }
+            InitStep::ResetPulse(duration) => {
+                let pin = reset_pin.ok_or(TransportError::InvalidOperation)?;
+                pin.write(false)?; // Low active
+                std::thread::sleep(*duration);
+                pin.write(true)?; // Release reset
+            }
+            InitStep::Delay(duration) => std::thread::sleep(*duration),
+        }
+    }
+    Ok(reads)
+}
+
+/// Runs `transaction` on the SPI targets of several independent debugger devices (separate USB
+/// devices) concurrently, e.g. to program identical flash chips in parallel.  Returns, for each
+/// device, the result of opening its targets, or the per-target results.
+///
+/// As `Rc<dyn Target>` cannot be moved between threads, each device is represented by a
+/// function opening its transport and targets, which is called on a dedicated thread.  Targets
+/// returned by the same function are run one after the other.  This is required for targets
+/// sharing one HyperDebug device, as it forwards bulk transfers to a single selected SPI bus at a
+/// time, so such targets must never be split across several openers.
+pub fn run_transaction_parallel(
+    devices: Vec<DeviceTargetsOpener>,
+    transaction: &[OwnedTransfer],
+) -> Vec<Result<Vec<TargetResult>>> {
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = devices
+            .into_iter()
+            .map(|open| scope.spawn(move || Ok(run_transaction_each(&open()?, transaction))))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("SPI worker thread panicked")))
+            })
+            .collect()
+    })
+}
+
 // Needs to be public in order for implementation of `Target` to be able to implement it.  Never
 // called by users of `Target`.
 pub trait TargetChipDeassert {
     fn deassert_cs(&self);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::{Cell, RefCell};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// A SPI target which sleeps for a fixed time in each transaction, records all data written,
+    /// and answers reads with a fixed response.
+    #[derive(Default)]
+    struct MockTarget {
+        delay: Duration,
+        response: Vec<u8>,
+        /// Continue the response where the previous transaction left off, rather than from the
+        /// start, as if chip select had been held.
+        streaming: bool,
+        /// Limits reported by `get_max_transfer_sizes()` and enforced on each transfer, defaults
+        /// to 256 bytes in either direction.
+        max_sizes: Option<MaxSizes>,
+        response_pos: Cell<usize>,
+        written: RefCell<Vec<u8>>,
+        speed: Cell<u32>,
+        /// Speed in effect during each transaction.
+        transaction_speeds: RefCell<Vec<u32>>,
+        /// Number of `AssertChipSelect` objects alive.
+        cs_count: Cell<usize>,
+        /// Changes of CS, and transactions, in order.
+        cs_log: RefCell<Vec<&'static str>>,
+    }
+
+    impl Target for MockTarget {
+        fn get_transfer_mode(&self) -> Result<TransferMode> {
+            Ok(TransferMode::Mode0)
+        }
+        fn set_transfer_mode(&self, _mode: TransferMode) -> Result<()> {
+            Ok(())
+        }
+        fn get_bits_per_word(&self) -> Result<u32> {
+            Ok(8)
+        }
+        fn set_bits_per_word(&self, _bits_per_word: u32) -> Result<()> {
+            Ok(())
+        }
+        fn get_max_speed(&self) -> Result<u32> {
+            Ok(self.speed.get())
+        }
+        fn set_max_speed(&self, max_speed: u32) -> Result<()> {
+            self.speed.set(max_speed);
+            Ok(())
+        }
+        fn get_max_transfer_count(&self) -> Result<usize> {
+            Ok(usize::MAX)
+        }
+        fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+            Ok(self.max_sizes.unwrap_or(MaxSizes {
+                read: 256,
+                write: 256,
+            }))
+        }
+        fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+            let max_sizes = self.get_max_transfer_sizes()?;
+            for transfer in transaction.iter() {
+                let (write_len, read_len) = match transfer {
+                    Transfer::Read(rbuf) => (0, rbuf.len()),
+                    Transfer::Write(wbuf) => (wbuf.len(), 0),
+                    Transfer::Both(wbuf, rbuf) => (wbuf.len(), rbuf.len()),
+                    Transfer::Fill { .. } | Transfer::SetSpeed(_) | Transfer::Turnaround => (0, 0),
+                };
+                ensure!(
+                    write_len <= max_sizes.write,
+                    SpiError::InvalidDataLength(write_len)
+                );
+                ensure!(
+                    read_len <= max_sizes.read,
+                    SpiError::InvalidDataLength(read_len)
+                );
+            }
+            std::thread::sleep(self.delay);
+            self.cs_log.borrow_mut().push("transaction");
+            self.transaction_speeds.borrow_mut().push(self.speed.get());
+            let start = if self.streaming {
+                self.response_pos.get()
+            } else {
+                0
+            };
+            let mut response = self.response.iter().copied().skip(start);
+            for transfer in transaction.iter_mut() {
+                match transfer {
+                    Transfer::Read(rbuf) => rbuf.fill_with(|| response.next().unwrap_or(0)),
+                    Transfer::Write(wbuf) => self.written.borrow_mut().extend_from_slice(wbuf),
+                    Transfer::Both(wbuf, rbuf) => {
+                        self.written.borrow_mut().extend_from_slice(wbuf);
+                        rbuf.fill_with(|| response.next().unwrap_or(0));
+                    }
+                    Transfer::Fill { value, count } => {
+                        fill_chunks(*value, *count, max_sizes.write, |chunk| {
+                            self.written.borrow_mut().extend_from_slice(chunk);
+                            Ok(())
+                        })?;
+                    }
+                    Transfer::SetSpeed(speed) => self.speed.set(*speed),
+                    Transfer::Turnaround => (),
+                }
+            }
+            let read: usize = transaction
+                .iter()
+                .map(|t| match t {
+                    Transfer::Read(rbuf) | Transfer::Both(_, rbuf) => rbuf.len(),
+                    Transfer::Write(_)
+                    | Transfer::Fill { .. }
+                    | Transfer::SetSpeed(_)
+                    | Transfer::Turnaround => 0,
+                })
+                .sum();
+            self.response_pos.set(start + read);
+            Ok(())
+        }
+        fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+            if self.cs_count.replace(self.cs_count.get() + 1) == 0 {
+                self.cs_log.borrow_mut().push("assert");
+            }
+            Ok(AssertChipSelect::new(self))
+        }
+    }
+
+    impl TargetChipDeassert for MockTarget {
+        fn deassert_cs(&self) {
+            self.cs_count.set(self.cs_count.get() - 1);
+            if self.cs_count.get() == 0 {
+                self.cs_log.borrow_mut().push("deassert");
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_transaction_timed() -> Result<()> {
+        let delay = Duration::from_millis(20);
+        let target = MockTarget {
+            delay,
+            ..Default::default()
+        };
+        let mut buf = [0u8; 4];
+        let elapsed = target.run_transaction_timed(&mut [Transfer::Read(&mut buf)])?;
+        assert!(elapsed >= delay);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_transaction_parallel() -> Result<()> {
+        fn target(id: u8) -> Rc<dyn Target> {
+            Rc::new(MockTarget {
+                response: vec![id, 0x5a],
+                ..Default::default()
+            })
+        }
+        // Each opener waits for all of them to have started, which only succeeds if they run
+        // concurrently.
+        let started = Arc::new(AtomicUsize::new(0));
+        let opener = |ids: Vec<u8>| -> DeviceTargetsOpener {
+            let started = Arc::clone(&started);
+            Box::new(move || {
+                started.fetch_add(1, Ordering::SeqCst);
+                let deadline = Instant::now() + Duration::from_secs(10);
+                while started.load(Ordering::SeqCst) < 3 {
+                    ensure!(Instant::now() < deadline, "Devices not opened concurrently");
+                    std::thread::yield_now();
+                }
+                Ok(ids.into_iter().map(target).collect())
+            })
+        };
+        // Two targets sharing one device, run one after the other.
+        let devices = vec![opener(vec![1]), opener(vec![2]), opener(vec![3, 4])];
+        let transaction = [
+            OwnedTransfer::Write(vec![0x9f]),
+            OwnedTransfer::Read(1),
+            OwnedTransfer::Both(vec![0x00]),
+        ];
+        let results = run_transaction_parallel(devices, &transaction);
+
+        let reads: Vec<Vec<Vec<Vec<u8>>>> = results
+            .into_iter()
+            .map(|device| device?.into_iter().collect::<Result<Vec<_>>>())
+            .collect::<Result<_>>()?;
+        assert_eq!(
+            reads,
+            vec![
+                vec![vec![vec![1], vec![0x5a]]],
+                vec![vec![vec![2], vec![0x5a]]],
+                vec![vec![vec![3], vec![0x5a]], vec![vec![4], vec![0x5a]]],
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_unique_id() -> Result<()> {
+        let target = MockTarget {
+            response: vec![0xde, 0xad, 0xbe, 0xef, 0x01, 0x23, 0x45, 0x67],
+            ..Default::default()
+        };
+        let id = target.read_unique_id(0x4b, 4, 8)?;
+        assert_eq!(id, vec![0xde, 0xad, 0xbe, 0xef, 0x01, 0x23, 0x45, 0x67]);
+        assert_eq!(*target.written.borrow(), vec![0x4b, 0, 0, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_array() -> Result<()> {
+        let target = MockTarget {
+            response: vec![0x12, 0x34, 0x56, 0x78, 0x9a],
+            max_sizes: Some(MaxSizes { read: 4, write: 4 }),
+            ..Default::default()
+        };
+        let value: [u8; 4] = target.read_array(&[0x0b, 0x00])?;
+        assert_eq!(value, [0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(*target.written.borrow(), vec![0x0b, 0x00]);
+
+        let err = target.read_array::<5>(&[0x0b, 0x00]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SpiError>(),
+            Some(SpiError::InvalidDataLength(5))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_registers() -> Result<()> {
+        let target = MockTarget {
+            response: (1..=12).collect(),
+            streaming: true,
+            max_sizes: Some(MaxSizes { read: 4, write: 1 }),
+            ..Default::default()
+        };
+        let values = target.read_registers(
+            &[&[0x05], &[0x35], &[0x15], &[0x9f], &[0x4b]],
+            &[2, 2, 3, 1, 4],
+        )?;
+        assert_eq!(
+            values,
+            vec![
+                vec![1, 2],
+                vec![3, 4],
+                vec![5, 6, 7],
+                vec![8],
+                vec![9, 10, 11, 12],
+            ]
+        );
+        // A transaction per register, the limits applying to each transfer rather than to
+        // their sum.
+        assert_eq!(target.transaction_speeds.borrow().len(), 5);
+        assert_eq!(*target.written.borrow(), vec![0x05, 0x35, 0x15, 0x9f, 0x4b]);
+
+        let err = target
+            .read_registers(&[&[0x05], &[0x03]], &[1, 5])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SpiError>(),
+            Some(SpiError::InvalidDataLength(5))
+        ));
+        let err = target
+            .read_registers(&[&[0x05], &[0x03, 0, 0, 0]], &[1, 1])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SpiError>(),
+            Some(SpiError::InvalidDataLength(4))
+        ));
+        // Nothing was sent for the rejected reads, not even the valid first register.
+        assert_eq!(target.transaction_speeds.borrow().len(), 5);
+        assert!(target.read_registers(&[&[0x05]], &[1, 1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_device() -> Result<()> {
+        let target = Rc::new(MockTarget {
+            response: vec![0xab, 0xcd],
+            ..Default::default()
+        });
+        // 8-bit address, 16-bit value, read bit in the MSB of the address.
+        let dev = SpiRegisterDevice::new(target.clone(), 1, 2, 7, Endianness::Big)?;
+
+        assert_eq!(dev.read_reg(0x12)?, 0xabcd);
+        assert_eq!(target.written.take(), vec![0x92]);
+
+        dev.write_reg(0x12, 0x1234)?;
+        assert_eq!(target.written.take(), vec![0x12, 0x12, 0x34]);
+
+        // Address colliding with the read bit, and value wider than the register.
+        assert!(dev.read_reg(0x80).is_err());
+        assert!(dev.write_reg(0x100, 0).is_err());
+        assert!(dev.write_reg(0x12, 0x10000).is_err());
+        assert!(target.written.borrow().is_empty());
+
+        let dev = SpiRegisterDevice::new(target.clone(), 1, 2, 7, Endianness::Little)?;
+        assert_eq!(dev.read_reg(0x12)?, 0xcdab);
+        assert_eq!(target.written.take(), vec![0x92]);
+        dev.write_reg(0x12, 0x1234)?;
+        assert_eq!(target.written.take(), vec![0x12, 0x34, 0x12]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_transaction_at_speed() -> Result<()> {
+        let target = MockTarget::default();
+        target.set_max_speed(1_000_000)?;
+        target.run_transaction(&mut [Transfer::Write(&[0x9f])])?;
+        target.run_transaction_at_speed(&mut [Transfer::Write(&[0x03])], 20_000_000)?;
+        target.run_transaction(&mut [Transfer::Write(&[0x05])])?;
+        assert_eq!(
+            *target.transaction_speeds.borrow(),
+            vec![1_000_000, 20_000_000, 1_000_000]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_until() -> Result<()> {
+        let mock = Rc::new(MockTarget {
+            response: b"abcdefg\r\nhij".to_vec(),
+            streaming: true,
+            max_sizes: Some(MaxSizes { read: 4, write: 1 }),
+            ..Default::default()
+        });
+        let target: Rc<dyn Target> = Rc::clone(&mock) as Rc<dyn Target>;
+        // Sentinel straddles the second and third chunk.
+        assert_eq!(read_until(&target, &[0x0b], b"\r\n", 64)?, b"abcdefg\r\n");
+        assert_eq!(*mock.written.borrow(), vec![0x0b]);
+        // CS stays asserted across all chunks.
+        assert_eq!(
+            *mock.cs_log.borrow(),
+            vec![
+                "assert",
+                "transaction",
+                "transaction",
+                "transaction",
+                "deassert"
+            ]
+        );
+
+        // Limited by `max_len`.
+        let target: Rc<dyn Target> = Rc::new(MockTarget {
+            response: b"abcdefg\r\nhij".to_vec(),
+            streaming: true,
+            max_sizes: Some(MaxSizes { read: 4, write: 1 }),
+            ..Default::default()
+        });
+        assert_eq!(read_until(&target, &[0x0b], b"\r\n", 6)?, b"abcdef");
+        Ok(())
+    }
+
+    #[test]
+    fn test_fill_chunks() -> Result<()> {
+        const MEGABYTE: usize = 1024 * 1024;
+        let mut total = 0;
+        let mut chunks = 0;
+        fill_chunks(0xff, MEGABYTE, 256, |chunk| {
+            assert!(chunk.len() <= 256);
+            assert!(chunk.iter().all(|&b| b == 0xff));
+            total += chunk.len();
+            chunks += 1;
+            Ok(())
+        })?;
+        assert_eq!(total, MEGABYTE);
+        assert_eq!(chunks, MEGABYTE / 256);
+
+        // Count not a multiple of the chunk size.
+        let mut lens = Vec::new();
+        fill_chunks(0x00, 600, 256, |chunk| {
+            lens.push(chunk.len());
+            Ok(())
+        })?;
+        assert_eq!(lens, vec![256, 256, 88]);
+
+        let target = MockTarget::default();
+        target.run_transaction(&mut [
+            Transfer::Write(&[0x02, 0x00, 0x10, 0x00]),
+            Transfer::Fill {
+                value: 0xa5,
+                count: 3,
+            },
+        ])?;
+        assert_eq!(
+            *target.written.borrow(),
+            vec![0x02, 0x00, 0x10, 0x00, 0xa5, 0xa5, 0xa5]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_mock_max_sizes() -> Result<()> {
+        let response: Vec<u8> = (0..100).collect();
+        let target = Rc::new(MockTarget {
+            response: response.clone(),
+            streaming: true,
+            max_sizes: Some(MaxSizes { read: 7, write: 5 }),
+            ..Default::default()
+        });
+        // Single transfers exceeding the limits are rejected, as by real hardware.
+        let err = target
+            .run_transaction(&mut [Transfer::Read(&mut [0u8; 8])])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SpiError>(),
+            Some(SpiError::InvalidDataLength(8))
+        ));
+        assert!(target
+            .run_transaction(&mut [Transfer::Write(&[0u8; 6])])
+            .is_err());
+        assert!(target.transaction_speeds.borrow().is_empty());
+
+        // Reads chunked by `read_until()` reassemble into the original response.
+        let data = read_until(
+            &(Rc::clone(&target) as Rc<dyn Target>),
+            &[0x0b],
+            &[98, 99],
+            200,
+        )?;
+        assert_eq!(data, response);
+        assert_eq!(target.transaction_speeds.borrow().len(), 15);
+
+        // Fills are chunked to the write limit.
+        target.written.borrow_mut().clear();
+        target.run_transaction(&mut [Transfer::Fill {
+            value: 0x5a,
+            count: 23,
+        }])?;
+        assert_eq!(*target.written.borrow(), vec![0x5a; 23]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_cs_asserted() -> Result<()> {
+        let mock = Rc::new(MockTarget::default());
+        let target: Rc<dyn Target> = mock.clone();
+        let value = with_cs_asserted(&target, |spi| {
+            spi.run_transaction(&mut [Transfer::Write(&[0x01])])?;
+            spi.run_transaction(&mut [Transfer::Write(&[0x02])])?;
+            Ok(42)
+        })?;
+        assert_eq!(value, 42);
+        assert_eq!(
+            *mock.cs_log.borrow(),
+            vec!["assert", "transaction", "transaction", "deassert"]
+        );
+
+        // CS is deasserted when the closure fails.
+        mock.cs_log.borrow_mut().clear();
+        let result: Result<()> = with_cs_asserted(&target, |spi| {
+            spi.run_transaction(&mut [Transfer::Write(&[0x03])])?;
+            bail!("failure")
+        });
+        assert!(result.is_err());
+        assert_eq!(
+            *mock.cs_log.borrow(),
+            vec!["assert", "transaction", "deassert"]
+        );
+
+        // And when it panics.
+        mock.cs_log.borrow_mut().clear();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            with_cs_asserted(&target, |_| -> Result<()> { panic!("failure") })
+        }));
+        assert!(result.is_err());
+        assert_eq!(*mock.cs_log.borrow(), vec!["assert", "deassert"]);
+        assert_eq!(mock.cs_count.get(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect_floating() -> Result<()> {
+        let target: Rc<dyn Target> = Rc::new(MockTarget {
+            response: vec![0xFF; 8],
+            ..Default::default()
+        });
+        let mut buf = [0u8; 8];
+        // Without detection, the data is returned as is.
+        target.run_transaction(&mut [Transfer::Write(&[0x9f]), Transfer::Read(&mut buf)])?;
+        assert_eq!(buf, [0xFF; 8]);
+
+        let warn = FloatingDetectTarget::new(Rc::clone(&target), FloatingDetection::Warn);
+        warn.run_transaction(&mut [Transfer::Write(&[0x9f]), Transfer::Read(&mut buf)])?;
+        assert_eq!(buf, [0xFF; 8]);
+
+        let error = FloatingDetectTarget::new(Rc::clone(&target), FloatingDetection::Error);
+        let err = error
+            .run_transaction(&mut [Transfer::Write(&[0x9f]), Transfer::Read(&mut buf)])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SpiError>(),
+            Some(SpiError::BusContention(8, 0xFF))
+        ));
+        // Short reads, such as of a status register, are not checked.
+        error.run_transaction(&mut [Transfer::Read(&mut buf[..1])])?;
+        // Any read fails, also one which is not an identification read.
+        let err = error
+            .run_transaction(&mut [
+                Transfer::Write(&[0x03, 0x00, 0x10, 0x00]),
+                Transfer::Read(&mut buf),
+            ])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SpiError>(),
+            Some(SpiError::BusContention(8, 0xFF))
+        ));
+
+        // The caller may exclude reads which can legitimately be uniform, e.g. of erased flash.
+        let filtered = FloatingDetectTarget::new(Rc::clone(&target), FloatingDetection::Error)
+            .with_filter(|opcode| opcode != Some(0x03));
+        filtered.run_transaction(&mut [
+            Transfer::Write(&[0x03, 0x00, 0x10, 0x00]),
+            Transfer::Read(&mut buf),
+        ])?;
+        assert_eq!(buf, [0xFF; 8]);
+        assert!(filtered
+            .run_transaction(&mut [Transfer::Write(&[0x9f]), Transfer::Read(&mut buf)])
+            .is_err());
+
+        // Data which is not uniform passes.
+        let mixed = FloatingDetectTarget::new(
+            Rc::new(MockTarget {
+                response: vec![0xFF, 0xFF, 0x00, 0xFF],
+                ..Default::default()
+            }),
+            FloatingDetection::Error,
+        );
+        mixed.run_transaction(&mut [Transfer::Read(&mut buf[..4])])?;
+        Ok(())
+    }
+
+    /// Reset pin recording its levels in the CS log of a `MockTarget`.
+    struct LoggedResetPin(Rc<MockTarget>);
+
+    impl GpioPin for LoggedResetPin {
+        fn read(&self) -> Result<bool> {
+            unimplemented!()
+        }
+        fn write(&self, value: bool) -> Result<()> {
+            self.0
+                .cs_log
+                .borrow_mut()
+                .push(if value { "reset high" } else { "reset low" });
+            Ok(())
+        }
+        fn set_mode(&self, _mode: crate::io::gpio::PinMode) -> Result<()> {
+            Ok(())
+        }
+        fn set_pull_mode(&self, _mode: crate::io::gpio::PullMode) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_init_sequence_reset_pulse() -> Result<()> {
+        let target = Rc::new(MockTarget {
+            response: vec![0x9f],
+            ..Default::default()
+        });
+        let reset_pin = LoggedResetPin(Rc::clone(&target));
+        let reads = run_init_sequence(
+            target.as_ref(),
+            Some(&reset_pin),
+            &[
+                InitStep::Transaction(vec![OwnedTransfer::Write(vec![0x01, 0x02])]),
+                InitStep::ResetPulse(Duration::from_millis(1)),
+                InitStep::Delay(Duration::from_millis(1)),
+                InitStep::Transaction(vec![
+                    OwnedTransfer::Write(vec![0x03]),
+                    OwnedTransfer::Read(1),
+                ]),
+            ],
+        )?;
+        assert_eq!(reads, vec![vec![0x9f]]);
+        assert_eq!(*target.written.borrow(), vec![0x01, 0x02, 0x03]);
+        // The pulse falls between the two transactions, while CS is deasserted.
+        assert_eq!(
+            *target.cs_log.borrow(),
+            vec!["transaction", "reset low", "reset high", "transaction"]
+        );
+        assert_eq!(target.cs_count.get(), 0);
+
+        // A reset pulse requires a reset pin.
+        assert!(run_init_sequence(
+            target.as_ref(),
+            None,
+            &[InitStep::ResetPulse(Duration::from_millis(1))]
+        )
+        .is_err());
+        Ok(())
+    }
+}
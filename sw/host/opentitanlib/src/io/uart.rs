@@ -4,13 +4,16 @@
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::io::Write;
 use std::rc::Rc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 use thiserror::Error;
 
 use crate::app::TransportWrapper;
 use crate::impl_serializable_error;
+use crate::transport::TransportError;
 
 #[derive(Clone, Debug, StructOpt, Serialize, Deserialize)]
 pub struct UartParams {
@@ -50,10 +53,16 @@ pub enum FlowControl {
 /// A trait which represents a UART.
 pub trait Uart {
     /// Returns the UART baudrate.  May return zero for virtual UARTs.
-    fn get_baudrate(&self) -> Result<u32>;
+    fn get_baudrate(&self) -> Result<u32> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
 
-    /// Sets the UART baudrate.  May do nothing for virtual UARTs.
-    fn set_baudrate(&self, baudrate: u32) -> Result<()>;
+    /// Sets the UART baudrate, taking effect on the open UART, such that e.g. a console whose
+    /// speed is changed by the device can be followed without re-opening.  May do nothing for
+    /// virtual UARTs.
+    fn set_baudrate(&self, _baudrate: u32) -> Result<()> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
 
     /// Enables software flow control for `write`s.
     fn set_flow_control(&self, flow_control: bool) -> Result<()> {
@@ -72,9 +81,39 @@ pub trait Uart {
     /// If timeout expires without any data arriving `Ok(0)` will be returned, never `Err(_)`.
     fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize>;
 
+    /// Like `read_timeout()`, but returns `Err(TransportError::Cancelled)` soon after `cancel` is
+    /// set, e.g. by another thread holding a clone of the `Arc<AtomicBool>`.  The UART is waited
+    /// on in short intervals, such that no data is lost if the read is cancelled.
+    fn read_cancellable(
+        &self,
+        buf: &mut [u8],
+        timeout: Duration,
+        cancel: &AtomicBool,
+    ) -> Result<usize> {
+        const POLL_INTERVAL: Duration = Duration::from_millis(10);
+        let deadline = Instant::now() + timeout;
+        loop {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(TransportError::Cancelled.into());
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let len = self.read_timeout(buf, std::cmp::min(remaining, POLL_INTERVAL))?;
+            if len > 0 || remaining <= POLL_INTERVAL {
+                return Ok(len);
+            }
+        }
+    }
+
     /// Writes data from `buf` to the UART.
     fn write(&self, buf: &[u8]) -> Result<()>;
 
+    /// Registers a sink, such as a log file, to which all data subsequently returned by `read()`
+    /// and `read_timeout()` will be copied.  Errors writing to a sink are logged, and do not
+    /// affect reading from the UART.
+    fn add_sink(&self, _sink: Box<dyn Write + Send>) -> Result<()> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
+
     /// Clears the UART RX buffer.
     fn clear_rx_buffer(&self) -> Result<()> {
         // Keep reading while until the RX buffer is empty.
@@ -101,7 +140,139 @@ pub enum UartError {
     ReadError(String),
     #[error("Writing: {0}")]
     WriteError(String),
+    #[error("No data received for {0:?}")]
+    Inactive(Duration),
     #[error("{0}")]
     GenericError(String),
 }
 impl_serializable_error!(UartError);
+
+/// Liveness watchdog for a UART, such as the console of a long running test.  Reads from `uart`,
+/// passing all data received to `on_data`, until `on_data` returns `false`.  If no byte arrives
+/// for `window`, the link is presumed dead, and `UartError::Inactive` is returned.  The window
+/// restarts with each byte received, such that a device which is merely quiet between outputs
+/// for less than `window` is not mistaken for a hang.
+pub fn watch_activity(
+    uart: &dyn Uart,
+    window: Duration,
+    mut on_data: impl FnMut(&[u8]) -> Result<bool>,
+) -> Result<()> {
+    let mut buf = [0u8; 256];
+    let mut deadline = Instant::now() + window;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(UartError::Inactive(window).into());
+        }
+        let len = uart.read_timeout(&mut buf, remaining)?;
+        if len > 0 {
+            deadline = Instant::now() + window;
+            if !on_data(&buf[..len])? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+
+    /// Delivers data pushed by the test, waiting the full timeout if there is none.
+    #[derive(Default)]
+    struct MockUart {
+        rx: RefCell<VecDeque<u8>>,
+    }
+
+    impl Uart for MockUart {
+        fn get_baudrate(&self) -> Result<u32> {
+            Ok(0)
+        }
+        fn set_baudrate(&self, _baudrate: u32) -> Result<()> {
+            Ok(())
+        }
+        fn read(&self, buf: &mut [u8]) -> Result<usize> {
+            self.read_timeout(buf, Duration::from_secs(3600))
+        }
+        fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+            let mut rx = self.rx.borrow_mut();
+            if rx.is_empty() {
+                std::thread::sleep(timeout);
+                return Ok(0);
+            }
+            let len = std::cmp::min(buf.len(), rx.len());
+            for (byte, ch) in buf.iter_mut().zip(rx.drain(..len)) {
+                *byte = ch;
+            }
+            Ok(len)
+        }
+        fn write(&self, _buf: &[u8]) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_read_cancellable() -> Result<()> {
+        let uart = MockUart::default();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let controller = {
+            let cancel = Arc::clone(&cancel);
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                cancel.store(true, Ordering::Relaxed);
+            })
+        };
+
+        let start = Instant::now();
+        let mut buf = [0u8; 16];
+        let err = uart
+            .read_cancellable(&mut buf, Duration::from_secs(60), &cancel)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransportError>(),
+            Some(TransportError::Cancelled)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        controller.join().unwrap();
+
+        // The UART remains usable, and subsequent reads see all data.
+        uart.rx.borrow_mut().extend(b"PASS!");
+        cancel.store(false, Ordering::Relaxed);
+        let len = uart.read_cancellable(&mut buf, Duration::from_secs(1), &cancel)?;
+        assert_eq!(&buf[..len], b"PASS!");
+
+        // Expiry of the timeout is not an error.
+        let len = uart.read_cancellable(&mut buf, Duration::from_millis(25), &cancel)?;
+        assert_eq!(len, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_activity() -> Result<()> {
+        let uart = MockUart::default();
+        uart.rx.borrow_mut().extend(b"Running test\n");
+        let window = Duration::from_millis(50);
+        let mut received = Vec::new();
+        let start = Instant::now();
+        let err = watch_activity(&uart, window, |data| {
+            received.extend_from_slice(data);
+            Ok(true)
+        })
+        .unwrap_err();
+        // All data is seen before the link goes silent for the entire window.
+        assert_eq!(received, b"Running test\n");
+        assert!(matches!(
+            err.downcast_ref::<UartError>(),
+            Some(UartError::Inactive(w)) if *w == window
+        ));
+        assert!(start.elapsed() >= window);
+
+        // The caller may end watching on seeing the expected output.
+        uart.rx.borrow_mut().extend(b"PASS!");
+        watch_activity(&uart, window, |data| Ok(data != b"PASS!"))?;
+        Ok(())
+    }
+}
@@ -68,6 +68,16 @@ impl<'a> TransportCommandHandler<'a> {
                         instance.set_pull_mode(*pull)?;
                         Ok(Response::Gpio(GpioResponse::SetPullMode))
                     }
+                    GpioRequest::GetMode => {
+                        let mode = instance.get_mode()?;
+                        Ok(Response::Gpio(GpioResponse::GetMode { mode }))
+                    }
+                    GpioRequest::GetCapabilities => {
+                        let capabilities = instance.capabilities()?;
+                        Ok(Response::Gpio(GpioResponse::GetCapabilities {
+                            capabilities,
+                        }))
+                    }
                 }
             }
             Request::Uart { id, command } => {
@@ -127,6 +137,10 @@ impl<'a> TransportCommandHandler<'a> {
                         instance.set_max_speed(*value)?;
                         Ok(Response::Spi(SpiResponse::SetMaxSpeed))
                     }
+                    SpiRequest::GetHardwareMaxSpeed => {
+                        let speed = instance.get_hardware_max_speed()?;
+                        Ok(Response::Spi(SpiResponse::GetHardwareMaxSpeed { speed }))
+                    }
                     SpiRequest::GetMaxTransferCount => {
                         let number = instance.get_max_transfer_count()?;
                         Ok(Response::Spi(SpiResponse::GetMaxTransferCount { number }))
@@ -145,6 +159,22 @@ impl<'a> TransportCommandHandler<'a> {
                         instance.set_voltage(*voltage)?;
                         Ok(Response::Spi(SpiResponse::SetVoltage))
                     }
+                    SpiRequest::SetCsIdleLevel { high } => {
+                        instance.set_cs_idle_level(*high)?;
+                        Ok(Response::Spi(SpiResponse::SetCsIdleLevel))
+                    }
+                    SpiRequest::SetCsPolarity { active_high } => {
+                        instance.set_cs_polarity(*active_high)?;
+                        Ok(Response::Spi(SpiResponse::SetCsPolarity))
+                    }
+                    SpiRequest::SetMinDeselectTime { duration } => {
+                        instance.set_min_deselect_time(*duration)?;
+                        Ok(Response::Spi(SpiResponse::SetMinDeselectTime))
+                    }
+                    SpiRequest::SetTransactionTimeout { timeout } => {
+                        instance.set_transaction_timeout(*timeout)?;
+                        Ok(Response::Spi(SpiResponse::SetTransactionTimeout))
+                    }
                     SpiRequest::RunTransaction { transaction: reqs } => {
                         // Construct proper response to each transfer in request.
                         let mut resps: Vec<SpiTransferResponse> = reqs
@@ -153,7 +183,10 @@ impl<'a> TransportCommandHandler<'a> {
                                 SpiTransferRequest::Read { len } => SpiTransferResponse::Read {
                                     data: vec![0; *len as usize],
                                 },
-                                SpiTransferRequest::Write { .. } => SpiTransferResponse::Write,
+                                SpiTransferRequest::Write { .. }
+                                | SpiTransferRequest::Fill { .. }
+                                | SpiTransferRequest::SetSpeed { .. }
+                                | SpiTransferRequest::Turnaround => SpiTransferResponse::Write,
                                 SpiTransferRequest::Both { data } => SpiTransferResponse::Both {
                                     data: vec![0; data.len()],
                                 },
@@ -179,6 +212,20 @@ impl<'a> TransportCommandHandler<'a> {
                                     SpiTransferRequest::Both { data: wdata },
                                     SpiTransferResponse::Both { data },
                                 ) => spi::Transfer::Both(wdata, data),
+                                (
+                                    SpiTransferRequest::Fill { value, count },
+                                    SpiTransferResponse::Write,
+                                ) => spi::Transfer::Fill {
+                                    value: *value,
+                                    count: *count,
+                                },
+                                (
+                                    SpiTransferRequest::SetSpeed { speed },
+                                    SpiTransferResponse::Write,
+                                ) => spi::Transfer::SetSpeed(*speed),
+                                (SpiTransferRequest::Turnaround, SpiTransferResponse::Write) => {
+                                    spi::Transfer::Turnaround
+                                }
                                 _ => {
                                     // This can only happen if the logic in this method is
                                     // flawed.  (Never due to network input.)
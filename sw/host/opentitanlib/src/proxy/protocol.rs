@@ -4,10 +4,11 @@
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::Duration;
 
 use crate::bootstrap::BootstrapOptions;
 use crate::io::emu::{EmuState, EmuValue};
-use crate::io::gpio::{PinMode, PullMode};
+use crate::io::gpio::{GpioPinCapabilities, PinMode, PullMode};
 use crate::io::spi::{MaxSizes, TransferMode};
 use crate::proxy::errors::SerializedError;
 use crate::transport::Capabilities;
@@ -49,6 +50,8 @@ pub enum GpioRequest {
     Read,
     SetMode { mode: PinMode },
     SetPullMode { pull: PullMode },
+    GetMode,
+    GetCapabilities,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -57,6 +60,8 @@ pub enum GpioResponse {
     Read { value: bool },
     SetMode,
     SetPullMode,
+    GetMode { mode: PinMode },
+    GetCapabilities { capabilities: GpioPinCapabilities },
 }
 
 #[derive(Serialize, Deserialize)]
@@ -87,6 +92,9 @@ pub enum SpiTransferRequest {
     Read { len: u32 },
     Write { data: Vec<u8> },
     Both { data: Vec<u8> },
+    Fill { value: u8, count: usize },
+    SetSpeed { speed: u32 },
+    Turnaround,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -110,12 +118,25 @@ pub enum SpiRequest {
     SetMaxSpeed {
         value: u32,
     },
+    GetHardwareMaxSpeed,
     GetMaxTransferCount,
     GetMaxTransferSizes,
     GetEepromMaxTransferSizes,
     SetVoltage {
         voltage: Voltage,
     },
+    SetCsIdleLevel {
+        high: bool,
+    },
+    SetCsPolarity {
+        active_high: bool,
+    },
+    SetMinDeselectTime {
+        duration: Duration,
+    },
+    SetTransactionTimeout {
+        timeout: Duration,
+    },
     RunTransaction {
         transaction: Vec<SpiTransferRequest>,
     },
@@ -137,6 +158,9 @@ pub enum SpiResponse {
         speed: u32,
     },
     SetMaxSpeed,
+    GetHardwareMaxSpeed {
+        speed: u32,
+    },
     GetMaxTransferCount {
         number: usize,
     },
@@ -147,6 +171,10 @@ pub enum SpiResponse {
         sizes: MaxSizes,
     },
     SetVoltage,
+    SetCsIdleLevel,
+    SetCsPolarity,
+    SetMinDeselectTime,
+    SetTransactionTimeout,
     RunTransaction {
         transaction: Vec<SpiTransferResponse>,
     },
@@ -4,9 +4,12 @@
 
 use crate::io::eeprom::{AddressMode, Transaction, MODE_111};
 use crate::io::spi::Target;
+use crate::spiflash::jedec::FlashIdentity;
 use crate::spiflash::sfdp::{BlockEraseSize, Sfdp, SupportedAddressModes};
 use anyhow::{ensure, Result};
 use std::convert::TryFrom;
+use std::ops::Range;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -19,6 +22,28 @@ pub enum Error {
     BadEraseLength(u32, u32),
     #[error("bad sequence length: {0}")]
     BadSequenceLength(usize),
+    #[error("security registers not supported by flash with JEDEC ID {0:02x?}")]
+    SecurityRegistersUnsupported(Vec<u8>),
+    #[error("security register access at {0:#x} for {1} bytes outside of any register")]
+    BadSecurityRegisterAddress(u32, usize),
+    #[error("security registers of this flash cannot be erased")]
+    SecurityRegisterEraseUnsupported,
+    #[error("page program at address {0:#x} still busy after {1:?}")]
+    PageProgramTimeout(u32, Duration),
+    #[error("spare sector {0:#x} is misaligned, bad or overlaps the programmed region")]
+    InvalidSpareSector(u32),
+    #[error("bad sector {0:#x} is misaligned")]
+    InvalidBadSector(u32),
+    #[error("write enable latch did not set, flash may be write protected")]
+    WriteEnableFailed,
+    #[error("verify failed at address {0:#x}: expected {1:#04x}, read {2:#04x}")]
+    VerifyMismatch(u32, u8, u8),
+    #[error("flash still busy after {0:?}")]
+    BusyTimeout(Duration),
+    #[error("program failed, error status {0:#04x}")]
+    ProgramFailed(u8),
+    #[error("erase failed, error status {0:#04x}")]
+    EraseFailed(u8),
 }
 
 impl From<SupportedAddressModes> for AddressMode {
@@ -30,6 +55,159 @@ impl From<SupportedAddressModes> for AddressMode {
     }
 }
 
+/// Opcodes and layout of the security (OTP) registers of a particular flash part.  These
+/// registers occupy an address space separate from the main array.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SecurityRegisters {
+    pub read_opcode: u8,
+    pub program_opcode: u8,
+    /// `None` if the registers are one-time programmable.
+    pub erase_opcode: Option<u8>,
+    /// Address range of each register within the security register address space.
+    pub registers: Vec<Range<u32>>,
+}
+
+impl SecurityRegisters {
+    /// JEDEC manufacturer ID of Winbond.
+    pub const WINBOND: u8 = 0xef;
+    /// JEDEC manufacturer ID of Micron.
+    pub const MICRON: u8 = 0x20;
+
+    /// Winbond parts have three erasable 256-byte registers, selected by address bits 12-15.
+    pub fn winbond() -> Self {
+        SecurityRegisters {
+            read_opcode: SpiFlash::READ_SECURITY_REGISTER,
+            program_opcode: SpiFlash::PROGRAM_SECURITY_REGISTER,
+            erase_opcode: Some(SpiFlash::ERASE_SECURITY_REGISTER),
+            registers: vec![0x1000..0x1100, 0x2000..0x2100, 0x3000..0x3100],
+        }
+    }
+
+    /// Micron parts have a single 64-byte one-time programmable area.
+    pub fn micron() -> Self {
+        SecurityRegisters {
+            read_opcode: SpiFlash::READ_OTP,
+            program_opcode: SpiFlash::PROGRAM_SECURITY_REGISTER,
+            erase_opcode: None,
+            registers: vec![Range { start: 0, end: 64 }],
+        }
+    }
+
+    /// Determine the security register layout from the JEDEC ID of the flash part.
+    pub fn from_jedec_id(jedec_id: &[u8]) -> Result<Self> {
+        match jedec_id.first() {
+            Some(&Self::WINBOND) => Ok(Self::winbond()),
+            Some(&Self::MICRON) => Ok(Self::micron()),
+            _ => Err(Error::SecurityRegistersUnsupported(jedec_id.to_vec()).into()),
+        }
+    }
+
+    /// Read the JEDEC ID from the `spi` target and determine its security register layout.
+    pub fn detect(spi: &dyn Target) -> Result<Self> {
+        Self::from_jedec_id(&SpiFlash::read_jedec_id(spi, 3)?)
+    }
+
+    /// Returns the register containing all of `address..address + len`.
+    pub fn register(&self, address: u32, len: usize) -> Result<Range<u32>> {
+        let end = address as u64 + len as u64;
+        self.registers
+            .iter()
+            .find(|r| r.start <= address && end <= r.end as u64)
+            .cloned()
+            .ok_or_else(|| Error::BadSecurityRegisterAddress(address, len).into())
+    }
+}
+
+/// Register in which a flash part reports the failure of program and erase operations, which the
+/// busy bit alone does not reveal.  Only some parts have one, at a vendor-specific location.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorStatus {
+    pub read_opcode: u8,
+    /// Opcode resetting the failure bits, `None` if they clear on their own with the next
+    /// program or erase operation.
+    pub clear_opcode: Option<u8>,
+    pub program_fail: u8,
+    pub erase_fail: u8,
+}
+
+impl ErrorStatus {
+    /// JEDEC manufacturer ID of Macronix.
+    pub const MACRONIX: u8 = 0xc2;
+
+    /// Flag status register, found on Micron parts and indicated by SFDP.
+    pub fn flag_status() -> Self {
+        ErrorStatus {
+            read_opcode: SpiFlash::READ_FLAG_STATUS,
+            clear_opcode: Some(SpiFlash::CLEAR_FLAG_STATUS),
+            program_fail: 0x10,
+            erase_fail: 0x20,
+        }
+    }
+
+    /// Security register of Macronix parts, with the P_FAIL and E_FAIL bits.
+    pub fn macronix() -> Self {
+        ErrorStatus {
+            read_opcode: SpiFlash::READ_SECURITY_STATUS,
+            clear_opcode: None,
+            program_fail: 0x20,
+            erase_fail: 0x40,
+        }
+    }
+
+    /// Determine the error status register from the JEDEC ID of the flash part, `None` if the
+    /// part is not known to have one.
+    pub fn from_jedec_id(jedec_id: &[u8]) -> Option<Self> {
+        match jedec_id.first() {
+            Some(&SecurityRegisters::MICRON) => Some(Self::flag_status()),
+            Some(&Self::MACRONIX) => Some(Self::macronix()),
+            _ => None,
+        }
+    }
+
+    /// Read the register, failing with `Error::ProgramFailed` or `Error::EraseFailed` if a
+    /// failure bit is set.  The bits are cleared before reporting the failure, such that the
+    /// next operation starts afresh.
+    pub fn check(&self, spi: &dyn Target) -> Result<()> {
+        let mut status = 0u8;
+        spi.run_eeprom_transactions(&mut [Transaction::Read(
+            MODE_111.cmd(self.read_opcode),
+            std::slice::from_mut(&mut status),
+        )])?;
+        if status & (self.program_fail | self.erase_fail) == 0 {
+            return Ok(());
+        }
+        if let Some(opcode) = self.clear_opcode {
+            spi.run_eeprom_transactions(&mut [Transaction::Command(MODE_111.cmd(opcode))])?;
+        }
+        if status & self.program_fail != 0 {
+            Err(Error::ProgramFailed(status).into())
+        } else {
+            Err(Error::EraseFailed(status).into())
+        }
+    }
+}
+
+/// Sectors of a flash part known to be bad, for use with `SpiFlash::program_region()`.
+/// Sectors are identified by their start address, and have the size of `SpiFlash::erase_size`.
+#[derive(Clone, Debug, Default)]
+pub struct BadBlockTable {
+    /// Sectors which must not be programmed.
+    pub bad: Vec<u32>,
+    /// Spare sectors, outside of the region being programmed, which take the place of bad
+    /// sectors in order.  Relocation only happens if the caller provides spares, data destined
+    /// for a bad sector is skipped once no spare remains.
+    pub spares: Vec<u32>,
+}
+
+/// Outcome of `SpiFlash::program_region()` for sectors marked bad.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProgramRegionReport {
+    /// Bad sectors whose data was not written anywhere.
+    pub skipped: Vec<u32>,
+    /// Bad sectors whose data was written to a spare sector, as (bad, spare) pairs.
+    pub relocated: Vec<(u32, u32)>,
+}
+
 pub struct SpiFlash {
     pub size: u32,
     pub erase_size: u32,
@@ -37,6 +215,8 @@ pub struct SpiFlash {
     pub program_size: u32,
     pub address_mode: AddressMode,
     pub sfdp: Option<Sfdp>,
+    /// Register reporting failed program and erase operations, checked by `wait_ready()`.
+    pub error_status: Option<ErrorStatus>,
 }
 
 impl Default for SpiFlash {
@@ -48,6 +228,7 @@ impl Default for SpiFlash {
             program_size: SpiFlash::LEGACY_PAGE_SIZE,
             address_mode: AddressMode::default(),
             sfdp: None,
+            error_status: None,
         }
     }
 }
@@ -75,6 +256,16 @@ impl SpiFlash {
     pub const NOP: u8 = 0x00;
     pub const RESET_ENABLE: u8 = 0x66;
     pub const RESET: u8 = 0x99;
+    // Security register opcodes, as used by Winbond parts.  Micron parts use 0x4b to read their
+    // OTP area.
+    pub const READ_SECURITY_REGISTER: u8 = 0x48;
+    pub const PROGRAM_SECURITY_REGISTER: u8 = 0x42;
+    pub const ERASE_SECURITY_REGISTER: u8 = 0x44;
+    pub const READ_OTP: u8 = 0x4b;
+    // Error status registers, see `ErrorStatus`.
+    pub const READ_FLAG_STATUS: u8 = 0x70;
+    pub const CLEAR_FLAG_STATUS: u8 = 0x50;
+    pub const READ_SECURITY_STATUS: u8 = 0x2b;
 
     /// The legacy JEDEC page size for programming operations is 256 bytes.
     pub const LEGACY_PAGE_SIZE: u32 = 256;
@@ -95,6 +286,13 @@ impl SpiFlash {
         Ok(buf)
     }
 
+    /// Read the JEDEC ID from the `spi` target, and decode it into manufacturer and part.
+    pub fn identify(spi: &dyn Target) -> Result<FlashIdentity> {
+        Ok(FlashIdentity::from_jedec_id(&SpiFlash::read_jedec_id(
+            spi, 3,
+        )?))
+    }
+
     /// Read status register from the `spi` target.
     pub fn read_status(spi: &dyn Target) -> Result<u8> {
         let mut buf = [0u8; 1];
@@ -128,6 +326,21 @@ impl SpiFlash {
         Ok(())
     }
 
+    /// Poll the status register until the busy bit clears, failing with `Error::BusyTimeout`
+    /// after `timeout`.  If the part reports the outcome of program and erase operations, see
+    /// `error_status`, a failure is reported as `Error::ProgramFailed` or `Error::EraseFailed`,
+    /// rather than as success once the part is no longer busy.
+    pub fn wait_ready(&self, spi: &dyn Target, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while SpiFlash::read_status(spi)? & SpiFlash::STATUS_WIP != 0 {
+            ensure!(Instant::now() < deadline, Error::BusyTimeout(timeout));
+        }
+        match &self.error_status {
+            Some(error_status) => error_status.check(spi),
+            None => Ok(()),
+        }
+    }
+
     /// Send the WRITE_ENABLE opcode to the `spi` target.
     pub fn set_write_enable(spi: &dyn Target) -> Result<()> {
         spi.run_eeprom_transactions(&mut [Transaction::Command(
@@ -136,6 +349,34 @@ impl SpiFlash {
         Ok(())
     }
 
+    /// Runs `transactions` modifying the flash, after WRITE_ENABLE and a status read confirming
+    /// that the write enable latch got set.  A part which does not set WEL, e.g. because it is
+    /// write protected, would silently ignore the modification, so it is not sent, and
+    /// `Error::WriteEnableFailed` is reported instead.
+    fn run_write_transactions(spi: &dyn Target, mut transactions: Vec<Transaction>) -> Result<()> {
+        let mut status = 0u8;
+        spi.run_eeprom_transactions(&mut [
+            Transaction::Command(MODE_111.cmd(SpiFlash::WRITE_ENABLE)),
+            Transaction::Read(
+                MODE_111.cmd(SpiFlash::READ_STATUS),
+                std::slice::from_mut(&mut status),
+            ),
+        ])?;
+        ensure!(status & SpiFlash::STATUS_WEL != 0, Error::WriteEnableFailed);
+        spi.run_eeprom_transactions(&mut transactions)
+    }
+
+    /// Write `value` to the status register of the `spi` target.
+    pub fn write_status(spi: &dyn Target, value: u8) -> Result<()> {
+        SpiFlash::run_write_transactions(
+            spi,
+            vec![
+                Transaction::Write(MODE_111.cmd(SpiFlash::WRITE_STATUS), &[value]),
+                Transaction::WaitForBusyClear,
+            ],
+        )
+    }
+
     /// Send the WRITE_DISABLE opcode to the `spi` target.
     pub fn set_write_disable(spi: &dyn Target) -> Result<()> {
         spi.run_eeprom_transactions(&mut [Transaction::Command(
@@ -182,6 +423,11 @@ impl SpiFlash {
             BlockEraseSize::Block4KiB => (4096, sfdp.jedec.erase_opcode_4kib),
             _ => (sfdp.jedec.erase[0].size, sfdp.jedec.erase[0].opcode),
         };
+        // Bit 1 of the polling methods indicates the flag status register.
+        let flag_status = matches!(
+            &sfdp.jedec.rev_b,
+            Some(rev_b) if rev_b.status_register_polling & 0x02 != 0
+        );
         SpiFlash {
             size: sfdp.jedec.density,
             erase_size: erase_sz,
@@ -189,6 +435,7 @@ impl SpiFlash {
             program_size: SpiFlash::LEGACY_PAGE_SIZE,
             address_mode: AddressMode::from(sfdp.jedec.address_modes),
             sfdp: Some(sfdp),
+            error_status: flag_status.then(ErrorStatus::flag_status),
         }
     }
 
@@ -249,11 +496,13 @@ impl SpiFlash {
 
     /// Erase the entire EEPROM via the CHIP_ERASE opcode.
     pub fn chip_erase(&self, spi: &dyn Target) -> Result<&Self> {
-        spi.run_eeprom_transactions(&mut [
-            Transaction::Command(MODE_111.cmd(SpiFlash::WRITE_ENABLE)),
-            Transaction::Command(MODE_111.cmd(SpiFlash::CHIP_ERASE)),
-            Transaction::WaitForBusyClear,
-        ])?;
+        SpiFlash::run_write_transactions(
+            spi,
+            vec![
+                Transaction::Command(MODE_111.cmd(SpiFlash::CHIP_ERASE)),
+                Transaction::WaitForBusyClear,
+            ],
+        )?;
         Ok(self)
     }
 
@@ -281,15 +530,17 @@ impl SpiFlash {
         }
         let end = address + length;
         for addr in (address..end).step_by(self.erase_size as usize) {
-            spi.run_eeprom_transactions(&mut [
-                Transaction::Command(MODE_111.cmd(SpiFlash::WRITE_ENABLE)),
-                Transaction::Command(MODE_111.cmd_addr(
-                    SpiFlash::SECTOR_ERASE,
-                    addr,
-                    self.address_mode,
-                )),
-                Transaction::WaitForBusyClear,
-            ])?;
+            SpiFlash::run_write_transactions(
+                spi,
+                vec![
+                    Transaction::Command(MODE_111.cmd_addr(
+                        SpiFlash::SECTOR_ERASE,
+                        addr,
+                        self.address_mode,
+                    )),
+                    Transaction::WaitForBusyClear,
+                ],
+            )?;
             progress(addr, self.erase_size);
         }
         Ok(self)
@@ -327,14 +578,16 @@ impl SpiFlash {
             let chunk = &buffer[chunk_start..chunk_end];
             // Skip this chunk if all bytes are 0xff.
             if !chunk.iter().all(|&x| x == 0xff) {
-                spi.run_eeprom_transactions(&mut [
-                    Transaction::Command(MODE_111.cmd(SpiFlash::WRITE_ENABLE)),
-                    Transaction::Write(
-                        MODE_111.cmd_addr(SpiFlash::PAGE_PROGRAM, address, self.address_mode),
-                        chunk,
-                    ),
-                    Transaction::WaitForBusyClear,
-                ])?;
+                SpiFlash::run_write_transactions(
+                    spi,
+                    vec![
+                        Transaction::Write(
+                            MODE_111.cmd_addr(SpiFlash::PAGE_PROGRAM, address, self.address_mode),
+                            chunk,
+                        ),
+                        Transaction::WaitForBusyClear,
+                    ],
+                )?;
             }
             address += chunk_size as u32;
             chunk_start += chunk_size;
@@ -344,6 +597,215 @@ impl SpiFlash {
         Ok(self)
     }
 
+    /// Program a segment of the SPI flash starting at `address` with the contents of `data`, like
+    /// `program()`, but polling the status register with a timeout after each page.  Once the write
+    /// enable is confirmed, the page program and first status read of each page are sent
+    /// together, further status reads are only needed while the page is still being programmed.  Fails with
+    /// `Error::PageProgramTimeout` naming the page address, if the busy bit does not clear within
+    /// `page_timeout`.
+    pub fn program_with_autopoll(
+        &self,
+        spi: &dyn Target,
+        mut address: u32,
+        data: &[u8],
+        page_timeout: Duration,
+    ) -> Result<&Self> {
+        let mut remain = data.len();
+        let mut chunk_start = 0usize;
+        while remain != 0 {
+            // Align chunks to the programming page, as in `program_with_progress()`.
+            let chunk_size = (self.program_size - (address % self.program_size)) as usize;
+            let chunk_size = std::cmp::min(chunk_size, remain);
+            let chunk = &data[chunk_start..chunk_start + chunk_size];
+            if !chunk.iter().all(|&x| x == 0xff) {
+                let deadline = Instant::now() + page_timeout;
+                let mut status = 0u8;
+                SpiFlash::run_write_transactions(
+                    spi,
+                    vec![
+                        Transaction::Write(
+                            MODE_111.cmd_addr(SpiFlash::PAGE_PROGRAM, address, self.address_mode),
+                            chunk,
+                        ),
+                        Transaction::Read(
+                            MODE_111.cmd(SpiFlash::READ_STATUS),
+                            std::slice::from_mut(&mut status),
+                        ),
+                    ],
+                )?;
+                while status & SpiFlash::STATUS_WIP != 0 {
+                    ensure!(
+                        Instant::now() < deadline,
+                        Error::PageProgramTimeout(address, page_timeout)
+                    );
+                    status = SpiFlash::read_status(spi)?;
+                }
+            }
+            address += chunk_size as u32;
+            chunk_start += chunk_size;
+            remain -= chunk_size;
+        }
+        Ok(self)
+    }
+
+    /// Read back a segment of the SPI flash starting at `address`, and compare it with
+    /// `expected`, failing with `Error::VerifyMismatch` naming the first differing address.
+    pub fn verify(&self, spi: &dyn Target, address: u32, expected: &[u8]) -> Result<&Self> {
+        let mut actual = vec![0u8; expected.len()];
+        self.read(spi, address, &mut actual)?;
+        if let Some(pos) = actual.iter().zip(expected).position(|(a, e)| a != e) {
+            return Err(
+                Error::VerifyMismatch(address + pos as u32, expected[pos], actual[pos]).into(),
+            );
+        }
+        Ok(self)
+    }
+
+    /// Program a segment of the SPI flash starting at `address` with the contents of `buffer`,
+    /// like `program()`, but avoiding sectors marked bad in `bad_blocks`.  Data belonging to a
+    /// bad sector is written at the same offset within the next unused spare sector, or skipped
+    /// if no spare remains.  This function will not erase the spare sectors first.  If `verify`,
+    /// all data written is read back afterwards, see `verify()`.
+    pub fn program_region(
+        &self,
+        spi: &dyn Target,
+        address: u32,
+        buffer: &[u8],
+        bad_blocks: Option<&BadBlockTable>,
+        verify: bool,
+    ) -> Result<ProgramRegionReport> {
+        let mut report = ProgramRegionReport::default();
+        let bad_blocks = match bad_blocks {
+            Some(table) => table,
+            None => {
+                self.program(spi, address, buffer)?;
+                if verify {
+                    self.verify(spi, address, buffer)?;
+                }
+                return Ok(report);
+            }
+        };
+        let end = address as u64 + buffer.len() as u64;
+        if let Some(&bad) = bad_blocks
+            .bad
+            .iter()
+            .find(|&&bad| bad % self.erase_size != 0)
+        {
+            return Err(Error::InvalidBadSector(bad).into());
+        }
+        for &spare in &bad_blocks.spares {
+            if spare % self.erase_size != 0
+                || bad_blocks.bad.contains(&spare)
+                || ((spare as u64) < end
+                    && (address as u64) < spare as u64 + self.erase_size as u64)
+            {
+                return Err(Error::InvalidSpareSector(spare).into());
+            }
+        }
+        let mut spares = bad_blocks.spares.iter();
+        // Flash address and data of each chunk written.
+        let mut written = Vec::new();
+        let mut chunk_address = address;
+        let mut chunk_start = 0usize;
+        while chunk_start < buffer.len() {
+            // Split the buffer at sector boundaries.
+            let offset = chunk_address % self.erase_size;
+            let sector = chunk_address - offset;
+            let chunk_size = std::cmp::min(
+                (self.erase_size - offset) as usize,
+                buffer.len() - chunk_start,
+            );
+            let chunk = &buffer[chunk_start..chunk_start + chunk_size];
+            if !bad_blocks.bad.contains(&sector) {
+                self.program(spi, chunk_address, chunk)?;
+                written.push((chunk_address, chunk));
+            } else if let Some(&spare) = spares.next() {
+                self.program(spi, spare + offset, chunk)?;
+                written.push((spare + offset, chunk));
+                report.relocated.push((sector, spare));
+            } else {
+                log::warn!("Skipping bad sector at {:#x}", sector);
+                report.skipped.push(sector);
+            }
+            chunk_address += chunk_size as u32;
+            chunk_start += chunk_size;
+        }
+        if verify {
+            for (chunk_address, chunk) in written {
+                self.verify(spi, chunk_address, chunk)?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Read `len` bytes from the security registers of the SPI flash starting at `address`,
+    /// which must lie within a single register.  The part is identified by its JEDEC ID.
+    pub fn read_security_register(
+        &self,
+        spi: &dyn Target,
+        address: u32,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let regs = SecurityRegisters::detect(spi)?;
+        regs.register(address, len)?;
+        let mut buf = vec![0u8; len];
+        let mut chunk_address = address;
+        for chunk in buf.chunks_mut(spi.get_eeprom_max_transfer_sizes()?.read) {
+            // Security register reads are followed by a dummy byte, like fast reads.
+            spi.run_eeprom_transactions(&mut [Transaction::Read(
+                MODE_111.dummy_cycles(8).cmd_addr(
+                    regs.read_opcode,
+                    chunk_address,
+                    self.address_mode,
+                ),
+                chunk,
+            )])?;
+            chunk_address += chunk.len() as u32;
+        }
+        Ok(buf)
+    }
+
+    /// Program the security registers of the SPI flash starting at `address` with the contents
+    /// of `buffer`, which must lie within a single register.  This function will not erase the
+    /// register first.
+    pub fn program_security_register(
+        &self,
+        spi: &dyn Target,
+        address: u32,
+        buffer: &[u8],
+    ) -> Result<&Self> {
+        let regs = SecurityRegisters::detect(spi)?;
+        regs.register(address, buffer.len())?;
+        SpiFlash::run_write_transactions(
+            spi,
+            vec![
+                Transaction::Write(
+                    MODE_111.cmd_addr(regs.program_opcode, address, self.address_mode),
+                    buffer,
+                ),
+                Transaction::WaitForBusyClear,
+            ],
+        )?;
+        Ok(self)
+    }
+
+    /// Erase the security register containing `address`, if the part supports it.
+    pub fn erase_security_register(&self, spi: &dyn Target, address: u32) -> Result<&Self> {
+        let regs = SecurityRegisters::detect(spi)?;
+        let register = regs.register(address, 0)?;
+        let opcode = regs
+            .erase_opcode
+            .ok_or(Error::SecurityRegisterEraseUnsupported)?;
+        SpiFlash::run_write_transactions(
+            spi,
+            vec![
+                Transaction::Command(MODE_111.cmd_addr(opcode, register.start, self.address_mode)),
+                Transaction::WaitForBusyClear,
+            ],
+        )?;
+        Ok(self)
+    }
+
     /// Send the software reset sequence to the `spi` target.
     pub fn chip_reset(spi: &dyn Target) -> Result<()> {
         spi.run_eeprom_transactions(&mut [
@@ -353,3 +815,485 @@ impl SpiFlash {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::spi::{AssertChipSelect, MaxSizes, TransferMode};
+    use anyhow::bail;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    /// Emulates a flash part whose security registers are stored separately from the main array.
+    struct FlashMock {
+        jedec_id: Vec<u8>,
+        regs: SecurityRegisters,
+        main: RefCell<Vec<u8>>,
+        security: RefCell<Vec<u8>>,
+        write_enabled: RefCell<bool>,
+        /// Ignores WRITE_ENABLE, and therefore any modification, as with the WP pin asserted.
+        write_protected: bool,
+        status: Cell<u8>,
+        /// Micron flag status register, with the failure bits of the previous operations.
+        flag_status: Cell<u8>,
+        /// Number of status reads reporting busy after each program operation.
+        busy_reads: usize,
+        busy_remaining: Cell<usize>,
+        /// Program operations at this address never complete.
+        stuck_address: Option<u32>,
+        /// The byte at this address is programmed with one bit flipped.
+        weak_address: Option<u32>,
+        /// Address and length of each page program operation.
+        programs: RefCell<Vec<(u32, usize)>>,
+        /// Number of calls to `run_eeprom_transactions()`.
+        round_trips: Cell<usize>,
+    }
+
+    impl FlashMock {
+        fn new(jedec_id: &[u8]) -> Self {
+            FlashMock {
+                jedec_id: jedec_id.to_vec(),
+                regs: SecurityRegisters::from_jedec_id(jedec_id)
+                    .unwrap_or_else(|_| SecurityRegisters::winbond()),
+                main: RefCell::new(vec![0xff; 0x4000]),
+                security: RefCell::new(vec![0xff; 0x4000]),
+                write_enabled: RefCell::new(false),
+                write_protected: false,
+                status: Cell::new(0),
+                flag_status: Cell::new(0),
+                busy_reads: 0,
+                busy_remaining: Cell::new(0),
+                stuck_address: None,
+                weak_address: None,
+                programs: RefCell::new(Vec::new()),
+                round_trips: Cell::new(0),
+            }
+        }
+
+        /// Returns whether a modification is to be carried out, like a write protected part,
+        /// ignoring it silently.  Fails on modifications not preceded by WRITE_ENABLE.
+        fn take_write_enable(&self) -> Result<bool> {
+            if self.write_protected {
+                return Ok(false);
+            }
+            ensure!(self.write_enabled.replace(false), "write not enabled");
+            Ok(true)
+        }
+    }
+
+    impl Target for FlashMock {
+        fn get_transfer_mode(&self) -> Result<TransferMode> {
+            Ok(TransferMode::Mode0)
+        }
+        fn set_transfer_mode(&self, _mode: TransferMode) -> Result<()> {
+            Ok(())
+        }
+        fn get_bits_per_word(&self) -> Result<u32> {
+            Ok(8)
+        }
+        fn set_bits_per_word(&self, _bits_per_word: u32) -> Result<()> {
+            Ok(())
+        }
+        fn get_max_speed(&self) -> Result<u32> {
+            Ok(1_000_000)
+        }
+        fn set_max_speed(&self, _max_speed: u32) -> Result<()> {
+            Ok(())
+        }
+        fn get_max_transfer_count(&self) -> Result<usize> {
+            Ok(usize::MAX)
+        }
+        fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+            Ok(MaxSizes {
+                read: 64,
+                write: 256,
+            })
+        }
+        fn run_transaction(&self, _transaction: &mut [crate::io::spi::Transfer]) -> Result<()> {
+            unimplemented!();
+        }
+        fn run_eeprom_transactions(&self, transactions: &mut [Transaction]) -> Result<()> {
+            self.round_trips.set(self.round_trips.get() + 1);
+            for transaction in transactions {
+                match transaction {
+                    Transaction::Command(cmd) => match cmd.get_opcode() {
+                        [SpiFlash::WRITE_ENABLE] => {
+                            *self.write_enabled.borrow_mut() = !self.write_protected
+                        }
+                        [SpiFlash::CLEAR_FLAG_STATUS] => self.flag_status.set(0),
+                        [SpiFlash::CHIP_ERASE] => {
+                            if self.take_write_enable()? {
+                                self.main.borrow_mut().fill(0xff);
+                            }
+                        }
+                        [SpiFlash::SECTOR_ERASE] => {
+                            if self.take_write_enable()? {
+                                let addr = cmd.get_address() as usize;
+                                self.main.borrow_mut()[addr..addr + 0x1000].fill(0xff);
+                            }
+                        }
+                        [SpiFlash::ERASE_SECURITY_REGISTER] => {
+                            if !self.take_write_enable()? {
+                                continue;
+                            }
+                            let register = self.regs.register(cmd.get_address(), 0)?;
+                            ensure!(cmd.get_address() == register.start);
+                            self.security.borrow_mut()
+                                [register.start as usize..register.end as usize]
+                                .fill(0xff);
+                        }
+                        op => bail!("unexpected command {:x?}", op),
+                    },
+                    Transaction::Read(cmd, buf) => {
+                        let addr = cmd.get_address() as usize;
+                        match cmd.get_opcode() {
+                            [SpiFlash::READ_ID] => buf.copy_from_slice(&self.jedec_id[..buf.len()]),
+                            [SpiFlash::READ_STATUS] => {
+                                let busy = self.busy_remaining.get();
+                                self.busy_remaining.set(busy.saturating_sub(1));
+                                buf[0] = self.status.get();
+                                if busy > 0 {
+                                    buf[0] |= SpiFlash::STATUS_WIP;
+                                }
+                                if *self.write_enabled.borrow() {
+                                    buf[0] |= SpiFlash::STATUS_WEL;
+                                }
+                            }
+                            [SpiFlash::READ_FLAG_STATUS] => buf[0] = self.flag_status.get(),
+                            [SpiFlash::READ] => {
+                                buf.copy_from_slice(&self.main.borrow()[addr..addr + buf.len()])
+                            }
+                            [op] if *op == self.regs.read_opcode => {
+                                ensure!(cmd.get_dummy_cycles() == 8, "missing dummy byte");
+                                buf.copy_from_slice(&self.security.borrow()[addr..addr + buf.len()])
+                            }
+                            op => bail!("unexpected read {:x?}", op),
+                        }
+                    }
+                    Transaction::Write(cmd, buf) => {
+                        if !self.take_write_enable()? {
+                            continue;
+                        }
+                        if cmd.get_opcode() == [SpiFlash::WRITE_STATUS] {
+                            self.status.set(buf[0]);
+                            continue;
+                        }
+                        let mut array = match cmd.get_opcode() {
+                            [SpiFlash::PAGE_PROGRAM] => self.main.borrow_mut(),
+                            [SpiFlash::PROGRAM_SECURITY_REGISTER] => self.security.borrow_mut(),
+                            op => bail!("unexpected write {:x?}", op),
+                        };
+                        if cmd.get_opcode() == [SpiFlash::PAGE_PROGRAM] {
+                            self.programs
+                                .borrow_mut()
+                                .push((cmd.get_address(), buf.len()));
+                        }
+                        self.busy_remaining
+                            .set(if self.stuck_address == Some(cmd.get_address()) {
+                                usize::MAX
+                            } else {
+                                self.busy_reads
+                            });
+                        let addr = cmd.get_address() as usize;
+                        for (cell, byte) in array[addr..].iter_mut().zip(*buf) {
+                            *cell &= byte;
+                        }
+                        if let Some(weak) = self.weak_address {
+                            let weak = weak as usize;
+                            if (addr..addr + buf.len()).contains(&weak) {
+                                array[weak] ^= 0x10;
+                            }
+                        }
+                    }
+                    Transaction::WaitForBusyClear => {}
+                }
+            }
+            Ok(())
+        }
+        fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+            unimplemented!();
+        }
+    }
+
+    #[test]
+    fn test_winbond_security_registers() -> Result<()> {
+        let spi = FlashMock::new(&[SecurityRegisters::WINBOND, 0x40, 0x18]);
+        let flash = SpiFlash::default();
+        flash.program_security_register(&spi, 0x2010, b"serial:1234")?;
+        assert_eq!(
+            flash.read_security_register(&spi, 0x2010, 11)?,
+            b"serial:1234"
+        );
+        // The main array at the same address is unaffected.
+        let mut main = [0u8; 11];
+        flash.read(&spi, 0x2010, &mut main)?;
+        assert_eq!(main, [0xff; 11]);
+
+        // Reading a whole register takes several chunks.
+        let register = flash.read_security_register(&spi, 0x2000, 256)?;
+        assert_eq!(&register[0x10..0x1b], b"serial:1234");
+
+        flash.erase_security_register(&spi, 0x20ff)?;
+        assert_eq!(flash.read_security_register(&spi, 0x2010, 11)?, [0xff; 11]);
+
+        // Accesses must lie within a single register.
+        for (address, len) in [(0x0000, 1), (0x20f0, 0x20), (0x4000, 1)] {
+            let err = flash
+                .read_security_register(&spi, address, len)
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::BadSecurityRegisterAddress(a, l)) if (*a, *l) == (address, len)
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_program_with_autopoll() -> Result<()> {
+        let mut spi = FlashMock::new(&[SecurityRegisters::WINBOND, 0x40, 0x18]);
+        spi.busy_reads = 2;
+        let flash = SpiFlash::default();
+        let data = (0..600).map(|i| i as u8).collect::<Vec<u8>>();
+        flash.program_with_autopoll(&spi, 0x80, &data, Duration::from_secs(1))?;
+        assert_eq!(
+            *spi.programs.borrow(),
+            vec![(0x80, 0x80), (0x100, 0x100), (0x200, 0xd8)]
+        );
+        assert_eq!(&spi.main.borrow()[0x80..0x80 + 600], &data[..]);
+        // For each page, the write enable is confirmed first, the first of the two status reads
+        // finding it busy is combined with the page program, then two more reads are needed for
+        // the busy bit to clear.
+        assert_eq!(spi.round_trips.get(), 3 * 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_ready_program_failed() -> Result<()> {
+        let spi = FlashMock::new(&[SecurityRegisters::MICRON, 0xba, 0x18]);
+        let mut flash = SpiFlash {
+            error_status: ErrorStatus::from_jedec_id(&spi.jedec_id),
+            ..Default::default()
+        };
+        assert_eq!(flash.error_status, Some(ErrorStatus::flag_status()));
+
+        // The busy bit clears, but the part reports that programming failed.
+        spi.busy_remaining.set(2);
+        spi.flag_status.set(0x80 | 0x10);
+        let err = flash.wait_ready(&spi, Duration::from_secs(1)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ProgramFailed(0x90))
+        ));
+        assert_eq!(spi.busy_remaining.get(), 0);
+        // The failure bits were cleared, so the next operation succeeds.
+        assert_eq!(spi.flag_status.get(), 0);
+        flash.wait_ready(&spi, Duration::from_secs(1))?;
+
+        spi.flag_status.set(0x20);
+        let err = flash.wait_ready(&spi, Duration::from_secs(1)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::EraseFailed(0x20))
+        ));
+
+        // Without an error status register, only the busy bit is checked.
+        flash.error_status = None;
+        spi.flag_status.set(0x10);
+        flash.wait_ready(&spi, Duration::from_secs(1))?;
+        spi.busy_remaining.set(usize::MAX);
+        let err = flash
+            .wait_ready(&spi, Duration::from_millis(10))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::BusyTimeout(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_program_with_autopoll_stuck() -> Result<()> {
+        let mut spi = FlashMock::new(&[SecurityRegisters::WINBOND, 0x40, 0x18]);
+        spi.stuck_address = Some(0x200);
+        let flash = SpiFlash::default();
+        let data = vec![0u8; 0x400];
+        let err = flash
+            .program_with_autopoll(&spi, 0, &data, Duration::from_millis(10))
+            .map(|_| ())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::PageProgramTimeout(0x200, _))
+        ));
+        assert!(err.to_string().contains("0x200"));
+        // Pages after the stuck one were not programmed.
+        assert_eq!(spi.programs.borrow().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_program_region_bad_sector() -> Result<()> {
+        let spi = FlashMock::new(&[SecurityRegisters::WINBOND, 0x40, 0x18]);
+        let flash = SpiFlash::default();
+        let data = (0..0x1800).map(|i| i as u8).collect::<Vec<u8>>();
+        let bad_blocks = BadBlockTable {
+            bad: vec![0x1000],
+            spares: vec![0x3000],
+        };
+        // Region spans the second half of sector 0 and all of sector 1.
+        let report = flash.program_region(&spi, 0x800, &data, Some(&bad_blocks), false)?;
+        assert_eq!(report.relocated, vec![(0x1000, 0x3000)]);
+        assert!(report.skipped.is_empty());
+        let main = spi.main.borrow();
+        assert_eq!(&main[0x800..0x1000], &data[..0x800]);
+        assert!(main[0x1000..0x2000].iter().all(|&b| b == 0xff));
+        assert_eq!(&main[0x3000..0x4000], &data[0x800..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_program_region_skip_without_spares() -> Result<()> {
+        let spi = FlashMock::new(&[SecurityRegisters::WINBOND, 0x40, 0x18]);
+        let flash = SpiFlash::default();
+        let data = vec![0x5a; 0x3000];
+        let bad_blocks = BadBlockTable {
+            bad: vec![0x1000],
+            spares: vec![],
+        };
+        let report = flash.program_region(&spi, 0, &data, Some(&bad_blocks), false)?;
+        assert_eq!(report.skipped, vec![0x1000]);
+        let main = spi.main.borrow();
+        assert!(main[..0x1000].iter().all(|&b| b == 0x5a));
+        assert!(main[0x1000..0x2000].iter().all(|&b| b == 0xff));
+        assert!(main[0x2000..0x3000].iter().all(|&b| b == 0x5a));
+        drop(main);
+
+        // Spares must not overlap the programmed region.
+        let bad_blocks = BadBlockTable {
+            bad: vec![0x1000],
+            spares: vec![0x2000],
+        };
+        let err = flash
+            .program_region(&spi, 0, &data, Some(&bad_blocks), false)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidSpareSector(0x2000))
+        ));
+
+        // A spare in the topmost sector of the address space is valid.
+        let bad_blocks = BadBlockTable {
+            bad: vec![0x3000],
+            spares: vec![0xffff_f000],
+        };
+        flash.program_region(&spi, 0, &data, Some(&bad_blocks), false)?;
+
+        // Bad sectors must be aligned, like spares, rather than being silently programmed.
+        let bad_blocks = BadBlockTable {
+            bad: vec![0x1800],
+            spares: vec![],
+        };
+        let err = flash
+            .program_region(&spi, 0, &data, Some(&bad_blocks), false)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidBadSector(0x1800))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_program_region_verify() -> Result<()> {
+        let mut spi = FlashMock::new(&[SecurityRegisters::WINBOND, 0x40, 0x18]);
+        let flash = SpiFlash::default();
+        let data = (0..0x1800).map(|i| i as u8).collect::<Vec<u8>>();
+        flash.program_region(&spi, 0x800, &data, None, true)?;
+
+        // Marginal write in the spare sector, to which a bad sector got relocated.
+        spi.main.borrow_mut().fill(0xff);
+        spi.weak_address = Some(0x3123);
+        let bad_blocks = BadBlockTable {
+            bad: vec![0x1000],
+            spares: vec![0x3000],
+        };
+        // Not noticed without verification.
+        flash.program_region(&spi, 0x800, &data, Some(&bad_blocks), false)?;
+        spi.main.borrow_mut().fill(0xff);
+        let err = flash
+            .program_region(&spi, 0x800, &data, Some(&bad_blocks), true)
+            .unwrap_err();
+        // Byte 0x123 of sector 0x1000, at offset 0x923 in `data`.
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::VerifyMismatch(0x3123, 0x23, 0x33))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_enable() -> Result<()> {
+        let spi = FlashMock::new(&[SecurityRegisters::WINBOND, 0x40, 0x18]);
+        let flash = SpiFlash::default();
+        SpiFlash::write_status(&spi, 0x1c)?;
+        assert_eq!(SpiFlash::read_status(&spi)?, 0x1c);
+        flash.program(&spi, 0x10, &[0x12, 0x34])?;
+        flash.erase(&spi, 0x1000, 0x1000)?;
+        assert_eq!(&spi.main.borrow()[0x10..0x12], &[0x12, 0x34]);
+
+        let mut spi = FlashMock::new(&[SecurityRegisters::WINBOND, 0x40, 0x18]);
+        spi.write_protected = true;
+        let is_write_enable_failed = |result: Result<&SpiFlash>| {
+            matches!(
+                result.map(|_| ()).unwrap_err().downcast_ref::<Error>(),
+                Some(Error::WriteEnableFailed)
+            )
+        };
+        assert!(is_write_enable_failed(flash.program(&spi, 0x10, &[0x12])));
+        // Only the write enable and status read were sent, not the page program.
+        assert_eq!(spi.round_trips.get(), 1);
+        assert!(is_write_enable_failed(flash.erase(&spi, 0, 0x1000)));
+        assert!(is_write_enable_failed(flash.chip_erase(&spi)));
+        assert!(matches!(
+            SpiFlash::write_status(&spi, 0x1c)
+                .unwrap_err()
+                .downcast_ref::<Error>(),
+            Some(Error::WriteEnableFailed)
+        ));
+        // The part ignored the modifications.
+        assert_eq!(spi.main.borrow()[0x10], 0xff);
+        assert_eq!(SpiFlash::read_status(&spi)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_micron_otp() -> Result<()> {
+        let spi = FlashMock::new(&[SecurityRegisters::MICRON, 0xba, 0x19]);
+        let flash = SpiFlash::default();
+        flash.program_security_register(&spi, 0, &[0x12, 0x34])?;
+        assert_eq!(flash.read_security_register(&spi, 0, 2)?, [0x12, 0x34]);
+        let err = flash
+            .erase_security_register(&spi, 0)
+            .map(|_| ())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::SecurityRegisterEraseUnsupported)
+        ));
+        assert!(flash.read_security_register(&spi, 60, 8).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_part() {
+        let spi = FlashMock::new(&[0xc2, 0x20, 0x18]);
+        let err = SpiFlash::default()
+            .read_security_register(&spi, 0x1000, 1)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::SecurityRegistersUnsupported(id)) if id == &[0xc2, 0x20, 0x18]
+        ));
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+use serde_annotate::Annotate;
+use std::fmt;
+
+/// JEDEC manufacturer IDs (bank 1) of common SPI flash vendors.
+const MANUFACTURERS: &[(u8, &str)] = &[
+    (0x01, "Infineon (Spansion)"),
+    (0x1f, "Adesto (Atmel)"),
+    (0x20, "Micron"),
+    (0x85, "Puya"),
+    (0x9d, "ISSI"),
+    (0xbf, "Microchip (SST)"),
+    (0xc2, "Macronix"),
+    (0xc8, "GigaDevice"),
+    (0xef, "Winbond"),
+];
+
+/// Known parts, by the three first bytes of the JEDEC ID.  Parts of a family often share IDs
+/// across revisions, so the name is a best guess.
+const PARTS: &[([u8; 3], &str)] = &[
+    ([0x20, 0xba, 0x18], "MT25QL128"),
+    ([0x20, 0xba, 0x19], "MT25QL256"),
+    ([0x20, 0xba, 0x20], "MT25QL512"),
+    ([0x20, 0xba, 0x21], "MT25QL01G"),
+    ([0x20, 0xbb, 0x18], "MT25QU128"),
+    ([0x9d, 0x60, 0x18], "IS25LP128"),
+    ([0x9d, 0x60, 0x19], "IS25LP256"),
+    ([0xc2, 0x20, 0x18], "MX25L12835F"),
+    ([0xc2, 0x20, 0x19], "MX25L25645G"),
+    ([0xc2, 0x20, 0x1a], "MX66L51235F"),
+    ([0xc2, 0x20, 0x1b], "MX66L1G45G"),
+    ([0xc8, 0x40, 0x18], "GD25Q128"),
+    ([0xef, 0x40, 0x16], "W25Q32JV"),
+    ([0xef, 0x40, 0x17], "W25Q64JV"),
+    ([0xef, 0x40, 0x18], "W25Q128JV"),
+    ([0xef, 0x40, 0x19], "W25Q256JV"),
+    ([0xef, 0x70, 0x18], "W25Q128JV-M"),
+];
+
+/// Human-readable description of a flash part, decoded from its JEDEC ID.  Fields which cannot
+/// be decoded are `None`, the raw ID is always available.
+#[derive(Annotate, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FlashIdentity {
+    #[annotate(format = hex)]
+    pub jedec_id: Vec<u8>,
+    pub manufacturer: Option<String>,
+    /// Size in bytes, according to the capacity code in the third byte of the ID.
+    pub density: Option<u64>,
+    pub part: Option<String>,
+}
+
+impl FlashIdentity {
+    /// Decode the (at least three byte) JEDEC ID returned by the READ_ID command.
+    pub fn from_jedec_id(jedec_id: &[u8]) -> Self {
+        let manufacturer = jedec_id.first().and_then(|id| {
+            MANUFACTURERS
+                .iter()
+                .find(|(m, _)| m == id)
+                .map(|(_, name)| name.to_string())
+        });
+        let density = jedec_id.get(2).and_then(|&code| Self::decode_density(code));
+        let part = PARTS
+            .iter()
+            .find(|(id, _)| jedec_id.starts_with(id))
+            .map(|(_, name)| name.to_string());
+        FlashIdentity {
+            jedec_id: jedec_id.to_vec(),
+            manufacturer,
+            density,
+            part,
+        }
+    }
+
+    /// Most manufacturers encode the size as a power of two, continuing from 0x20 after 0x19 in
+    /// the case of Micron and Macronix.
+    fn decode_density(code: u8) -> Option<u64> {
+        match code {
+            0x10..=0x1f => Some(1 << code),
+            0x20..=0x22 => Some(1 << (code - 6)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FlashIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.manufacturer
+                .as_deref()
+                .unwrap_or("Unknown manufacturer"),
+            self.part.as_deref().unwrap_or("unknown part"),
+        )?;
+        if let Some(density) = self.density {
+            write!(f, ", {} KiB", density / 1024)?;
+        }
+        write!(f, " (JEDEC ID {:02x?})", self.jedec_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_jedec_id() {
+        let winbond = FlashIdentity::from_jedec_id(&[0xef, 0x40, 0x18]);
+        assert_eq!(winbond.manufacturer.as_deref(), Some("Winbond"));
+        assert_eq!(winbond.density, Some(16 * 1024 * 1024));
+        assert_eq!(winbond.part.as_deref(), Some("W25Q128JV"));
+        assert_eq!(
+            winbond.to_string(),
+            "Winbond W25Q128JV, 16384 KiB (JEDEC ID [ef, 40, 18])"
+        );
+
+        // Trailing bytes of the ID are ignored.
+        let micron = FlashIdentity::from_jedec_id(&[0x20, 0xba, 0x21, 0x10, 0x44]);
+        assert_eq!(micron.manufacturer.as_deref(), Some("Micron"));
+        assert_eq!(micron.density, Some(128 * 1024 * 1024));
+        assert_eq!(micron.part.as_deref(), Some("MT25QL01G"));
+
+        // Known manufacturer, unknown part.
+        let macronix = FlashIdentity::from_jedec_id(&[0xc2, 0x25, 0x38]);
+        assert_eq!(macronix.manufacturer.as_deref(), Some("Macronix"));
+        assert_eq!(macronix.part, None);
+
+        let unknown = FlashIdentity::from_jedec_id(&[0x12, 0x34, 0x56]);
+        assert_eq!(unknown.manufacturer, None);
+        assert_eq!(unknown.density, None);
+        assert_eq!(unknown.part, None);
+        assert_eq!(
+            unknown.to_string(),
+            "Unknown manufacturer unknown part (JEDEC ID [12, 34, 56])"
+        );
+    }
+}
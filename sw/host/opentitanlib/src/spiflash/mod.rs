@@ -3,7 +3,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod flash;
+pub mod jedec;
 pub mod sfdp;
 
 pub use flash::SpiFlash;
+pub use jedec::FlashIdentity;
 pub use sfdp::{BlockEraseSize, Sfdp, SupportedAddressModes, WriteGranularity};
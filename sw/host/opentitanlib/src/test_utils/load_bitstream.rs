@@ -2,15 +2,19 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
 use serde_annotate::Annotate;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
+use thiserror::Error;
 
 use crate::app::{self, TransportWrapper};
-use crate::transport::common::fpga::FpgaProgram;
-use crate::util::rom_detect::RomKind;
+use crate::impl_serializable_error;
+use crate::io::uart::Uart;
+use crate::transport::common::fpga::{FpgaProgram, FpgaProgramMethod};
+use crate::util::rom_detect::{RomDetect, RomKind};
 
 /// Load a bitstream into the FPGA.
 #[derive(Debug, StructOpt)]
@@ -29,6 +33,30 @@ pub struct LoadBitstream {
     pub rom_reset_pulse: Duration,
     #[structopt(long, parse(try_from_str=humantime::parse_duration), default_value="2s", help = "Duration of ROM detection timeout")]
     pub rom_timeout: Duration,
+    #[structopt(
+        long,
+        possible_values = &FpgaProgramMethod::variants(),
+        case_insensitive = true,
+        default_value = "Spi",
+        help = "FPGA configuration interface"
+    )]
+    pub program_method: FpgaProgramMethod,
+    #[structopt(
+        long,
+        help = "Verify that the part named in the bitstream header matches the board"
+    )]
+    pub verify_part: bool,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Number of times to retry programming if it fails"
+    )]
+    pub program_retries: u32,
+    #[structopt(
+        long,
+        help = "Reset and detect the ROM even if this process has already loaded or detected the same bitstream"
+    )]
+    pub force: bool,
 }
 
 impl LoadBitstream {
@@ -46,18 +74,279 @@ impl LoadBitstream {
         file: &Path,
     ) -> Result<Option<Box<dyn Annotate>>> {
         log::info!("Loading bitstream: {:?}", file);
-        let payload = std::fs::read(file)?;
-        let progress = app::progress_bar(payload.len() as u64);
+        let progress = app::progress_bar(std::fs::metadata(file)?.len());
         let pfunc = Box::new(move |_, chunk| {
             progress.inc(chunk as u64);
         });
         let operation = FpgaProgram {
-            bitstream: payload,
+            bitstream: Vec::new(),
+            bitstream_path: Some(file.to_path_buf()),
+            method: self.program_method,
             rom_kind: self.rom_kind,
             rom_reset_pulse: self.rom_reset_pulse,
             rom_timeout: self.rom_timeout,
+            verify_part: self.verify_part,
+            retries: self.program_retries,
+            force: self.force,
             progress: Some(pfunc),
         };
         transport.dispatch(&operation)
     }
+
+    /// Provision a freshly connected board in one go: program the bitstream, verify that the
+    /// FPGA reports being configured by reading `done_pin` (if given), reset the target, and
+    /// confirm that the ROM prints its identification (if `rom_kind` is given).  Unlike `load()`,
+    /// the bitstream is programmed even if the FPGA is already running it.
+    pub fn provision(
+        &self,
+        transport: &TransportWrapper,
+        file: &Path,
+        done_pin: Option<&str>,
+        uart: &dyn Uart,
+    ) -> Result<ProvisionReport> {
+        log::info!("Provisioning with bitstream: {:?}", file);
+        let bitstream = std::fs::read(file)?;
+        provision(&TransportProvisionOps {
+            params: self,
+            transport,
+            bitstream: &bitstream,
+            done_pin,
+            uart,
+        })
+    }
+}
+
+/// Stages of `LoadBitstream::provision()`, in the order they are performed.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ProvisionStage {
+    Program,
+    VerifyDone,
+    Reset,
+    RomBanner,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum StageStatus {
+    Ok,
+    /// The stage was not applicable, e.g. because no ROM kind was given.
+    Skipped,
+    Failed(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StageReport {
+    pub stage: ProvisionStage,
+    pub status: StageStatus,
+    pub duration: Duration,
+}
+
+/// Outcome of each stage of provisioning.  On failure, the report ends with the failing stage.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProvisionReport {
+    pub stages: Vec<StageReport>,
+}
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum ProvisionError {
+    #[error("Provisioning failed in stage {0:?}")]
+    StageFailed(ProvisionStage, ProvisionReport),
+}
+impl_serializable_error!(ProvisionError);
+
+/// Primitive operations performed by `provision()`.  Each returns `Ok(false)` if the stage does
+/// not apply, and an `Err` if it failed.
+trait ProvisionOps {
+    fn program(&self) -> Result<bool>;
+    fn verify_done(&self) -> Result<bool>;
+    fn reset(&self) -> Result<bool>;
+    fn detect_rom(&self) -> Result<bool>;
+}
+
+struct TransportProvisionOps<'a> {
+    params: &'a LoadBitstream,
+    transport: &'a TransportWrapper,
+    bitstream: &'a [u8],
+    done_pin: Option<&'a str>,
+    uart: &'a dyn Uart,
+}
+
+impl ProvisionOps for TransportProvisionOps<'_> {
+    fn program(&self) -> Result<bool> {
+        let progress = app::progress_bar(self.bitstream.len() as u64);
+        let operation = FpgaProgram {
+            bitstream: self.bitstream.to_vec(),
+            bitstream_path: None,
+            method: self.params.program_method,
+            // Always program, rather than checking for the ROM of an existing bitstream.
+            rom_kind: None,
+            rom_reset_pulse: self.params.rom_reset_pulse,
+            rom_timeout: self.params.rom_timeout,
+            verify_part: self.params.verify_part,
+            retries: self.params.program_retries,
+            force: false,
+            progress: Some(Box::new(move |_, chunk| {
+                progress.inc(chunk as u64);
+            })),
+        };
+        self.transport.dispatch(&operation)?;
+        Ok(true)
+    }
+
+    fn verify_done(&self) -> Result<bool> {
+        match self.done_pin {
+            Some(pin) => {
+                ensure!(
+                    self.transport.gpio_pin(pin)?.read()?,
+                    "FPGA configuration pin {} not asserted",
+                    pin
+                );
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn reset(&self) -> Result<bool> {
+        self.transport
+            .reset_target(self.params.rom_reset_pulse, true)?;
+        Ok(true)
+    }
+
+    fn detect_rom(&self) -> Result<bool> {
+        match self.params.rom_kind {
+            Some(rom_kind) => {
+                let mut rd =
+                    RomDetect::new(rom_kind, self.bitstream, Some(self.params.rom_timeout))?;
+                ensure!(rd.detect(self.uart)?, "ROM identification not detected");
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+fn provision(ops: &dyn ProvisionOps) -> Result<ProvisionReport> {
+    let stages: [(ProvisionStage, &dyn Fn() -> Result<bool>); 4] = [
+        (ProvisionStage::Program, &|| ops.program()),
+        (ProvisionStage::VerifyDone, &|| ops.verify_done()),
+        (ProvisionStage::Reset, &|| ops.reset()),
+        (ProvisionStage::RomBanner, &|| ops.detect_rom()),
+    ];
+    let mut report = ProvisionReport::default();
+    for (stage, op) in stages {
+        let t0 = Instant::now();
+        let result = op();
+        let duration = t0.elapsed();
+        let status = match &result {
+            Ok(true) => StageStatus::Ok,
+            Ok(false) => StageStatus::Skipped,
+            Err(e) => StageStatus::Failed(e.to_string()),
+        };
+        log::info!(
+            "Provisioning stage {:?}: {:?} in {:?}",
+            stage,
+            status,
+            duration
+        );
+        report.stages.push(StageReport {
+            stage,
+            status,
+            duration,
+        });
+        if result.is_err() {
+            return Err(ProvisionError::StageFailed(stage, report).into());
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Scripted outcome of each stage, recording which stages were performed.
+    struct MockOps {
+        outcomes: [Option<bool>; 4],
+        performed: RefCell<Vec<ProvisionStage>>,
+    }
+
+    impl MockOps {
+        fn run(&self, stage: ProvisionStage, index: usize) -> Result<bool> {
+            self.performed.borrow_mut().push(stage);
+            self.outcomes[index].ok_or_else(|| anyhow::anyhow!("{:?} broke", stage))
+        }
+    }
+
+    impl ProvisionOps for MockOps {
+        fn program(&self) -> Result<bool> {
+            self.run(ProvisionStage::Program, 0)
+        }
+        fn verify_done(&self) -> Result<bool> {
+            self.run(ProvisionStage::VerifyDone, 1)
+        }
+        fn reset(&self) -> Result<bool> {
+            self.run(ProvisionStage::Reset, 2)
+        }
+        fn detect_rom(&self) -> Result<bool> {
+            self.run(ProvisionStage::RomBanner, 3)
+        }
+    }
+
+    fn statuses(report: &ProvisionReport) -> Vec<(ProvisionStage, StageStatus)> {
+        report
+            .stages
+            .iter()
+            .map(|s| (s.stage, s.status.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_provision_success() -> Result<()> {
+        let ops = MockOps {
+            outcomes: [Some(true), Some(false), Some(true), Some(true)],
+            performed: RefCell::default(),
+        };
+        let report = provision(&ops)?;
+        assert_eq!(
+            statuses(&report),
+            vec![
+                (ProvisionStage::Program, StageStatus::Ok),
+                (ProvisionStage::VerifyDone, StageStatus::Skipped),
+                (ProvisionStage::Reset, StageStatus::Ok),
+                (ProvisionStage::RomBanner, StageStatus::Ok),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_provision_failure() {
+        let ops = MockOps {
+            outcomes: [Some(true), None, Some(true), Some(true)],
+            performed: RefCell::default(),
+        };
+        let err = provision(&ops).unwrap_err();
+        match err.downcast_ref::<ProvisionError>() {
+            Some(ProvisionError::StageFailed(stage, report)) => {
+                assert_eq!(*stage, ProvisionStage::VerifyDone);
+                assert_eq!(
+                    statuses(report),
+                    vec![
+                        (ProvisionStage::Program, StageStatus::Ok),
+                        (
+                            ProvisionStage::VerifyDone,
+                            StageStatus::Failed("VerifyDone broke".to_string())
+                        ),
+                    ]
+                );
+            }
+            _ => panic!("Unexpected error: {:?}", err),
+        }
+        // Later stages are not attempted.
+        assert_eq!(
+            *ops.performed.borrow(),
+            vec![ProvisionStage::Program, ProvisionStage::VerifyDone]
+        );
+    }
 }
@@ -0,0 +1,487 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Injection of scripted failures into the interfaces of a transport, such that the error
+//! handling of code using the transport (retries, recovery) can be tested deterministically.
+//! The interface objects of any transport are wrapped in `FaultyTarget`, `FaultyGpioPin` or
+//! `FaultyUart`, which pass each operation through to the wrapped object, unless a `FaultScript`
+//! shared among them says that this particular call is to fail.
+
+use anyhow::Result;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::io::eeprom;
+use crate::io::gpio::{GpioPin, GpioPinCapabilities, PinMode, PullMode, PullStrength};
+use crate::io::spi::{AssertChipSelect, MaxSizes, Target, Transfer, TransferMode};
+use crate::io::uart::Uart;
+use crate::transport::TransportError;
+use crate::util::voltage::Voltage;
+
+/// Kind of operation for which failures can be scripted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FaultOp {
+    SpiTransaction,
+    GpioRead,
+    GpioWrite,
+    GpioSetMode,
+    UartRead,
+    UartWrite,
+}
+
+/// Failure to be injected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Fail with `TransportError::CommunicationError`.
+    CommunicationError(String),
+    /// The operation times out.  UART reads return no data, as they would on an actual timeout,
+    /// other operations fail with `TransportError::Timeout`.
+    Timeout,
+}
+
+impl Fault {
+    fn into_error(self, op: FaultOp) -> anyhow::Error {
+        match self {
+            Fault::CommunicationError(msg) => TransportError::CommunicationError(msg).into(),
+            Fault::Timeout => TransportError::Timeout(format!("{:?}", op)).into(),
+        }
+    }
+}
+
+/// Failures to inject, by the ordinal number of the call of each kind of operation.  Each
+/// scripted failure happens once, calls before and after it are passed through as usual.
+#[derive(Default)]
+pub struct FaultScript {
+    calls: RefCell<HashMap<FaultOp, usize>>,
+    faults: RefCell<HashMap<(FaultOp, usize), Fault>>,
+}
+
+impl FaultScript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes the `nth` call of `op`, counting from 1, fail with `fault`.
+    pub fn fail_at(self, op: FaultOp, nth: usize, fault: Fault) -> Self {
+        self.faults.borrow_mut().insert((op, nth), fault);
+        self
+    }
+
+    /// Returns the number of calls of `op` so far, whether failed or not.
+    pub fn calls(&self, op: FaultOp) -> usize {
+        self.calls.borrow().get(&op).copied().unwrap_or(0)
+    }
+
+    /// Counts a call of `op`, returning the failure scripted for it, if any.
+    fn next(&self, op: FaultOp) -> Option<Fault> {
+        let mut calls = self.calls.borrow_mut();
+        let count = calls.entry(op).or_insert(0);
+        *count += 1;
+        self.faults.borrow_mut().remove(&(op, *count))
+    }
+
+    fn check(&self, op: FaultOp) -> Result<()> {
+        match self.next(op) {
+            Some(fault) => Err(fault.into_error(op)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// SPI target subject to `FaultOp::SpiTransaction` failures.  Each call running a transaction, or
+/// a list of EEPROM transactions, counts once.
+pub struct FaultyTarget {
+    inner: Rc<dyn Target>,
+    script: Rc<FaultScript>,
+}
+
+impl FaultyTarget {
+    pub fn new(inner: Rc<dyn Target>, script: Rc<FaultScript>) -> Self {
+        Self { inner, script }
+    }
+}
+
+impl Target for FaultyTarget {
+    fn get_transfer_mode(&self) -> Result<TransferMode> {
+        self.inner.get_transfer_mode()
+    }
+    fn set_transfer_mode(&self, mode: TransferMode) -> Result<()> {
+        self.inner.set_transfer_mode(mode)
+    }
+    fn get_bits_per_word(&self) -> Result<u32> {
+        self.inner.get_bits_per_word()
+    }
+    fn set_bits_per_word(&self, bits_per_word: u32) -> Result<()> {
+        self.inner.set_bits_per_word(bits_per_word)
+    }
+    fn get_max_speed(&self) -> Result<u32> {
+        self.inner.get_max_speed()
+    }
+    fn set_max_speed(&self, max_speed: u32) -> Result<()> {
+        self.inner.set_max_speed(max_speed)
+    }
+    fn get_hardware_max_speed(&self) -> Result<u32> {
+        self.inner.get_hardware_max_speed()
+    }
+    fn get_max_transfer_count(&self) -> Result<usize> {
+        self.inner.get_max_transfer_count()
+    }
+    fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+        self.inner.get_max_transfer_sizes()
+    }
+    fn set_voltage(&self, voltage: Voltage) -> Result<()> {
+        self.inner.set_voltage(voltage)
+    }
+    fn cs_pin_name(&self) -> Option<&str> {
+        self.inner.cs_pin_name()
+    }
+    fn set_cs_idle_level(&self, high: bool) -> Result<()> {
+        self.inner.set_cs_idle_level(high)
+    }
+    fn set_cs_polarity(&self, active_high: bool) -> Result<()> {
+        self.inner.set_cs_polarity(active_high)
+    }
+    fn set_idle_clock_high(&self, high: bool) -> Result<()> {
+        self.inner.set_idle_clock_high(high)
+    }
+    fn set_min_deselect_time(&self, duration: Duration) -> Result<()> {
+        self.inner.set_min_deselect_time(duration)
+    }
+    fn set_transaction_timeout(&self, timeout: Duration) -> Result<()> {
+        self.inner.set_transaction_timeout(timeout)
+    }
+    fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+        self.script.check(FaultOp::SpiTransaction)?;
+        self.inner.run_transaction(transaction)
+    }
+    fn run_transaction_at_speed(&self, transaction: &mut [Transfer], max_speed: u32) -> Result<()> {
+        self.script.check(FaultOp::SpiTransaction)?;
+        self.inner.run_transaction_at_speed(transaction, max_speed)
+    }
+    fn run_transaction_timed(&self, transaction: &mut [Transfer]) -> Result<Duration> {
+        self.script.check(FaultOp::SpiTransaction)?;
+        self.inner.run_transaction_timed(transaction)
+    }
+    fn get_eeprom_max_transfer_sizes(&self) -> Result<MaxSizes> {
+        self.inner.get_eeprom_max_transfer_sizes()
+    }
+    fn run_eeprom_transactions(&self, transactions: &mut [eeprom::Transaction]) -> Result<()> {
+        self.script.check(FaultOp::SpiTransaction)?;
+        self.inner.run_eeprom_transactions(transactions)
+    }
+    fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+        Rc::clone(&self.inner).assert_cs()
+    }
+}
+
+/// GPIO pin subject to `FaultOp::GpioRead`, `GpioWrite` and `GpioSetMode` failures.  A batched
+/// read counts as one `GpioRead`, and combined settings through `set()` count as a `GpioSetMode`
+/// and a `GpioWrite`, if including a mode and a value respectively.
+pub struct FaultyGpioPin {
+    inner: Rc<dyn GpioPin>,
+    script: Rc<FaultScript>,
+}
+
+impl FaultyGpioPin {
+    pub fn new(inner: Rc<dyn GpioPin>, script: Rc<FaultScript>) -> Self {
+        Self { inner, script }
+    }
+}
+
+impl GpioPin for FaultyGpioPin {
+    fn read(&self) -> Result<bool> {
+        self.script.check(FaultOp::GpioRead)?;
+        self.inner.read()
+    }
+    fn read_batch(&self, pins: &[&dyn GpioPin]) -> Result<Option<Vec<bool>>> {
+        self.script.check(FaultOp::GpioRead)?;
+        self.inner.read_batch(pins)
+    }
+    fn write(&self, value: bool) -> Result<()> {
+        self.script.check(FaultOp::GpioWrite)?;
+        self.inner.write(value)
+    }
+    fn set_mode(&self, mode: PinMode) -> Result<()> {
+        self.script.check(FaultOp::GpioSetMode)?;
+        self.inner.set_mode(mode)
+    }
+    fn set_pull_mode(&self, mode: PullMode) -> Result<()> {
+        self.inner.set_pull_mode(mode)
+    }
+    fn set_pull_mode_ex(&self, mode: PullMode, strength: Option<PullStrength>) -> Result<()> {
+        self.inner.set_pull_mode_ex(mode, strength)
+    }
+    fn set_drive_strength(&self, milliamps: u32) -> Result<()> {
+        self.inner.set_drive_strength(milliamps)
+    }
+    fn analog_read(&self) -> Result<f32> {
+        self.inner.analog_read()
+    }
+    fn analog_write(&self, volts: f32) -> Result<()> {
+        self.inner.analog_write(volts)
+    }
+    fn set_voltage(&self, voltage: Voltage) -> Result<()> {
+        self.inner.set_voltage(voltage)
+    }
+    fn set(
+        &self,
+        mode: Option<PinMode>,
+        value: Option<bool>,
+        pull: Option<PullMode>,
+        analog_value: Option<f32>,
+    ) -> Result<()> {
+        if mode.is_some() {
+            self.script.check(FaultOp::GpioSetMode)?;
+        }
+        if value.is_some() {
+            self.script.check(FaultOp::GpioWrite)?;
+        }
+        self.inner.set(mode, value, pull, analog_value)
+    }
+    fn get_mode(&self) -> Result<PinMode> {
+        self.inner.get_mode()
+    }
+    fn get_pull_mode(&self) -> Result<PullMode> {
+        self.inner.get_pull_mode()
+    }
+    fn capabilities(&self) -> Result<GpioPinCapabilities> {
+        self.inner.capabilities()
+    }
+    fn get_internal_pin_name(&self) -> Option<&str> {
+        self.inner.get_internal_pin_name()
+    }
+}
+
+/// UART subject to `FaultOp::UartRead` and `UartWrite` failures.
+pub struct FaultyUart {
+    inner: Rc<dyn Uart>,
+    script: Rc<FaultScript>,
+}
+
+impl FaultyUart {
+    pub fn new(inner: Rc<dyn Uart>, script: Rc<FaultScript>) -> Self {
+        Self { inner, script }
+    }
+
+    /// Returns `Some` with the outcome of a read, if a failure was scripted for it.
+    fn read_fault(&self) -> Option<Result<usize>> {
+        match self.script.next(FaultOp::UartRead)? {
+            Fault::Timeout => Some(Ok(0)),
+            fault => Some(Err(fault.into_error(FaultOp::UartRead))),
+        }
+    }
+}
+
+impl Uart for FaultyUart {
+    fn get_baudrate(&self) -> Result<u32> {
+        self.inner.get_baudrate()
+    }
+    fn set_baudrate(&self, baudrate: u32) -> Result<()> {
+        self.inner.set_baudrate(baudrate)
+    }
+    fn set_flow_control(&self, flow_control: bool) -> Result<()> {
+        self.inner.set_flow_control(flow_control)
+    }
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        self.read_fault().unwrap_or_else(|| self.inner.read(buf))
+    }
+    fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        self.read_fault()
+            .unwrap_or_else(|| self.inner.read_timeout(buf, timeout))
+    }
+    fn write(&self, buf: &[u8]) -> Result<()> {
+        self.script.check(FaultOp::UartWrite)?;
+        self.inner.write(buf)
+    }
+    fn add_sink(&self, sink: Box<dyn Write + Send>) -> Result<()> {
+        self.inner.add_sink(sink)
+    }
+    fn clear_rx_buffer(&self) -> Result<()> {
+        self.inner.clear_rx_buffer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// SPI target answering every read with 0x5a.
+    struct FixedTarget;
+
+    impl Target for FixedTarget {
+        fn get_transfer_mode(&self) -> Result<TransferMode> {
+            Ok(TransferMode::Mode0)
+        }
+        fn set_transfer_mode(&self, _mode: TransferMode) -> Result<()> {
+            Ok(())
+        }
+        fn get_bits_per_word(&self) -> Result<u32> {
+            Ok(8)
+        }
+        fn set_bits_per_word(&self, _bits_per_word: u32) -> Result<()> {
+            Ok(())
+        }
+        fn get_max_speed(&self) -> Result<u32> {
+            Ok(1_000_000)
+        }
+        fn set_max_speed(&self, _max_speed: u32) -> Result<()> {
+            Ok(())
+        }
+        fn get_max_transfer_count(&self) -> Result<usize> {
+            Ok(usize::MAX)
+        }
+        fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+            Ok(MaxSizes {
+                read: 256,
+                write: 256,
+            })
+        }
+        fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+            for transfer in transaction.iter_mut() {
+                if let Transfer::Read(rbuf) | Transfer::Both(_, rbuf) = transfer {
+                    rbuf.fill(0x5a);
+                }
+            }
+            Ok(())
+        }
+        fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+            unimplemented!();
+        }
+    }
+
+    #[derive(Default)]
+    struct LoopbackPin(Cell<bool>);
+
+    impl GpioPin for LoopbackPin {
+        fn read(&self) -> Result<bool> {
+            Ok(self.0.get())
+        }
+        fn write(&self, value: bool) -> Result<()> {
+            self.0.set(value);
+            Ok(())
+        }
+        fn set_mode(&self, _mode: PinMode) -> Result<()> {
+            Ok(())
+        }
+        fn set_pull_mode(&self, _mode: PullMode) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// UART which always has the same line of data to deliver.
+    struct ChattyUart;
+
+    impl Uart for ChattyUart {
+        fn get_baudrate(&self) -> Result<u32> {
+            Ok(115200)
+        }
+        fn set_baudrate(&self, _baudrate: u32) -> Result<()> {
+            Ok(())
+        }
+        fn read(&self, buf: &mut [u8]) -> Result<usize> {
+            self.read_timeout(buf, Duration::ZERO)
+        }
+        fn read_timeout(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize> {
+            let data = b"PASS!\n";
+            buf[..data.len()].copy_from_slice(data);
+            Ok(data.len())
+        }
+        fn write(&self, _buf: &[u8]) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn is_communication_error(result: Result<impl std::fmt::Debug>) -> bool {
+        matches!(
+            result.unwrap_err().downcast_ref::<TransportError>(),
+            Some(TransportError::CommunicationError(_))
+        )
+    }
+
+    fn is_timeout(result: Result<impl std::fmt::Debug>) -> bool {
+        matches!(
+            result.unwrap_err().downcast_ref::<TransportError>(),
+            Some(TransportError::Timeout(_))
+        )
+    }
+
+    #[test]
+    fn test_spi_fault() -> Result<()> {
+        let script = Rc::new(FaultScript::new().fail_at(
+            FaultOp::SpiTransaction,
+            3,
+            Fault::CommunicationError("injected".to_string()),
+        ));
+        let spi = FaultyTarget::new(Rc::new(FixedTarget), Rc::clone(&script));
+        let mut buf = [0u8; 2];
+        spi.run_transaction(&mut [Transfer::Read(&mut buf)])?;
+        spi.run_transaction(&mut [Transfer::Read(&mut buf)])?;
+        assert!(is_communication_error(
+            spi.run_transaction(&mut [Transfer::Read(&mut buf)])
+        ));
+        // Retrying succeeds.
+        buf = [0u8; 2];
+        spi.run_transaction(&mut [Transfer::Read(&mut buf)])?;
+        assert_eq!(buf, [0x5a, 0x5a]);
+        assert_eq!(script.calls(FaultOp::SpiTransaction), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gpio_fault() -> Result<()> {
+        let script = Rc::new(
+            FaultScript::new()
+                .fail_at(FaultOp::GpioRead, 1, Fault::Timeout)
+                .fail_at(
+                    FaultOp::GpioWrite,
+                    2,
+                    Fault::CommunicationError("injected".to_string()),
+                ),
+        );
+        let pin = FaultyGpioPin::new(Rc::new(LoopbackPin::default()), Rc::clone(&script));
+        pin.write(true)?;
+        assert!(is_timeout(pin.read()));
+        assert!(pin.read()?);
+        // The value of a combined setting counts as a write.
+        assert!(is_communication_error(pin.set(
+            None,
+            Some(false),
+            None,
+            None
+        )));
+        assert!(pin.read()?);
+        pin.set(None, Some(false), None, None)?;
+        assert!(!pin.read()?);
+        assert_eq!(script.calls(FaultOp::GpioWrite), 3);
+        assert_eq!(script.calls(FaultOp::GpioSetMode), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_uart_fault() -> Result<()> {
+        let script = Rc::new(
+            FaultScript::new()
+                .fail_at(FaultOp::UartRead, 2, Fault::Timeout)
+                .fail_at(
+                    FaultOp::UartWrite,
+                    1,
+                    Fault::CommunicationError("injected".to_string()),
+                ),
+        );
+        let uart = FaultyUart::new(Rc::new(ChattyUart), script);
+        let mut buf = [0u8; 16];
+        assert_eq!(uart.read(&mut buf)?, 6);
+        // Timeout means no data, rather than an error.
+        assert_eq!(uart.read_timeout(&mut buf, Duration::from_millis(10))?, 0);
+        assert_eq!(uart.read(&mut buf)?, 6);
+
+        assert!(is_communication_error(uart.write(b"x")));
+        uart.write(b"x")?;
+        Ok(())
+    }
+}
@@ -2,32 +2,117 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
 use std::time::Duration;
+use structopt::clap::arg_enum;
 
 use crate::io::gpio::GpioPin;
 use crate::io::uart::Uart;
+use crate::transport::TransportError;
+use crate::util::bitstream::BitstreamInfo;
 use crate::util::rom_detect::{RomDetect, RomKind};
+use crate::util::usr_access::usr_access_get;
+
+arg_enum! {
+    /// Configuration interface of the FPGA through which to load the bitstream.  `Spi` is serial
+    /// configuration driven by the board's USB controller, currently the only interface
+    /// implemented by any transport.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum FpgaProgramMethod {
+        Spi,
+    }
+}
 
 /// Command for Transport::dispatch().
 pub struct FpgaProgram<'a> {
-    /// The bitstream content to load into the FPGA.
+    /// The bitstream content to load into the FPGA, ignored if `bitstream_path` is given.
     pub bitstream: Vec<u8>,
+    /// File to read the bitstream from instead of `bitstream`.  The file is only read in full
+    /// once the checks for skipping the programming have failed.
+    pub bitstream_path: Option<PathBuf>,
+    /// Configuration interface to use.
+    pub method: FpgaProgramMethod,
     /// What type of ROM to expect.
     pub rom_kind: Option<RomKind>,
     /// How long of a reset pulse to send to the device.
     pub rom_reset_pulse: Duration,
     /// How long to wait for the ROM to print its type and version.
     pub rom_timeout: Duration,
+    /// Whether to verify that the part named in the bitstream header matches the board.
+    pub verify_part: bool,
+    /// How many times to retry programming from scratch, if it fails.
+    pub retries: u32,
+    /// Whether to reset and detect the ROM even if this process has just detected or programmed
+    /// the same bitstream, see `check_correct_version_cached()`.
+    pub force: bool,
     /// A progress function to provide user feedback.
     /// Will be called with the address and length of each chunk sent to the target device.
     pub progress: Option<Box<dyn Fn(u32, u32) + 'a>>,
 }
 
 impl FpgaProgram<'_> {
+    /// Time to wait before retrying a failed attempt at programming.
+    const RETRY_DELAY: Duration = Duration::from_millis(250);
+    /// Marker at the start of a bitstream, requesting that programming be skipped.
+    const SKIP_MARKER: &'static [u8] = b"__skip__";
+    /// Length of the start of the bitstream searched for the USR_ACCESS value identifying it,
+    /// which is found among the first configuration packets, before resorting to the entire
+    /// bitstream.
+    const USR_ACCESS_SEARCH_LEN: usize = 4096;
+
+    /// Returns the entire bitstream, reading it from `bitstream_path` if given.
+    pub fn load_bitstream(&self) -> Result<Cow<'_, [u8]>> {
+        match &self.bitstream_path {
+            Some(path) => Ok(Cow::Owned(std::fs::read(path).with_context(|| {
+                format!("Failed to read bitstream {}", path.display())
+            })?)),
+            None => Ok(Cow::Borrowed(&self.bitstream)),
+        }
+    }
+
+    /// Returns up to `len` bytes from the start of the bitstream, reading no more than that
+    /// from `bitstream_path` if given.
+    fn bitstream_prefix(&self, len: usize) -> Result<Cow<'_, [u8]>> {
+        match &self.bitstream_path {
+            Some(path) => {
+                let mut prefix = Vec::with_capacity(len);
+                File::open(path)
+                    .and_then(|file| file.take(len as u64).read_to_end(&mut prefix))
+                    .with_context(|| format!("Failed to read bitstream {}", path.display()))?;
+                Ok(Cow::Owned(prefix))
+            }
+            None => Ok(Cow::Borrowed(
+                &self.bitstream[..len.min(self.bitstream.len())],
+            )),
+        }
+    }
+
+    /// Returns the USR_ACCESS value by which the ROM identifies the bitstream.
+    pub fn usr_access(&self) -> Result<u32> {
+        match usr_access_get(&self.bitstream_prefix(Self::USR_ACCESS_SEARCH_LEN)?) {
+            Ok(usr_access) => Ok(usr_access),
+            Err(_) => usr_access_get(&self.load_bitstream()?),
+        }
+    }
+
+    /// Identity of the bitstream as established by `check_correct_version()`, that is the
+    /// expected ROM kind and USR_ACCESS value.  `None` if no ROM kind is given, or the bitstream
+    /// has no USR_ACCESS value.
+    pub fn rom_identity(&self) -> Option<(RomKind, u32)> {
+        let rom_kind = self.rom_kind?;
+        self.usr_access()
+            .ok()
+            .map(|usr_access| (rom_kind, usr_access))
+    }
+
     pub fn check_correct_version(&self, uart: &dyn Uart, reset_pin: &dyn GpioPin) -> Result<bool> {
         if let Some(rom_kind) = &self.rom_kind {
-            let mut rd = RomDetect::new(*rom_kind, &self.bitstream, Some(self.rom_timeout))?;
+            let mut rd =
+                RomDetect::with_usr_access(*rom_kind, self.usr_access()?, Some(self.rom_timeout));
 
             // Send a reset pulse so the ROM will print the FPGA version.
             // Reset is active low, sleep, then drive high.
@@ -48,10 +133,288 @@ impl FpgaProgram<'_> {
         Ok(false)
     }
 
+    /// Like `check_correct_version()`, but without the reset and ROM detection if `running`, the
+    /// identity of the bitstream most recently detected or programmed by the transport, matches
+    /// `rom_identity()`, unless `force` is given.  Updates `running` with the outcome, the
+    /// transport is to set it to `rom_identity()` after programming.
+    pub fn check_correct_version_cached(
+        &self,
+        uart: &dyn Uart,
+        reset_pin: &dyn GpioPin,
+        running: &mut Option<(RomKind, u32)>,
+    ) -> Result<bool> {
+        let identity = self.rom_identity();
+        if !self.force && identity.is_some() && *running == identity {
+            log::info!("Bitstream loaded earlier by this process.  Skip loading bitstream.");
+            return Ok(true);
+        }
+        *running = None;
+        let correct = self.check_correct_version(uart, reset_pin)?;
+        if correct {
+            *running = identity;
+        }
+        Ok(correct)
+    }
+
+    /// Whether the bitstream is the `__skip__` marker.  A `bitstream_path` which cannot be read
+    /// is not skipped, leaving the error to be reported by `load_bitstream()`.
     pub fn skip(&self) -> bool {
-        self.bitstream.starts_with(b"__skip__")
+        matches!(
+            self.bitstream_prefix(Self::SKIP_MARKER.len()),
+            Ok(prefix) if *prefix == *Self::SKIP_MARKER
+        )
+    }
+
+    /// If requested by `verify_part`, verify that `bitstream`, as returned by `load_bitstream()`,
+    /// is for the given FPGA part.
+    pub fn check_part(&self, bitstream: &[u8], expected: &str) -> Result<()> {
+        if self.verify_part {
+            BitstreamInfo::parse(bitstream)?.check_part(expected)?;
+        }
+        Ok(())
+    }
+
+    /// Run `program`, which should load the bitstream from scratch, retrying up to `retries`
+    /// times if it fails.  The error of the final attempt is returned.  Checks such as `skip()`
+    /// and `check_correct_version()` should be evaluated before calling this.
+    pub fn program_with_retries(&self, mut program: impl FnMut() -> Result<()>) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            match program() {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "FPGA programming failed: {}, retrying ({}/{})",
+                        e,
+                        attempt,
+                        self.retries
+                    );
+                    std::thread::sleep(Self::RETRY_DELAY);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Verify that the requested programming method is among those supported by the transport.
+    pub fn check_method(&self, supported: &[FpgaProgramMethod]) -> Result<()> {
+        if supported.contains(&self.method) {
+            Ok(())
+        } else {
+            log::error!("Programming via {} not supported", self.method);
+            Err(TransportError::UnsupportedOperation.into())
+        }
     }
 }
 
 /// Command for Transport::dispatch().
 pub struct ClearBitstream;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::gpio::{PinMode, PullMode};
+    use anyhow::ensure;
+    use std::cell::Cell;
+
+    fn program(method: FpgaProgramMethod) -> FpgaProgram<'static> {
+        FpgaProgram {
+            bitstream: Vec::new(),
+            bitstream_path: None,
+            method,
+            rom_kind: None,
+            rom_reset_pulse: Duration::from_millis(50),
+            rom_timeout: Duration::from_secs(2),
+            verify_part: false,
+            retries: 0,
+            force: false,
+            progress: None,
+        }
+    }
+
+    #[test]
+    fn test_program_with_retries() -> Result<()> {
+        let fail_once = |attempts: &mut u32| {
+            *attempts += 1;
+            if *attempts == 1 {
+                Err(TransportError::FpgaProgramFailed("unknown error".to_string()).into())
+            } else {
+                Ok(())
+            }
+        };
+
+        let mut attempts = 0;
+        let mut op = program(FpgaProgramMethod::Spi);
+        op.retries = 2;
+        op.program_with_retries(|| fail_once(&mut attempts))?;
+        assert_eq!(attempts, 2);
+
+        // Without retries, the first failure is reported.
+        let mut attempts = 0;
+        op.retries = 0;
+        let err = op
+            .program_with_retries(|| fail_once(&mut attempts))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransportError>(),
+            Some(TransportError::FpgaProgramFailed(_))
+        ));
+        assert_eq!(attempts, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_method() {
+        assert!(program(FpgaProgramMethod::Spi)
+            .check_method(&[FpgaProgramMethod::Spi])
+            .is_ok());
+        let err = program(FpgaProgramMethod::Spi)
+            .check_method(&[])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransportError>(),
+            Some(TransportError::UnsupportedOperation)
+        ));
+    }
+
+    #[test]
+    fn test_bitstream_path() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("fpga_program_{}.bit", std::process::id()));
+        let mut op = program(FpgaProgramMethod::Spi);
+        op.bitstream = b"ignored".to_vec();
+        op.bitstream_path = Some(path.clone());
+
+        // A missing file is not skipped, but fails to load.
+        assert!(!op.skip());
+        assert!(op.load_bitstream().is_err());
+
+        std::fs::write(&path, b"__skip__ trailing data")?;
+        assert!(op.skip());
+        std::fs::write(&path, b"__skip")?;
+        assert!(!op.skip());
+        std::fs::write(&path, b"bitstream content")?;
+        assert!(!op.skip());
+        assert_eq!(*op.bitstream_prefix(9)?, *b"bitstream");
+        assert_eq!(*op.load_bitstream()?, *b"bitstream content");
+        std::fs::remove_file(&path)?;
+
+        // Without a path, the bitstream in memory is used.
+        op.bitstream_path = None;
+        op.bitstream = b"__skip__".to_vec();
+        assert!(op.skip());
+        assert_eq!(*op.bitstream_prefix(100)?, *b"__skip__");
+        Ok(())
+    }
+
+    /// Reset pin counting writes, and failing them unless `working`, such that ROM detection
+    /// fails at the reset pulse, its first step.
+    #[derive(Default)]
+    struct ResetPin {
+        writes: Cell<usize>,
+        working: bool,
+    }
+
+    impl GpioPin for ResetPin {
+        fn read(&self) -> Result<bool> {
+            unimplemented!();
+        }
+        fn write(&self, _value: bool) -> Result<()> {
+            self.writes.set(self.writes.get() + 1);
+            ensure!(self.working, TransportError::UnsupportedOperation);
+            Ok(())
+        }
+        fn set_mode(&self, _mode: PinMode) -> Result<()> {
+            Ok(())
+        }
+        fn set_pull_mode(&self, _mode: PullMode) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    struct NoUart;
+
+    impl Uart for NoUart {
+        fn read(&self, _buf: &mut [u8]) -> Result<usize> {
+            unimplemented!();
+        }
+        fn read_timeout(&self, _buf: &mut [u8], _timeout: Duration) -> Result<usize> {
+            unimplemented!();
+        }
+        fn write(&self, _buf: &[u8]) -> Result<()> {
+            unimplemented!();
+        }
+    }
+
+    /// UART on which the ROM prints its identification, once the receive buffer has been cleared
+    /// during the reset pulse.
+    #[derive(Default)]
+    struct BannerUart {
+        cleared: Cell<bool>,
+    }
+
+    impl Uart for BannerUart {
+        fn read(&self, _buf: &mut [u8]) -> Result<usize> {
+            unimplemented!();
+        }
+        fn read_timeout(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize> {
+            if !self.cleared.replace(false) {
+                return Ok(0);
+            }
+            let banner = b"TestROM:12345678\r\n";
+            buf[..banner.len()].copy_from_slice(banner);
+            Ok(banner.len())
+        }
+        fn write(&self, _buf: &[u8]) -> Result<()> {
+            unimplemented!();
+        }
+        fn clear_rx_buffer(&self) -> Result<()> {
+            self.cleared.set(true);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_check_correct_version_cached() -> Result<()> {
+        let mut op = program(FpgaProgramMethod::Spi);
+        // Write 0x12345678 to USR_ACCESS.
+        op.bitstream = vec![
+            0xff, 0xff, 0xff, 0xff, 0x30, 0x01, 0xa0, 0x01, 0x12, 0x34, 0x56, 0x78,
+        ];
+        assert_eq!(op.rom_identity(), None);
+        op.rom_kind = Some(RomKind::TestRom);
+        assert_eq!(op.rom_identity(), Some((RomKind::TestRom, 0x12345678)));
+
+        // Same bitstream as running, no reset.
+        let pin = ResetPin::default();
+        let mut running = Some((RomKind::TestRom, 0x12345678));
+        assert!(op.check_correct_version_cached(&NoUart, &pin, &mut running)?);
+        assert_eq!(pin.writes.get(), 0);
+        assert_eq!(running, Some((RomKind::TestRom, 0x12345678)));
+
+        // Forced, or a different bitstream, the ROM is detected afresh, forgetting the running
+        // bitstream until detection succeeds.
+        for (force, usr_access) in [(true, 0x12345678), (false, 0x87654321)] {
+            op.force = force;
+            let mut running = Some((RomKind::TestRom, usr_access));
+            assert!(op
+                .check_correct_version_cached(&NoUart, &pin, &mut running)
+                .is_err());
+            assert_eq!(running, None);
+        }
+        assert_eq!(pin.writes.get(), 2);
+
+        // Once detection succeeds, the bitstream is known to be running.
+        op.force = false;
+        op.rom_reset_pulse = Duration::from_millis(1);
+        let pin = ResetPin {
+            working: true,
+            ..Default::default()
+        };
+        let mut running = None;
+        assert!(op.check_correct_version_cached(&BannerUart::default(), &pin, &mut running)?);
+        assert_eq!(pin.writes.get(), 2);
+        assert_eq!(running, Some((RomKind::TestRom, 0x12345678)));
+        Ok(())
+    }
+}
@@ -2,5 +2,7 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod fault;
 pub mod fpga;
+pub mod trace;
 pub mod uart;
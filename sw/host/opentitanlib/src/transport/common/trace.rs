@@ -0,0 +1,819 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Recording of every operation of a transport session, for inspecting intermittent failures
+//! offline.  `TracingTransport` wraps any transport, and wraps the SPI, GPIO and UART interfaces
+//! it hands out, such that each call is passed through to the wrapped object, and then written
+//! along with its outcome as one line of JSON to a shared `TraceLog`.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::io::eeprom;
+use crate::io::emu::Emulator;
+use crate::io::gpio::{
+    GpioMonitoring, GpioPatternGenerator, GpioPin, GpioPinCapabilities, PinMode, PinSnapshot,
+    PullMode, PullStrength,
+};
+use crate::io::i2c::Bus;
+use crate::io::spi::{AssertChipSelect, MaxSizes, Target, Transfer, TransferMode};
+use crate::io::uart::Uart;
+use crate::transport::{Capabilities, ProxyOps, Transport};
+use crate::util::usb::UsbStrings;
+use crate::util::voltage::Voltage;
+
+/// Operation carried out on an interface of the transport.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum TraceOp {
+    /// The interface was requested from the transport.
+    Open,
+    ApplyDefaultConfiguration,
+    Dispatch,
+    SpiSetTransferMode(TransferMode),
+    SpiSetMaxSpeed(u32),
+    SpiSetCsIdleLevel(bool),
+    SpiSetCsPolarity(bool),
+    SpiSetIdleClockHigh(bool),
+    SpiSetMinDeselectTime(Duration),
+    SpiSetTransactionTimeout(Duration),
+    /// Outgoing part of a transaction, the data read is recorded as the result.
+    SpiTransaction(Vec<TransferRecord>),
+    /// Like `SpiTransaction`, run at the given speed.
+    SpiTransactionAtSpeed(Vec<TransferRecord>, u32),
+    /// Outgoing part of EEPROM transactions, the data read is recorded as the result.
+    SpiEepromTransactions(Vec<EepromRecord>),
+    GpioRead,
+    /// Read of the given pins at once, by their names as known to the transport.
+    GpioReadBatch(Vec<Option<String>>),
+    GpioWrite(bool),
+    GpioSet {
+        mode: Option<PinMode>,
+        value: Option<bool>,
+        pull: Option<PullMode>,
+        analog_value: Option<f32>,
+    },
+    GpioSetMode(PinMode),
+    GpioSetPullMode(PullMode),
+    GpioSetDriveStrength(u32),
+    GpioAnalogRead,
+    GpioAnalogWrite(f32),
+    /// I/O voltage of the domain of the pin, in Volts.
+    GpioSetVoltage(f64),
+    UartSetBaudrate(u32),
+    UartRead,
+    UartWrite(Vec<u8>),
+}
+
+/// Outgoing part of a single SPI transfer.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TransferRecord {
+    Read(usize),
+    Write(Vec<u8>),
+    Both(Vec<u8>),
+    Fill { value: u8, count: usize },
+    SetSpeed(u32),
+    Turnaround,
+}
+
+impl TransferRecord {
+    fn new(transfer: &Transfer) -> Self {
+        match transfer {
+            Transfer::Read(rbuf) => TransferRecord::Read(rbuf.len()),
+            Transfer::Write(wbuf) => TransferRecord::Write(wbuf.to_vec()),
+            Transfer::Both(wbuf, _) => TransferRecord::Both(wbuf.to_vec()),
+            Transfer::Fill { value, count } => TransferRecord::Fill {
+                value: *value,
+                count: *count,
+            },
+            Transfer::SetSpeed(speed) => TransferRecord::SetSpeed(*speed),
+            Transfer::Turnaround => TransferRecord::Turnaround,
+        }
+    }
+}
+
+/// Outgoing part of a single EEPROM transaction, commands are recorded as the bytes sent.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum EepromRecord {
+    Command(Vec<u8>),
+    Read(Vec<u8>, usize),
+    Write(Vec<u8>, Vec<u8>),
+    WaitForBusyClear,
+}
+
+impl EepromRecord {
+    fn new(transaction: &eeprom::Transaction) -> Self {
+        let bytes = |cmd: &eeprom::Cmd| cmd.to_bytes().map(<[u8]>::to_vec).unwrap_or_default();
+        match transaction {
+            eeprom::Transaction::Command(cmd) => EepromRecord::Command(bytes(cmd)),
+            eeprom::Transaction::Read(cmd, rbuf) => EepromRecord::Read(bytes(cmd), rbuf.len()),
+            eeprom::Transaction::Write(cmd, wbuf) => EepromRecord::Write(bytes(cmd), wbuf.to_vec()),
+            eeprom::Transaction::WaitForBusyClear => EepromRecord::WaitForBusyClear,
+        }
+    }
+}
+
+/// One line of a trace file.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TraceRecord {
+    /// Time at which the operation completed, since the start of the session.
+    pub elapsed: Duration,
+    /// Interface on which the operation was carried out, e.g. `spi:0` or `gpio:RESET`, or
+    /// `transport` for operations on the transport itself.
+    pub interface: String,
+    pub op: TraceOp,
+    /// Value returned by the operation, or the error message.
+    pub result: std::result::Result<serde_json::Value, String>,
+}
+
+/// Destination of the records of a session, shared among all the wrapped interfaces.
+pub struct TraceLog {
+    start: Instant,
+    out: RefCell<Box<dyn Write>>,
+}
+
+impl TraceLog {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self {
+            start: Instant::now(),
+            out: RefCell::new(out),
+        }
+    }
+
+    /// Creates a trace file, overwriting any existing file.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Unable to create trace file {}", path.display()))?;
+        Ok(Self::new(Box::new(file)))
+    }
+
+    /// Writes a record of `op` on `interface`, completing with `result`.  Failure to write the
+    /// record is logged, rather than affecting the outcome of the operation.
+    fn record<T: Serialize>(
+        &self,
+        interface: &str,
+        op: TraceOp,
+        result: std::result::Result<T, &anyhow::Error>,
+    ) {
+        let result = match result {
+            Ok(value) => serde_json::to_value(value).map_err(|e| e.to_string()),
+            Err(e) => Err(format!("{:#}", e)),
+        };
+        let record = TraceRecord {
+            elapsed: self.start.elapsed(),
+            interface: interface.to_string(),
+            op,
+            result,
+        };
+        let mut out = self.out.borrow_mut();
+        if let Err(e) = serde_json::to_writer(&mut *out, &record)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| Ok(writeln!(out)?))
+        {
+            log::warn!("Unable to write trace record: {}", e);
+        }
+    }
+}
+
+/// Reads the records of a trace file written through `TraceLog`.
+pub fn read_trace(reader: impl BufRead) -> Result<Vec<TraceRecord>> {
+    reader
+        .lines()
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// Transport which records every operation on itself, and on the SPI, GPIO and UART interfaces
+/// obtained from it, to a `TraceLog`.  Other interfaces are passed through without tracing.
+pub struct TracingTransport {
+    inner: Box<dyn Transport>,
+    log: Rc<TraceLog>,
+}
+
+impl TracingTransport {
+    pub fn new(inner: Box<dyn Transport>, log: Rc<TraceLog>) -> Self {
+        Self { inner, log }
+    }
+
+    /// Opens an interface of the inner transport, recording the outcome.
+    fn open<T: ?Sized>(&self, interface: &str, result: Result<Rc<T>>) -> Result<Rc<T>> {
+        self.log
+            .record(interface, TraceOp::Open, result.as_ref().map(|_| ()));
+        result
+    }
+}
+
+impl Transport for TracingTransport {
+    fn capabilities(&self) -> Result<Capabilities> {
+        self.inner.capabilities()
+    }
+    fn apply_default_configuration(&self) -> Result<()> {
+        let result = self.inner.apply_default_configuration();
+        self.log.record(
+            "transport",
+            TraceOp::ApplyDefaultConfiguration,
+            result.as_ref(),
+        );
+        result
+    }
+    fn spi(&self, instance: &str) -> Result<Rc<dyn Target>> {
+        let interface = format!("spi:{}", instance);
+        let inner = self.open(&interface, self.inner.spi(instance))?;
+        Ok(Rc::new(TracingTarget {
+            inner,
+            interface,
+            log: Rc::clone(&self.log),
+        }))
+    }
+    fn i2c(&self, instance: &str) -> Result<Rc<dyn Bus>> {
+        self.inner.i2c(instance)
+    }
+    fn uart(&self, instance: &str) -> Result<Rc<dyn Uart>> {
+        let interface = format!("uart:{}", instance);
+        let inner = self.open(&interface, self.inner.uart(instance))?;
+        Ok(Rc::new(TracingUart {
+            inner,
+            interface,
+            log: Rc::clone(&self.log),
+        }))
+    }
+    fn gpio_pin(&self, instance: &str) -> Result<Rc<dyn GpioPin>> {
+        let interface = format!("gpio:{}", instance);
+        let inner = self.open(&interface, self.inner.gpio_pin(instance))?;
+        Ok(Rc::new(TracingGpioPin {
+            inner,
+            interface,
+            log: Rc::clone(&self.log),
+        }))
+    }
+    fn gpio_monitoring(&self) -> Result<Rc<dyn GpioMonitoring>> {
+        self.inner.gpio_monitoring()
+    }
+    fn gpio_pattern_generator(&self) -> Result<Rc<dyn GpioPatternGenerator>> {
+        self.inner.gpio_pattern_generator()
+    }
+    fn emulator(&self) -> Result<Rc<dyn Emulator>> {
+        self.inner.emulator()
+    }
+    fn proxy_ops(&self) -> Result<Rc<dyn ProxyOps>> {
+        self.inner.proxy_ops()
+    }
+    fn snapshot_pins(&self) -> Result<PinSnapshot> {
+        self.inner.snapshot_pins()
+    }
+    fn restore_pins(&self, snapshot: &PinSnapshot) -> Result<()> {
+        self.inner.restore_pins(snapshot)
+    }
+    fn description(&self) -> Result<UsbStrings> {
+        self.inner.description()
+    }
+    fn dispatch(&self, action: &dyn Any) -> Result<Option<Box<dyn serde_annotate::Annotate>>> {
+        let result = self.inner.dispatch(action);
+        self.log
+            .record("transport", TraceOp::Dispatch, result.as_ref());
+        result
+    }
+}
+
+/// SPI target recording each change of settings and each transaction.
+pub struct TracingTarget {
+    inner: Rc<dyn Target>,
+    interface: String,
+    log: Rc<TraceLog>,
+}
+
+impl TracingTarget {
+    /// Records `op` along with the data read by each transfer of the completed `transaction`.
+    fn record_transaction<T>(
+        &self,
+        op: TraceOp,
+        transaction: &[Transfer],
+        result: Result<T>,
+    ) -> Result<T> {
+        let read_data = result.as_ref().map(|_| {
+            transaction
+                .iter()
+                .filter_map(|transfer| match transfer {
+                    Transfer::Read(rbuf) | Transfer::Both(_, rbuf) => Some(rbuf.to_vec()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        });
+        self.log.record(&self.interface, op, read_data);
+        result
+    }
+
+    /// Records a change of setting.
+    fn record_setting(&self, op: TraceOp, result: Result<()>) -> Result<()> {
+        self.log.record(&self.interface, op, result.as_ref());
+        result
+    }
+}
+
+impl Target for TracingTarget {
+    fn get_transfer_mode(&self) -> Result<TransferMode> {
+        self.inner.get_transfer_mode()
+    }
+    fn set_transfer_mode(&self, mode: TransferMode) -> Result<()> {
+        let result = self.inner.set_transfer_mode(mode);
+        self.log.record(
+            &self.interface,
+            TraceOp::SpiSetTransferMode(mode),
+            result.as_ref(),
+        );
+        result
+    }
+    fn get_bits_per_word(&self) -> Result<u32> {
+        self.inner.get_bits_per_word()
+    }
+    fn set_bits_per_word(&self, bits_per_word: u32) -> Result<()> {
+        self.inner.set_bits_per_word(bits_per_word)
+    }
+    fn get_max_speed(&self) -> Result<u32> {
+        self.inner.get_max_speed()
+    }
+    fn set_max_speed(&self, max_speed: u32) -> Result<()> {
+        let result = self.inner.set_max_speed(max_speed);
+        self.log.record(
+            &self.interface,
+            TraceOp::SpiSetMaxSpeed(max_speed),
+            result.as_ref(),
+        );
+        result
+    }
+    fn get_hardware_max_speed(&self) -> Result<u32> {
+        self.inner.get_hardware_max_speed()
+    }
+    fn get_max_transfer_count(&self) -> Result<usize> {
+        self.inner.get_max_transfer_count()
+    }
+    fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+        self.inner.get_max_transfer_sizes()
+    }
+    fn set_voltage(&self, voltage: Voltage) -> Result<()> {
+        self.inner.set_voltage(voltage)
+    }
+    fn cs_pin_name(&self) -> Option<&str> {
+        self.inner.cs_pin_name()
+    }
+    fn set_cs_idle_level(&self, high: bool) -> Result<()> {
+        self.record_setting(
+            TraceOp::SpiSetCsIdleLevel(high),
+            self.inner.set_cs_idle_level(high),
+        )
+    }
+    fn set_cs_polarity(&self, active_high: bool) -> Result<()> {
+        self.record_setting(
+            TraceOp::SpiSetCsPolarity(active_high),
+            self.inner.set_cs_polarity(active_high),
+        )
+    }
+    fn set_idle_clock_high(&self, high: bool) -> Result<()> {
+        self.record_setting(
+            TraceOp::SpiSetIdleClockHigh(high),
+            self.inner.set_idle_clock_high(high),
+        )
+    }
+    fn set_min_deselect_time(&self, duration: Duration) -> Result<()> {
+        self.record_setting(
+            TraceOp::SpiSetMinDeselectTime(duration),
+            self.inner.set_min_deselect_time(duration),
+        )
+    }
+    fn set_transaction_timeout(&self, timeout: Duration) -> Result<()> {
+        self.record_setting(
+            TraceOp::SpiSetTransactionTimeout(timeout),
+            self.inner.set_transaction_timeout(timeout),
+        )
+    }
+    fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+        let op = TraceOp::SpiTransaction(transaction.iter().map(TransferRecord::new).collect());
+        let result = self.inner.run_transaction(transaction);
+        self.record_transaction(op, transaction, result)
+    }
+    fn run_transaction_at_speed(&self, transaction: &mut [Transfer], max_speed: u32) -> Result<()> {
+        let op = TraceOp::SpiTransactionAtSpeed(
+            transaction.iter().map(TransferRecord::new).collect(),
+            max_speed,
+        );
+        let result = self.inner.run_transaction_at_speed(transaction, max_speed);
+        self.record_transaction(op, transaction, result)
+    }
+    fn run_transaction_timed(&self, transaction: &mut [Transfer]) -> Result<Duration> {
+        let op = TraceOp::SpiTransaction(transaction.iter().map(TransferRecord::new).collect());
+        let result = self.inner.run_transaction_timed(transaction);
+        self.record_transaction(op, transaction, result)
+    }
+    fn get_eeprom_max_transfer_sizes(&self) -> Result<MaxSizes> {
+        self.inner.get_eeprom_max_transfer_sizes()
+    }
+    fn run_eeprom_transactions(&self, transactions: &mut [eeprom::Transaction]) -> Result<()> {
+        let op =
+            TraceOp::SpiEepromTransactions(transactions.iter().map(EepromRecord::new).collect());
+        let result = self.inner.run_eeprom_transactions(transactions);
+        let read_data = result.as_ref().map(|_| {
+            transactions
+                .iter()
+                .filter_map(|transaction| match transaction {
+                    eeprom::Transaction::Read(_, rbuf) => Some(rbuf.to_vec()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        });
+        self.log.record(&self.interface, op, read_data);
+        result
+    }
+    fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+        Rc::clone(&self.inner).assert_cs()
+    }
+}
+
+/// GPIO pin recording each read, write and change of mode.
+pub struct TracingGpioPin {
+    inner: Rc<dyn GpioPin>,
+    interface: String,
+    log: Rc<TraceLog>,
+}
+
+impl GpioPin for TracingGpioPin {
+    fn read(&self) -> Result<bool> {
+        let result = self.inner.read();
+        self.log
+            .record(&self.interface, TraceOp::GpioRead, result.as_ref());
+        result
+    }
+    fn read_batch(&self, pins: &[&dyn GpioPin]) -> Result<Option<Vec<bool>>> {
+        let names = pins
+            .iter()
+            .map(|pin| pin.get_internal_pin_name().map(str::to_string))
+            .collect();
+        let result = self.inner.read_batch(pins);
+        self.log.record(
+            &self.interface,
+            TraceOp::GpioReadBatch(names),
+            result.as_ref(),
+        );
+        result
+    }
+    fn write(&self, value: bool) -> Result<()> {
+        let result = self.inner.write(value);
+        self.log
+            .record(&self.interface, TraceOp::GpioWrite(value), result.as_ref());
+        result
+    }
+    fn set_mode(&self, mode: PinMode) -> Result<()> {
+        let result = self.inner.set_mode(mode);
+        self.log
+            .record(&self.interface, TraceOp::GpioSetMode(mode), result.as_ref());
+        result
+    }
+    fn set_pull_mode(&self, mode: PullMode) -> Result<()> {
+        let result = self.inner.set_pull_mode(mode);
+        self.log.record(
+            &self.interface,
+            TraceOp::GpioSetPullMode(mode),
+            result.as_ref(),
+        );
+        result
+    }
+    fn set_pull_mode_ex(&self, mode: PullMode, strength: Option<PullStrength>) -> Result<()> {
+        let result = self.inner.set_pull_mode_ex(mode, strength);
+        self.log.record(
+            &self.interface,
+            TraceOp::GpioSetPullMode(mode),
+            result.as_ref(),
+        );
+        result
+    }
+    fn set_drive_strength(&self, milliamps: u32) -> Result<()> {
+        let result = self.inner.set_drive_strength(milliamps);
+        self.log.record(
+            &self.interface,
+            TraceOp::GpioSetDriveStrength(milliamps),
+            result.as_ref(),
+        );
+        result
+    }
+    fn analog_read(&self) -> Result<f32> {
+        let result = self.inner.analog_read();
+        self.log
+            .record(&self.interface, TraceOp::GpioAnalogRead, result.as_ref());
+        result
+    }
+    fn analog_write(&self, volts: f32) -> Result<()> {
+        let result = self.inner.analog_write(volts);
+        self.log.record(
+            &self.interface,
+            TraceOp::GpioAnalogWrite(volts),
+            result.as_ref(),
+        );
+        result
+    }
+    fn set_voltage(&self, voltage: Voltage) -> Result<()> {
+        let result = self.inner.set_voltage(voltage);
+        self.log.record(
+            &self.interface,
+            TraceOp::GpioSetVoltage(voltage.0),
+            result.as_ref(),
+        );
+        result
+    }
+    fn set(
+        &self,
+        mode: Option<PinMode>,
+        value: Option<bool>,
+        pull: Option<PullMode>,
+        analog_value: Option<f32>,
+    ) -> Result<()> {
+        let result = self.inner.set(mode, value, pull, analog_value);
+        self.log.record(
+            &self.interface,
+            TraceOp::GpioSet {
+                mode,
+                value,
+                pull,
+                analog_value,
+            },
+            result.as_ref(),
+        );
+        result
+    }
+    fn get_mode(&self) -> Result<PinMode> {
+        self.inner.get_mode()
+    }
+    fn get_pull_mode(&self) -> Result<PullMode> {
+        self.inner.get_pull_mode()
+    }
+    fn capabilities(&self) -> Result<GpioPinCapabilities> {
+        self.inner.capabilities()
+    }
+    fn poll_changes(
+        &self,
+        interval: Duration,
+        stop: Arc<AtomicBool>,
+        callback: &mut dyn FnMut(bool),
+    ) -> Result<()> {
+        self.inner.poll_changes(interval, stop, callback)
+    }
+    fn closest_supported(&self, mode: PinMode) -> Option<PinMode> {
+        self.inner.closest_supported(mode)
+    }
+    fn get_internal_pin_name(&self) -> Option<&str> {
+        self.inner.get_internal_pin_name()
+    }
+}
+
+/// UART recording each change of baud rate, and all data read and written.
+pub struct TracingUart {
+    inner: Rc<dyn Uart>,
+    interface: String,
+    log: Rc<TraceLog>,
+}
+
+impl TracingUart {
+    fn record_read(&self, buf: &[u8], result: Result<usize>) -> Result<usize> {
+        let data = result.as_ref().map(|&len| buf[..len].to_vec());
+        self.log.record(&self.interface, TraceOp::UartRead, data);
+        result
+    }
+}
+
+impl Uart for TracingUart {
+    fn get_baudrate(&self) -> Result<u32> {
+        self.inner.get_baudrate()
+    }
+    fn set_baudrate(&self, baudrate: u32) -> Result<()> {
+        let result = self.inner.set_baudrate(baudrate);
+        self.log.record(
+            &self.interface,
+            TraceOp::UartSetBaudrate(baudrate),
+            result.as_ref(),
+        );
+        result
+    }
+    fn set_flow_control(&self, flow_control: bool) -> Result<()> {
+        self.inner.set_flow_control(flow_control)
+    }
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        let result = self.inner.read(buf);
+        self.record_read(buf, result)
+    }
+    fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let result = self.inner.read_timeout(buf, timeout);
+        self.record_read(buf, result)
+    }
+    fn read_cancellable(
+        &self,
+        buf: &mut [u8],
+        timeout: Duration,
+        cancel: &AtomicBool,
+    ) -> Result<usize> {
+        let result = self.inner.read_cancellable(buf, timeout, cancel);
+        self.record_read(buf, result)
+    }
+    fn write(&self, buf: &[u8]) -> Result<()> {
+        let result = self.inner.write(buf);
+        self.log.record(
+            &self.interface,
+            TraceOp::UartWrite(buf.to_vec()),
+            result.as_ref(),
+        );
+        result
+    }
+    fn add_sink(&self, sink: Box<dyn Write + Send>) -> Result<()> {
+        self.inner.add_sink(sink)
+    }
+    fn clear_rx_buffer(&self) -> Result<()> {
+        self.inner.clear_rx_buffer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{Capability, TransportError};
+    use std::cell::Cell;
+    use std::io::Cursor;
+
+    /// Trace destination which can be inspected after being handed to `TraceLog`.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// SPI target answering every read with 0x5a.
+    struct FixedTarget;
+
+    impl Target for FixedTarget {
+        fn get_transfer_mode(&self) -> Result<TransferMode> {
+            Ok(TransferMode::Mode0)
+        }
+        fn set_transfer_mode(&self, _mode: TransferMode) -> Result<()> {
+            Ok(())
+        }
+        fn get_bits_per_word(&self) -> Result<u32> {
+            Ok(8)
+        }
+        fn set_bits_per_word(&self, _bits_per_word: u32) -> Result<()> {
+            Ok(())
+        }
+        fn get_max_speed(&self) -> Result<u32> {
+            Ok(1_000_000)
+        }
+        fn set_max_speed(&self, _max_speed: u32) -> Result<()> {
+            Ok(())
+        }
+        fn get_max_transfer_count(&self) -> Result<usize> {
+            Ok(usize::MAX)
+        }
+        fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+            Ok(MaxSizes {
+                read: 256,
+                write: 256,
+            })
+        }
+        fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+            for transfer in transaction.iter_mut() {
+                if let Transfer::Read(rbuf) | Transfer::Both(_, rbuf) = transfer {
+                    rbuf.fill(0x5a);
+                }
+            }
+            Ok(())
+        }
+        fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+            unimplemented!();
+        }
+    }
+
+    #[derive(Default)]
+    struct LoopbackPin(Cell<bool>);
+
+    impl GpioPin for LoopbackPin {
+        fn read(&self) -> Result<bool> {
+            Ok(self.0.get())
+        }
+        fn write(&self, value: bool) -> Result<()> {
+            self.0.set(value);
+            Ok(())
+        }
+        fn set_mode(&self, _mode: PinMode) -> Result<()> {
+            Ok(())
+        }
+        fn set_pull_mode(&self, _mode: PullMode) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Transport with a single SPI bus and a single GPIO pin.
+    struct MockTransport;
+
+    impl Transport for MockTransport {
+        fn capabilities(&self) -> Result<Capabilities> {
+            Ok(Capabilities::new(Capability::SPI | Capability::GPIO))
+        }
+        fn spi(&self, _instance: &str) -> Result<Rc<dyn Target>> {
+            Ok(Rc::new(FixedTarget))
+        }
+        fn gpio_pin(&self, instance: &str) -> Result<Rc<dyn GpioPin>> {
+            match instance {
+                "RESET" => Ok(Rc::new(LoopbackPin::default())),
+                _ => Err(TransportError::InvalidInstance(
+                    crate::transport::TransportInterfaceType::Gpio,
+                    instance.to_string(),
+                )
+                .into()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_tracing_transport() -> Result<()> {
+        let buffer = SharedBuffer::default();
+        let log = Rc::new(TraceLog::new(Box::new(buffer.clone())));
+        let transport = TracingTransport::new(Box::new(MockTransport), log);
+
+        let reset = transport.gpio_pin("RESET")?;
+        reset.set_mode(PinMode::PushPull)?;
+        reset.write(true)?;
+        assert!(reset.read()?);
+        reset.set(None, Some(false), None, None)?;
+        assert!(transport.gpio_pin("BOGUS").is_err());
+        let spi = transport.spi("0")?;
+        let mut rbuf = [0u8; 2];
+        spi.run_transaction(&mut [Transfer::Write(&[0x9f]), Transfer::Read(&mut rbuf)])?;
+        spi.run_transaction_at_speed(&mut [Transfer::Read(&mut rbuf)], 500_000)?;
+        assert!(spi
+            .set_transaction_timeout(Duration::from_millis(100))
+            .is_err());
+        assert!(transport.dispatch(&()).is_err());
+
+        let records = read_trace(Cursor::new(buffer.0.borrow().clone()))?;
+        let ops: Vec<(&str, &TraceOp)> = records
+            .iter()
+            .map(|record| (record.interface.as_str(), &record.op))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                ("gpio:RESET", &TraceOp::Open),
+                ("gpio:RESET", &TraceOp::GpioSetMode(PinMode::PushPull)),
+                ("gpio:RESET", &TraceOp::GpioWrite(true)),
+                ("gpio:RESET", &TraceOp::GpioRead),
+                (
+                    "gpio:RESET",
+                    &TraceOp::GpioSet {
+                        mode: None,
+                        value: Some(false),
+                        pull: None,
+                        analog_value: None,
+                    }
+                ),
+                ("gpio:BOGUS", &TraceOp::Open),
+                ("spi:0", &TraceOp::Open),
+                (
+                    "spi:0",
+                    &TraceOp::SpiTransaction(vec![
+                        TransferRecord::Write(vec![0x9f]),
+                        TransferRecord::Read(2),
+                    ])
+                ),
+                (
+                    "spi:0",
+                    &TraceOp::SpiTransactionAtSpeed(vec![TransferRecord::Read(2)], 500_000)
+                ),
+                (
+                    "spi:0",
+                    &TraceOp::SpiSetTransactionTimeout(Duration::from_millis(100))
+                ),
+                ("transport", &TraceOp::Dispatch),
+            ]
+        );
+        assert_eq!(records[3].result, Ok(serde_json::json!(true)));
+        assert!(!reset.read()?);
+        assert!(records[5].result.is_err());
+        assert_eq!(records[7].result, Ok(serde_json::json!([[0x5a, 0x5a]])));
+        assert_eq!(records[8].result, Ok(serde_json::json!([[0x5a, 0x5a]])));
+        assert!(records[9].result.is_err());
+        assert!(records[10].result.is_err());
+        assert!(records
+            .windows(2)
+            .all(|pair| pair[0].elapsed <= pair[1].elapsed));
+        Ok(())
+    }
+}
@@ -8,7 +8,7 @@ use nix::sys::signal;
 use nix::unistd::Pid;
 use serialport::ClearBuffer;
 //use serialport::{FlowControl, SerialPort};
-use serialport::{SerialPort, TTYPort};
+use serialport::{DataBits, Parity, SerialPort, StopBits, TTYPort};
 use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
 use std::fs::OpenOptions;
@@ -16,15 +16,125 @@ use std::io::{ErrorKind, Read, Write};
 use std::os::unix::io::AsRawFd;
 use std::time::Duration;
 
+use crate::app::config::{UartParity, UartStopBits};
 //use crate::io::uart::{Uart, UartError};
 use crate::io::uart::{FlowControl, Uart, UartError};
 use crate::transport::TransportError;
 
+/// Settings applied when opening a serial port with `SerialPortUart::open_with()`.
+#[derive(Clone, Debug)]
+pub struct UartConfig {
+    pub baudrate: u32,
+    /// Number of data bits per character, 5 through 8.
+    pub data_bits: u8,
+    pub parity: UartParity,
+    pub stop_bits: UartStopBits,
+    /// Timeout of `read()` and `write()`, they wait practically forever if `None`.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for UartConfig {
+    fn default() -> Self {
+        Self {
+            baudrate: 115200,
+            data_bits: 8,
+            parity: UartParity::None,
+            stop_bits: UartStopBits::Stop1,
+            timeout: None,
+        }
+    }
+}
+
+impl UartConfig {
+    /// Translates the settings for the serialport library, reporting any unsupported setting.
+    fn builder(&self, port_name: &str) -> Result<serialport::SerialPortBuilder> {
+        if self.baudrate == 0 {
+            bail!(UartError::InvalidSpeed(self.baudrate));
+        }
+        let data_bits = match self.data_bits {
+            5 => DataBits::Five,
+            6 => DataBits::Six,
+            7 => DataBits::Seven,
+            8 => DataBits::Eight,
+            n => bail!(UartError::InvalidOption(format!("{} data bits", n))),
+        };
+        let parity = match self.parity {
+            UartParity::None => Parity::None,
+            UartParity::Even => Parity::Even,
+            UartParity::Odd => Parity::Odd,
+            ref p => bail!(UartError::InvalidOption(format!("{:?} parity", p))),
+        };
+        let stop_bits = match self.stop_bits {
+            UartStopBits::Stop1 => StopBits::One,
+            UartStopBits::Stop2 => StopBits::Two,
+            ref s => bail!(UartError::InvalidOption(format!("{:?} stop bits", s))),
+        };
+        Ok(serialport::new(port_name, self.baudrate)
+            .data_bits(data_bits)
+            .parity(parity)
+            .stop_bits(stop_bits)
+            .timeout(self.timeout.unwrap_or(SerialPortUart::FOREVER)))
+    }
+}
+
+/// Data received from a serial port, but not yet consumed.  The buffer is filled by reading up to
+/// `read_size` bytes at a time from the operating system, such that many small reads by the
+/// caller (e.g. line by line console scraping) do not each result in a system call.
+struct RxBuffer {
+    data: VecDeque<u8>,
+    read_size: usize,
+    /// Destinations to which a copy of all data taken out of the buffer is written.
+    sinks: Vec<Box<dyn Write + Send>>,
+}
+
+impl RxBuffer {
+    fn new(read_size: usize) -> Self {
+        Self {
+            data: VecDeque::new(),
+            read_size,
+            sinks: Vec::new(),
+        }
+    }
+
+    /// Performs a single read from `port`, appending any data received to the buffer, except for
+    /// bytes which `filter` returns `false` for.  Timeout results in no data being appended.
+    fn fill(&mut self, port: &mut dyn Read, mut filter: impl FnMut(u8) -> bool) -> Result<()> {
+        let mut buf = vec![0u8; self.read_size];
+        let len = match port.read(&mut buf) {
+            Ok(n) => n,
+            Err(ioerr) if ioerr.kind() == ErrorKind::TimedOut => 0,
+            Err(e) => return Err(e.into()),
+        };
+        for &ch in &buf[..len] {
+            if filter(ch) {
+                self.data.push_back(ch);
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves as much buffered data as will fit into `buf`, returning the number of bytes.
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        let len = std::cmp::min(buf.len(), self.data.len());
+        for (byte, rx) in buf.iter_mut().zip(self.data.drain(..len)) {
+            *byte = rx;
+        }
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.write_all(&buf[..len]).and_then(|_| sink.flush()) {
+                log::error!("Error writing UART data to sink: {}", e);
+            }
+        }
+        len
+    }
+}
+
 /// Implementation of the `Uart` trait on top of a serial device, such as `/dev/ttyUSB0`.
 pub struct SerialPortUart {
     flow_control: Cell<FlowControl>,
+    /// Timeout of `read()`, and of writes.
+    timeout: Duration,
     port: RefCell<TTYPort>,
-    rxbuf: RefCell<VecDeque<u8>>,
+    rxbuf: RefCell<RxBuffer>,
     /// Lock field, will remove lock file via the `Drop` trait.
     _lock: SerialPortExclusiveLock,
 }
@@ -37,61 +147,64 @@ impl SerialPortUart {
     // longer than any invocation of this program.
     const FOREVER: Duration = Duration::from_secs(100 * 365 * 86400);
 
+    /// Default maximum number of bytes to request from the operating system in each read.
+    pub const DEFAULT_READ_SIZE: usize = 256;
+
     /// Open the given serial device, such as `/dev/ttyUSB0`.
     pub fn open(port_name: &str) -> Result<Self> {
+        Self::open_with(port_name, &UartConfig::default())
+    }
+
+    /// Open the given serial device, with all of `config` applied as part of opening, such that
+    /// no data is ever exchanged using other settings.
+    pub fn open_with(port_name: &str, config: &UartConfig) -> Result<Self> {
+        let builder = config.builder(port_name)?;
         let lock = SerialPortExclusiveLock::lock(port_name)?;
-        let port = TTYPort::open(&serialport::new(port_name, 115200))
-            .map_err(|e| UartError::OpenError(e.to_string()))?;
-        flock_serial(&port, port_name)?;
+        let port = Self::open_port(port_name, &builder)?;
         Ok(SerialPortUart {
             flow_control: Cell::new(FlowControl::None),
+            timeout: config.timeout.unwrap_or(Self::FOREVER),
             port: RefCell::new(port),
-            rxbuf: RefCell::default(),
+            rxbuf: RefCell::new(RxBuffer::new(Self::DEFAULT_READ_SIZE)),
             _lock: lock,
         })
     }
 
+    fn open_port(port_name: &str, builder: &serialport::SerialPortBuilder) -> Result<TTYPort> {
+        let port = TTYPort::open(builder).map_err(|e| UartError::OpenError(e.to_string()))?;
+        flock_serial(&port, port_name)?;
+        Ok(port)
+    }
+
+    /// Sets the maximum number of bytes to request from the operating system in each read.  Data
+    /// is buffered internally, a larger size lets more small reads be served without system
+    /// calls, while never delaying the delivery of data already received.
+    pub fn with_read_size(self, read_size: usize) -> Self {
+        self.rxbuf.borrow_mut().read_size = std::cmp::max(read_size, 1);
+        self
+    }
+
     fn read_worker(&self, timeout: Duration) -> Result<()> {
-        let mut buf = [0u8; 256];
         let mut port = self.port.borrow_mut();
 
         port.set_timeout(timeout).context("UART read error")?;
-        let result = port.read(&mut buf);
-        let len = match result {
-            Ok(n) => n,
-            Err(ioerr) if ioerr.kind() == ErrorKind::TimedOut => 0,
-            Err(e) => return Err(e.into()),
-        };
-        for &ch in &buf[..len] {
+        self.rxbuf.borrow_mut().fill(&mut *port, |ch| {
             if self.flow_control.get() != FlowControl::None {
                 if ch == FlowControl::Resume as u8 {
                     log::debug!("Got RESUME");
                     self.flow_control.set(FlowControl::Resume);
-                    continue;
+                    return false;
                 } else if ch == FlowControl::Pause as u8 {
                     log::debug!("Got PAUSE");
                     self.flow_control.set(FlowControl::Pause);
-                    continue;
+                    return false;
                 }
             }
-            self.rxbuf.borrow_mut().push_back(ch);
-        }
-        port.set_timeout(Self::FOREVER).context("UART read error")?;
+            true
+        })?;
+        port.set_timeout(self.timeout).context("UART read error")?;
         Ok(())
     }
-
-    fn read_buffer(&self, buf: &mut [u8]) -> Result<usize> {
-        let mut rxbuf = self.rxbuf.borrow_mut();
-        let mut i = 0;
-        for byte in buf.iter_mut() {
-            let Some(rx) = rxbuf.pop_front() else {
-                break;
-            };
-            *byte = rx;
-            i += 1;
-        }
-        Ok(i)
-    }
 }
 
 impl Uart for SerialPortUart {
@@ -122,16 +235,16 @@ impl Uart for SerialPortUart {
     /// Reads UART receive data into `buf`, returning the number of bytes read.
     /// The `timeout` may be used to specify a duration to wait for data.
     fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
-        if self.rxbuf.borrow().is_empty() {
+        if self.rxbuf.borrow().data.is_empty() {
             self.read_worker(timeout)?;
         }
-        self.read_buffer(buf)
+        Ok(self.rxbuf.borrow_mut().take(buf))
     }
 
     /// Reads UART receive data into `buf`, returning the number of bytes read.
     /// This function _may_ block.
     fn read(&self, buf: &mut [u8]) -> Result<usize> {
-        self.read_timeout(buf, Self::FOREVER)
+        self.read_timeout(buf, self.timeout)
     }
 
     /// Writes data from `buf` to the UART.
@@ -163,9 +276,14 @@ impl Uart for SerialPortUart {
         Ok(())
     }
 
+    fn add_sink(&self, sink: Box<dyn Write + Send>) -> Result<()> {
+        self.rxbuf.borrow_mut().sinks.push(sink);
+        Ok(())
+    }
+
     /// Clears the UART RX buffer.
     fn clear_rx_buffer(&self) -> Result<()> {
-        self.rxbuf.borrow_mut().clear();
+        self.rxbuf.borrow_mut().data.clear();
         self.port.borrow_mut().clear(ClearBuffer::Input)?;
         Ok(())
     }
@@ -246,3 +364,211 @@ pub fn flock_serial(port: &TTYPort, port_name: &str) -> Result<()> {
     })?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Delivers data like a serial port would, up to the requested number of bytes of whatever
+    /// has been "received" so far, counting the number of reads.
+    struct CountingPort {
+        data: VecDeque<u8>,
+        reads: usize,
+    }
+
+    impl Read for CountingPort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            if self.data.is_empty() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            let len = std::cmp::min(buf.len(), self.data.len());
+            for (byte, rx) in buf.iter_mut().zip(self.data.drain(..len)) {
+                *byte = rx;
+            }
+            Ok(len)
+        }
+    }
+
+    /// Consumes lines one byte at a time, as console scraping does, returning the lines and the
+    /// number of reads made from the port.
+    fn read_lines(input: &str, read_size: usize) -> Result<(Vec<String>, usize)> {
+        let mut port = CountingPort {
+            data: input.bytes().collect(),
+            reads: 0,
+        };
+        let mut rxbuf = RxBuffer::new(read_size);
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        loop {
+            if rxbuf.data.is_empty() {
+                rxbuf.fill(&mut port, |_| true)?;
+            }
+            let mut ch = [0u8; 1];
+            if rxbuf.take(&mut ch) == 0 {
+                break;
+            }
+            if ch[0] == b'\n' {
+                lines.push(std::mem::take(&mut line));
+            } else {
+                line.push(ch[0] as char);
+            }
+        }
+        Ok((lines, port.reads))
+    }
+
+    #[test]
+    fn test_buffered_reads() -> Result<()> {
+        let input = "ROM: Boot\nBFV:0142500d\nLCV:21f2\nPASS!\n";
+        let (lines, unbuffered_reads) = read_lines(input, 1)?;
+        assert_eq!(
+            lines,
+            vec!["ROM: Boot", "BFV:0142500d", "LCV:21f2", "PASS!"]
+        );
+        // One read per byte, plus the final one timing out.
+        assert_eq!(unbuffered_reads, input.len() + 1);
+
+        let (buffered_lines, buffered_reads) = read_lines(input, 16)?;
+        assert_eq!(buffered_lines, lines);
+        // 38 bytes are received in three reads of up to 16 bytes.
+        assert_eq!(buffered_reads, 3 + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_fill_does_not_block() -> Result<()> {
+        let mut port = CountingPort {
+            data: b"OK".iter().copied().collect(),
+            reads: 0,
+        };
+        let mut rxbuf = RxBuffer::new(SerialPortUart::DEFAULT_READ_SIZE);
+        rxbuf.fill(&mut port, |_| true)?;
+        // Less than `read_size` available, delivered after a single read.
+        let mut buf = [0u8; 16];
+        assert_eq!(rxbuf.take(&mut buf), 2);
+        assert_eq!(&buf[..2], b"OK");
+        assert_eq!(port.reads, 1);
+        Ok(())
+    }
+
+    /// In-memory sink, which can be inspected after having been handed over to `RxBuffer`.
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct BrokenSink;
+
+    impl Write for BrokenSink {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sinks() -> Result<()> {
+        let mut port = CountingPort {
+            data: b"ROM: Boot\x13\nPASS!\n".iter().copied().collect(),
+            reads: 0,
+        };
+        let sink = SharedSink::default();
+        let mut rxbuf = RxBuffer::new(4);
+        rxbuf.sinks.push(Box::new(BrokenSink));
+        rxbuf.sinks.push(Box::new(sink.clone()));
+
+        let mut seen = Vec::new();
+        let mut buf = [0u8; 3];
+        loop {
+            // Flow control characters are filtered out before reaching the reader.
+            rxbuf.fill(&mut port, |ch| ch != FlowControl::Pause as u8)?;
+            let len = rxbuf.take(&mut buf);
+            if len == 0 {
+                break;
+            }
+            seen.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(seen, b"ROM: Boot\nPASS!\n");
+        assert_eq!(*sink.0.lock().unwrap(), seen);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_with_settings() -> Result<()> {
+        let (_master, slave) = TTYPort::pair()?;
+        let name = slave.name().unwrap();
+        let config = UartConfig {
+            baudrate: 57600,
+            data_bits: 7,
+            parity: UartParity::Even,
+            stop_bits: UartStopBits::Stop2,
+            timeout: Some(Duration::from_millis(100)),
+        };
+        // The Linux pty driver forces 8 data bits without parity, so those are only checked on
+        // the settings passed to the serialport library.
+        assert_eq!(
+            config.builder(&name)?,
+            serialport::new(&name, 57600)
+                .data_bits(DataBits::Seven)
+                .parity(Parity::Even)
+                .stop_bits(StopBits::Two)
+                .timeout(Duration::from_millis(100))
+        );
+        let uart = SerialPortUart::open_with(&name, &config)?;
+        assert_eq!(uart.get_baudrate()?, 57600);
+        let port = uart.port.borrow();
+        assert_eq!(port.stop_bits()?, StopBits::Two);
+        assert_eq!(port.timeout(), Duration::from_millis(100));
+        assert_eq!(uart.timeout, Duration::from_millis(100));
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_baudrate() -> Result<()> {
+        let (_master, slave) = TTYPort::pair()?;
+        let name = slave.name().unwrap();
+        let uart = SerialPortUart::open(&name)?;
+        uart.set_baudrate(115200)?;
+        assert_eq!(uart.get_baudrate()?, 115200);
+        uart.set_baudrate(230400)?;
+        assert_eq!(uart.get_baudrate()?, 230400);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let invalid = [
+            UartConfig {
+                baudrate: 0,
+                ..Default::default()
+            },
+            UartConfig {
+                data_bits: 9,
+                ..Default::default()
+            },
+            UartConfig {
+                parity: UartParity::Mark,
+                ..Default::default()
+            },
+            UartConfig {
+                stop_bits: UartStopBits::Stop1_5,
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            let err = config.builder("/dev/null").unwrap_err();
+            assert!(err.downcast_ref::<UartError>().is_some(), "{:?}", config);
+        }
+    }
+}
@@ -2,54 +2,950 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::io::gpio::{GpioError, GpioPin, PinMode, PullMode};
+use crate::io::gpio::{
+    ClockNature, Edge, GpioError, GpioMonitoring, GpioPin, GpioPinCapabilities, MonitoringEvent,
+    MonitoringReadResponse, MonitoringSession, MonitoringStartResponse, PinMode, PullMode,
+};
 use crate::transport::cw310::usb::Backend;
+use crate::transport::TransportError;
+
+/// Cache of recently read pin levels, shared among all GPIO pins of a CW310 board, in order to
+/// avoid repeated USB round trips when the same pins are polled in a tight loop.  A cached level
+/// is used for up to `ttl` after it was read from the board, meaning that changes driven by the
+/// target can go unnoticed for that long.  Any write or mode change through this transport
+/// invalidates the entire cache.
+pub struct PinCache {
+    ttl: Duration,
+    levels: HashMap<u8, (bool, Instant)>,
+}
+
+impl PinCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            levels: HashMap::new(),
+        }
+    }
+
+    /// Returns the level of the given pin, calling `fetch` to read it from the board only if
+    /// there is no sufficiently recent value in the cache.
+    pub fn read(&mut self, pinnum: u8, fetch: impl FnOnce() -> Result<bool>) -> Result<bool> {
+        if let Some((level, timestamp)) = self.levels.get(&pinnum) {
+            if timestamp.elapsed() < self.ttl {
+                return Ok(*level);
+            }
+        }
+        let level = fetch()?;
+        self.levels.insert(pinnum, (level, Instant::now()));
+        Ok(level)
+    }
+
+    /// Discard all cached levels.
+    pub fn invalidate(&mut self) {
+        self.levels.clear();
+    }
+}
+
+/// Modes of the GPIO pins of a CW310 board, as most recently set through this transport, shared
+/// among all pins of the board.  The SAM3X firmware offers no way of reading back the direction
+/// of a pin, so this table is the only record of pin modes.  It is keyed by pin number, such that
+/// different names of the same pin agree.
+#[derive(Default)]
+pub struct PinModes {
+    modes: HashMap<u8, PinMode>,
+}
+
+impl PinModes {
+    pub fn get(&self, pinname: &str) -> Result<PinMode> {
+        let pinnum = Backend::pin_name_to_number(pinname)?;
+        self.modes
+            .get(&pinnum)
+            .copied()
+            .ok_or_else(|| GpioError::Generic(format!("Mode of pin {} not known", pinname)).into())
+    }
+
+    pub fn set(&mut self, pinname: &str, mode: PinMode) -> Result<()> {
+        self.modes
+            .insert(Backend::pin_name_to_number(pinname)?, mode);
+        Ok(())
+    }
+
+    /// Returns the modes of all of `pins`, in the same order.  Fails if any of the pins does not
+    /// belong to a CW310 board, or has not had its mode set.
+    pub fn get_many(&self, pins: &[&dyn GpioPin]) -> Result<Vec<PinMode>> {
+        pins.iter()
+            .map(|pin| {
+                let pinname = pin.get_internal_pin_name().ok_or_else(|| {
+                    GpioError::Generic("Pin does not belong to CW310".to_string())
+                })?;
+                self.get(pinname)
+            })
+            .collect()
+    }
+}
+
+/// Operation performed on a GPIO pin, as recorded in a `GpioLog`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum GpioAction {
+    Read(bool),
+    Write(bool),
+    SetMode(PinMode),
+    SetPullMode(PullMode),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GpioLogEntry {
+    pub pin: String,
+    pub action: GpioAction,
+    /// Microseconds since the log was created.
+    pub timestamp: u64,
+}
+
+/// Record of every successful operation on the GPIO pins of a CW310 board, for debugging tests
+/// with failing GPIO expectations.  Shared among all pins of the board.
+pub struct GpioLog {
+    start: Instant,
+    entries: Vec<GpioLogEntry>,
+}
+
+impl GpioLog {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, pin: &str, action: GpioAction) {
+        self.entries.push(GpioLogEntry {
+            pin: pin.to_string(),
+            action,
+            timestamp: self.start.elapsed().as_micros() as u64,
+        });
+    }
+
+    pub fn entries(&self) -> &[GpioLogEntry] {
+        &self.entries
+    }
+}
+
+impl Default for GpioLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Set of physical pins whose level is inverted by circuitry on the board, such that e.g. a
+/// high level at the SAM3X is seen as low by the OpenTitan chip.  Levels of these pins are
+/// corrected at the transport boundary, such that users of the transport always see the levels
+/// of the OpenTitan side.
+#[derive(Clone, Debug, Default)]
+pub struct PinInversion {
+    pins: HashSet<String>,
+}
+
+impl PinInversion {
+    pub fn new(pins: &[&str]) -> Self {
+        Self {
+            pins: pins.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// Whether the pin, or any alias of it, is inverted.
+    pub fn is_inverted(&self, pinname: &str) -> bool {
+        if self.pins.contains(pinname) {
+            return true;
+        }
+        match Backend::pin_name_to_number(pinname) {
+            Ok(pinnum) => self
+                .pins
+                .iter()
+                .any(|name| Backend::pin_name_to_number(name).ok() == Some(pinnum)),
+            Err(_) => false,
+        }
+    }
+
+    /// Converts between physical and logical level of the given pin (the conversion is the same
+    /// in both directions).
+    pub fn correct_level(&self, pinname: &str, level: bool) -> bool {
+        level ^ self.is_inverted(pinname)
+    }
+
+    /// Corrects the initial levels reported when starting a monitoring session.
+    pub fn correct_start(&self, response: &mut MonitoringStartResponse) {
+        let pinnames = response.session.pin_names();
+        for (level, pinname) in response.initial_levels.iter_mut().zip(pinnames) {
+            *level = self.correct_level(pinname, *level);
+        }
+    }
+
+    /// Corrects the direction of edges reported for the given monitoring session.
+    pub fn correct_read(&self, session: &MonitoringSession, response: &mut MonitoringReadResponse) {
+        for event in &mut response.events {
+            match session.pin_names().get(event.signal_index as usize) {
+                Some(pinname) if self.is_inverted(pinname) => {
+                    event.edge = match event.edge {
+                        Edge::Rising => Edge::Falling,
+                        Edge::Falling => Edge::Rising,
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Command to the SAM3X firmware, as ordered by `set_commands()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PinCommand {
+    /// Enable or disable the output driver.
+    SetOutput(bool),
+    /// Set the level of the output register, driven on the pin only if the output is enabled.
+    SetState(bool),
+}
+
+/// Orders the commands for changing the mode and (physical) level of a pin together, such that
+/// the pin never drives anything other than its old or new level.  The output register is loaded
+/// before the driver is enabled, and the driver is disabled before the output register is changed.
+/// In particular, a pin switching from driving high to input never drives low in between, which
+/// matters for lines shared with other drivers.
+fn set_commands(mode: Option<PinMode>, level: Option<bool>) -> Result<Vec<PinCommand>> {
+    let set_state = level.map(PinCommand::SetState);
+    Ok(match mode {
+        None => set_state.into_iter().collect(),
+        Some(PinMode::PushPull) => set_state
+            .into_iter()
+            .chain([PinCommand::SetOutput(true)])
+            .collect(),
+        Some(PinMode::Input) => [PinCommand::SetOutput(false)]
+            .into_iter()
+            .chain(set_state)
+            .collect(),
+        Some(mode) => return Err(GpioError::UnsupportedPinMode(mode).into()),
+    })
+}
 
 pub struct CW310GpioPin {
     device: Rc<RefCell<Backend>>,
+    cache: Option<Rc<RefCell<PinCache>>>,
+    log: Option<Rc<RefCell<GpioLog>>>,
+    modes: Rc<RefCell<PinModes>>,
     pinname: String,
+    /// Whether the board inverts the level of this pin, see `PinInversion`.
+    inverted: bool,
 }
 
 impl CW310GpioPin {
-    pub fn open(backend: Rc<RefCell<Backend>>, pinname: String) -> Result<Self> {
+    pub fn open(
+        backend: Rc<RefCell<Backend>>,
+        cache: Option<Rc<RefCell<PinCache>>>,
+        log: Option<Rc<RefCell<GpioLog>>>,
+        modes: Rc<RefCell<PinModes>>,
+        pinname: String,
+        inverted: bool,
+    ) -> Result<Self> {
         Ok(Self {
             device: backend,
+            cache,
+            log,
+            modes,
             pinname,
+            inverted,
         })
     }
+
+    fn record(&self, action: GpioAction) {
+        if let Some(log) = &self.log {
+            log.borrow_mut().record(&self.pinname, action);
+        }
+    }
+
+    /// Capabilities of the given pin, according to how it is wired on the board.  The SAM3X
+    /// GPIO firmware offers neither pull resistors nor analog functions.  Pins carrying status
+    /// signals of the FPGA configuration interface are driven by the FPGA, and can only be read.
+    pub fn pin_capabilities(pinname: &str) -> Result<GpioPinCapabilities> {
+        let pinnum = Backend::pin_name_to_number(pinname)?;
+        let input_only = ["CFG_DONE", "CFG_INITB"]
+            .iter()
+            .any(|name| Backend::pin_name_to_number(name).ok() == Some(pinnum));
+        Ok(GpioPinCapabilities {
+            modes: if input_only {
+                vec![PinMode::Input]
+            } else {
+                vec![PinMode::Input, PinMode::PushPull]
+            },
+            pull_modes: vec![PullMode::None],
+            analog_read: false,
+            analog_write: false,
+            voltage_domain: None,
+        })
+    }
+
+    fn invalidate_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.borrow_mut().invalidate();
+        }
+    }
 }
 
 impl GpioPin for CW310GpioPin {
     fn read(&self) -> Result<bool> {
         let usb = self.device.borrow();
-        let pin = usb.pin_get_state(&self.pinname)?;
-        Ok(pin != 0)
+        let value = match &self.cache {
+            Some(cache) => cache
+                .borrow_mut()
+                .read(Backend::pin_name_to_number(&self.pinname)?, || {
+                    Ok(usb.pin_get_state(&self.pinname)? != 0)
+                })?,
+            None => usb.pin_get_state(&self.pinname)? != 0,
+        } ^ self.inverted;
+        self.record(GpioAction::Read(value));
+        Ok(value)
     }
 
     fn write(&self, value: bool) -> Result<()> {
+        self.invalidate_cache();
         let usb = self.device.borrow();
-        usb.pin_set_state(&self.pinname, value)?;
+        usb.pin_set_state(&self.pinname, value ^ self.inverted)?;
+        self.record(GpioAction::Write(value));
         Ok(())
     }
 
     fn set_mode(&self, mode: PinMode) -> Result<()> {
+        self.invalidate_cache();
         let usb = self.device.borrow();
         match mode {
             PinMode::Input => usb.pin_set_output(&self.pinname, false)?,
             PinMode::PushPull => usb.pin_set_output(&self.pinname, true)?,
             _ => return Err(GpioError::UnsupportedPinMode(mode).into()),
         }
+        self.modes.borrow_mut().set(&self.pinname, mode)?;
+        self.record(GpioAction::SetMode(mode));
+        Ok(())
+    }
+
+    /// Applies mode and level in an order which avoids glitches, see `set_commands()`.
+    fn set(
+        &self,
+        mode: Option<PinMode>,
+        value: Option<bool>,
+        pull: Option<PullMode>,
+        analog_value: Option<f32>,
+    ) -> Result<()> {
+        if analog_value.is_some() {
+            return Err(TransportError::UnsupportedOperation.into());
+        }
+        if let Some(pull) = pull {
+            self.set_pull_mode(pull)?;
+        }
+        let commands = set_commands(mode, value.map(|v| v ^ self.inverted))?;
+        self.invalidate_cache();
+        let usb = self.device.borrow();
+        for command in commands {
+            match command {
+                PinCommand::SetOutput(output) => usb.pin_set_output(&self.pinname, output)?,
+                PinCommand::SetState(level) => usb.pin_set_state(&self.pinname, level)?,
+            }
+        }
+        if let Some(mode) = mode {
+            self.modes.borrow_mut().set(&self.pinname, mode)?;
+            self.record(GpioAction::SetMode(mode));
+        }
+        if let Some(value) = value {
+            self.record(GpioAction::Write(value));
+        }
         Ok(())
     }
 
+    fn get_mode(&self) -> Result<PinMode> {
+        self.modes.borrow().get(&self.pinname)
+    }
+
+    fn get_pull_mode(&self) -> Result<PullMode> {
+        // Pull resistors are not supported.
+        Ok(PullMode::None)
+    }
+
+    fn capabilities(&self) -> Result<GpioPinCapabilities> {
+        Self::pin_capabilities(&self.pinname)
+    }
+
     fn set_pull_mode(&self, mode: PullMode) -> Result<()> {
         match mode {
-            PullMode::None => Ok(()),
+            PullMode::None => {
+                self.record(GpioAction::SetPullMode(mode));
+                Ok(())
+            }
             _ => Err(GpioError::UnsupportedPullMode(mode).into()),
         }
     }
+
+    fn get_internal_pin_name(&self) -> Option<&str> {
+        Some(&self.pinname)
+    }
+}
+
+/// Interval between successive samples of the pins of a monitoring session.
+const MONITORING_POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// Number of events buffered for each monitoring session, before reporting an overrun.
+const MONITORING_MAX_EVENTS: usize = 65536;
+
+/// Edges found by repeatedly sampling the levels of the pins of a monitoring session.  An edge is
+/// timestamped with the time of the first sample showing the new level, such that pulses shorter
+/// than the time between samples may go unnoticed.
+pub struct PolledEvents {
+    levels: Vec<bool>,
+    events: Vec<MonitoringEvent>,
+    /// Time of the most recent sample, every edge up to then has been recorded.
+    watermark: u64,
+    max_events: usize,
+    /// Buffer overrun or failure to sample, after which nothing more is recorded.
+    error: Option<anyhow::Error>,
+}
+
+impl PolledEvents {
+    pub fn new(initial_levels: Vec<bool>, timestamp: u64, max_events: usize) -> Self {
+        Self {
+            levels: initial_levels,
+            events: Vec::new(),
+            watermark: timestamp,
+            max_events,
+            error: None,
+        }
+    }
+
+    /// Records the levels of all pins, as sampled at `timestamp`.  Returns `false` once
+    /// recording has stopped, due to buffer overrun.
+    pub fn record(&mut self, levels: &[bool], timestamp: u64) -> bool {
+        if self.error.is_some() {
+            return false;
+        }
+        for (index, (level, &new_level)) in self.levels.iter_mut().zip(levels).enumerate() {
+            if *level == new_level {
+                continue;
+            }
+            if self.events.len() >= self.max_events {
+                self.error = Some(GpioError::MonitoringOverrun(self.max_events).into());
+                return false;
+            }
+            self.events.push(MonitoringEvent {
+                signal_index: index as u8,
+                edge: if new_level {
+                    Edge::Rising
+                } else {
+                    Edge::Falling
+                },
+                timestamp,
+            });
+            *level = new_level;
+        }
+        self.watermark = timestamp;
+        true
+    }
+
+    /// Records a failure to sample the pins, to be reported by the next call to `take()`.
+    pub fn fail(&mut self, error: anyhow::Error) {
+        self.error.get_or_insert(error);
+    }
+
+    /// Removes the events recorded so far, returning them along with the time of the most
+    /// recent sample as watermark, or the error which stopped the recording.
+    pub fn take(&mut self) -> Result<MonitoringReadResponse> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        Ok(MonitoringReadResponse {
+            events: std::mem::take(&mut self.events),
+            timestamp: self.watermark,
+        })
+    }
+}
+
+/// Microseconds since the Unix epoch.
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_micros() as u64)
+}
+
+/// Source of the pin levels sampled by a monitoring session, moved to the polling thread.
+pub trait PinSampler: Send {
+    /// Reads the physical level of each of the given pins.
+    fn sample(&self, pinnames: &[String]) -> Result<Vec<bool>>;
+}
+
+impl PinSampler for Backend {
+    /// One USB request per pin.
+    fn sample(&self, pinnames: &[String]) -> Result<Vec<bool>> {
+        pinnames
+            .iter()
+            .map(|pinname| Ok(self.pin_get_state(pinname)? != 0))
+            .collect()
+    }
+}
+
+/// Opens a separate `PinSampler` for each monitoring session.
+type SamplerOpener = Box<dyn Fn() -> Result<Box<dyn PinSampler>>>;
+
+/// Thread sampling the pins of a single monitoring session, stopped when dropped.
+struct Poller {
+    events: Arc<Mutex<PolledEvents>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Poller {
+    fn start(sampler: Box<dyn PinSampler>, pinnames: Vec<String>, events: PolledEvents) -> Self {
+        let events = Arc::new(Mutex::new(events));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let events = Arc::clone(&events);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut next = Instant::now();
+                while !stop.load(Ordering::Relaxed) {
+                    next += MONITORING_POLL_INTERVAL;
+                    let levels = sampler.sample(&pinnames);
+                    let timestamp = now_micros();
+                    match levels {
+                        Ok(levels) if events.lock().unwrap().record(&levels, timestamp) => (),
+                        Ok(_) => break,
+                        Err(e) => {
+                            events.lock().unwrap().fail(e);
+                            break;
+                        }
+                    }
+                    match next.checked_duration_since(Instant::now()) {
+                        Some(delay) => thread::sleep(delay),
+                        // Sampling takes longer than the interval, poll as fast as possible.
+                        None => next = Instant::now(),
+                    }
+                }
+            })
+        };
+        Self {
+            events,
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Logic analyzer functionality for the CW310, whose SAM3X firmware offers no edge detection.
+/// Each session is served by a thread polling the pins over a separate USB connection to the
+/// board, so the time resolution is limited by the USB round trip of one request per pin, in
+/// practice a few milliseconds.  Timestamps are microseconds since the Unix epoch.
+pub struct CW310GpioMonitoring {
+    open_sampler: SamplerOpener,
+    inversion: PinInversion,
+    sessions: RefCell<HashMap<MonitoringSession, Poller>>,
+}
+
+impl CW310GpioMonitoring {
+    pub fn new(device: Rc<RefCell<Backend>>, inversion: PinInversion) -> Self {
+        Self::with_sampler(
+            Box::new(move || Ok(Box::new(device.borrow().reopen()?))),
+            inversion,
+        )
+    }
+
+    fn with_sampler(open_sampler: SamplerOpener, inversion: PinInversion) -> Self {
+        Self {
+            open_sampler,
+            inversion,
+            sessions: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl GpioMonitoring for CW310GpioMonitoring {
+    fn get_clock_nature(&self) -> Result<ClockNature> {
+        Ok(ClockNature::Wallclock {
+            resolution: 1_000_000,
+            offset: Some(0),
+        })
+    }
+
+    fn monitoring_start(&self, pins: &[&dyn GpioPin]) -> Result<MonitoringStartResponse> {
+        let session = MonitoringSession::from_pins(pins)?;
+        let mut sessions = self.sessions.borrow_mut();
+        for pinname in session.pin_names() {
+            let pinnum = Backend::pin_name_to_number(pinname)?;
+            if sessions
+                .keys()
+                .flat_map(|other| other.pin_names())
+                .any(|other| Backend::pin_name_to_number(other).ok() == Some(pinnum))
+            {
+                bail!(GpioError::Generic(format!(
+                    "Pin {} is already being monitored",
+                    pinname
+                )));
+            }
+        }
+        let sampler = (self.open_sampler)()?;
+        let initial_levels = sampler.sample(session.pin_names())?;
+        let timestamp = now_micros();
+        let events = PolledEvents::new(initial_levels.clone(), timestamp, MONITORING_MAX_EVENTS);
+        sessions.insert(
+            session.clone(),
+            Poller::start(sampler, session.pin_names().to_vec(), events),
+        );
+        let mut response = MonitoringStartResponse {
+            session,
+            timestamp,
+            initial_levels,
+        };
+        self.inversion.correct_start(&mut response);
+        Ok(response)
+    }
+
+    fn monitoring_read(
+        &self,
+        session: &MonitoringSession,
+        continue_monitoring: bool,
+    ) -> Result<MonitoringReadResponse> {
+        let mut sessions = self.sessions.borrow_mut();
+        let events = match sessions.get(session) {
+            Some(poller) => Arc::clone(&poller.events),
+            None => bail!(GpioError::Generic("No such monitoring session".to_string())),
+        };
+        if !continue_monitoring {
+            // Stop the thread first, such that the final read includes all remaining events.
+            sessions.remove(session);
+        }
+        let result = events.lock().unwrap().take();
+        if result.is_err() {
+            sessions.remove(session);
+        }
+        let mut response = result?;
+        self.inversion.correct_read(session, &mut response);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_pin_cache() -> Result<()> {
+        let fetches = Cell::new(0);
+        let fetch = |level: bool| {
+            let fetches = &fetches;
+            move || {
+                fetches.set(fetches.get() + 1);
+                Ok(level)
+            }
+        };
+        let mut cache = PinCache::new(Duration::from_secs(3600));
+        assert!(cache.read(5, fetch(true))?);
+        assert_eq!(fetches.get(), 1);
+
+        // Second read within the TTL is served from the cache, sibling pins are not.
+        assert!(cache.read(5, fetch(false))?);
+        assert_eq!(fetches.get(), 1);
+        assert!(!cache.read(6, fetch(false))?);
+        assert_eq!(fetches.get(), 2);
+
+        // A write invalidates the cache.
+        cache.invalidate();
+        assert!(!cache.read(5, fetch(false))?);
+        assert_eq!(fetches.get(), 3);
+
+        // Entries older than the TTL are read again.
+        let mut cache = PinCache::new(Duration::from_millis(1));
+        cache.read(5, fetch(true))?;
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(!cache.read(5, fetch(false))?);
+        assert_eq!(fetches.get(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_commands() -> Result<()> {
+        // From output high to input, the driver is released before the level is touched.
+        assert_eq!(
+            set_commands(Some(PinMode::Input), Some(false))?,
+            vec![PinCommand::SetOutput(false), PinCommand::SetState(false)]
+        );
+        // From input to output, the level is in place before the driver is enabled.
+        assert_eq!(
+            set_commands(Some(PinMode::PushPull), Some(true))?,
+            vec![PinCommand::SetState(true), PinCommand::SetOutput(true)]
+        );
+        assert_eq!(
+            set_commands(Some(PinMode::Input), None)?,
+            vec![PinCommand::SetOutput(false)]
+        );
+        assert_eq!(
+            set_commands(None, Some(true))?,
+            vec![PinCommand::SetState(true)]
+        );
+        assert!(set_commands(Some(PinMode::OpenDrain), Some(true)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gpio_log() {
+        let mut log = GpioLog::new();
+        log.record("USB_A13", GpioAction::SetMode(PinMode::PushPull));
+        log.record("USB_A13", GpioAction::Write(false));
+        log.record("USB_A14", GpioAction::Read(true));
+        log.record("USB_A13", GpioAction::Write(true));
+
+        let entries = log.entries();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (e.pin.as_str(), e.action.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("USB_A13", GpioAction::SetMode(PinMode::PushPull)),
+                ("USB_A13", GpioAction::Write(false)),
+                ("USB_A14", GpioAction::Read(true)),
+                ("USB_A13", GpioAction::Write(true)),
+            ]
+        );
+        assert!(entries.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+    }
+
+    struct NamedPin(&'static str);
+
+    impl GpioPin for NamedPin {
+        fn read(&self) -> Result<bool> {
+            unimplemented!();
+        }
+        fn write(&self, _value: bool) -> Result<()> {
+            unimplemented!();
+        }
+        fn set_mode(&self, _mode: PinMode) -> Result<()> {
+            unimplemented!();
+        }
+        fn set_pull_mode(&self, _mode: PullMode) -> Result<()> {
+            unimplemented!();
+        }
+        fn get_internal_pin_name(&self) -> Option<&str> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn test_pin_capabilities() -> Result<()> {
+        let caps = CW310GpioPin::pin_capabilities("USB_A13")?;
+        assert!(caps.supports_mode(PinMode::PushPull));
+        assert!(!caps.supports_mode(PinMode::AnalogInput));
+        assert!(!caps.analog_read);
+
+        // Also when referred to by SAM3X name.
+        for name in ["CFG_DONE", "pb17"] {
+            let caps = CW310GpioPin::pin_capabilities(name)?;
+            assert_eq!(caps.modes, vec![PinMode::Input]);
+        }
+        assert!(CW310GpioPin::pin_capabilities("NO_SUCH_PIN").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_modes() -> Result<()> {
+        let mut modes = PinModes::default();
+        modes.set("USB_A13", PinMode::PushPull)?;
+        modes.set("USB_A14", PinMode::Input)?;
+        modes.set("pb17", PinMode::Input)?;
+        assert!(modes.set("NO_SUCH_PIN", PinMode::Input).is_err());
+
+        // Results follow the order of the request, also for pins referred to by another name.
+        let pins = [
+            &NamedPin("USB_A14") as &dyn GpioPin,
+            &NamedPin("CFG_DONE"),
+            &NamedPin("USB_A13"),
+            &NamedPin("USB_A14"),
+        ];
+        assert_eq!(
+            modes.get_many(&pins)?,
+            vec![
+                PinMode::Input,
+                PinMode::Input,
+                PinMode::PushPull,
+                PinMode::Input
+            ]
+        );
+        // A pin never configured fails the entire request.
+        assert!(modes
+            .get_many(&[&NamedPin("USB_A13"), &NamedPin("USB_A15")])
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_inversion() {
+        let inversion = PinInversion::new(&["USB_A14"]);
+        // Levels as read from the board.
+        assert!(inversion.correct_level("USB_A13", true));
+        assert!(!inversion.correct_level("USB_A14", true));
+        assert!(inversion.correct_level("USB_A14", false));
+        assert!(inversion.is_inverted(&Backend::canonical_pin_name("USB_A14").unwrap()));
+
+        let pins = [&NamedPin("USB_A13") as &dyn GpioPin, &NamedPin("USB_A14")];
+        let mut start = MonitoringStartResponse {
+            session: MonitoringSession::from_pins(&pins).unwrap(),
+            timestamp: 0,
+            initial_levels: vec![true, true],
+        };
+        inversion.correct_start(&mut start);
+        assert_eq!(start.initial_levels, vec![true, false]);
+
+        let event = |signal_index, edge| MonitoringEvent {
+            signal_index,
+            edge,
+            timestamp: 0,
+        };
+        let mut read = MonitoringReadResponse {
+            events: vec![
+                event(1, Edge::Rising),
+                event(0, Edge::Falling),
+                event(1, Edge::Falling),
+            ],
+            timestamp: 0,
+        };
+        inversion.correct_read(&start.session, &mut read);
+        assert_eq!(
+            read.events.iter().map(|e| e.edge).collect::<Vec<_>>(),
+            vec![Edge::Falling, Edge::Falling, Edge::Rising]
+        );
+    }
+
+    #[test]
+    fn test_polled_events() {
+        let mut polled = PolledEvents::new(vec![false, true], 100, 3);
+        assert!(polled.record(&[false, true], 110));
+        assert!(polled.record(&[true, false], 120));
+        let read = polled.take().unwrap();
+        assert_eq!(
+            read.events,
+            vec![
+                MonitoringEvent {
+                    signal_index: 0,
+                    edge: Edge::Rising,
+                    timestamp: 120,
+                },
+                MonitoringEvent {
+                    signal_index: 1,
+                    edge: Edge::Falling,
+                    timestamp: 120,
+                },
+            ]
+        );
+        assert_eq!(read.timestamp, 120);
+
+        // Nothing new, the watermark still advances.
+        assert!(polled.record(&[true, false], 130));
+        let read = polled.take().unwrap();
+        assert!(read.events.is_empty());
+        assert_eq!(read.timestamp, 130);
+
+        // Four edges exceed the buffer of three, after which sampling stops.
+        assert!(polled.record(&[false, true], 140));
+        assert!(!polled.record(&[true, false], 150));
+        assert!(!polled.record(&[false, true], 160));
+        let err = polled.take().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GpioError>(),
+            Some(GpioError::MonitoringOverrun(3))
+        ));
+    }
+
+    /// Board whose pin levels are set by the test, shared by all sessions.
+    struct FakeBoard(Arc<Mutex<HashMap<String, bool>>>);
+
+    impl PinSampler for FakeBoard {
+        fn sample(&self, pinnames: &[String]) -> Result<Vec<bool>> {
+            let levels = self.0.lock().unwrap();
+            Ok(pinnames
+                .iter()
+                .map(|pinname| levels.get(pinname).copied().unwrap_or(false))
+                .collect())
+        }
+    }
+
+    /// Reads `session` until at least one event has been recorded.
+    fn await_events(
+        monitoring: &CW310GpioMonitoring,
+        session: &MonitoringSession,
+    ) -> Result<Vec<MonitoringEvent>> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let events = monitoring.monitoring_read(session, true)?.events;
+            if !events.is_empty() || Instant::now() > deadline {
+                return Ok(events);
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_monitoring_sessions() -> Result<()> {
+        let levels = Arc::new(Mutex::new(HashMap::new()));
+        let monitoring = {
+            let levels = Arc::clone(&levels);
+            CW310GpioMonitoring::with_sampler(
+                Box::new(move || Ok(Box::new(FakeBoard(Arc::clone(&levels))))),
+                PinInversion::new(&[]),
+            )
+        };
+        let a = monitoring.monitoring_start(&[&NamedPin("USB_A10"), &NamedPin("USB_A11")])?;
+        let b = monitoring.monitoring_start(&[&NamedPin("USB_A12")])?;
+        assert_eq!(a.initial_levels, vec![false, false]);
+        // A pin can be part of only one session.
+        assert!(monitoring
+            .monitoring_start(&[&NamedPin("USB_A11")])
+            .is_err());
+
+        // An edge on a pin of one session is not reported by the other.
+        levels.lock().unwrap().insert("USB_A11".to_string(), true);
+        let events = await_events(&monitoring, &a.session)?;
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].signal_index, events[0].edge), (1, Edge::Rising));
+        assert!(monitoring
+            .monitoring_read(&b.session, true)?
+            .events
+            .is_empty());
+
+        levels.lock().unwrap().insert("USB_A12".to_string(), true);
+        let events = await_events(&monitoring, &b.session)?;
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].signal_index, events[0].edge), (0, Edge::Rising));
+        assert!(monitoring
+            .monitoring_read(&a.session, true)?
+            .events
+            .is_empty());
+
+        // Stopping one session leaves the other running.
+        monitoring.monitoring_read(&b.session, false)?;
+        assert!(monitoring.monitoring_read(&b.session, true).is_err());
+        levels.lock().unwrap().insert("USB_A10".to_string(), true);
+        let events = await_events(&monitoring, &a.session)?;
+        assert_eq!((events[0].signal_index, events[0].edge), (0, Edge::Rising));
+        monitoring.monitoring_read(&a.session, false)?;
+        Ok(())
+    }
 }
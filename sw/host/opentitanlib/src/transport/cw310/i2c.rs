@@ -0,0 +1,328 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{bail, ensure, Result};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::io::i2c::{Bus, I2cError, Transfer};
+use crate::transport::cw310::usb::Backend;
+use crate::transport::{TransportError, TransportInterfaceType};
+
+/// The two lines of an I2C bus, each either released, to be pulled high by external resistors,
+/// or driven low.
+trait Lines {
+    fn release_scl(&self, release: bool) -> Result<()>;
+    fn release_sda(&self, release: bool) -> Result<()>;
+    fn sda(&self) -> Result<bool>;
+}
+
+/// I2C controller bit-banged over a pair of SAM3X GPIO pins, neither of which has a dedicated
+/// I2C function on the CW310.  Each change of level takes a USB request, so this is only
+/// suitable for slow peripherals such as sensors, and clock stretching is not supported.
+pub struct CW310I2cBus {
+    device: Rc<RefCell<Backend>>,
+    scl: String,
+    sda: String,
+}
+
+impl CW310I2cBus {
+    /// Opens a bus on the pins named by `instance`, as "SCL:SDA", e.g. "USB_A10:USB_A11".  Both
+    /// lines must have pull-up resistors on the board or FPGA design.
+    pub fn open(device: Rc<RefCell<Backend>>, instance: &str) -> Result<Self> {
+        let (scl, sda) = Self::parse_instance(instance)?;
+        {
+            let usb = device.borrow();
+            // Lines are driven low by switching the pin to output, and released by switching it
+            // back to input, the output level stays low throughout.
+            for pin in [&scl, &sda] {
+                usb.pin_set_output(pin, false)?;
+                usb.pin_set_state(pin, false)?;
+            }
+        }
+        Ok(CW310I2cBus { device, scl, sda })
+    }
+
+    fn parse_instance(instance: &str) -> Result<(String, String)> {
+        match instance.split_once(':') {
+            Some((scl, sda)) if !scl.is_empty() && !sda.is_empty() => {
+                let scl = Backend::canonical_pin_name(scl)?;
+                let sda = Backend::canonical_pin_name(sda)?;
+                ensure!(
+                    scl != sda,
+                    TransportError::InvalidInstance(TransportInterfaceType::I2c, instance.into())
+                );
+                Ok((scl, sda))
+            }
+            _ => bail!(TransportError::InvalidInstance(
+                TransportInterfaceType::I2c,
+                instance.to_string()
+            )),
+        }
+    }
+}
+
+impl Lines for CW310I2cBus {
+    fn release_scl(&self, release: bool) -> Result<()> {
+        self.device.borrow().pin_set_output(&self.scl, !release)
+    }
+    fn release_sda(&self, release: bool) -> Result<()> {
+        self.device.borrow().pin_set_output(&self.sda, !release)
+    }
+    fn sda(&self) -> Result<bool> {
+        Ok(self.device.borrow().pin_get_state(&self.sda)? != 0)
+    }
+}
+
+impl Bus for CW310I2cBus {
+    fn run_transaction(&self, addr: u8, transaction: &mut [Transfer]) -> Result<()> {
+        run_transaction(self, addr, transaction)
+    }
+}
+
+fn start(lines: &dyn Lines) -> Result<()> {
+    // Release SDA before SCL, such that this also works as a repeated start.
+    lines.release_sda(true)?;
+    lines.release_scl(true)?;
+    lines.release_sda(false)?;
+    lines.release_scl(false)
+}
+
+fn stop(lines: &dyn Lines) -> Result<()> {
+    lines.release_sda(false)?;
+    lines.release_scl(true)?;
+    lines.release_sda(true)
+}
+
+fn write_bit(lines: &dyn Lines, bit: bool) -> Result<()> {
+    lines.release_sda(bit)?;
+    lines.release_scl(true)?;
+    lines.release_scl(false)
+}
+
+fn read_bit(lines: &dyn Lines) -> Result<bool> {
+    lines.release_sda(true)?;
+    lines.release_scl(true)?;
+    let bit = lines.sda()?;
+    lines.release_scl(false)?;
+    Ok(bit)
+}
+
+/// Writes a byte, returning whether it was acknowledged.
+fn write_byte(lines: &dyn Lines, byte: u8) -> Result<bool> {
+    for i in (0..8).rev() {
+        write_bit(lines, byte & (1 << i) != 0)?;
+    }
+    Ok(!read_bit(lines)?)
+}
+
+fn read_byte(lines: &dyn Lines, ack: bool) -> Result<u8> {
+    let mut byte = 0u8;
+    for _ in 0..8 {
+        byte = byte << 1 | read_bit(lines)? as u8;
+    }
+    write_bit(lines, !ack)?;
+    Ok(byte)
+}
+
+/// Carries out the transaction, with a (repeated) start condition whenever the direction
+/// changes, and a stop condition at the end, also if the device fails to acknowledge.
+fn run_transaction(lines: &dyn Lines, addr: u8, transaction: &mut [Transfer]) -> Result<()> {
+    ensure!(
+        addr < 0x80,
+        I2cError::Generic(format!("Invalid 7-bit address {:#x}", addr))
+    );
+    let result = run_transfers(lines, addr, transaction);
+    stop(lines)?;
+    result
+}
+
+fn run_transfers(lines: &dyn Lines, addr: u8, transaction: &mut [Transfer]) -> Result<()> {
+    let mut reading = None;
+    for idx in 0..transaction.len() {
+        let next_is_read = matches!(transaction.get(idx + 1), Some(Transfer::Read(_)));
+        match &mut transaction[idx] {
+            Transfer::Write(wbuf) => {
+                if reading != Some(false) {
+                    start(lines)?;
+                    ensure!(write_byte(lines, addr << 1)?, I2cError::Nack(addr));
+                    reading = Some(false);
+                }
+                for byte in wbuf.iter() {
+                    ensure!(write_byte(lines, *byte)?, I2cError::Nack(addr));
+                }
+            }
+            Transfer::Read(rbuf) => {
+                if reading != Some(true) {
+                    start(lines)?;
+                    ensure!(write_byte(lines, addr << 1 | 1)?, I2cError::Nack(addr));
+                    reading = Some(true);
+                }
+                // Acknowledge every byte, except the last of consecutive reads.
+                let len = rbuf.len();
+                for (i, byte) in rbuf.iter_mut().enumerate() {
+                    *byte = read_byte(lines, i + 1 < len || next_is_read)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Event {
+        Start,
+        Stop,
+        Bit(bool),
+    }
+
+    /// Decodes the conditions and bits driven onto the lines, responding to reads of SDA with
+    /// scripted levels.
+    struct FakeLines {
+        scl: Cell<bool>,
+        sda: Cell<bool>,
+        events: RefCell<Vec<Event>>,
+        responses: RefCell<VecDeque<bool>>,
+    }
+
+    impl FakeLines {
+        fn new(responses: &[bool]) -> Self {
+            FakeLines {
+                scl: Cell::new(true),
+                sda: Cell::new(true),
+                events: RefCell::new(Vec::new()),
+                responses: RefCell::new(responses.iter().copied().collect()),
+            }
+        }
+
+        /// Conditions, and bytes transferred in hex, marked "+" if acknowledged.
+        fn decode(&self) -> Vec<String> {
+            let mut result = Vec::new();
+            let mut bits = Vec::new();
+            for event in self.events.borrow().iter() {
+                match event {
+                    // Drop the clock pulse preceding the condition.
+                    Event::Start => {
+                        result.push("S".to_string());
+                        bits.clear();
+                    }
+                    Event::Stop => {
+                        result.push("P".to_string());
+                        bits.clear();
+                    }
+                    Event::Bit(bit) => {
+                        bits.push(*bit);
+                        if bits.len() == 9 {
+                            let byte = bits[..8].iter().fold(0u8, |b, &bit| b << 1 | bit as u8);
+                            result.push(format!("{:02x}{}", byte, if bits[8] { "" } else { "+" }));
+                            bits.clear();
+                        }
+                    }
+                }
+            }
+            result
+        }
+    }
+
+    impl Lines for FakeLines {
+        fn release_scl(&self, release: bool) -> Result<()> {
+            if release && !self.scl.get() {
+                self.events.borrow_mut().push(Event::Bit(self.sda.get()));
+            }
+            self.scl.set(release);
+            Ok(())
+        }
+        fn release_sda(&self, release: bool) -> Result<()> {
+            if self.scl.get() && release != self.sda.get() {
+                self.events
+                    .borrow_mut()
+                    .push(if release { Event::Stop } else { Event::Start });
+            }
+            self.sda.set(release);
+            Ok(())
+        }
+        fn sda(&self) -> Result<bool> {
+            assert!(self.scl.get() && self.sda.get());
+            let level = self.responses.borrow_mut().pop_front().unwrap();
+            // Record the bit as seen on the bus, rather than as released by the controller.
+            if let Some(Event::Bit(bit)) = self.events.borrow_mut().last_mut() {
+                *bit = level;
+            }
+            Ok(level)
+        }
+    }
+
+    fn byte_bits(byte: u8) -> Vec<bool> {
+        (0..8).rev().map(|i| byte & (1 << i) != 0).collect()
+    }
+
+    #[test]
+    fn test_write_then_read() -> Result<()> {
+        // Acknowledge address and register, then acknowledge the address of the read, followed
+        // by two bytes of data.
+        let mut responses = vec![false, false, false];
+        responses.extend(byte_bits(0x12));
+        responses.extend(byte_bits(0x34));
+        let lines = FakeLines::new(&responses);
+        let mut data = [0u8; 2];
+        run_transaction(
+            &lines,
+            0x48,
+            &mut [Transfer::Write(&[0x05]), Transfer::Read(&mut data)],
+        )?;
+        assert_eq!(data, [0x12, 0x34]);
+        // Acknowledged bytes are marked with "+", the last byte read is not acknowledged.
+        assert_eq!(
+            lines.decode(),
+            vec!["S", "90+", "05+", "S", "91+", "12+", "34", "P"]
+        );
+        assert!(lines.responses.borrow().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nack() -> Result<()> {
+        let lines = FakeLines::new(&[true]);
+        let err = run_transaction(&lines, 0x50, &mut [Transfer::Write(&[0x00, 0x01])]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<I2cError>(),
+            Some(I2cError::Nack(0x50))
+        ));
+        // The bus is released after the failed address.
+        assert_eq!(lines.decode(), vec!["S", "a0", "P"]);
+        assert!(lines.scl.get() && lines.sda.get());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_instance() -> Result<()> {
+        assert_eq!(
+            CW310I2cBus::parse_instance("USB_A10:USB_A11")?,
+            (
+                Backend::canonical_pin_name("USB_A10")?,
+                Backend::canonical_pin_name("USB_A11")?
+            )
+        );
+        for instance in [
+            "0",
+            "USB_A10",
+            "USB_A10:",
+            "USB_A10:USB_A10",
+            "USB_A10:NOPE",
+        ] {
+            assert!(
+                CW310I2cBus::parse_instance(instance).is_err(),
+                "{}",
+                instance
+            );
+        }
+        Ok(())
+    }
+}
@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::{ensure, Result};
+use serde::Serialize;
 use serde_annotate::Annotate;
 use serialport::SerialPortType;
 use std::any::Any;
@@ -10,31 +11,106 @@ use std::cell::RefCell;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::rc::Rc;
+use std::time::Duration;
 
-use crate::io::gpio::GpioPin;
+use crate::bootstrap::{run_with_reset, send_frames, BootstrapReset};
+use crate::io::gpio::{GpioMonitoring, GpioPin, PinMode, PinSnapshot};
+use crate::io::i2c::Bus;
 use crate::io::spi::Target;
 use crate::io::uart::{Uart, UartError};
-use crate::transport::common::fpga::{ClearBitstream, FpgaProgram};
+use crate::transport::common::fpga::{ClearBitstream, FpgaProgram, FpgaProgramMethod};
 use crate::transport::common::uart::SerialPortUart;
 use crate::transport::{
     Capabilities, Capability, Transport, TransportError, TransportInterfaceType,
 };
 use crate::util::parse_int::ParseInt;
+use crate::util::rom_detect::RomKind;
+use crate::util::usb::UsbStrings;
 
 pub mod gpio;
+pub mod i2c;
 pub mod spi;
 pub mod usb;
 
+/// Outcome of `CW310::probe()`, telling why a board could not be used before attempting to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum ProbeResult {
+    /// A single matching board, ready to be opened by `CW310::new()`.
+    Found {
+        serial_number: String,
+        firmware_version: String,
+    },
+    /// No matching board.
+    NotFound,
+    /// Several matching boards, given by serial number, one of which must be chosen.
+    Multiple(Vec<String>),
+    /// No board could be opened, but some were present, which typically means that permissions
+    /// are lacking.  As probing claims no interface, a board in use by another process is not
+    /// reported as such, but found.  Reasons are given per board.
+    Busy(Vec<String>),
+    /// The board does not respond to requests of the SAM3X firmware of the CW310.
+    WrongFirmware {
+        serial_number: String,
+        error: String,
+    },
+}
+
+/// Optional settings of `CW310::new()`, the defaults of which suit a board used on its own.
+#[derive(Clone, Debug, Default)]
+pub struct Cw310Options<'a> {
+    /// USB serial number of the UART ports, if different from that of the SAM3X.
+    pub console_serial: Option<&'a str>,
+    /// Cache GPIO levels read from the board for up to this duration.
+    pub gpio_cache_ttl: Option<Duration>,
+    /// Record all operations on GPIO pins, see `GetGpioLog`.
+    pub gpio_log: bool,
+    /// GPIO pins which are inverted by board circuitry.
+    pub inverted_pins: &'a [&'a str],
+    /// Named SPI instances reached through an on-board mux, as "NAME=PIN:LEVEL".
+    pub spi_mux: &'a [&'a str],
+}
+
+/// A board opened while probing.
+trait ProbedBoard {
+    fn serial_number(&self) -> &str;
+    fn firmware_version(&self) -> Result<usb::FirmwareVersion>;
+}
+
+impl ProbedBoard for usb::Backend {
+    fn serial_number(&self) -> &str {
+        self.get_serial_number()
+    }
+    fn firmware_version(&self) -> Result<usb::FirmwareVersion> {
+        self.get_firmware_version()
+    }
+}
+
 #[derive(Default)]
 struct Inner {
     spi: Option<Rc<dyn Target>>,
+    /// SPI instance most recently returned.
+    spi_instance: Option<String>,
+    i2c: HashMap<String, Rc<dyn Bus>>,
     gpio: HashMap<String, Rc<dyn GpioPin>>,
+    gpio_monitoring: Option<Rc<dyn GpioMonitoring>>,
     uart: HashMap<u32, Rc<dyn Uart>>,
+    /// ROM kind and USR_ACCESS value of the bitstream most recently detected or programmed, see
+    /// `FpgaProgram::check_correct_version_cached()`.
+    running_bitstream: Option<(RomKind, u32)>,
 }
 
 pub struct CW310 {
     pub(crate) device: Rc<RefCell<usb::Backend>>,
+    gpio_cache: Option<Rc<RefCell<gpio::PinCache>>>,
+    gpio_log: Option<Rc<RefCell<gpio::GpioLog>>>,
+    gpio_modes: Rc<RefCell<gpio::PinModes>>,
+    gpio_inversion: gpio::PinInversion,
+    /// Named SPI instances other than "0", selected through an on-board mux.
+    spi_mux: HashMap<String, spi::SpiMuxSetting>,
     uart_override: Vec<String>,
+    /// USB serial number of the UART ports, if different from that of the SAM3X used for
+    /// programming, e.g. when the FPGA design provides its own USB-serial bridge.
+    console_serial: Option<String>,
     inner: RefCell<Inner>,
 }
 
@@ -44,6 +120,8 @@ impl CW310 {
     const PIN_SDI: &'static str = "USB_SPI_COPI";
     const PIN_SDO: &'static str = "USB_SPI_CIPO";
     const PIN_CS: &'static str = "USB_SPI_CS";
+    // Xilinx part name of the FPGA on the CW310 board, as found in bitstream headers.
+    const FPGA_PART: &'static str = "7k410tfbg676";
     // Pins needed for reset & bootstrap on the CW310 board.
     const PIN_TRST: &'static str = "USB_A13";
     const PIN_SRST: &'static str = "USB_A14";
@@ -52,18 +130,88 @@ impl CW310 {
     const PIN_SW_STRAP2: &'static str = "USB_A17";
     const PIN_TAP_STRAP0: &'static str = "USB_A18";
     const PIN_TAP_STRAP1: &'static str = "USB_A19";
+    // The user LEDs (USRLED0-7) and DIP switches (USRDIP0-7) of the CW310 board are wired only
+    // to the FPGA, see `pins_cw310.xdc`, and so cannot be accessed from the host.
+    // Likewise, the USB interface of the board is the SAM3X rather than an FTDI bridge, so there
+    // is no latency timer holding back received data, and nothing to configure.
+
+    /// Checks for a board matching VID/PID and serial number, as `new()` would open, and
+    /// whether it responds to the SAM3X firmware, without claiming any interface or changing
+    /// any pins, such that tools can explain what is wrong before calling `new()`.
+    pub fn probe(
+        usb_vid: Option<u16>,
+        usb_pid: Option<u16>,
+        usb_serial: Option<&str>,
+    ) -> Result<ProbeResult> {
+        let boards = usb::Backend::probe(usb_vid, usb_pid)?
+            .into_iter()
+            .map(|board| board.map(|b| Box::new(b) as Box<dyn ProbedBoard>))
+            .collect();
+        Ok(Self::classify_probed(boards, usb_serial))
+    }
+
+    fn classify_probed(
+        boards: Vec<Result<Box<dyn ProbedBoard>>>,
+        usb_serial: Option<&str>,
+    ) -> ProbeResult {
+        let mut errors = Vec::new();
+        let mut matching = Vec::new();
+        for board in boards {
+            match board {
+                Ok(board) if usb_serial.is_none() || usb_serial == Some(board.serial_number()) => {
+                    matching.push(board)
+                }
+                Ok(_) => (),
+                Err(e) => errors.push(format!("{:#}", e)),
+            }
+        }
+        match matching.len() {
+            0 if errors.is_empty() => ProbeResult::NotFound,
+            0 => ProbeResult::Busy(errors),
+            1 => {
+                let serial_number = matching[0].serial_number().to_string();
+                match matching[0].firmware_version() {
+                    Ok(version) => ProbeResult::Found {
+                        serial_number,
+                        firmware_version: version.to_string(),
+                    },
+                    Err(e) => ProbeResult::WrongFirmware {
+                        serial_number,
+                        error: format!("{:#}", e),
+                    },
+                }
+            }
+            _ => ProbeResult::Multiple(
+                matching
+                    .iter()
+                    .map(|board| board.serial_number().to_string())
+                    .collect(),
+            ),
+        }
+    }
 
     pub fn new(
         usb_vid: Option<u16>,
         usb_pid: Option<u16>,
         usb_serial: Option<&str>,
         uart_override: &[&str],
+        options: &Cw310Options,
     ) -> anyhow::Result<Self> {
         let board = CW310 {
             device: Rc::new(RefCell::new(usb::Backend::new(
                 usb_vid, usb_pid, usb_serial,
             )?)),
+            gpio_cache: options
+                .gpio_cache_ttl
+                .map(|ttl| Rc::new(RefCell::new(gpio::PinCache::new(ttl)))),
+            gpio_log: options
+                .gpio_log
+                .then(|| Rc::new(RefCell::new(gpio::GpioLog::new()))),
+            gpio_modes: Default::default(),
+            gpio_inversion: gpio::PinInversion::new(options.inverted_pins),
+            spi_mux: spi::SpiMuxSetting::parse_list(options.spi_mux)?,
             uart_override: uart_override.iter().map(|s| s.to_string()).collect(),
+            console_serial: options.console_serial.map(str::to_string),
             inner: RefCell::default(),
         };
         board.init_pin_directions()?;
@@ -74,13 +222,19 @@ impl CW310 {
     // Initialize the IO direction of some basic pins on the board.
     fn init_pin_directions(&self) -> anyhow::Result<()> {
         let device = self.device.borrow();
-        device.pin_set_output(Self::PIN_TRST, true)?;
-        device.pin_set_output(Self::PIN_SRST, true)?;
-        device.pin_set_output(Self::PIN_TAP_STRAP0, true)?;
-        device.pin_set_output(Self::PIN_TAP_STRAP1, true)?;
-        device.pin_set_output(Self::PIN_SW_STRAP0, true)?;
-        device.pin_set_output(Self::PIN_SW_STRAP1, true)?;
-        device.pin_set_output(Self::PIN_SW_STRAP2, true)?;
+        let mut modes = self.gpio_modes.borrow_mut();
+        for pin in [
+            Self::PIN_TRST,
+            Self::PIN_SRST,
+            Self::PIN_TAP_STRAP0,
+            Self::PIN_TAP_STRAP1,
+            Self::PIN_SW_STRAP0,
+            Self::PIN_SW_STRAP1,
+            Self::PIN_SW_STRAP2,
+        ] {
+            device.pin_set_output(pin, true)?;
+            modes.set(pin, PinMode::PushPull)?;
+        }
         Ok(())
     }
 
@@ -97,47 +251,220 @@ impl CW310 {
         Ok(())
     }
 
-    fn open_uart(&self, instance: u32) -> Result<SerialPortUart> {
-        if self.uart_override.is_empty() {
-            let usb = self.device.borrow();
-            let serial_number = usb.get_serial_number();
-
-            let mut ports = serialport::available_ports()
-                .map_err(|e| UartError::EnumerationError(e.to_string()))?;
-            ports.retain(|port| {
-                if let SerialPortType::UsbPort(info) = &port.port_type {
-                    if info.serial_number.as_deref() == Some(serial_number) {
-                        return true;
-                    }
-                }
-                false
-            });
-            // The CW board seems to have the last port connected as OpenTitan UART 0.
-            // Reverse the sort order so the last port will be instance 0.
-            ports.sort_by(|a, b| b.port_name.cmp(&a.port_name));
-
-            let port = ports.get(instance as usize).ok_or_else(|| {
-                TransportError::InvalidInstance(TransportInterfaceType::Uart, instance.to_string())
-            })?;
-            SerialPortUart::open(&port.port_name)
-        } else {
-            let instance = instance as usize;
+    /// Returns the modes of all of `pins` in one go, in the same order.  As the SAM3X firmware
+    /// cannot report pin directions, these are the modes most recently set through this
+    /// transport, which involves no USB transfers.
+    pub fn get_modes(&self, pins: &[&dyn GpioPin]) -> Result<Vec<PinMode>> {
+        self.gpio_modes.borrow().get_many(pins)
+    }
+
+    /// Number of times a bootstrap frame is sent before giving up.
+    const BOOTSTRAP_ATTEMPTS: usize = 3;
+    const BOOTSTRAP_RESET_DELAY: Duration = Duration::from_millis(100);
+    const BOOTSTRAP_INTER_FRAME_DELAY: Duration = Duration::from_millis(50);
+    const BOOTSTRAP_FLASH_ERASE_DELAY: Duration = Duration::from_millis(200);
+
+    /// Returns the pin from `pins`, keyed by the SAM3X name of the pin, such that all aliases of
+    /// a physical pin share a single object.  If not present, the pin is created by calling
+    /// `open` with the SAM3X name.
+    fn cached_pin(
+        pins: &mut HashMap<String, Rc<dyn GpioPin>>,
+        pinname: &str,
+        open: impl FnOnce(String) -> Result<Rc<dyn GpioPin>>,
+    ) -> Result<Rc<dyn GpioPin>> {
+        let name = usb::Backend::canonical_pin_name(pinname)?;
+        Ok(match pins.entry(name) {
+            Entry::Vacant(v) => {
+                let pin = open(v.key().clone())?;
+                Rc::clone(v.insert(pin))
+            }
+            Entry::Occupied(o) => Rc::clone(o.get()),
+        })
+    }
+
+    /// Fails unless `sent` is the full number of bytes to be sent for a bitstream of
+    /// `bitstream_len` bytes, catching truncated USB transfers.
+    pub(crate) fn check_bitstream_sent(bitstream_len: usize, sent: usize) -> Result<()> {
+        let expected = usb::Backend::fpga_stream_len(bitstream_len);
+        ensure!(
+            sent == expected,
+            TransportError::FpgaProgramFailed(format!(
+                "sent only {} of {} bytes of bitstream",
+                sent, expected
+            ))
+        );
+        Ok(())
+    }
+
+    /// Fails if `pinname` names the same physical pin as `spi_cs`, the CS of SPI `instance`.
+    fn check_cs_conflict(spi_cs: &str, instance: &str, pinname: &str) -> Result<()> {
+        // Unknown pin names are reported when attempting to open the pin.
+        if let Ok(pin) = usb::Backend::pin_name_to_number(pinname) {
             ensure!(
-                instance < self.uart_override.len(),
-                TransportError::InvalidInstance(TransportInterfaceType::Uart, instance.to_string())
+                pin != usb::Backend::pin_name_to_number(spi_cs)?,
+                TransportError::CsPinInUse(pinname.to_string(), instance.to_string())
             );
-            SerialPortUart::open(&self.uart_override[instance])
         }
+        Ok(())
+    }
+
+    /// Returns the software strap pins, all of which are driven high to request bootstrap.
+    fn strap_pins(&self) -> Result<Vec<Rc<dyn GpioPin>>> {
+        [
+            Self::PIN_SW_STRAP0,
+            Self::PIN_SW_STRAP1,
+            Self::PIN_SW_STRAP2,
+        ]
+        .iter()
+        .map(|pin| self.gpio_pin(pin))
+        .collect()
+    }
+
+    /// Drives the straps high if `strap_bootstrap`, then holds `reset` low for `pulse`.  The
+    /// straps are left in place, such that the ROM samples them after the reset.  Otherwise the
+    /// straps are not touched, keeping any strapping applied by the user.
+    fn pulse_reset(
+        reset: &dyn GpioPin,
+        straps: &[Rc<dyn GpioPin>],
+        pulse: Duration,
+        strap_bootstrap: bool,
+    ) -> Result<()> {
+        if strap_bootstrap {
+            for strap in straps {
+                strap.write(true)?;
+            }
+        }
+        reset.write(false)?;
+        std::thread::sleep(pulse);
+        reset.write(true)?;
+        Ok(())
+    }
+
+    /// Loads `firmware` into the flash of the OpenTitan chip using the primitive SPI bootstrap
+    /// protocol of the ROM: the software straps are held high while the chip is reset, then the
+    /// firmware is sent in frames, each acknowledged by the ROM echoing its hash during the
+    /// following SPI transfer.  Fails if a frame is not acknowledged after a few attempts.
+    /// Finally the straps are released and the chip is reset, to run the new firmware.
+    pub fn bootstrap(&self, firmware: &[u8]) -> Result<()> {
+        let spi = self.spi("0")?;
+        let pins = BootstrapPins {
+            straps: self.strap_pins()?,
+            reset: self.gpio_pin(Self::PIN_SRST)?,
+        };
+        run_with_reset(&pins, true, false, || {
+            send_frames(
+                &*spi,
+                firmware,
+                Self::BOOTSTRAP_INTER_FRAME_DELAY,
+                Self::BOOTSTRAP_FLASH_ERASE_DELAY,
+                Some(Self::BOOTSTRAP_ATTEMPTS),
+                &|_, _| {},
+            )
+        })
+    }
+
+    fn open_uart(&self, instance: u32) -> Result<SerialPortUart> {
+        SerialPortUart::open(&self.uart_device_path(instance)?)
+    }
+
+    /// Name of the serial port used for the given UART instance, taken from the `uart_override`
+    /// list if given, or else found among the USB serial ports of the board.
+    pub fn uart_device_path(&self, instance: u32) -> Result<String> {
+        Self::resolve_uart_port(&self.uart_override, instance, || {
+            let usb = self.device.borrow();
+            // Fall back to the serial number of the SAM3X, which also provides the UARTs.
+            let serial_number = self
+                .console_serial
+                .as_deref()
+                .unwrap_or_else(|| usb.get_serial_number());
+
+            let ports = serialport::available_ports()
+                .map_err(|e| UartError::EnumerationError(e.to_string()))?
+                .into_iter()
+                .map(|port| match port.port_type {
+                    SerialPortType::UsbPort(info) => (port.port_name, info.serial_number),
+                    _ => (port.port_name, None),
+                })
+                .collect::<Vec<_>>();
+            Self::select_uart_port(&ports, serial_number, instance)
+        })
+    }
+
+    /// Name of the port of the given UART instance, from `uart_override` unless empty, in which
+    /// case `enumerate` is called to find it.
+    fn resolve_uart_port(
+        uart_override: &[String],
+        instance: u32,
+        enumerate: impl FnOnce() -> Result<String>,
+    ) -> Result<String> {
+        if uart_override.is_empty() {
+            return enumerate();
+        }
+        let port = uart_override.get(instance as usize).ok_or_else(|| {
+            TransportError::InvalidInstance(TransportInterfaceType::Uart, instance.to_string())
+        })?;
+        Ok(port.clone())
+    }
+
+    /// Name of the port of the given UART instance, among `ports` given as pairs of port name
+    /// and USB serial number.
+    fn select_uart_port(
+        ports: &[(String, Option<String>)],
+        serial_number: &str,
+        instance: u32,
+    ) -> Result<String> {
+        let mut ports = ports
+            .iter()
+            .filter(|(_, serial)| serial.as_deref() == Some(serial_number))
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        // The CW board seems to have the last port connected as OpenTitan UART 0.
+        // Reverse the sort order so the last port will be instance 0.
+        ports.sort_by(|a, b| b.cmp(a));
+
+        let port = ports.get(instance as usize).ok_or_else(|| {
+            TransportError::InvalidInstance(TransportInterfaceType::Uart, instance.to_string())
+        })?;
+        Ok(port.to_string())
+    }
+}
+
+/// Software straps and reset pin of the board, driven by `CW310::bootstrap()`.
+struct BootstrapPins {
+    straps: Vec<Rc<dyn GpioPin>>,
+    reset: Rc<dyn GpioPin>,
+}
+
+impl BootstrapReset for BootstrapPins {
+    fn set_bootstrap_strapping(&self, bootstrap: bool) -> Result<()> {
+        for strap in &self.straps {
+            strap.write(bootstrap)?;
+        }
+        Ok(())
+    }
+    /// The console UART is not touched.
+    fn reset(&self, _clear_uart_rx: bool) -> Result<()> {
+        CW310::pulse_reset(&*self.reset, &[], CW310::BOOTSTRAP_RESET_DELAY, false)?;
+        std::thread::sleep(CW310::BOOTSTRAP_RESET_DELAY);
+        Ok(())
     }
 }
 
 impl Transport for CW310 {
     fn capabilities(&self) -> Result<Capabilities> {
         Ok(Capabilities::new(
-            Capability::SPI | Capability::GPIO | Capability::UART,
+            Capability::SPI
+                | Capability::GPIO
+                | Capability::GPIO_MONITORING
+                | Capability::UART
+                | Capability::I2C,
         ))
     }
 
+    fn description(&self) -> Result<UsbStrings> {
+        self.device.borrow().string_descriptors()
+    }
+
     fn uart(&self, instance: &str) -> Result<Rc<dyn Uart>> {
         let mut inner = self.inner.borrow_mut();
         let instance = u32::from_str(instance).ok().ok_or_else(|| {
@@ -155,32 +482,112 @@ impl Transport for CW310 {
 
     fn gpio_pin(&self, pinname: &str) -> Result<Rc<dyn GpioPin>> {
         let mut inner = self.inner.borrow_mut();
-        Ok(match inner.gpio.entry(pinname.to_string()) {
-            Entry::Vacant(v) => {
-                let u = v.insert(Rc::new(gpio::CW310GpioPin::open(
-                    Rc::clone(&self.device),
-                    pinname.to_string(),
-                )?));
-                Rc::clone(u)
-            }
-            Entry::Occupied(o) => Rc::clone(o.get()),
+        if let (Some(spi_cs), Some(instance)) = (
+            inner.spi.as_ref().and_then(|spi| spi.cs_pin_name()),
+            inner.spi_instance.as_deref(),
+        ) {
+            Self::check_cs_conflict(spi_cs, instance, pinname)?;
+        }
+        Self::cached_pin(&mut inner.gpio, pinname, |name| {
+            let inverted = self.gpio_inversion.is_inverted(&name);
+            Ok(Rc::new(gpio::CW310GpioPin::open(
+                Rc::clone(&self.device),
+                self.gpio_cache.clone(),
+                self.gpio_log.clone(),
+                Rc::clone(&self.gpio_modes),
+                name,
+                inverted,
+            )?))
         })
     }
 
+    /// Monitoring sessions are kept by the returned instance, so the same one is returned every
+    /// time.
+    fn gpio_monitoring(&self) -> Result<Rc<dyn GpioMonitoring>> {
+        let mut inner = self.inner.borrow_mut();
+        let monitoring = inner.gpio_monitoring.get_or_insert_with(|| {
+            Rc::new(gpio::CW310GpioMonitoring::new(
+                Rc::clone(&self.device),
+                self.gpio_inversion.clone(),
+            ))
+        });
+        Ok(Rc::clone(monitoring))
+    }
+
+    fn snapshot_pins(&self) -> Result<PinSnapshot> {
+        let pins = self
+            .inner
+            .borrow()
+            .gpio
+            .iter()
+            .map(|(name, pin)| (name.clone(), Rc::clone(pin)))
+            .collect::<Vec<_>>();
+        Ok(PinSnapshot::capture(&pins))
+    }
+
+    /// Instance "0" is the default routing of the on-board mux, with all of its control pins
+    /// released, other named instances drive a control pin to select their device.  All
+    /// instances share the SPI bus of the SAM3X, and each switches the mux to its own route
+    /// before every transaction.
     fn spi(&self, instance: &str) -> Result<Rc<dyn Target>> {
-        ensure!(
-            instance == "0",
-            TransportError::InvalidInstance(TransportInterfaceType::Spi, instance.to_string())
-        );
+        let route = if instance == "0" {
+            let mut pinnames = self
+                .spi_mux
+                .values()
+                .map(|mux| mux.pin.as_str())
+                .collect::<Vec<_>>();
+            pinnames.sort_unstable();
+            pinnames.dedup();
+            spi::SpiMuxRoute::Default(
+                pinnames
+                    .into_iter()
+                    .map(|pinname| self.gpio_pin(pinname))
+                    .collect::<Result<_>>()?,
+            )
+        } else {
+            let mux = self.spi_mux.get(instance).ok_or_else(|| {
+                TransportError::InvalidInstance(TransportInterfaceType::Spi, instance.to_string())
+            })?;
+            spi::SpiMuxRoute::Select {
+                name: instance.to_string(),
+                setting: mux.clone(),
+                pin: self.gpio_pin(&mux.pin)?,
+            }
+        };
+        route.select()?;
         let mut inner = self.inner.borrow_mut();
         if inner.spi.is_none() {
+            // The CS pin must not already be in use as GPIO.
+            for pinname in inner.gpio.keys() {
+                Self::check_cs_conflict(Self::PIN_CS, instance, pinname)?;
+            }
             inner.spi = Some(Rc::new(spi::CW310Spi::open(Rc::clone(&self.device))?));
         }
-        Ok(Rc::clone(inner.spi.as_ref().unwrap()))
+        inner.spi_instance = Some(instance.to_string());
+        Ok(Rc::new(spi::CW310MuxedSpi::new(
+            Rc::clone(inner.spi.as_ref().unwrap()),
+            route,
+        )))
+    }
+
+    /// Opens an I2C bus bit-banged over the two pins named by `instance` as "SCL:SDA".
+    fn i2c(&self, instance: &str) -> Result<Rc<dyn Bus>> {
+        let mut inner = self.inner.borrow_mut();
+        let bus = match inner.i2c.entry(instance.to_string()) {
+            Entry::Vacant(v) => {
+                let bus: Rc<dyn Bus> =
+                    Rc::new(i2c::CW310I2cBus::open(Rc::clone(&self.device), instance)?);
+                Rc::clone(v.insert(bus))
+            }
+            Entry::Occupied(o) => Rc::clone(o.get()),
+        };
+        Ok(bus)
     }
 
     fn dispatch(&self, action: &dyn Any) -> Result<Option<Box<dyn Annotate>>> {
         if let Some(fpga_program) = action.downcast_ref::<FpgaProgram>() {
+            // The SAM3X firmware only implements serial configuration of the FPGA.
+            fpga_program.check_method(&[FpgaProgramMethod::Spi])?;
             // Open the console UART.  We do this first so we get the receiver
             // started and the uart buffering data for us.
             let uart = self.uart("0")?;
@@ -189,17 +596,34 @@ impl Transport for CW310 {
                 log::info!("Skip loading the __skip__ bitstream.");
                 return Ok(None);
             }
-            if fpga_program.check_correct_version(&*uart, &*reset_pin)? {
+            let mut running = self.inner.borrow().running_bitstream;
+            let correct =
+                fpga_program.check_correct_version_cached(&*uart, &*reset_pin, &mut running);
+            self.inner.borrow_mut().running_bitstream = running;
+            if correct? {
                 return Ok(None);
             }
+            let bitstream = fpga_program.load_bitstream()?;
+            fpga_program.check_part(&bitstream, Self::FPGA_PART)?;
 
             // Program the FPGA bitstream.
             log::info!("Programming the FPGA bitstream.");
             let usb = self.device.borrow();
-            usb.spi1_enable(false)?;
-            usb.fpga_program(
-                &fpga_program.bitstream,
-                fpga_program.progress.as_ref().map(Box::as_ref),
+            fpga_program.program_with_retries(|| {
+                usb.spi1_enable(false)?;
+                let sent =
+                    usb.fpga_program(&bitstream, fpga_program.progress.as_ref().map(Box::as_ref))?;
+                Self::check_bitstream_sent(bitstream.len(), sent)
+            })?;
+            self.inner.borrow_mut().running_bitstream = fpga_program.rom_identity();
+            Ok(None)
+        } else if let Some(reset_target) = action.downcast_ref::<ResetTarget>() {
+            let reset = self.gpio_pin(Self::PIN_SRST)?;
+            Self::pulse_reset(
+                &*reset,
+                &self.strap_pins()?,
+                reset_target.pulse,
+                reset_target.strap_bootstrap,
             )?;
             Ok(None)
         } else if action.downcast_ref::<ResetSam3x>().is_some() {
@@ -216,7 +640,16 @@ impl Transport for CW310 {
             usb.pll_out_enable(2, false)?;
             usb.pll_write_defaults()?;
             Ok(None)
+        } else if action.downcast_ref::<GetGpioLog>().is_some() {
+            let log = self
+                .gpio_log
+                .as_ref()
+                .ok_or(TransportError::UnsupportedOperation)?;
+            Ok(Some(Box::new(GpioLogResponse {
+                entries: log.borrow().entries().to_vec(),
+            })))
         } else if action.downcast_ref::<ClearBitstream>().is_some() {
+            self.inner.borrow_mut().running_bitstream = None;
             let usb = self.device.borrow();
             usb.spi1_enable(false)?;
             usb.clear_bitstream()?;
@@ -232,3 +665,297 @@ pub struct SetPll {}
 
 /// Command for Transport::dispatch(). Resets the CW310's SAM3X chip.
 pub struct ResetSam3x {}
+
+/// Command for Transport::dispatch(). Resets the OpenTitan chip by holding SRST low for `pulse`,
+/// without touching the FPGA bitstream.  If `strap_bootstrap`, the software straps are driven
+/// high during and after the reset, otherwise they are left as they are.
+pub struct ResetTarget {
+    pub pulse: Duration,
+    pub strap_bootstrap: bool,
+}
+
+/// Command for Transport::dispatch(). Retrieves all GPIO operations recorded since the
+/// transport was created, requires GPIO logging to have been enabled.
+pub struct GetGpioLog {}
+
+#[derive(Annotate, Serialize)]
+pub struct GpioLogResponse {
+    pub entries: Vec<gpio::GpioLogEntry>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::gpio::{PinMode, PullMode};
+
+    /// Records every level written, along with the pin name, into a log shared among pins.
+    struct RecordingPin {
+        name: &'static str,
+        log: Rc<RefCell<Vec<(&'static str, bool)>>>,
+    }
+
+    impl GpioPin for RecordingPin {
+        fn read(&self) -> Result<bool> {
+            unimplemented!();
+        }
+        fn write(&self, value: bool) -> Result<()> {
+            self.log.borrow_mut().push((self.name, value));
+            Ok(())
+        }
+        fn set_mode(&self, _mode: PinMode) -> Result<()> {
+            Ok(())
+        }
+        fn set_pull_mode(&self, _mode: PullMode) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn reset_sequence(strap_bootstrap: bool) -> Result<Vec<(&'static str, bool)>> {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let pin = |name| -> Rc<dyn GpioPin> {
+            Rc::new(RecordingPin {
+                name,
+                log: Rc::clone(&log),
+            })
+        };
+        let straps = [pin("STRAP0"), pin("STRAP1"), pin("STRAP2")];
+        CW310::pulse_reset(
+            &*pin("SRST"),
+            &straps,
+            Duration::from_millis(1),
+            strap_bootstrap,
+        )?;
+        let sequence = log.borrow().clone();
+        Ok(sequence)
+    }
+
+    #[test]
+    fn test_bitstream_truncated() -> Result<()> {
+        let bitstream = vec![0u8; 0x7C + 5000];
+        let stream_len = usb::Backend::fpga_stream_len(bitstream.len());
+        assert_eq!(stream_len, 5032);
+        let stream = vec![0u8; stream_len];
+
+        let mut chunks = Vec::new();
+        let sent = usb::send_chunks(&stream, None, &mut |chunk| {
+            chunks.push(chunk.len());
+            Ok(chunk.len())
+        })?;
+        assert_eq!(chunks, vec![2048, 2048, 936]);
+        CW310::check_bitstream_sent(bitstream.len(), sent)?;
+
+        // The second chunk is cut short, the remainder is not sent.
+        let mut chunks = 0;
+        let sent = usb::send_chunks(&stream, None, &mut |chunk| {
+            chunks += 1;
+            Ok(if chunks == 2 { 1000 } else { chunk.len() })
+        })?;
+        assert_eq!(sent, 3048);
+        let err = CW310::check_bitstream_sent(bitstream.len(), sent).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransportError>(),
+            Some(TransportError::FpgaProgramFailed(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_pin_aliases() -> Result<()> {
+        let mut pins = HashMap::new();
+        let mut opened = Vec::new();
+        let mut open = |name: &str| {
+            CW310::cached_pin(&mut pins, name, |name| {
+                opened.push(name);
+                Ok(Rc::new(RecordingPin {
+                    name: "",
+                    log: Rc::default(),
+                }))
+            })
+        };
+        let by_schematic_name = open("USB_SPI_CS")?;
+        let by_sam3x_name = open("pa28")?;
+        let other = open(CW310::PIN_SRST)?;
+        assert!(open("NO_SUCH_PIN").is_err());
+        assert!(Rc::ptr_eq(&by_schematic_name, &by_sam3x_name));
+        assert!(!Rc::ptr_eq(&by_schematic_name, &other));
+        assert_eq!(opened.len(), 2);
+        assert_eq!(opened[0], "PA28");
+        Ok(())
+    }
+
+    #[test]
+    fn test_cs_conflict() -> Result<()> {
+        CW310::check_cs_conflict(CW310::PIN_CS, "0", CW310::PIN_SRST)?;
+        // Either a GPIO opened after SPI, by any alias, or the cached name of a GPIO opened
+        // before SPI.
+        for pinname in ["USB_SPI_CS", "pa28", "PA28"] {
+            let err = CW310::check_cs_conflict(CW310::PIN_CS, "EC", pinname).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<TransportError>(),
+                Some(TransportError::CsPinInUse(pin, instance)) if pin == pinname && instance == "EC"
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_target() -> Result<()> {
+        assert_eq!(
+            reset_sequence(false)?,
+            vec![("SRST", false), ("SRST", true)]
+        );
+        // Straps remain asserted after the reset is released.
+        assert_eq!(
+            reset_sequence(true)?,
+            vec![
+                ("STRAP0", true),
+                ("STRAP1", true),
+                ("STRAP2", true),
+                ("SRST", false),
+                ("SRST", true),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_uart_port() -> Result<()> {
+        let ports = vec![
+            ("/dev/ttyACM0".to_string(), Some("SAM3X".to_string())),
+            ("/dev/ttyACM1".to_string(), Some("SAM3X".to_string())),
+            ("/dev/ttyUSB0".to_string(), Some("FPGA".to_string())),
+            ("/dev/ttyUSB1".to_string(), Some("FPGA".to_string())),
+            ("/dev/ttyS0".to_string(), None),
+        ];
+        // Without a console serial, the UARTs of the programming interface are used.
+        assert_eq!(CW310::select_uart_port(&ports, "SAM3X", 0)?, "/dev/ttyACM1");
+        // A console serial selects the ports of the FPGA design instead.
+        assert_eq!(CW310::select_uart_port(&ports, "FPGA", 0)?, "/dev/ttyUSB1");
+        assert_eq!(CW310::select_uart_port(&ports, "FPGA", 1)?, "/dev/ttyUSB0");
+        assert!(CW310::select_uart_port(&ports, "FPGA", 2).is_err());
+        assert!(CW310::select_uart_port(&ports, "other", 0).is_err());
+        Ok(())
+    }
+
+    struct FakeBoard {
+        serial_number: &'static str,
+        firmware_version: Option<usb::FirmwareVersion>,
+    }
+
+    impl ProbedBoard for FakeBoard {
+        fn serial_number(&self) -> &str {
+            self.serial_number
+        }
+        fn firmware_version(&self) -> Result<usb::FirmwareVersion> {
+            self.firmware_version
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("USB error: Pipe error"))
+        }
+    }
+
+    fn board(
+        serial_number: &'static str,
+        firmware_version: Option<usb::FirmwareVersion>,
+    ) -> Result<Box<dyn ProbedBoard>> {
+        Ok(Box::new(FakeBoard {
+            serial_number,
+            firmware_version,
+        }))
+    }
+
+    #[test]
+    fn test_classify_probed() {
+        let version = Some(usb::FirmwareVersion(1, 5, 0));
+        assert_eq!(
+            CW310::classify_probed(vec![board("A", version.clone())], None),
+            ProbeResult::Found {
+                serial_number: "A".to_string(),
+                firmware_version: "1.5.0".to_string(),
+            }
+        );
+        assert_eq!(CW310::classify_probed(vec![], None), ProbeResult::NotFound);
+        // Only other boards.
+        assert_eq!(
+            CW310::classify_probed(vec![board("A", version.clone())], Some("B")),
+            ProbeResult::NotFound
+        );
+        assert_eq!(
+            CW310::classify_probed(
+                vec![Err(anyhow::anyhow!(
+                    "USB open error: Access denied (insufficient permissions)"
+                ))],
+                Some("A")
+            ),
+            ProbeResult::Busy(vec![
+                "USB open error: Access denied (insufficient permissions)".to_string()
+            ])
+        );
+        // A board which could be opened takes precedence over one which could not.
+        assert_eq!(
+            CW310::classify_probed(
+                vec![
+                    Err(anyhow::anyhow!("USB open error: Access denied")),
+                    board("A", version.clone()),
+                ],
+                None
+            ),
+            ProbeResult::Found {
+                serial_number: "A".to_string(),
+                firmware_version: "1.5.0".to_string(),
+            }
+        );
+        assert_eq!(
+            CW310::classify_probed(vec![board("A", None)], None),
+            ProbeResult::WrongFirmware {
+                serial_number: "A".to_string(),
+                error: "USB error: Pipe error".to_string(),
+            }
+        );
+        assert_eq!(
+            CW310::classify_probed(
+                vec![board("A", version.clone()), board("B", version.clone())],
+                None
+            ),
+            ProbeResult::Multiple(vec!["A".to_string(), "B".to_string()])
+        );
+        assert_eq!(
+            CW310::classify_probed(vec![board("A", None), board("B", version)], Some("B")),
+            ProbeResult::Found {
+                serial_number: "B".to_string(),
+                firmware_version: "1.5.0".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_resolve_uart_port() -> Result<()> {
+        let ports = vec![
+            ("/dev/ttyACM0".to_string(), Some("SAM3X".to_string())),
+            ("/dev/ttyACM1".to_string(), Some("SAM3X".to_string())),
+        ];
+        let ports = &ports;
+        let enumerate = |instance| move || CW310::select_uart_port(ports, "SAM3X", instance);
+        // Heuristic, when no override is given.
+        assert_eq!(
+            CW310::resolve_uart_port(&[], 0, enumerate(0))?,
+            "/dev/ttyACM1"
+        );
+        assert_eq!(
+            CW310::resolve_uart_port(&[], 1, enumerate(1))?,
+            "/dev/ttyACM0"
+        );
+        // The override list is used as given, without enumerating ports.
+        let uart_override = vec!["/dev/ttyUSB3".to_string(), "/dev/ttyUSB2".to_string()];
+        let unused = || -> Result<String> { panic!("ports enumerated") };
+        assert_eq!(
+            CW310::resolve_uart_port(&uart_override, 0, unused)?,
+            "/dev/ttyUSB3"
+        );
+        assert_eq!(
+            CW310::resolve_uart_port(&uart_override, 1, unused)?,
+            "/dev/ttyUSB2"
+        );
+        assert!(CW310::resolve_uart_port(&uart_override, 2, unused).is_err());
+        Ok(())
+    }
+}
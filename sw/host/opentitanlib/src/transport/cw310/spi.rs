@@ -2,20 +2,152 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
-use crate::io::spi::{AssertChipSelect, MaxSizes, SpiError, Target, Transfer, TransferMode};
+use crate::io::gpio::{GpioPin, PinMode};
+use crate::io::spi::{
+    fill_chunks, AssertChipSelect, MaxSizes, SpiError, Target, Transfer, TransferMode,
+};
 use crate::transport::cw310::usb::Backend;
 use crate::transport::cw310::CW310;
-use crate::transport::TransportError;
+use crate::transport::{TransportError, TransportInterfaceType};
+
+/// Named SPI instance, reaching one of several devices on the board through a mux which routes
+/// the SPI bus of the SAM3X according to the level of a control pin.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpiMuxSetting {
+    pub pin: String,
+    pub level: bool,
+}
+
+impl SpiMuxSetting {
+    /// Parses a list of named instances, each given as "NAME=PIN:LEVEL", e.g. "EC=USB_A10:1".
+    /// The name "0" is reserved for the default routing, with all control pins released.
+    pub fn parse_list(specs: &[&str]) -> Result<HashMap<String, Self>> {
+        let mut result = HashMap::new();
+        for spec in specs {
+            let invalid =
+                || TransportError::InvalidInstance(TransportInterfaceType::Spi, spec.to_string());
+            let (name, setting) = spec.split_once('=').ok_or_else(invalid)?;
+            let (pin, level) = setting.split_once(':').ok_or_else(invalid)?;
+            let level = match level {
+                "0" => false,
+                "1" => true,
+                _ => bail!(invalid()),
+            };
+            ensure!(
+                !name.is_empty() && name != "0" && !pin.is_empty(),
+                invalid()
+            );
+            let setting = Self {
+                pin: pin.to_string(),
+                level,
+            };
+            ensure!(
+                result.insert(name.to_string(), setting).is_none(),
+                invalid()
+            );
+        }
+        Ok(result)
+    }
+
+    /// Drives the control pin of the mux to select the instance `name`, and reads the level back,
+    /// failing if it was overridden, e.g. by a jumper setting.
+    pub fn select(&self, name: &str, pin: &dyn GpioPin) -> Result<()> {
+        pin.set(Some(PinMode::PushPull), Some(self.level), None, None)?;
+        ensure!(
+            pin.read()? == self.level,
+            SpiError::MuxNotSelected(self.pin.clone(), name.to_string())
+        );
+        Ok(())
+    }
+}
+
+/// Routing of the on-board SPI mux for one instance.
+pub enum SpiMuxRoute {
+    /// Instance "0", with all mux control pins released, such that the board selects its
+    /// default routing.
+    Default(Vec<Rc<dyn GpioPin>>),
+    /// Named instance, selected by driving a control pin.
+    Select {
+        name: String,
+        setting: SpiMuxSetting,
+        pin: Rc<dyn GpioPin>,
+    },
+}
+
+impl SpiMuxRoute {
+    /// Switches the mux to this route.
+    pub fn select(&self) -> Result<()> {
+        match self {
+            Self::Default(pins) => pins.iter().try_for_each(|pin| pin.set_mode(PinMode::Input)),
+            Self::Select { name, setting, pin } => setting.select(name, &**pin),
+        }
+    }
+}
+
+/// SPI instance sharing the bus of the SAM3X with the other instances, which switches the mux to
+/// its own route before every transaction, such that each handle keeps reaching its device
+/// regardless of other instances used in between.
+pub struct CW310MuxedSpi {
+    spi: Rc<dyn Target>,
+    route: SpiMuxRoute,
+}
+
+impl CW310MuxedSpi {
+    pub fn new(spi: Rc<dyn Target>, route: SpiMuxRoute) -> Self {
+        Self { spi, route }
+    }
+}
+
+impl Target for CW310MuxedSpi {
+    fn get_transfer_mode(&self) -> Result<TransferMode> {
+        self.spi.get_transfer_mode()
+    }
+    fn set_transfer_mode(&self, mode: TransferMode) -> Result<()> {
+        self.spi.set_transfer_mode(mode)
+    }
+    fn get_bits_per_word(&self) -> Result<u32> {
+        self.spi.get_bits_per_word()
+    }
+    fn set_bits_per_word(&self, bits_per_word: u32) -> Result<()> {
+        self.spi.set_bits_per_word(bits_per_word)
+    }
+    fn get_max_speed(&self) -> Result<u32> {
+        self.spi.get_max_speed()
+    }
+    fn set_max_speed(&self, frequency: u32) -> Result<()> {
+        self.spi.set_max_speed(frequency)
+    }
+    fn get_max_transfer_count(&self) -> Result<usize> {
+        self.spi.get_max_transfer_count()
+    }
+    fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+        self.spi.get_max_transfer_sizes()
+    }
+    fn cs_pin_name(&self) -> Option<&str> {
+        self.spi.cs_pin_name()
+    }
+    fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+        self.route.select()?;
+        self.spi.run_transaction(transaction)
+    }
+    fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+        self.route.select()?;
+        Rc::clone(&self.spi).assert_cs()
+    }
+}
 
 pub struct CW310Spi {
     device: Rc<RefCell<Backend>>,
 }
 
 impl CW310Spi {
+    const MAX_TRANSFER_SIZE: usize = 65536;
+
     pub fn open(device: Rc<RefCell<Backend>>) -> Result<Self> {
         {
             let usb = device.borrow();
@@ -44,6 +176,14 @@ impl CW310Spi {
                 Transfer::Read(buf) => usb.spi1_read(buf)?,
                 Transfer::Write(buf) => usb.spi1_write(buf)?,
                 Transfer::Both(wbuf, rbuf) => usb.spi1_both(wbuf, rbuf)?,
+                Transfer::Fill { value, count } => {
+                    fill_chunks(*value, *count, Self::MAX_TRANSFER_SIZE, |chunk| {
+                        usb.spi1_write(chunk)
+                    })?
+                }
+                Transfer::SetSpeed(_) => unreachable!(),
+                // Separate COPI and CIPO lines, no turnaround needed.
+                Transfer::Turnaround => (),
             }
         }
         Ok(())
@@ -91,12 +231,22 @@ impl Target for CW310Spi {
 
     fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
         Ok(MaxSizes {
-            read: 65536,
-            write: 65536,
+            read: Self::MAX_TRANSFER_SIZE,
+            write: Self::MAX_TRANSFER_SIZE,
         })
     }
 
+    fn cs_pin_name(&self) -> Option<&str> {
+        Some(CW310::PIN_CS)
+    }
+
     fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+        ensure!(
+            !transaction
+                .iter()
+                .any(|t| matches!(t, Transfer::SetSpeed(_))),
+            TransportError::UnsupportedOperation
+        );
         // Assert CS# (drive low).
         self.device.borrow().spi1_set_cs_pin(false)?;
         // Translate SPI Read/Write Transactions into CW310 spi operations.
@@ -110,3 +260,158 @@ impl Target for CW310Spi {
         Err(TransportError::UnsupportedOperation.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::gpio::PullMode;
+    use std::cell::Cell;
+
+    /// Pin whose level can be held by other circuitry, regardless of what it drives.
+    #[derive(Default)]
+    struct MuxPin {
+        mode: Cell<Option<PinMode>>,
+        level: Cell<bool>,
+        stuck: Option<bool>,
+    }
+
+    impl GpioPin for MuxPin {
+        fn read(&self) -> Result<bool> {
+            Ok(self.stuck.unwrap_or(self.level.get()))
+        }
+        fn write(&self, value: bool) -> Result<()> {
+            self.level.set(value);
+            Ok(())
+        }
+        fn set_mode(&self, mode: PinMode) -> Result<()> {
+            self.mode.set(Some(mode));
+            Ok(())
+        }
+        fn set_pull_mode(&self, _mode: PullMode) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_spi_mux() -> Result<()> {
+        let mux = SpiMuxSetting::parse_list(&["EC=USB_A10:1", "AP=USB_A10:0", "ROM=USB_A11:1"])?;
+        assert_eq!(mux.len(), 3);
+        for (name, level) in [("EC", true), ("AP", false), ("ROM", true), ("AP", false)] {
+            let pin = MuxPin::default();
+            pin.level.set(!level);
+            mux[name].select(name, &pin)?;
+            assert_eq!(pin.mode.get(), Some(PinMode::PushPull));
+            assert_eq!(pin.level.get(), level, "{}", name);
+        }
+        assert_eq!(mux["ROM"].pin, "USB_A11");
+
+        // A jumper holds the control pin low.
+        let pin = MuxPin {
+            stuck: Some(false),
+            ..Default::default()
+        };
+        let err = mux["EC"].select("EC", &pin).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SpiError>(),
+            Some(SpiError::MuxNotSelected(pin, name)) if pin == "USB_A10" && name == "EC"
+        ));
+
+        for spec in [
+            "EC",
+            "EC=USB_A10",
+            "EC=USB_A10:2",
+            "=USB_A10:1",
+            "0=USB_A10:1",
+            "EC=:1",
+        ] {
+            assert!(SpiMuxSetting::parse_list(&[spec]).is_err(), "{}", spec);
+        }
+        assert!(SpiMuxSetting::parse_list(&["EC=USB_A10:1", "EC=USB_A11:1"]).is_err());
+        Ok(())
+    }
+
+    /// Bus recording the mode and level of the mux control pin during each transaction.
+    struct MuxedBus {
+        pin: Rc<MuxPin>,
+        routes: RefCell<Vec<(Option<PinMode>, bool)>>,
+    }
+
+    impl Target for MuxedBus {
+        fn get_transfer_mode(&self) -> Result<TransferMode> {
+            Ok(TransferMode::Mode0)
+        }
+        fn set_transfer_mode(&self, _mode: TransferMode) -> Result<()> {
+            Ok(())
+        }
+        fn get_bits_per_word(&self) -> Result<u32> {
+            Ok(8)
+        }
+        fn set_bits_per_word(&self, _bits_per_word: u32) -> Result<()> {
+            Ok(())
+        }
+        fn get_max_speed(&self) -> Result<u32> {
+            Ok(6_000_000)
+        }
+        fn set_max_speed(&self, _frequency: u32) -> Result<()> {
+            Ok(())
+        }
+        fn get_max_transfer_count(&self) -> Result<usize> {
+            Ok(42)
+        }
+        fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+            Ok(MaxSizes {
+                read: 256,
+                write: 256,
+            })
+        }
+        fn run_transaction(&self, _transaction: &mut [Transfer]) -> Result<()> {
+            self.routes
+                .borrow_mut()
+                .push((self.pin.mode.get(), self.pin.level.get()));
+            Ok(())
+        }
+        fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+            Err(TransportError::UnsupportedOperation.into())
+        }
+    }
+
+    #[test]
+    fn test_muxed_spi() -> Result<()> {
+        let mux = SpiMuxSetting::parse_list(&["EC=USB_A10:1", "AP=USB_A10:0"])?;
+        let pin = Rc::new(MuxPin::default());
+        let bus = Rc::new(MuxedBus {
+            pin: Rc::clone(&pin),
+            routes: RefCell::new(Vec::new()),
+        });
+        let handle = |name: &str| {
+            let route = if name == "0" {
+                SpiMuxRoute::Default(vec![Rc::clone(&pin) as Rc<dyn GpioPin>])
+            } else {
+                SpiMuxRoute::Select {
+                    name: name.to_string(),
+                    setting: mux[name].clone(),
+                    pin: Rc::clone(&pin) as Rc<dyn GpioPin>,
+                }
+            };
+            CW310MuxedSpi::new(Rc::clone(&bus) as Rc<dyn Target>, route)
+        };
+        let ec = handle("EC");
+        let ap = handle("AP");
+        let default = handle("0");
+        // Each handle keeps reaching its own device, whichever was used last.
+        ec.run_transaction(&mut [])?;
+        ap.run_transaction(&mut [])?;
+        ec.run_transaction(&mut [])?;
+        default.run_transaction(&mut [])?;
+        assert_eq!(
+            *bus.routes.borrow(),
+            vec![
+                (Some(PinMode::PushPull), true),
+                (Some(PinMode::PushPull), false),
+                (Some(PinMode::PushPull), true),
+                (Some(PinMode::Input), true),
+            ]
+        );
+        Ok(())
+    }
+}
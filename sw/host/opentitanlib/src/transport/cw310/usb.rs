@@ -8,6 +8,7 @@ use std::cmp;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::convert::TryInto;
+use std::fmt;
 use std::time::Duration;
 
 use crate::collection;
@@ -15,7 +16,7 @@ use crate::io::gpio::GpioError;
 use crate::io::spi::SpiError;
 use crate::transport::{TransportError, TransportInterfaceType};
 use crate::util::parse_int::ParseInt;
-use crate::util::usb::UsbBackend;
+use crate::util::usb::{UsbBackend, UsbStrings};
 
 /// The `Backend` struct provides high-level access to the CW310 board.
 pub struct Backend {
@@ -32,7 +33,13 @@ struct PllMulDiv {
 }
 
 #[derive(Ord, PartialOrd, Eq, PartialEq, Debug, Clone)]
-pub struct FirmwareVersion(u8, u8, u8);
+pub struct FirmwareVersion(pub u8, pub u8, pub u8);
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
 
 impl Backend {
     /// Commands for the CW310 board.
@@ -99,8 +106,8 @@ impl Backend {
 
     const LAST_PIN_NUMBER: u8 = 106;
 
-    const VID_NEWAE: u16 = 0x2b3e;
-    const PID_CW310: u16 = 0xc310;
+    pub const VID_NEWAE: u16 = 0x2b3e;
+    pub const PID_CW310: u16 = 0xc310;
 
     /// Create a new connection to a CW310 board.
     pub fn new(
@@ -117,6 +124,31 @@ impl Backend {
         })
     }
 
+    /// Open each board matching VID/PID, without claiming any interface, for probing.  Boards
+    /// which cannot be opened are returned as errors.
+    pub fn probe(usb_vid: Option<u16>, usb_pid: Option<u16>) -> Result<Vec<Result<Self>>> {
+        Ok(UsbBackend::matching_devices(
+            usb_vid.unwrap_or(Self::VID_NEWAE),
+            usb_pid.unwrap_or(Self::PID_CW310),
+        )?
+        .into_iter()
+        .map(|device| {
+            Ok(Backend {
+                usb: UsbBackend::from_device(device)?,
+            })
+        })
+        .collect())
+    }
+
+    /// Open another connection to the same board, for use by a separate thread.
+    pub fn reopen(&self) -> Result<Self> {
+        Self::new(
+            Some(self.usb.get_vendor_id()),
+            Some(self.usb.get_product_id()),
+            Some(self.get_serial_number()),
+        )
+    }
+
     /// Send a control write transaction to the CW310 board.
     pub fn send_ctrl(&self, cmd: u8, value: u16, data: &[u8]) -> Result<usize> {
         log::debug!("WRITE_CTRL: bmRequestType: {:02x}, bRequest: {:02x}, wValue: {:04x}, wIndex: {:04x}, data: {:?}",
@@ -136,6 +168,11 @@ impl Backend {
         self.usb.get_serial_number()
     }
 
+    /// Reads the manufacturer, product and serial number strings of the device.
+    pub fn string_descriptors(&self) -> Result<UsbStrings> {
+        self.usb.string_descriptors()
+    }
+
     /// Get the firmware build date as a string.
     pub fn get_firmware_build_date(&self) -> Result<String> {
         let mut buf = [0u8; 100];
@@ -190,6 +227,31 @@ impl Backend {
         Ok(())
     }
 
+    /// Reads the level of every GPIO pin of the SAM3X, regardless of whether it is in use as a
+    /// GPIO, returning a bitmap in which bit `n` is the level of pin number `n`, see
+    /// `pin_name_to_number()`.  The pins of port A are bits 0-31 (PA0 is bit 0), port B bits
+    /// 32-63, port C bits 64-95 and port D bits 96-127.  Use `pin_level()` to look up named pins.
+    ///
+    /// The firmware has no request for reading an entire port, so this makes one USB request per
+    /// pin, but it is a consistent way of inspecting the state of the board.
+    pub fn read_all_pins(&self) -> Result<u128> {
+        let mut bitmap = 0u128;
+        for &pinnum in SAM3X_PIN_NAMES.values() {
+            let mut buf = [0u8; 1];
+            self.read_ctrl(Backend::CMD_FPGAIO_UTIL, pinnum as u16, &mut buf)
+                .context("USB error")?;
+            if buf[0] != 0 {
+                bitmap |= 1 << pinnum;
+            }
+        }
+        Ok(bitmap)
+    }
+
+    /// Returns the level of the named pin, according to a bitmap from `read_all_pins()`.
+    pub fn pin_level(bitmap: u128, pinname: &str) -> Result<bool> {
+        Ok(bitmap & (1 << Backend::pin_name_to_number(pinname)?) != 0)
+    }
+
     /// Sends a reset signal to the SAM3U chip. Does not wait for the SAM3U to
     /// finish resetting.
     pub fn reset_sam3x(&self) -> Result<()> {
@@ -334,35 +396,42 @@ impl Backend {
         Ok(())
     }
 
-    fn fpga_download(&self, bitstream: &[u8], progress: Option<&dyn Fn(u32, u32)>) -> Result<()> {
+    /// Number of bytes sent to the board by `fpga_program()` for a bitstream of `bitstream_len`
+    /// bytes.
+    pub fn fpga_stream_len(bitstream_len: usize) -> usize {
         // This isn't really documented well in the python implementation:
         // There appears to be a header on the bitstream which we do not
         // want to send to the board.
-        let mut stream = bitstream[0x7C..].to_vec();
+        let len = bitstream_len.saturating_sub(0x7C);
 
         // Then, we need to extend the buffer a little to make sure we send
         // enough clocks at the end to finish programming.  Apparently, we
         // cannot end with a multiple of 64 bytes.
-        let newlen = stream.len() + if stream.len() % 32 != 0 { 32 } else { 33 };
-        stream.resize(newlen, 0xFF);
-
-        // Finally, chunk the payload into 2k chunks and send it to the
-        // bulk endpoint.
-        for chunk in stream.chunks(2048) {
-            if let Some(prg) = progress {
-                prg(0, chunk.len() as u32)
-            }
-            self.usb.write_bulk(Backend::BULK_OUT_EP, chunk)?;
+        len + match len % 32 {
+            0 => 33,
+            _ => 32,
         }
-        Ok(())
     }
 
-    /// Program a bitstream into the FPGA.
+    fn fpga_download(
+        &self,
+        bitstream: &[u8],
+        progress: Option<&dyn Fn(u32, u32)>,
+    ) -> Result<usize> {
+        let mut stream = bitstream[0x7C..].to_vec();
+        stream.resize(Backend::fpga_stream_len(bitstream.len()), 0xFF);
+        send_chunks(&stream, progress, &mut |chunk| {
+            self.usb.write_bulk(Backend::BULK_OUT_EP, chunk)
+        })
+    }
+
+    /// Program a bitstream into the FPGA, returning the number of bytes actually sent, see
+    /// `fpga_stream_len()`.
     pub fn fpga_program(
         &self,
         bitstream: &[u8],
         progress: Option<&dyn Fn(u32, u32)>,
-    ) -> Result<()> {
+    ) -> Result<usize> {
         self.fpga_prepare(Backend::FPGA_PROG_SPEED)?;
         let result = self.fpga_download(bitstream, progress);
 
@@ -378,12 +447,12 @@ impl Backend {
         }
         self.send_ctrl(Backend::CMD_FPGA_PROGRAM, Backend::PROGRAM_EXIT, &[])?;
 
-        if let Err(e) = result {
-            Err(TransportError::FpgaProgramFailed(e.to_string()).into())
-        } else if !status {
-            Err(TransportError::FpgaProgramFailed("unknown error".to_string()).into())
-        } else {
-            Ok(())
+        match result {
+            Err(e) => Err(TransportError::FpgaProgramFailed(e.to_string()).into()),
+            Ok(_) if !status => {
+                Err(TransportError::FpgaProgramFailed("unknown error".to_string()).into())
+            }
+            Ok(sent) => Ok(sent),
         }
     }
 
@@ -397,6 +466,16 @@ impl Backend {
         }
     }
 
+    /// Given a CW310 pin name, return the SAM3X name of the pin, which is the same for all
+    /// aliases of the pin.  Pins without a SAM3X name are named by number.
+    pub fn canonical_pin_name(pinname: &str) -> Result<String> {
+        let pinnum = Backend::pin_name_to_number(pinname)?;
+        Ok(SAM3X_PIN_NAMES
+            .iter()
+            .find(|(_, &n)| n == pinnum)
+            .map_or_else(|| pinnum.to_string(), |(name, _)| name.to_string()))
+    }
+
     /// Given a CW310 pin name, return its pin number.
     pub fn pin_name_to_number(pinname: &str) -> Result<u8> {
         // If the pinname is an integer, use it; otherwise try to see if it
@@ -610,6 +689,28 @@ impl Backend {
     }
 }
 
+/// Chunks the FPGA configuration `stream` into 2k chunks, and sends them through `write_chunk`,
+/// which returns the number of bytes accepted.  Stops at the first chunk not accepted in full,
+/// returning the number of bytes sent.
+pub(crate) fn send_chunks(
+    stream: &[u8],
+    progress: Option<&dyn Fn(u32, u32)>,
+    write_chunk: &mut dyn FnMut(&[u8]) -> Result<usize>,
+) -> Result<usize> {
+    let mut sent = 0;
+    for chunk in stream.chunks(2048) {
+        if let Some(prg) = progress {
+            prg(0, chunk.len() as u32)
+        }
+        let written = write_chunk(chunk)?;
+        sent += written;
+        if written != chunk.len() {
+            break;
+        }
+    }
+    Ok(sent)
+}
+
 lazy_static! {
     // Mapping of SAM3 pin names to pin numbers.
     static ref SAM3X_PIN_NAMES: HashMap<&'static str, u8> = collection! {
@@ -775,3 +876,23 @@ lazy_static! {
         "USB_SPI_CS" => "PA28"
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pin_level() -> Result<()> {
+        // PA0, PB17 (CFG_DONE), PD3 (USB_A13) high.
+        let bitmap: u128 = 1 | 1 << 49 | 1 << 99;
+        assert!(Backend::pin_level(bitmap, "PA0")?);
+        assert!(!Backend::pin_level(bitmap, "PA1")?);
+        assert!(Backend::pin_level(bitmap, "CFG_DONE")?);
+        assert!(Backend::pin_level(bitmap, "pb17")?);
+        assert!(Backend::pin_level(bitmap, "USB_A13")?);
+        assert!(!Backend::pin_level(bitmap, "USB_A14")?);
+        assert!(Backend::pin_level(bitmap, "99")?);
+        assert!(Backend::pin_level(bitmap, "NO_SUCH_PIN").is_err());
+        Ok(())
+    }
+}
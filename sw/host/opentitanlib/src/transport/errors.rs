@@ -19,10 +19,16 @@ pub enum TransportError {
     NoDevice,
     #[error("Found multiple USB devices, use --serial")]
     MultipleDevices,
+    #[error("Found no supported debug board, looked for {0}")]
+    NoSupportedBoard(String),
+    #[error("Found multiple debug boards ({0}), use --interface")]
+    MultipleBoards(String),
     #[error("USB error: {0}")]
     UsbGenericError(String),
     #[error("Error opening USB device: {0}")]
     UsbOpenError(String),
+    #[error("USB interface {0} is busy (in use by another process?), retried for {1:?}")]
+    UsbInterfaceBusy(u8, std::time::Duration),
     #[error("Transport does not support {0:?}")]
     InvalidInterface(TransportInterfaceType),
     #[error("Transport does not support {0:?} instance {1}")]
@@ -41,16 +47,22 @@ pub enum TransportError {
     ClearBitstreamFailed(),
     #[error("PLL programming failed: {0}")]
     PllProgramFailed(String),
+    #[error("Pin {0} is the chip select of SPI instance {1}, and cannot be used as GPIO")]
+    CsPinInUse(String, String),
     #[error("Invalid pin strapping name \"{0}\"")]
     InvalidStrappingName(String),
     #[error("Transport does not support the requested operation")]
     UnsupportedOperation,
     #[error("Requested operation invalid at this time")]
     InvalidOperation,
+    #[error("Operation cancelled")]
+    Cancelled,
     #[error("Error communicating with FTDI: {0}")]
     FtdiError(String),
     #[error("Error communicating with debugger: {0}")]
     CommunicationError(String),
+    #[error("Timeout waiting for completion of {0}")]
+    Timeout(String),
     #[error("Proxy unable to resolve `{0}`: {1}")]
     ProxyLookupError(String, String),
     #[error("Proxy unable to connect to `{0}`: {1}")]
@@ -74,4 +86,5 @@ pub enum TransportInterfaceType {
     Emulator,
     ProxyOps,
     GpioMonitoring,
+    GpioPatternGenerator,
 }
@@ -5,11 +5,14 @@
 use anyhow::{bail, Result};
 use lazy_static::lazy_static;
 use regex::Regex;
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
 use crate::io::gpio::{
-    ClockNature, Edge, GpioError, GpioMonitoring, GpioPin, MonitoringEvent, MonitoringReadResponse,
-    MonitoringStartResponse, PinMode, PullMode,
+    timestamp_divisor, ClockNature, Edge, GpioError, GpioMonitoring, GpioPin, MonitoringEvent,
+    MonitoringReadResponse, MonitoringSession, MonitoringStartResponse, PinMode, PullMode,
+    PullStrength,
 };
 use crate::transport::hyperdebug::Inner;
 use crate::transport::TransportError;
@@ -17,16 +20,72 @@ use crate::transport::TransportError;
 pub struct HyperdebugGpioPin {
     inner: Rc<Inner>,
     pinname: String,
+    /// Mode most recently set through this object, `None` if the pin is still in the mode
+    /// configured by HyperDebug firmware.
+    mode: Cell<Option<PinMode>>,
 }
 
 impl HyperdebugGpioPin {
+    /// Highest voltage which can be produced by the DAC of HyperDebug.
+    const DAC_MAX_VOLTS: f32 = 3.3;
+
     pub fn open(inner: &Rc<Inner>, pinname: &str) -> Result<Self> {
         let result = Self {
             inner: Rc::clone(inner),
             pinname: pinname.to_string(),
+            mode: Cell::new(None),
         };
         Ok(result)
     }
+
+    /// Argument of the `gpiopullmode` command.  The STM32 has only its internal pull resistors
+    /// of around 40k, so a strong pull cannot be provided.
+    fn pull_mode_arg(mode: PullMode, strength: Option<PullStrength>) -> Result<&'static str> {
+        if strength == Some(PullStrength::Strong) && mode != PullMode::None {
+            bail!(GpioError::UnsupportedPullMode(mode));
+        }
+        Ok(match mode {
+            PullMode::None => "none",
+            PullMode::PullUp => "up",
+            PullMode::PullDown => "down",
+        })
+    }
+
+    /// Parses a line of the listing of all pins output by `gpioget` without arguments, e.g.
+    /// `  1* CN10_1`, the asterisk marking a level changed since the previous listing.
+    fn parse_gpioget_line(line: &str) -> Option<(&str, bool)> {
+        let (level, name) = line.trim_start().split_once(char::is_whitespace)?;
+        let level = match level.trim_end_matches('*') {
+            "0" => false,
+            "1" => true,
+            _ => return None,
+        };
+        Some((name.trim(), level))
+    }
+
+    /// Looks up the levels of `pinnames` among those listed by `gpioget`.  Returns `None` if any
+    /// pin is missing from the listing, e.g. with older firmware, such that the pins are read
+    /// separately instead.
+    fn batch_levels(levels: &HashMap<String, bool>, pinnames: &[&str]) -> Option<Vec<bool>> {
+        pinnames
+            .iter()
+            .map(|pinname| levels.get(*pinname).copied())
+            .collect()
+    }
+
+    /// Verify that analog output is possible if the pin were in the given mode, and convert the
+    /// requested voltage to the millivolt value used by HyperDebug firmware to program its DAC.
+    fn dac_value(pinname: &str, mode: Option<PinMode>, volts: f32) -> Result<u32> {
+        if let Some(mode) = mode {
+            if mode != PinMode::AnalogOutput {
+                return Err(GpioError::NotAnalogOutput(pinname.to_string()).into());
+            }
+        }
+        if !(0.0..=Self::DAC_MAX_VOLTS).contains(&volts) {
+            return Err(GpioError::UnsupportedPinVoltage(volts).into());
+        }
+        Ok((volts * 1000.0).round() as u32)
+    }
 }
 
 impl GpioPin for HyperdebugGpioPin {
@@ -38,6 +97,25 @@ impl GpioPin for HyperdebugGpioPin {
         Ok(line.trim_start().starts_with('1'))
     }
 
+    /// Lists the levels of all pins in a single console command.  The pins are matched by name,
+    /// which assumes that they all belong to this HyperDebug.
+    fn read_batch(&self, pins: &[&dyn GpioPin]) -> Result<Option<Vec<bool>>> {
+        let Some(pinnames) = pins
+            .iter()
+            .map(|pin| pin.get_internal_pin_name())
+            .collect::<Option<Vec<_>>>()
+        else {
+            return Ok(None);
+        };
+        let mut levels = HashMap::new();
+        self.inner.execute_command("gpioget", |line| {
+            if let Some((name, level)) = Self::parse_gpioget_line(line) {
+                levels.insert(name.to_string(), level);
+            }
+        })?;
+        Ok(Self::batch_levels(&levels, &pinnames))
+    }
+
     /// Sets the value of the GPIO pin `id` to `value`.
     fn write(&self, value: bool) -> Result<()> {
         self.inner
@@ -56,18 +134,20 @@ impl GpioPin for HyperdebugGpioPin {
                 PinMode::AnalogOutput => "dac",
                 PinMode::Alternate => "alternate",
             }
-        ))
+        ))?;
+        self.mode.set(Some(mode));
+        Ok(())
     }
 
     fn set_pull_mode(&self, mode: PullMode) -> Result<()> {
+        self.set_pull_mode_ex(mode, None)
+    }
+
+    fn set_pull_mode_ex(&self, mode: PullMode, strength: Option<PullStrength>) -> Result<()> {
         self.inner.cmd_no_output(&format!(
             "gpiopullmode {} {}",
             &self.pinname,
-            match mode {
-                PullMode::None => "none",
-                PullMode::PullUp => "up",
-                PullMode::PullDown => "down",
-            }
+            Self::pull_mode_arg(mode, strength)?
         ))
     }
 
@@ -88,10 +168,7 @@ impl GpioPin for HyperdebugGpioPin {
     }
 
     fn analog_write(&self, volts: f32) -> Result<()> {
-        if volts > 3.3 || volts < 0.0 {
-            return Err(GpioError::UnsupportedPinVoltage(volts).into());
-        }
-        let milli_volts = (volts * 1000.0) as u32;
+        let milli_volts = Self::dac_value(&self.pinname, self.mode.get(), volts)?;
         self.inner.cmd_no_output(&format!(
             "gpio analog-set {} {}",
             &self.pinname, milli_volts,
@@ -105,11 +182,10 @@ impl GpioPin for HyperdebugGpioPin {
         pull: Option<PullMode>,
         volts: Option<f32>,
     ) -> Result<()> {
-        if let Some(v) = volts {
-            if v > 3.3 || v < 0.0 {
-                return Err(GpioError::UnsupportedPinVoltage(v).into());
-            }
-        }
+        let milli_volts = match volts {
+            Some(v) => Some(Self::dac_value(&self.pinname, mode.or(self.mode.get()), v)?),
+            None => None,
+        };
         self.inner
             .cmd_no_output(&format!(
                 "gpio multiset {} {} {} {} {}",
@@ -134,13 +210,13 @@ impl GpioPin for HyperdebugGpioPin {
                     Some(PullMode::PullDown) => "down",
                     None => "-",
                 },
-                if let Some(v) = volts {
-                    format!("{}", (v * 1000.0) as u32)
+                if let Some(mv) = milli_volts {
+                    format!("{}", mv)
                 } else {
                     "-".to_string()
                 },
             ))
-            .or_else(|_| {
+            .or_else(|_| -> Result<()> {
                 // HyperDebug firmware does not support atomically setting all three, fall back to
                 // separate commands.
                 if let Some(mode) = mode {
@@ -156,7 +232,11 @@ impl GpioPin for HyperdebugGpioPin {
                     self.analog_write(volts)?;
                 }
                 Ok(())
-            })
+            })?;
+        if let Some(mode) = mode {
+            self.mode.set(Some(mode));
+        }
+        Ok(())
     }
 
     fn get_internal_pin_name(&self) -> Option<&str> {
@@ -169,35 +249,28 @@ pub struct HyperdebugGpioMonitoring {
 }
 
 impl HyperdebugGpioMonitoring {
+    /// Frequency of the timer used by HyperDebug firmware to timestamp events.
+    pub const CLOCK_FREQUENCY: u64 = 1_000_000;
+
     pub fn open(inner: &Rc<Inner>) -> Result<Self> {
         Ok(Self {
             inner: Rc::clone(inner),
         })
     }
-}
 
-impl GpioMonitoring for HyperdebugGpioMonitoring {
-    fn get_clock_nature(&self) -> Result<ClockNature> {
-        Ok(ClockNature::Wallclock {
-            resolution: 1_000_000,
-            offset: None,
-        })
+    /// Number of firmware timestamp units per unit of the currently selected resolution.
+    fn divisor(&self) -> Result<u64> {
+        timestamp_divisor(Self::CLOCK_FREQUENCY, self.inner.timestamp_resolution.get())
     }
 
-    /// Set up edge trigger detection on the given set of pins, transport will buffer the list
-    /// internally.
-    fn monitoring_start(&self, pins: &[&dyn GpioPin]) -> Result<MonitoringStartResponse> {
-        let mut pin_names = Vec::new();
-        for pin in pins {
-            pin_names.push(
-                pin.get_internal_pin_name()
-                    .ok_or(TransportError::InvalidOperation)?,
-            );
-        }
+    /// Start edge detection in HyperDebug firmware, bypassing the check for paused sessions.
+    fn start(&self, session: &MonitoringSession) -> Result<MonitoringStartResponse> {
+        let pin_names = session.pin_names();
         lazy_static! {
             pub static ref START_TIME_REGEX: Regex = Regex::new("^ +@([0-9]+)").unwrap();
             pub static ref SIGNAL_REGEX: Regex = Regex::new("^ +([0-9]+) ([^ ])+ ([01])").unwrap();
         }
+        let divisor = self.divisor()?;
         let mut start_time: u64 = 0;
         let mut signals = Vec::new();
         let mut unexpected_output = false;
@@ -220,30 +293,25 @@ impl GpioMonitoring for HyperdebugGpioMonitoring {
             ))
         }
         Ok(MonitoringStartResponse {
-            timestamp: start_time,
+            session: session.clone(),
+            timestamp: start_time / divisor,
             initial_levels: signals,
         })
     }
 
-    /// Retrieve list of events detected thus far, optionally stopping the possibly expensive edge
-    /// detection.  Buffer overrun will be reported as an `Err`, and result in the stopping of the
-    /// edge detection irrespective of the parameter value.
-    fn monitoring_read(
+    /// Retrieve list of events buffered by HyperDebug firmware, optionally stopping the edge
+    /// detection.
+    fn read(
         &self,
-        pins: &[&dyn GpioPin],
+        session: &MonitoringSession,
         continue_monitoring: bool,
     ) -> Result<MonitoringReadResponse> {
-        let mut pin_names = Vec::new();
-        for pin in pins {
-            pin_names.push(
-                pin.get_internal_pin_name()
-                    .ok_or(TransportError::InvalidOperation)?,
-            );
-        }
+        let pin_names = session.pin_names();
         lazy_static! {
             pub static ref START_TIME_REGEX: Regex = Regex::new("^ +@([0-9]+)").unwrap();
             pub static ref EDGE_REGEX: Regex = Regex::new("^ +([0-9]+) (-?[0-9]+) ([RF])").unwrap();
         }
+        let divisor = self.divisor()?;
         let mut reference_time: u64 = 0;
         let mut events = Vec::new();
         loop {
@@ -264,7 +332,8 @@ impl GpioMonitoring for HyperdebugGpioMonitoring {
                             },
                             timestamp: (reference_time as i64
                                 + captures.get(2).unwrap().as_str().parse::<i64>().unwrap())
-                                as u64,
+                                as u64
+                                / divisor,
                         });
                     } else if line == "Warning: more data" {
                         more_data = true;
@@ -289,7 +358,162 @@ impl GpioMonitoring for HyperdebugGpioMonitoring {
         }
         Ok(MonitoringReadResponse {
             events,
-            timestamp: reference_time, // TODO: adjust in case of event later than this timestamp
+            timestamp: reference_time / divisor,
+        })
+    }
+
+    /// Read from HyperDebug firmware, holding back events later than the returned timestamp
+    /// until the next read, see `MonitoringWatermarks`.
+    fn read_deduplicated(
+        &self,
+        session: &MonitoringSession,
+        continue_monitoring: bool,
+    ) -> Result<MonitoringReadResponse> {
+        let resp = self.read(session, continue_monitoring)?;
+        Ok(self
+            .inner
+            .monitoring_watermarks
+            .apply(session, resp, continue_monitoring))
+    }
+}
+
+impl GpioMonitoring for HyperdebugGpioMonitoring {
+    fn get_clock_nature(&self) -> Result<ClockNature> {
+        Ok(ClockNature::Wallclock {
+            resolution: self.inner.timestamp_resolution.get(),
+            offset: None,
         })
     }
+
+    /// HyperDebug firmware always timestamps events in microseconds, coarser resolutions are
+    /// obtained by dividing the timestamps reported by the firmware.
+    fn set_timestamp_resolution(&self, resolution: u64) -> Result<()> {
+        timestamp_divisor(Self::CLOCK_FREQUENCY, resolution)?;
+        self.inner.timestamp_resolution.set(resolution);
+        Ok(())
+    }
+
+    /// Set up edge trigger detection on the given set of pins, transport will buffer the list
+    /// internally.  HyperDebug firmware keeps a separate buffer for each set of pins, and refuses
+    /// to monitor a pin which is already part of another active session.
+    fn monitoring_start(&self, pins: &[&dyn GpioPin]) -> Result<MonitoringStartResponse> {
+        let session = MonitoringSession::from_pins(pins)?;
+        self.inner.paused_monitoring.check_start(&session)?;
+        let resp = self.start(&session)?;
+        self.inner.monitoring_watermarks.start(&session);
+        Ok(resp)
+    }
+
+    /// Retrieve list of events detected thus far, optionally stopping the possibly expensive edge
+    /// detection.  Buffer overrun will be reported as an `Err`, and result in the stopping of the
+    /// edge detection irrespective of the parameter value.
+    fn monitoring_read(
+        &self,
+        session: &MonitoringSession,
+        continue_monitoring: bool,
+    ) -> Result<MonitoringReadResponse> {
+        self.inner
+            .paused_monitoring
+            .read(session, continue_monitoring, |cont| {
+                self.read_deduplicated(session, cont)
+            })
+    }
+
+    /// HyperDebug firmware has no notion of pausing, the session is stopped after reading out
+    /// the events buffered so far, and started anew on resume.  Firmware timestamps are relative
+    /// to its boot, so they remain comparable across the pause.
+    fn monitoring_pause(&self, session: &MonitoringSession) -> Result<()> {
+        self.inner
+            .paused_monitoring
+            .pause(session, || self.read_deduplicated(session, false))
+    }
+
+    fn monitoring_resume(&self, session: &MonitoringSession) -> Result<MonitoringStartResponse> {
+        self.inner
+            .paused_monitoring
+            .resume(session, || self.start(session))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dac_value(mode: Option<PinMode>, volts: f32) -> Result<u32> {
+        HyperdebugGpioPin::dac_value("CN7_9", mode, volts)
+    }
+
+    #[test]
+    fn test_parse_gpioget_line() {
+        assert_eq!(
+            HyperdebugGpioPin::parse_gpioget_line("  1* CN10_1"),
+            Some(("CN10_1", true))
+        );
+        assert_eq!(
+            HyperdebugGpioPin::parse_gpioget_line("  0  CN7_9\r"),
+            Some(("CN7_9", false))
+        );
+        assert_eq!(
+            HyperdebugGpioPin::parse_gpioget_line("Parameter 1 invalid"),
+            None
+        );
+        assert_eq!(HyperdebugGpioPin::parse_gpioget_line(""), None);
+    }
+
+    #[test]
+    fn test_batch_levels() {
+        let levels = HashMap::from([("CN10_1".to_string(), true), ("CN7_9".to_string(), false)]);
+        assert_eq!(
+            HyperdebugGpioPin::batch_levels(&levels, &["CN7_9", "CN10_1"]),
+            Some(vec![false, true])
+        );
+        assert_eq!(
+            HyperdebugGpioPin::batch_levels(&levels, &["CN7_9", "CN10_29"]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_pull_mode_arg() -> Result<()> {
+        for strength in [None, Some(PullStrength::Weak)] {
+            assert_eq!(
+                HyperdebugGpioPin::pull_mode_arg(PullMode::PullUp, strength)?,
+                "up"
+            );
+        }
+        let err = HyperdebugGpioPin::pull_mode_arg(PullMode::PullDown, Some(PullStrength::Strong))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GpioError>(),
+            Some(GpioError::UnsupportedPullMode(PullMode::PullDown))
+        ));
+        assert_eq!(
+            HyperdebugGpioPin::pull_mode_arg(PullMode::None, Some(PullStrength::Strong))?,
+            "none"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dac_value() -> Result<()> {
+        assert_eq!(dac_value(Some(PinMode::AnalogOutput), 0.0)?, 0);
+        assert_eq!(dac_value(Some(PinMode::AnalogOutput), 1.8)?, 1800);
+        assert_eq!(dac_value(Some(PinMode::AnalogOutput), 3.3)?, 3300);
+        // Mode unknown, leave it to HyperDebug firmware to verify.
+        assert_eq!(dac_value(None, 0.25)?, 250);
+
+        for volts in [-0.1, 3.31, f32::NAN] {
+            let err = dac_value(Some(PinMode::AnalogOutput), volts).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<GpioError>(),
+                Some(GpioError::UnsupportedPinVoltage(_))
+            ));
+        }
+        let err = dac_value(Some(PinMode::PushPull), 1.0).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GpioError>(),
+            Some(GpioError::NotAnalogOutput(_))
+        ));
+        Ok(())
+    }
 }
@@ -74,7 +74,7 @@ impl HyperdebugI2cBus {
         let mut usb_handle = inner.usb_device.borrow_mut();
 
         // Exclusively claim I2C interface, preparing for bulk transfers.
-        usb_handle.claim_interface(i2c_interface.interface)?;
+        inner.claim_interface(&mut usb_handle, "I2C", i2c_interface)?;
 
         Ok(Self {
             inner: Rc::clone(inner),
@@ -165,19 +165,12 @@ impl HyperdebugI2cBus {
 
     /// Send one USB packet.
     fn usb_write_bulk(&self, buf: &[u8]) -> Result<()> {
-        self.inner
-            .usb_device
-            .borrow()
-            .write_bulk(self.interface.out_endpoint, buf)?;
-        Ok(())
+        self.inner.write_bulk(self.interface.out_endpoint, buf)
     }
 
     /// Receive one USB packet.
     fn usb_read_bulk(&self, buf: &mut [u8]) -> Result<usize> {
-        self.inner
-            .usb_device
-            .borrow()
-            .read_bulk(self.interface.in_endpoint, buf)
+        self.inner.read_bulk(self.interface.in_endpoint, buf)
     }
 }
 
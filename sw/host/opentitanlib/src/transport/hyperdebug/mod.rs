@@ -5,6 +5,7 @@
 use anyhow::{bail, ensure, Context, Result};
 use lazy_static::lazy_static;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use serde_annotate::Annotate;
 use serialport::TTYPort;
 use std::any::Any;
@@ -19,18 +20,19 @@ use std::io::Write;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use crate::io::gpio::{GpioMonitoring, GpioPin};
+use crate::io::gpio::{GpioMonitoring, GpioPin, MonitoringWatermarks, PausedMonitoring};
 use crate::io::i2c::Bus;
 use crate::io::spi::Target;
 use crate::io::uart::Uart;
-use crate::transport::common::fpga::{ClearBitstream, FpgaProgram};
+use crate::transport::common::fpga::{ClearBitstream, FpgaProgram, FpgaProgramMethod};
 use crate::transport::common::uart::{flock_serial, SerialPortExclusiveLock, SerialPortUart};
 use crate::transport::cw310::CW310;
 use crate::transport::{
     Capabilities, Capability, Transport, TransportError, TransportInterfaceType, UpdateFirmware,
 };
-use crate::util::usb::UsbBackend;
+use crate::util::usb::{UsbBackend, UsbStrings};
 
 pub mod c2d2;
 pub mod dfu;
@@ -89,9 +91,116 @@ pub struct BulkInterface {
     interface: u8,
     in_endpoint: u8,
     out_endpoint: u8,
+    /// Largest packet supported by both endpoints, see `bulk_packet_size()`.
+    max_packet_size: usize,
+}
+
+impl BulkInterface {
+    /// Size of bulk packets on a full-speed USB connection, assumed if the endpoint descriptors do
+    /// not tell otherwise.
+    pub const FULL_SPEED_PACKET_SIZE: usize = 64;
+    /// Size of bulk packets on a high-speed USB connection, the largest supported.
+    pub const HIGH_SPEED_PACKET_SIZE: usize = 512;
+
+    /// Returns the largest packet which can be sent and received on the bulk endpoints.
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+}
+
+/// Computes the packet size to use on a pair of bulk endpoints, from the `wMaxPacketSize` field
+/// of their descriptors, falling back to the full-speed size if either reports nothing usable.
+fn bulk_packet_size(in_max_packet_size: u16, out_max_packet_size: u16) -> usize {
+    // Bits 11-12 specify additional transactions per microframe, not used for bulk endpoints.
+    let size = (in_max_packet_size.min(out_max_packet_size) & 0x7ff) as usize;
+    if size < BulkInterface::FULL_SPEED_PACKET_SIZE {
+        BulkInterface::FULL_SPEED_PACKET_SIZE
+    } else {
+        size.min(BulkInterface::HIGH_SPEED_PACKET_SIZE)
+    }
+}
+
+/// USB interface claimed by this transport, with the bulk endpoints used on it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ClaimedInterface {
+    /// What the interface is used for, e.g. "SPI".
+    pub purpose: String,
+    pub interface: u8,
+    pub in_endpoint: u8,
+    pub out_endpoint: u8,
+}
+
+/// Record of the USB interfaces claimed by a HyperDebug transport, for diagnosing "device busy"
+/// errors, e.g. when several tools attempt to use the same HyperDebug at the same time.
+#[derive(Annotate, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UsbClaims {
+    pub interfaces: Vec<ClaimedInterface>,
+}
+
+impl UsbClaims {
+    /// Record that the given interface has been claimed.  Claiming the same interface again,
+    /// e.g. when opening a second SPI target, does not produce a new entry.
+    pub fn record(&mut self, purpose: &str, bulk_interface: &BulkInterface) {
+        if self
+            .interfaces
+            .iter()
+            .any(|i| i.interface == bulk_interface.interface)
+        {
+            return;
+        }
+        self.interfaces.push(ClaimedInterface {
+            purpose: purpose.to_string(),
+            interface: bulk_interface.interface,
+            in_endpoint: bulk_interface.in_endpoint,
+            out_endpoint: bulk_interface.out_endpoint,
+        });
+    }
+}
+
+/// Counters of USB bulk operations performed by a HyperDebug transport, for diagnosing flaky
+/// connections.  Counted since the transport was opened, or since the last `reset_stats()`.
+#[derive(Annotate, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TransferStats {
+    pub writes: u64,
+    pub reads: u64,
+    pub failed_writes: u64,
+    pub failed_reads: u64,
+}
+
+impl TransferStats {
+    /// Count the outcome of one bulk write.
+    pub fn record_write<R>(&mut self, result: &Result<R>) {
+        self.writes += 1;
+        if result.is_err() {
+            self.failed_writes += 1;
+        }
+    }
+
+    /// Count the outcome of one bulk read.
+    pub fn record_read<R>(&mut self, result: &Result<R>) {
+        self.reads += 1;
+        if result.is_err() {
+            self.failed_reads += 1;
+        }
+    }
 }
 
 impl<T: Flavor> Hyperdebug<T> {
+    /// Sets the time allowed for each console command, see `Inner::set_command_timeout()`.
+    pub fn set_command_timeout(&self, timeout: Duration) {
+        self.inner.set_command_timeout(timeout);
+    }
+
+    /// Sets the time to wait for a busy USB interface, see `Inner::set_claim_timeout()`.
+    pub fn set_claim_timeout(&self, timeout: Duration) {
+        self.inner.set_claim_timeout(timeout);
+    }
+
+    /// Enables or disables discarding stale data, see `Inner::set_flush_on_open()`.
+    pub fn set_flush_on_open(&self, flush: bool) {
+        self.inner.set_flush_on_open(flush);
+    }
+
     const USB_CLASS_VENDOR: u8 = 255;
     const USB_SUBCLASS_UART: u8 = 80;
     const USB_SUBCLASS_SPI: u8 = 81;
@@ -100,12 +209,24 @@ impl<T: Flavor> Hyperdebug<T> {
     const USB_PROTOCOL_SPI: u8 = 2;
     const USB_PROTOCOL_I2C: u8 = 1;
 
-    /// Establish connection with a particular HyperDebug.
+    /// Establish connection with a particular HyperDebug.  If `throttle` is given, it limits the
+    /// sustained rate of USB bulk operations towards the SPI and I2C bridges.  `prompt` overrides
+    /// the console prompt expected after each command, for firmware using a different one.
     pub fn open(
         usb_vid: Option<u16>,
         usb_pid: Option<u16>,
         usb_serial: Option<&str>,
+        throttle: Option<Throttle>,
+        prompt: Option<&str>,
     ) -> Result<Self> {
+        let prompt = prompt.unwrap_or(Inner::DEFAULT_PROMPT);
+        ensure!(
+            !prompt.is_empty() && !prompt.contains('\n'),
+            TransportError::OpenError(
+                "HyperDebug console".to_string(),
+                format!("Invalid prompt {:?}", prompt)
+            )
+        );
         let device = UsbBackend::new(
             usb_vid.unwrap_or_else(T::get_default_usb_vid),
             usb_pid.unwrap_or_else(T::get_default_usb_pid),
@@ -215,12 +336,25 @@ impl<T: Flavor> Hyperdebug<T> {
                 console_tty: console_tty.ok_or_else(|| {
                     TransportError::CommunicationError("Missing console interface".to_string())
                 })?,
+                prompt: prompt.to_string(),
+                command_timeout: Cell::new(Inner::DEFAULT_COMMAND_TIMEOUT),
+                claim_timeout: Cell::new(Inner::DEFAULT_CLAIM_TIMEOUT),
+                flush_on_open: Cell::new(true),
                 usb_device: RefCell::new(device),
+                throttle: throttle.map(RefCell::new),
                 gpio: Default::default(),
                 spis: Default::default(),
                 selected_spi: Cell::new(0),
+                spi_speeds: Default::default(),
                 i2cs: Default::default(),
                 uarts: Default::default(),
+                usb_claims: Default::default(),
+                transfer_stats: Default::default(),
+                spi_trace_enabled: Cell::new(false),
+                spi_traces: Default::default(),
+                timestamp_resolution: Cell::new(gpio::HyperdebugGpioMonitoring::CLOCK_FREQUENCY),
+                paused_monitoring: Default::default(),
+                monitoring_watermarks: Default::default(),
             }),
             phantom: PhantomData,
         };
@@ -246,8 +380,8 @@ impl<T: Flavor> Hyperdebug<T> {
         interface: &rusb::Interface,
         interface_desc: &rusb::InterfaceDescriptor,
     ) -> Result<()> {
-        let mut in_endpoint: Option<u8> = None;
-        let mut out_endpoint: Option<u8> = None;
+        let mut in_endpoint: Option<(u8, u16)> = None;
+        let mut out_endpoint: Option<(u8, u16)> = None;
         for endpoint_desc in interface_desc.endpoint_descriptors() {
             if endpoint_desc.transfer_type() != rusb::TransferType::Bulk {
                 continue;
@@ -258,19 +392,20 @@ impl<T: Flavor> Hyperdebug<T> {
                         in_endpoint.is_none(),
                         TransportError::CommunicationError("Multiple IN endpoints".to_string())
                     );
-                    in_endpoint.replace(endpoint_desc.address());
+                    in_endpoint.replace((endpoint_desc.address(), endpoint_desc.max_packet_size()));
                 }
                 rusb::Direction::Out => {
                     ensure!(
                         out_endpoint.is_none(),
                         TransportError::CommunicationError("Multiple OUT endpoints".to_string())
                     );
-                    out_endpoint.replace(endpoint_desc.address());
+                    out_endpoint
+                        .replace((endpoint_desc.address(), endpoint_desc.max_packet_size()));
                 }
             }
         }
         match (in_endpoint, out_endpoint) {
-            (Some(in_endpoint), Some(out_endpoint)) => {
+            (Some((in_endpoint, in_size)), Some((out_endpoint, out_size))) => {
                 ensure!(
                     interface_variable_output.is_none(),
                     TransportError::CommunicationError("Multiple identical interfaces".to_string())
@@ -279,6 +414,7 @@ impl<T: Flavor> Hyperdebug<T> {
                     interface: interface.number(),
                     in_endpoint,
                     out_endpoint,
+                    max_packet_size: bulk_packet_size(in_size, out_size),
                 });
                 Ok(())
             }
@@ -289,20 +425,197 @@ impl<T: Flavor> Hyperdebug<T> {
     }
 }
 
+/// Limits the sustained rate of operations using a token bucket, allowing bursts of up to
+/// `burst` operations back-to-back, while on average spacing operations `interval` apart.
+pub struct Throttle {
+    interval: Duration,
+    burst: u32,
+    /// Theoretical time of the next operation, if the rate had been exactly one per `interval`.
+    next: Option<Instant>,
+}
+
+impl Throttle {
+    /// Fails if either the rate or the burst is zero, which would not allow any operation.
+    pub fn new(max_rate_per_second: u32, burst: u32) -> Result<Self> {
+        ensure!(
+            max_rate_per_second > 0,
+            "Rate limit must allow at least one operation per second"
+        );
+        ensure!(
+            burst > 0,
+            "Rate limit must allow bursts of at least one operation"
+        );
+        Ok(Self {
+            interval: Duration::from_secs(1) / max_rate_per_second,
+            burst,
+            next: None,
+        })
+    }
+
+    /// Registers an operation taking place at `now`, returns how long the caller must wait before
+    /// actually performing it.
+    pub fn acquire(&mut self, now: Instant) -> Duration {
+        let next = self.next.map_or(now, |next| next.max(now));
+        self.next = Some(next + self.interval);
+        next.saturating_duration_since(now)
+            .saturating_sub(self.interval * (self.burst - 1))
+    }
+}
+
 /// Internal state of the Hyperdebug struct, this struct is reference counted such that Gpio,
 /// Spi and Uart sub-structs can all refer to this shared data, which is guaranteed to live on,
 /// even if the caller lets the outer Hyperdebug struct run out of scope.
 pub struct Inner {
     console_tty: PathBuf,
+    /// Printed by the firmware on the console when ready for the next command.
+    prompt: String,
+    /// Time allowed for each console command to complete.
+    command_timeout: Cell<Duration>,
+    /// Time to wait for another process to release a USB interface being claimed.
+    claim_timeout: Cell<Duration>,
+    /// Whether to discard stale data on the bulk IN endpoint when opening SPI.
+    flush_on_open: Cell<bool>,
     usb_device: RefCell<UsbBackend>,
+    throttle: Option<RefCell<Throttle>>,
     gpio: RefCell<HashMap<String, Rc<dyn GpioPin>>>,
     spis: RefCell<HashMap<u8, Rc<dyn Target>>>,
     selected_spi: Cell<u8>,
+    /// Speed most recently requested for each SPI bus, forgotten on `reinit`.
+    spi_speeds: RefCell<HashMap<u8, u32>>,
     i2cs: RefCell<HashMap<u8, Rc<dyn Bus>>>,
     uarts: RefCell<HashMap<PathBuf, Rc<dyn Uart>>>,
+    usb_claims: RefCell<UsbClaims>,
+    transfer_stats: RefCell<TransferStats>,
+    /// Whether to record a `TransactionTrace` for each SPI transaction.
+    spi_trace_enabled: Cell<bool>,
+    /// Trace of the most recent transaction, by SPI bus index.
+    spi_traces: RefCell<HashMap<u8, spi::TransactionTrace>>,
+    /// Timestamp units per second of GPIO monitoring events.
+    timestamp_resolution: Cell<u64>,
+    /// Events of GPIO monitoring sessions stopped by `monitoring_pause()`.
+    paused_monitoring: PausedMonitoring,
+    /// Watermarks of GPIO monitoring sessions, see `MonitoringWatermarks`.
+    monitoring_watermarks: MonitoringWatermarks,
 }
 
 impl Inner {
+    /// Console prompt of the official HyperDebug firmware.
+    pub const DEFAULT_PROMPT: &'static str = "> ";
+
+    /// Time allowed for console commands, unless changed by `set_command_timeout()`.
+    pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Time to wait for a busy USB interface, unless changed by `set_claim_timeout()`.  Long
+    /// enough for a just-exited process to have released the interface.
+    pub const DEFAULT_CLAIM_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Sets the time allowed for each console command to complete, after which it fails with
+    /// `TransportError::Timeout`, rather than waiting forever on unresponsive firmware.
+    pub fn set_command_timeout(&self, timeout: Duration) {
+        self.command_timeout.set(timeout);
+    }
+
+    /// Sets the time to wait for another process to release a USB interface when opening SPI or
+    /// I2C, before failing with `TransportError::UsbInterfaceBusy`.
+    pub fn set_claim_timeout(&self, timeout: Duration) {
+        self.claim_timeout.set(timeout);
+    }
+
+    /// Sets whether opening a SPI target first discards any data left on the bulk IN endpoint,
+    /// e.g. the response to a transaction of a process which crashed before reading it, and which
+    /// would otherwise be mistaken for the response to the next request.  Enabled by default, it
+    /// may be disabled when investigating where such data comes from.
+    pub fn set_flush_on_open(&self, flush: bool) {
+        self.flush_on_open.set(flush);
+    }
+
+    /// Exclusively claim the given USB interface, preparing for bulk transfers.
+    pub fn claim_interface(
+        &self,
+        usb_handle: &mut UsbBackend,
+        purpose: &str,
+        bulk_interface: &BulkInterface,
+    ) -> Result<()> {
+        usb_handle
+            .claim_interface_with_retry(bulk_interface.interface, self.claim_timeout.get())?;
+        self.usb_claims.borrow_mut().record(purpose, bulk_interface);
+        Ok(())
+    }
+
+    /// Returns the USB interfaces claimed so far.
+    pub fn usb_claims(&self) -> UsbClaims {
+        self.usb_claims.borrow().clone()
+    }
+
+    /// Returns the USB bulk transfer counters.
+    pub fn transfer_stats(&self) -> TransferStats {
+        self.transfer_stats.borrow().clone()
+    }
+
+    /// Clears the USB bulk transfer counters.
+    pub fn reset_stats(&self) {
+        *self.transfer_stats.borrow_mut() = TransferStats::default();
+    }
+
+    /// Wait as necessary to respect the configured limit on the rate of USB bulk operations.
+    pub fn throttle_bulk(&self) {
+        if let Some(throttle) = &self.throttle {
+            let delay = throttle.borrow_mut().acquire(Instant::now());
+            if !delay.is_zero() {
+                std::thread::sleep(delay);
+            }
+        }
+    }
+
+    /// Send one USB packet on the given bulk endpoint, subject to throttling, and counted in the
+    /// transfer statistics.
+    pub fn write_bulk(&self, endpoint: u8, buf: &[u8]) -> Result<()> {
+        self.write_bulk_timeout(endpoint, buf, None)
+    }
+
+    /// Like `write_bulk()`, waiting at most `timeout`, if given, rather than the default USB
+    /// timeout.
+    pub fn write_bulk_timeout(
+        &self,
+        endpoint: u8,
+        buf: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        self.throttle_bulk();
+        let usb_device = self.usb_device.borrow();
+        let result = match timeout {
+            Some(timeout) => usb_device.write_bulk_timeout(endpoint, buf, timeout),
+            None => usb_device.write_bulk(endpoint, buf),
+        };
+        self.transfer_stats.borrow_mut().record_write(&result);
+        result?;
+        Ok(())
+    }
+
+    /// Receive one USB packet from the given bulk endpoint, subject to throttling, and counted in
+    /// the transfer statistics.
+    pub fn read_bulk(&self, endpoint: u8, buf: &mut [u8]) -> Result<usize> {
+        self.read_bulk_timeout(endpoint, buf, None)
+    }
+
+    /// Like `read_bulk()`, waiting at most `timeout`, if given, rather than the default USB
+    /// timeout.
+    pub fn read_bulk_timeout(
+        &self,
+        endpoint: u8,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> Result<usize> {
+        self.throttle_bulk();
+        let usb_device = self.usb_device.borrow();
+        let result = match timeout {
+            Some(timeout) => usb_device.read_bulk_timeout(endpoint, buf, timeout),
+            None => usb_device.read_bulk(endpoint, buf),
+        };
+        self.transfer_stats.borrow_mut().record_read(&result);
+        result
+    }
+
     /// Send a command to HyperDebug firmware, expecting to receive no output.  Any output will be
     /// reported through an `Err()` return.
     pub fn cmd_no_output(&self, cmd: &str) -> Result<()> {
@@ -372,7 +685,7 @@ impl Inner {
     }
 
     /// Send a command to HyperDebug firmware, with a callback to receive any output.
-    fn execute_command(&self, cmd: &str, mut callback: impl FnMut(&str)) -> Result<()> {
+    fn execute_command(&self, cmd: &str, callback: impl FnMut(&str)) -> Result<()> {
         let port_name = self
             .console_tty
             .to_str()
@@ -388,89 +701,108 @@ impl Inner {
         // device, to detect minicom or another instance of
         // opentitantool having the same serial port open.  Incoming
         // serial data could go silenly missing, in such cases.
-        let mut buf = [0u8; 128];
-        loop {
-            match port.read(&mut buf) {
-                Ok(rc) => {
-                    log::info!(
-                        "Discarded {} characters: {:?}",
-                        rc,
-                        &std::str::from_utf8(&buf[0..rc])
-                    );
-                }
-                Err(error) if error.kind() == ErrorKind::TimedOut => {
-                    break;
-                }
-                Err(error) => return Err(error).context("communication error"),
+        run_console_command(
+            &mut port,
+            cmd,
+            &self.prompt,
+            self.command_timeout.get(),
+            callback,
+        )
+    }
+}
+
+/// Conducts the exchange of a single command on the HyperDebug console `port`, passing each line
+/// of output to `callback`, until `prompt` indicates that the command has finished.  Fails with
+/// `TransportError::Timeout` if that has not happened within `timeout`.
+fn run_console_command(
+    port: &mut (impl Read + Write),
+    cmd: &str,
+    prompt: &str,
+    timeout: Duration,
+    mut callback: impl FnMut(&str),
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let check_deadline = || -> Result<()> {
+        ensure!(
+            Instant::now() < deadline,
+            TransportError::Timeout(cmd.to_string())
+        );
+        Ok(())
+    };
+    let mut buf = [0u8; 128];
+    loop {
+        match port.read(&mut buf) {
+            Ok(rc) => {
+                check_deadline()?;
+                log::info!(
+                    "Discarded {} characters: {:?}",
+                    rc,
+                    std::str::from_utf8(&buf[0..rc])
+                );
             }
+            Err(error) if error.kind() == ErrorKind::TimedOut => {
+                break;
+            }
+            Err(error) => return Err(error).context("communication error"),
         }
-        // Send Ctrl-C, followed by the command, then newline.  This will discard any previous
-        // partial input, before executing our command.
-        port.write(format!("\x03{}\n", cmd).as_bytes())
-            .context("communication error")?;
-
-        // Now process response from HyperDebug.  First we expect to see the echo of the command
-        // we just "typed". Then zero, one or more lines of useful output, which we want to pass
-        // to the callback, and then a prompt characters, indicating that the output is
-        // complete.
-        let mut seen_echo = false;
-        let mut len: usize = 0;
-        let mut repeated_timeouts: u8 = 0;
-        loop {
-            // Read more data, appending to existing buffer.
-            match port.read(&mut buf[len..128]) {
-                Ok(rc) => {
-                    repeated_timeouts = 0;
-                    len += rc;
-                    // See if we have one or more lines terminated with endline, if so, process
-                    // those and remove from the buffer by shifting the remaning data to the
-                    // front of the buffer.
-                    let mut line_start = 0;
-                    for i in 0..len {
-                        if buf[i] == b'\n' {
-                            // Found a complete line, process it
-                            let mut line_end = i;
-                            while line_end > line_start && buf[line_end - 1] == 13 {
-                                line_end -= 1;
-                            }
-                            let line = std::str::from_utf8(&buf[line_start..line_end])
-                                .context("communication error")?;
-                            if seen_echo {
-                                callback(line);
-                            } else if line.len() >= cmd.len()
-                                && line[line.len() - cmd.len()..] == *cmd
-                            {
-                                seen_echo = true;
-                            }
-                            line_start = i + 1;
+    }
+    // Send Ctrl-C, followed by the command, then newline.  This will discard any previous
+    // partial input, before executing our command.
+    port.write(format!("\x03{}\n", cmd).as_bytes())
+        .context("communication error")?;
+
+    // Now process response from HyperDebug.  First we expect to see the echo of the command
+    // we just "typed". Then zero, one or more lines of useful output, which we want to pass
+    // to the callback, and then a prompt characters, indicating that the output is
+    // complete.
+    let mut seen_echo = false;
+    let mut len: usize = 0;
+    loop {
+        check_deadline()?;
+        // Read more data, appending to existing buffer.
+        match port.read(&mut buf[len..128]) {
+            Ok(rc) => {
+                len += rc;
+                // See if we have one or more lines terminated with endline, if so, process
+                // those and remove from the buffer by shifting the remaning data to the
+                // front of the buffer.
+                let mut line_start = 0;
+                for i in 0..len {
+                    if buf[i] == b'\n' {
+                        // Found a complete line, process it
+                        let mut line_end = i;
+                        while line_end > line_start && buf[line_end - 1] == 13 {
+                            line_end -= 1;
                         }
-                    }
-                    // If any lines were processed, remove from the buffer.
-                    if line_start > 0 {
-                        buf.rotate_left(line_start);
-                        len -= line_start;
-                    }
-                }
-                Err(error) if error.kind() == ErrorKind::TimedOut => {
-                    if std::str::from_utf8(&buf[0..len]).context("communication error")? == "> " {
-                        // No data arrived for a while, and the last we got was a command
-                        // prompt, this is what we expect when the command has finished
-                        // successfully.
-                        return Ok(());
-                    } else {
-                        // No data arrived for a while, but the last was no a command prompt,
-                        // this could be the command taking a little time to produce its output,
-                        // wait a longer while for additional data.  (Implemented by repeated
-                        // calls, alternatively could have been done by fiddling with timeout
-                        // setting of the underlying serial port object.)
-                        repeated_timeouts += 1;
-                        if repeated_timeouts == 10 {
-                            return Err(error).context("communication error");
+                        let line = std::str::from_utf8(&buf[line_start..line_end])
+                            .context("communication error")?;
+                        if seen_echo {
+                            callback(line);
+                        } else if line.len() >= cmd.len() && line[line.len() - cmd.len()..] == *cmd
+                        {
+                            seen_echo = true;
                         }
+                        line_start = i + 1;
                     }
                 }
-                Err(error) => return Err(error).context("communication error"),
+                // If any lines were processed, remove from the buffer.
+                if line_start > 0 {
+                    buf.rotate_left(line_start);
+                    len -= line_start;
+                }
+            }
+            Err(error) if error.kind() == ErrorKind::TimedOut => {
+                if std::str::from_utf8(&buf[0..len]).context("communication error")? == prompt {
+                    // No data arrived for a while, and the last we got was a command
+                    // prompt, this is what we expect when the command has finished
+                    // successfully.
+                    return Ok(());
+                }
+                // No data arrived for a while, but the last was no a command prompt, this could
+                // be the command taking a little time to produce its output, wait for additional
+                // data, until the deadline.
             }
+            Err(error) => return Err(error).context("communication error"),
         }
     }
 }
@@ -486,7 +818,12 @@ impl<T: Flavor> Transport for Hyperdebug<T> {
         ))
     }
 
+    fn description(&self) -> Result<UsbStrings> {
+        self.inner.usb_device.borrow().string_descriptors()
+    }
+
     fn apply_default_configuration(&self) -> Result<()> {
+        self.inner.spi_speeds.borrow_mut().clear();
         self.inner.cmd_no_output("reinit")
     }
 
@@ -582,12 +919,54 @@ impl<T: Flavor> Transport for Hyperdebug<T> {
             T::load_bitstream(self, fpga_program).map(|_| None)
         } else if let Some(clear) = action.downcast_ref::<ClearBitstream>() {
             T::clear_bitstream(clear).map(|_| None)
+        } else if action.downcast_ref::<GetUsbClaims>().is_some() {
+            Ok(Some(Box::new(self.inner.usb_claims())))
+        } else if action.downcast_ref::<GetTransferStats>().is_some() {
+            Ok(Some(Box::new(self.inner.transfer_stats())))
+        } else if action.downcast_ref::<ResetTransferStats>().is_some() {
+            self.inner.reset_stats();
+            Ok(None)
+        } else if let Some(set_trace) = action.downcast_ref::<SetSpiTrace>() {
+            self.inner.spi_trace_enabled.set(set_trace.enabled);
+            if !set_trace.enabled {
+                self.inner.spi_traces.borrow_mut().clear();
+            }
+            Ok(None)
+        } else if let Some(get_trace) = action.downcast_ref::<GetSpiTrace>() {
+            let (_, idx) = T::spi_index(&self.inner, &get_trace.instance)?;
+            match self.inner.spi_traces.borrow().get(&idx) {
+                Some(trace) => Ok(Some(Box::new(trace.clone()))),
+                None => Ok(None),
+            }
         } else {
             Err(TransportError::UnsupportedOperation.into())
         }
     }
 }
 
+/// Command for Transport::dispatch().  Retrieves the USB interfaces claimed by the transport so
+/// far, as `UsbClaims`.
+pub struct GetUsbClaims {}
+
+/// Command for Transport::dispatch().  Retrieves the counters of USB bulk operations and their
+/// failures, as `TransferStats`.
+pub struct GetTransferStats {}
+
+/// Command for Transport::dispatch().  Clears the counters of USB bulk operations.
+pub struct ResetTransferStats {}
+
+/// Command for Transport::dispatch().  Enables or disables recording of a `TransactionTrace` for
+/// each SPI transaction.
+pub struct SetSpiTrace {
+    pub enabled: bool,
+}
+
+/// Command for Transport::dispatch().  Retrieves the `TransactionTrace` of the most recent
+/// transaction on the given SPI bus, if tracing was enabled at the time.
+pub struct GetSpiTrace {
+    pub instance: String,
+}
+
 /// A `StandardFlavor` is a plain Hyperdebug board.
 pub struct StandardFlavor;
 
@@ -679,6 +1058,7 @@ impl Flavor for CW310Flavor {
         StandardFlavor::get_default_usb_pid()
     }
     fn load_bitstream(transport: &impl Transport, fpga_program: &FpgaProgram) -> Result<()> {
+        fpga_program.check_method(&[FpgaProgramMethod::Spi])?;
         if fpga_program.skip() {
             log::info!("Skip loading the __skip__ bitstream.");
             return Ok(());
@@ -686,7 +1066,7 @@ impl Flavor for CW310Flavor {
 
         // First, try to establish a connection to the native CW310 interface
         // which we will use for bitstream loading.
-        let cw310 = CW310::new(None, None, None, &[])?;
+        let cw310 = CW310::new(None, None, None, &[], &Default::default())?;
 
         // The transport does not provide name resolution for the IO interface
         // names, so: console=UART2 and RESET=CN10_29 on the Hyp+CW310.
@@ -697,19 +1077,20 @@ impl Flavor for CW310Flavor {
         if fpga_program.check_correct_version(&*uart, &*reset_pin)? {
             return Ok(());
         }
+        let bitstream = fpga_program.load_bitstream()?;
 
         // Program the FPGA bitstream.
         log::info!("Programming the FPGA bitstream.");
         let usb = cw310.device.borrow();
-        usb.spi1_enable(false)?;
-        usb.fpga_program(
-            &fpga_program.bitstream,
-            fpga_program.progress.as_ref().map(Box::as_ref),
-        )?;
-        Ok(())
+        fpga_program.program_with_retries(|| {
+            usb.spi1_enable(false)?;
+            let sent =
+                usb.fpga_program(&bitstream, fpga_program.progress.as_ref().map(Box::as_ref))?;
+            CW310::check_bitstream_sent(bitstream.len(), sent)
+        })
     }
     fn clear_bitstream(_clear: &ClearBitstream) -> Result<()> {
-        let cw310 = CW310::new(None, None, None, &[])?;
+        let cw310 = CW310::new(None, None, None, &[], &Default::default())?;
         let usb = cw310.device.borrow();
         usb.spi1_enable(false)?;
         usb.clear_bitstream()?;
@@ -720,3 +1101,208 @@ impl Flavor for CW310Flavor {
 lazy_static! {
     pub static ref SPI_REGEX: Regex = Regex::new("^ +([0-9]+) ([^ ]+) ([0-9]+)").unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bulk_packet_size() {
+        assert_eq!(bulk_packet_size(64, 64), 64);
+        assert_eq!(bulk_packet_size(512, 512), 512);
+        // Both endpoints must support the size.
+        assert_eq!(bulk_packet_size(512, 64), 64);
+        // Missing or nonsensical descriptor values.
+        assert_eq!(bulk_packet_size(0, 512), 64);
+        assert_eq!(bulk_packet_size(1024, 1024), 512);
+    }
+
+    #[test]
+    fn test_transfer_stats() {
+        let mut stats = TransferStats::default();
+        // Simulate a flaky connection, where every third operation fails.
+        for i in 0..9 {
+            let result: Result<()> = if i % 3 == 2 {
+                Err(TransportError::CommunicationError("timeout".to_string()).into())
+            } else {
+                Ok(())
+            };
+            stats.record_write(&result);
+        }
+        stats.record_read(&Ok(64usize));
+        stats.record_read::<usize>(&Err(TransportError::CommunicationError(
+            "pipe error".to_string(),
+        )
+        .into()));
+        assert_eq!(
+            stats,
+            TransferStats {
+                writes: 9,
+                reads: 2,
+                failed_writes: 3,
+                failed_reads: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_usb_claims() {
+        let spi = BulkInterface {
+            interface: 2,
+            in_endpoint: 0x83,
+            out_endpoint: 0x03,
+            max_packet_size: 64,
+        };
+        let i2c = BulkInterface {
+            interface: 4,
+            in_endpoint: 0x85,
+            out_endpoint: 0x05,
+            max_packet_size: 64,
+        };
+        let mut claims = UsbClaims::default();
+        // Opening two SPI targets claims the same interface twice.
+        claims.record("SPI", &spi);
+        claims.record("SPI", &spi);
+        claims.record("I2C", &i2c);
+        assert_eq!(
+            claims.interfaces,
+            vec![
+                ClaimedInterface {
+                    purpose: "SPI".to_string(),
+                    interface: 2,
+                    in_endpoint: 0x83,
+                    out_endpoint: 0x03,
+                },
+                ClaimedInterface {
+                    purpose: "I2C".to_string(),
+                    interface: 4,
+                    in_endpoint: 0x85,
+                    out_endpoint: 0x05,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_throttle_paces_burst() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        // 100 operations per second, up to 3 back-to-back.
+        let mut throttle = Throttle::new(100, 3).unwrap();
+
+        // The first three operations of a burst go through immediately.
+        assert_eq!(throttle.acquire(start), ms(0));
+        assert_eq!(throttle.acquire(start), ms(0));
+        assert_eq!(throttle.acquire(start), ms(0));
+        // Subsequent ones are spaced 10ms apart.
+        assert_eq!(throttle.acquire(start), ms(10));
+        assert_eq!(throttle.acquire(start + ms(10)), ms(10));
+        assert_eq!(throttle.acquire(start + ms(20)), ms(10));
+
+        // After being idle for a while, a full burst is allowed again.
+        let later = start + ms(1000);
+        assert_eq!(throttle.acquire(later), ms(0));
+        assert_eq!(throttle.acquire(later), ms(0));
+        assert_eq!(throttle.acquire(later), ms(0));
+        assert_eq!(throttle.acquire(later), ms(10));
+    }
+
+    #[test]
+    fn test_throttle_rejects_zero() {
+        assert!(Throttle::new(0, 3).is_err());
+        assert!(Throttle::new(100, 0).is_err());
+    }
+
+    /// Console which answers each command with its echo, fixed lines of output, and a prompt,
+    /// unless `silent`.
+    struct MockConsole {
+        silent: bool,
+        prompt: &'static str,
+        output: Vec<&'static str>,
+        rx: std::collections::VecDeque<u8>,
+        written: Vec<u8>,
+    }
+
+    impl Read for MockConsole {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.rx.is_empty() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            let len = std::cmp::min(buf.len(), self.rx.len());
+            for (byte, rx) in buf.iter_mut().zip(self.rx.drain(..len)) {
+                *byte = rx;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for MockConsole {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            if self.silent {
+                return Ok(buf.len());
+            }
+            let typed = std::str::from_utf8(buf).unwrap().trim_start_matches('\x03');
+            let mut response = format!("{}\r\n", typed.trim_end());
+            for line in &self.output {
+                response.push_str(&format!("{}\r\n", line));
+            }
+            response.push_str(self.prompt);
+            self.rx.extend(response.bytes());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_console_prompt() -> Result<()> {
+        let console = || MockConsole {
+            silent: false,
+            prompt: "hdbg$ ",
+            output: vec!["mode 0", "speed 1000000 Hz"],
+            // Leftovers from a previous session, discarded before sending the command.
+            rx: "stale\r\nhdbg$ ".bytes().collect(),
+            written: Vec::new(),
+        };
+        let mut port = console();
+        let mut lines = Vec::new();
+        let timeout = Duration::from_millis(100);
+        run_console_command(&mut port, "spi info 0", "hdbg$ ", timeout, |line| {
+            lines.push(line.to_string())
+        })?;
+        assert_eq!(lines, vec!["mode 0", "speed 1000000 Hz"]);
+        assert_eq!(port.written, b"\x03spi info 0\n");
+
+        // Waiting for the default prompt, completion of the command is never recognized.
+        assert!(run_console_command(
+            &mut console(),
+            "spi info 0",
+            Inner::DEFAULT_PROMPT,
+            timeout,
+            |_| ()
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_timeout() {
+        let mut port = MockConsole {
+            silent: true,
+            prompt: Inner::DEFAULT_PROMPT,
+            output: Vec::new(),
+            rx: Default::default(),
+            written: Vec::new(),
+        };
+        let timeout = Duration::from_millis(50);
+        let start = Instant::now();
+        let err = run_console_command(&mut port, "spi info 0", "> ", timeout, |_| ()).unwrap_err();
+        assert!(start.elapsed() >= timeout);
+        assert!(matches!(
+            err.downcast_ref::<TransportError>(),
+            Some(TransportError::Timeout(cmd)) if cmd == "spi info 0"
+        ));
+    }
+}
@@ -4,13 +4,18 @@
 
 use anyhow::{ensure, Result};
 use rusb::{Direction, Recipient, RequestType};
-use std::cell::Cell;
+use serde::{Deserialize, Serialize};
+use serde_annotate::Annotate;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::mem::size_of;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 use zerocopy::{AsBytes, FromBytes};
 
 use crate::io::spi::{
-    AssertChipSelect, MaxSizes, SpiError, Target, TargetChipDeassert, Transfer, TransferMode,
+    fill_chunks, AssertChipSelect, ClockPolarity, MaxSizes, SpiError, Target, TargetChipDeassert,
+    Transfer, TransferMode,
 };
 use crate::transport::hyperdebug::{BulkInterface, Inner};
 use crate::transport::TransportError;
@@ -22,6 +27,51 @@ pub struct HyperdebugSpiTarget {
     target_idx: u8,
     max_sizes: MaxSizes,
     cs_asserted_count: Cell<u32>,
+    deselect_timer: RefCell<DeselectTimer>,
+    /// Most recently requested transfer mode, reported if the firmware cannot tell the mode
+    /// actually in use.
+    transfer_mode: Cell<TransferMode>,
+    /// Clock idle level set by `set_idle_clock_high()`, overriding the polarity of
+    /// `transfer_mode`.
+    idle_clock_high: Cell<Option<bool>>,
+    /// Whether CS is active high, set by `set_cs_polarity()`.
+    cs_active_high: Cell<bool>,
+    /// Time allowed for each USB packet, set by `set_transaction_timeout()`.
+    packet_timeout: Cell<Option<Duration>>,
+    /// Measures phases of transactions, while tracing is enabled.
+    phase_timer: PhaseTimer,
+    /// Data carried by each USB packet of the most recent request, while tracing is enabled.
+    chunks: RefCell<Option<TransferChunks>>,
+}
+
+/// Keeps track of when CS was last deasserted, in order to enforce a minimum deselect time.
+/// HyperDebug firmware has no notion of deselect time, so this is done on the host, by sleeping
+/// before sending the request which asserts CS again.
+#[derive(Default)]
+struct DeselectTimer {
+    min_deselect_time: Duration,
+    last_deselect: Option<Instant>,
+}
+
+impl DeselectTimer {
+    /// Sleep until CS has been deasserted for at least the minimum deselect time.
+    fn wait(&self) {
+        if let Some(last_deselect) = self.last_deselect {
+            let remaining = self
+                .min_deselect_time
+                .saturating_sub(last_deselect.elapsed());
+            if !remaining.is_zero() {
+                std::thread::sleep(remaining);
+            }
+        }
+    }
+
+    /// Record that CS has just been deasserted.
+    fn deselected(&mut self) {
+        if !self.min_deselect_time.is_zero() {
+            self.last_deselect = Some(Instant::now());
+        }
+    }
 }
 
 const USB_SPI_PKT_ID_CMD_GET_USB_SPI_CONFIG: u16 = 0;
@@ -39,7 +89,8 @@ pub const USB_SPI_REQ_ENABLE: u8 = 0;
 pub const USB_SPI_REQ_ENABLE_AP: u8 = 2;
 pub const USB_SPI_REQ_ENABLE_EC: u8 = 3;
 
-const USB_MAX_SIZE: usize = 64;
+/// Size of packet buffers, the actual packet size is given by `BulkInterface::max_packet_size()`.
+const USB_MAX_SIZE: usize = BulkInterface::HIGH_SPEED_PACKET_SIZE;
 const FULL_DUPLEX: usize = 65535;
 
 #[derive(AsBytes, FromBytes, Debug, Default)]
@@ -128,10 +179,11 @@ struct CmdChipSelect {
     flags: u16,
 }
 impl CmdChipSelect {
-    fn new(assert_chip_select: bool) -> Self {
+    /// The firmware asserts CS by driving it low, for active high CS the request is inverted.
+    fn new(assert_chip_select: bool, active_high: bool) -> Self {
         Self {
             packet_id: USB_SPI_PKT_ID_CMD_CHIP_SELECT,
-            flags: u16::from(assert_chip_select),
+            flags: u16::from(assert_chip_select ^ active_high),
         }
     }
 }
@@ -171,14 +223,32 @@ impl HyperdebugSpiTarget {
         )?;
 
         // Exclusively claim SPI interface, preparing for bulk transfers.
-        usb_handle.claim_interface(spi_interface.interface)?;
+        inner.claim_interface(&mut usb_handle, "SPI", spi_interface)?;
+
+        if inner.flush_on_open.get() {
+            let discarded = flush_stale_packets(
+                &mut |buf| {
+                    timeout_as_none(usb_handle.read_bulk_timeout(
+                        spi_interface.in_endpoint,
+                        buf,
+                        FLUSH_TIMEOUT,
+                    ))
+                },
+                spi_interface.max_packet_size(),
+            )?;
+            if discarded > 0 {
+                log::warn!("Discarded {} stale bytes from HyperDebug SPI", discarded);
+            }
+        }
 
         // Initial bulk request/response to query capabilities.
+        inner.throttle_bulk();
         usb_handle.write_bulk(
             spi_interface.out_endpoint,
             &USB_SPI_PKT_ID_CMD_GET_USB_SPI_CONFIG.to_le_bytes(),
         )?;
         let mut resp: RspUsbSpiConfig = Default::default();
+        inner.throttle_bulk();
         let rc = usb_handle.read_bulk(spi_interface.in_endpoint, resp.as_bytes_mut())?;
         ensure!(
             rc == size_of::<RspUsbSpiConfig>(),
@@ -210,9 +280,36 @@ impl HyperdebugSpiTarget {
                 write: resp.max_write_chunk as usize,
             },
             cs_asserted_count: Cell::new(0),
+            deselect_timer: RefCell::default(),
+            transfer_mode: Cell::new(TransferMode::Mode0),
+            idle_clock_high: Cell::new(None),
+            cs_active_high: Cell::new(false),
+            packet_timeout: Cell::new(None),
+            phase_timer: PhaseTimer::new(Box::new(Instant::now)),
+            chunks: RefCell::new(None),
         })
     }
 
+    /// Sends the console command setting the speed of the SPI bus, bypassing the cache.
+    fn send_speed(&self, frequency: u32) -> Result<()> {
+        self.inner
+            .cmd_no_output(&format!("spi set speed {} {}", &self.target_idx, frequency))
+            .or_else(|_| {
+                self.inner
+                    .cmd_no_output(&format!("spisetspeed {} {}", &self.target_idx, frequency))
+            })
+    }
+
+    /// HyperDebug firmware has no command for selecting the transfer mode, and always uses
+    /// `Mode0`, verify that the given mode is that one.
+    fn apply_transfer_mode(&self, mode: TransferMode) -> Result<()> {
+        ensure!(
+            mode == TransferMode::Mode0,
+            TransportError::UnsupportedOperation
+        );
+        Ok(())
+    }
+
     /// Instruct HyperDebug device which SPI bus subsequent transactions should be forwarded to.
     fn select_my_spi_bus(&self) -> Result<()> {
         if self.inner.selected_spi.get() != self.target_idx {
@@ -230,74 +327,38 @@ impl HyperdebugSpiTarget {
 
     /// Transmit data for a single SPI operation, using one or more USB packets.
     fn transmit(&self, wbuf: &[u8], rbuf_len: usize) -> Result<()> {
-        let mut req = CmdTransferStart::new();
-        req.write_count = wbuf.len() as u16;
-        req.read_count = rbuf_len as u16;
-        let databytes = std::cmp::min(USB_MAX_SIZE - 6, wbuf.len());
-        req.data[0..databytes].clone_from_slice(&wbuf[0..databytes]);
-        self.usb_write_bulk(&req.as_bytes()[0..6 + databytes])?;
-        let mut index = databytes;
-
-        while index < wbuf.len() {
-            let mut req = CmdTransferContinue::new();
-            req.data_index = index as u16;
-            let databytes = std::cmp::min(USB_MAX_SIZE - 4, wbuf.len() - index);
-            req.data[0..databytes].clone_from_slice(&wbuf[index..index + databytes]);
-            self.usb_write_bulk(&req.as_bytes()[0..4 + databytes])?;
-            index += databytes;
+        // Each request starts a new record of chunks.
+        if let Some(chunks) = self.chunks.borrow_mut().as_mut() {
+            *chunks = TransferChunks::default();
         }
-        Ok(())
+        timed_transmit(
+            &self.phase_timer,
+            &mut |buf| {
+                if let Some(chunks) = self.chunks.borrow_mut().as_mut() {
+                    chunks.record_write(buf);
+                }
+                self.usb_write_bulk(buf)
+            },
+            self.interface.max_packet_size(),
+            wbuf,
+            rbuf_len,
+        )
     }
 
     /// Receive data for a single SPI operation, using one or more USB packets.
     fn receive(&self, rbuf: &mut [u8]) -> Result<()> {
-        let mut resp = RspTransferStart::new();
-        let bytecount = self.usb_read_bulk(resp.as_bytes_mut())?;
-        ensure!(
-            bytecount >= 4,
-            TransportError::CommunicationError(
-                "Unrecognized reponse to TRANSFER_START".to_string()
-            )
-        );
-        ensure!(
-            resp.packet_id == USB_SPI_PKT_ID_RSP_TRANSFER_START,
-            TransportError::CommunicationError(
-                "Unrecognized reponse to TRANSFER_START".to_string()
-            )
-        );
-        ensure!(
-            resp.status_code == 0,
-            TransportError::CommunicationError(format!("SPI error ({})", resp.status_code))
-        );
-        let databytes = bytecount - 4;
-        rbuf[0..databytes].clone_from_slice(&resp.data[0..databytes]);
-        let mut index = databytes;
-        while index < rbuf.len() {
-            let mut resp = RspTransferContinue::new();
-            let bytecount = self.usb_read_bulk(resp.as_bytes_mut())?;
-            ensure!(
-                bytecount > 4,
-                TransportError::CommunicationError(
-                    "Unrecognized reponse to TRANSFER_START".to_string()
-                )
-            );
-            ensure!(
-                resp.packet_id == USB_SPI_PKT_ID_RSP_TRANSFER_CONTINUE,
-                TransportError::CommunicationError(
-                    "Unrecognized reponse to TRANSFER_START".to_string()
-                )
-            );
-            ensure!(
-                resp.data_index == index as u16,
-                TransportError::CommunicationError(
-                    "Unexpected byte index in reponse to TRANSFER_START".to_string()
-                )
-            );
-            let databytes = bytecount - 4;
-            rbuf[index..index + databytes].clone_from_slice(&resp.data[0..databytes]);
-            index += databytes;
-        }
-        Ok(())
+        timed_receive(
+            &self.phase_timer,
+            &mut |buf| {
+                let len = self.usb_read_bulk(buf)?;
+                if let Some(chunks) = self.chunks.borrow_mut().as_mut() {
+                    chunks.record_read(&buf[..len]);
+                }
+                Ok(len)
+            },
+            self.interface.max_packet_size(),
+            rbuf,
+        )
     }
 
     /// Request assertion or deassertion of chip select
@@ -305,12 +366,14 @@ impl HyperdebugSpiTarget {
         let mut count = self.cs_asserted_count.get();
         if assert {
             if count == 0 {
+                self.deselect_timer.borrow().wait();
                 self._do_assert_cs(assert)?;
             }
             count += 1;
         } else {
             if count == 1 {
                 self._do_assert_cs(assert)?;
+                self.deselect_timer.borrow_mut().deselected();
             }
             count -= 1;
         }
@@ -319,11 +382,14 @@ impl HyperdebugSpiTarget {
     }
 
     fn _do_assert_cs(&self, assert: bool) -> Result<()> {
-        let req = CmdChipSelect::new(assert);
-        self.usb_write_bulk(req.as_bytes())?;
+        let req = CmdChipSelect::new(assert, self.cs_active_high.get());
+        self.phase_timer
+            .time(Phase::UsbWrite, || self.usb_write_bulk(req.as_bytes()))?;
 
         let mut resp = RspChipSelect::new();
-        let bytecount = self.usb_read_bulk(resp.as_bytes_mut())?;
+        let bytecount = self
+            .phase_timer
+            .time(Phase::Firmware, || self.usb_read_bulk(resp.as_bytes_mut()))?;
         ensure!(
             bytecount >= 4,
             TransportError::CommunicationError("Unrecognized reponse to CHIP_SELECT".to_string())
@@ -341,28 +407,418 @@ impl HyperdebugSpiTarget {
 
     /// Send one USB packet.
     fn usb_write_bulk(&self, buf: &[u8]) -> Result<()> {
-        self.inner
-            .usb_device
-            .borrow()
-            .write_bulk(self.interface.out_endpoint, buf)?;
-        Ok(())
+        write_packet_timeout(self, self.packet_timeout.get(), buf)
     }
 
     /// Receive one USB packet.
     fn usb_read_bulk(&self, buf: &mut [u8]) -> Result<usize> {
+        read_packet_timeout(self, self.packet_timeout.get(), buf)
+    }
+
+    /// Time spent in each phase of the most recent transaction on this SPI bus, recorded only
+    /// while tracing is enabled through the `SetSpiTrace` dispatch command.
+    pub fn last_transaction_metrics(&self) -> Option<TransactionMetrics> {
         self.inner
-            .usb_device
+            .spi_traces
             .borrow()
-            .read_bulk(self.interface.in_endpoint, buf)
+            .get(&self.target_idx)
+            .map(|trace| trace.metrics.clone())
+    }
+}
+
+/// Phase of a transaction, to which time spent in USB operations is attributed.
+#[derive(Clone, Copy)]
+enum Phase {
+    UsbWrite,
+    Firmware,
+    UsbRead,
+}
+
+/// Accumulates the time spent in each phase of a transaction, as measured by `clock`.
+struct PhaseTimer {
+    clock: Box<dyn Fn() -> Instant>,
+    start: Cell<Option<Instant>>,
+    metrics: RefCell<TransactionMetrics>,
+}
+
+impl PhaseTimer {
+    fn new(clock: Box<dyn Fn() -> Instant>) -> Self {
+        Self {
+            clock,
+            start: Cell::new(None),
+            metrics: RefCell::default(),
+        }
+    }
+
+    /// Begin measuring a new transaction.
+    fn start(&self) {
+        self.metrics.take();
+        self.start.set(Some((self.clock)()));
+    }
+
+    /// Runs `f`, attributing its duration to `phase`, if a transaction is being measured.
+    fn time<R>(&self, phase: Phase, f: impl FnOnce() -> R) -> R {
+        if self.start.get().is_none() {
+            return f();
+        }
+        let begin = (self.clock)();
+        let result = f();
+        let elapsed = (self.clock)().saturating_duration_since(begin);
+        let mut metrics = self.metrics.borrow_mut();
+        match phase {
+            Phase::UsbWrite => metrics.usb_write += elapsed,
+            Phase::Firmware => metrics.firmware += elapsed,
+            Phase::UsbRead => metrics.usb_read += elapsed,
+        }
+        result
     }
+
+    /// Stop measuring, returning the time spent in each phase since `start()`.
+    fn finish(&self) -> TransactionMetrics {
+        let mut metrics = self.metrics.take();
+        if let Some(start) = self.start.take() {
+            metrics.host = (self.clock)()
+                .saturating_duration_since(start)
+                .saturating_sub(metrics.usb_write + metrics.firmware + metrics.usb_read);
+        }
+        metrics
+    }
+}
+
+/// Like `transmit_packets()`, attributing the time spent sending to `Phase::UsbWrite`.
+fn timed_transmit(
+    timer: &PhaseTimer,
+    write_packet: &mut dyn FnMut(&[u8]) -> Result<()>,
+    packet_size: usize,
+    wbuf: &[u8],
+    rbuf_len: usize,
+) -> Result<()> {
+    transmit_packets(
+        &mut |buf| timer.time(Phase::UsbWrite, || write_packet(buf)),
+        packet_size,
+        wbuf,
+        rbuf_len,
+    )
+}
+
+/// Access to the bulk endpoints of a HyperDebug SPI interface.
+trait BulkIo {
+    fn bulk_write(&self, buf: &[u8], timeout: Option<Duration>) -> Result<()>;
+    fn bulk_read(&self, buf: &mut [u8], timeout: Option<Duration>) -> Result<usize>;
+    fn packet_size(&self) -> usize;
+}
+
+impl BulkIo for HyperdebugSpiTarget {
+    fn bulk_write(&self, buf: &[u8], timeout: Option<Duration>) -> Result<()> {
+        self.inner
+            .write_bulk_timeout(self.interface.out_endpoint, buf, timeout)
+    }
+    fn bulk_read(&self, buf: &mut [u8], timeout: Option<Duration>) -> Result<usize> {
+        self.inner
+            .read_bulk_timeout(self.interface.in_endpoint, buf, timeout)
+    }
+    fn packet_size(&self) -> usize {
+        self.interface.max_packet_size()
+    }
+}
+
+/// Time to wait for stale data on the bulk IN endpoint, see `flush_stale_packets()`.
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Turns a USB timeout into `None`, for use with `flush_stale_packets()`.
+fn timeout_as_none(result: Result<usize>) -> Result<Option<usize>> {
+    match result {
+        Ok(len) => Ok(Some(len)),
+        Err(e) if matches!(e.downcast_ref(), Some(rusb::Error::Timeout)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Sends one packet, failing if it is not accepted within `timeout`, see
+/// `read_packet_timeout()`.
+fn write_packet_timeout(io: &dyn BulkIo, timeout: Option<Duration>, buf: &[u8]) -> Result<()> {
+    let result = io.bulk_write(buf, timeout);
+    flush_after_timeout(io, timeout, &result);
+    map_packet_timeout(timeout, result)
+}
+
+/// Receives one packet, failing if none arrives within `timeout`.  The response may still
+/// arrive later, and would then be taken for that of the next request, so any data arriving
+/// shortly after the timeout is discarded.
+fn read_packet_timeout(
+    io: &dyn BulkIo,
+    timeout: Option<Duration>,
+    buf: &mut [u8],
+) -> Result<usize> {
+    let result = io.bulk_read(buf, timeout);
+    flush_after_timeout(io, timeout, &result);
+    map_packet_timeout(timeout, result)
+}
+
+/// Flushes the IN endpoint if `result` is the expiry of `timeout`.  Failure to flush is only
+/// logged, the timeout being the error reported.
+fn flush_after_timeout<T>(io: &dyn BulkIo, timeout: Option<Duration>, result: &Result<T>) {
+    let timed_out = matches!(
+        result.as_ref().map_err(|e| e.downcast_ref()),
+        Err(Some(rusb::Error::Timeout))
+    );
+    if timeout.is_none() || !timed_out {
+        return;
+    }
+    match flush_stale_packets(
+        &mut |buf| timeout_as_none(io.bulk_read(buf, Some(FLUSH_TIMEOUT))),
+        io.packet_size(),
+    ) {
+        Ok(0) => (),
+        Ok(discarded) => log::warn!("Discarded {} late bytes from HyperDebug SPI", discarded),
+        Err(e) => log::warn!("Unable to flush HyperDebug SPI after timeout: {}", e),
+    }
+}
+
+/// Reports expiry of the `timeout` set by `set_transaction_timeout()` as a
+/// `TransportError::CommunicationError`.
+fn map_packet_timeout<T>(timeout: Option<Duration>, result: Result<T>) -> Result<T> {
+    match (timeout, result) {
+        (Some(timeout), Err(e)) if matches!(e.downcast_ref(), Some(rusb::Error::Timeout)) => {
+            Err(TransportError::CommunicationError(format!(
+                "SPI timeout, no USB packet within {:?}",
+                timeout
+            ))
+            .into())
+        }
+        (_, result) => result,
+    }
+}
+
+/// Maximum number of stale packets discarded by `flush_stale_packets()`.
+const MAX_STALE_PACKETS: usize = 256;
+
+/// Reads and discards packets through `read_packet` until it times out, returning `None`, and
+/// returns the number of bytes discarded.  A device still sending after `MAX_STALE_PACKETS`
+/// is reported as an `Err`, rather than flushed forever.
+fn flush_stale_packets(
+    read_packet: &mut dyn FnMut(&mut [u8]) -> Result<Option<usize>>,
+    packet_size: usize,
+) -> Result<usize> {
+    let mut buf = vec![0u8; packet_size];
+    let mut discarded = 0;
+    for _ in 0..MAX_STALE_PACKETS {
+        match read_packet(&mut buf)? {
+            Some(len) => discarded += len,
+            None => return Ok(discarded),
+        }
+    }
+    Err(TransportError::CommunicationError(
+        "HyperDebug SPI keeps sending unrequested data".to_string(),
+    )
+    .into())
+}
+
+/// Like `receive_packets()`, attributing the wait for the first packet to `Phase::Firmware`, as
+/// HyperDebug carries out the SPI operation before responding, and the time spent receiving any
+/// further packets to `Phase::UsbRead`.
+fn timed_receive(
+    timer: &PhaseTimer,
+    read_packet: &mut dyn FnMut(&mut [u8]) -> Result<usize>,
+    packet_size: usize,
+    rbuf: &mut [u8],
+) -> Result<()> {
+    let mut phase = Phase::Firmware;
+    receive_packets(
+        &mut |buf| {
+            let result = timer.time(phase, || read_packet(buf));
+            phase = Phase::UsbRead;
+            result
+        },
+        packet_size,
+        rbuf,
+    )
+}
+
+/// Transmit data for a single SPI operation, as USB packets of at most `packet_size` bytes sent
+/// through `write_packet`.
+fn transmit_packets(
+    write_packet: &mut dyn FnMut(&[u8]) -> Result<()>,
+    packet_size: usize,
+    wbuf: &[u8],
+    rbuf_len: usize,
+) -> Result<()> {
+    let mut req = CmdTransferStart::new();
+    req.write_count = wbuf.len() as u16;
+    req.read_count = rbuf_len as u16;
+    let databytes = std::cmp::min(packet_size - 6, wbuf.len());
+    req.data[0..databytes].clone_from_slice(&wbuf[0..databytes]);
+    write_packet(&req.as_bytes()[0..6 + databytes])?;
+    let mut index = databytes;
+
+    while index < wbuf.len() {
+        let mut req = CmdTransferContinue::new();
+        req.data_index = index as u16;
+        let databytes = std::cmp::min(packet_size - 4, wbuf.len() - index);
+        req.data[0..databytes].clone_from_slice(&wbuf[index..index + databytes]);
+        write_packet(&req.as_bytes()[0..4 + databytes])?;
+        index += databytes;
+    }
+    Ok(())
+}
+
+/// Receive data for a single SPI operation, using one or more USB packets of at most
+/// `packet_size` bytes obtained through `read_packet`.  If an unexpected packet is encountered,
+/// the remainder of the response is drained before returning an error, such that stale packets
+/// will not confuse the next transaction, and the operation can be cleanly retried.
+fn receive_packets(
+    read_packet: &mut dyn FnMut(&mut [u8]) -> Result<usize>,
+    packet_size: usize,
+    rbuf: &mut [u8],
+) -> Result<()> {
+    // Never offer a buffer larger than one packet, as that could merge several packets.
+    let mut resp = RspTransferStart::new();
+    let bytecount = read_packet(&mut resp.as_bytes_mut()[..packet_size])?;
+    if bytecount < 4 || resp.packet_id != USB_SPI_PKT_ID_RSP_TRANSFER_START {
+        return Err(resync(
+            read_packet,
+            packet_size,
+            bytecount,
+            "Unrecognized reponse to TRANSFER_START",
+        ));
+    }
+    ensure!(
+        resp.status_code == 0,
+        TransportError::CommunicationError(format!("SPI error ({})", resp.status_code))
+    );
+    let databytes = bytecount - 4;
+    rbuf[0..databytes].clone_from_slice(&resp.data[0..databytes]);
+    let mut index = databytes;
+    while index < rbuf.len() {
+        let mut resp = RspTransferContinue::new();
+        let bytecount = read_packet(&mut resp.as_bytes_mut()[..packet_size])?;
+        if bytecount <= 4 || resp.packet_id != USB_SPI_PKT_ID_RSP_TRANSFER_CONTINUE {
+            return Err(resync(
+                read_packet,
+                packet_size,
+                bytecount,
+                "Unrecognized reponse to TRANSFER_START",
+            ));
+        }
+        if resp.data_index != index as u16 {
+            return Err(resync(
+                read_packet,
+                packet_size,
+                bytecount,
+                "Unexpected byte index in reponse to TRANSFER_START",
+            ));
+        }
+        let databytes = bytecount - 4;
+        rbuf[index..index + databytes].clone_from_slice(&resp.data[0..databytes]);
+        index += databytes;
+    }
+    Ok(())
+}
+
+/// Called after receiving an unexpected packet of `bytecount` bytes.  A full-sized packet means
+/// that more may follow, keep reading until a short packet or an empty endpoint indicates that
+/// HyperDebug has nothing more to send.
+fn resync(
+    read_packet: &mut dyn FnMut(&mut [u8]) -> Result<usize>,
+    packet_size: usize,
+    mut bytecount: usize,
+    msg: &str,
+) -> anyhow::Error {
+    let mut buf = [0u8; USB_MAX_SIZE];
+    while bytecount == packet_size {
+        bytecount = read_packet(&mut buf[..packet_size]).unwrap_or(0);
+    }
+    TransportError::CommunicationError(format!("{} (resynchronized)", msg)).into()
+}
+
+/// Sets the speed of SPI bus `idx` by calling `set`, unless the same speed was most recently
+/// requested, saving the latency of a HyperDebug console command.
+fn set_speed_cached(
+    speeds: &RefCell<HashMap<u8, u32>>,
+    idx: u8,
+    frequency: u32,
+    set: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    if speeds.borrow().get(&idx) == Some(&frequency) {
+        return Ok(());
+    }
+    // Forget the previous speed, as it is unknown what happens if setting fails.
+    speeds.borrow_mut().remove(&idx);
+    set()?;
+    speeds.borrow_mut().insert(idx, frequency);
+    Ok(())
+}
+
+/// Runs `run` with SPI bus `idx` set to `frequency`, then restores the speed most recently
+/// requested by the caller, rather than the one rounded by the firmware.  Only if no speed has
+/// been requested is the current speed obtained through `query`.
+fn run_at_speed_cached<T>(
+    speeds: &RefCell<HashMap<u8, u32>>,
+    idx: u8,
+    frequency: u32,
+    query: impl FnOnce() -> Result<u32>,
+    set: impl Fn(u32) -> Result<()>,
+    run: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let cached = speeds.borrow().get(&idx).copied();
+    let previous = match cached {
+        Some(speed) => speed,
+        None => query()?,
+    };
+    set_speed_cached(speeds, idx, frequency, || set(frequency))?;
+    let result = run();
+    set_speed_cached(speeds, idx, previous, || set(previous))?;
+    result
+}
+
+/// The mode to apply in the firmware, given the most recently requested mode and clock idle
+/// level.  The idle level, if set, takes precedence over the polarity of the mode.
+fn effective_transfer_mode(mode: TransferMode, idle_clock_high: Option<bool>) -> TransferMode {
+    match idle_clock_high {
+        Some(true) => mode.with_polarity(ClockPolarity::IdleHigh),
+        Some(false) => mode.with_polarity(ClockPolarity::IdleLow),
+        None => mode,
+    }
+}
+
+/// Extracts the transfer mode from a line of `spi info` output, e.g.
+/// `  0 SPI2 1000000 mode 3`.  Older firmware does not report the mode.
+fn parse_transfer_mode(info: &str) -> Option<TransferMode> {
+    let mut words = info.split_whitespace();
+    words.find(|&w| w == "mode")?;
+    words.next()?.parse().ok()
+}
+
+/// Extracts the highest speed supported by the hardware from a line of `spi info` output, e.g.
+/// `  0 SPI2 1000000 mode 3 max 24000000`.  Older firmware does not report it.
+fn parse_hardware_max_speed(info: &str) -> Option<u32> {
+    let mut words = info.split_whitespace();
+    words.find(|&w| w == "max")?;
+    words.next()?.parse().ok()
+}
+
+/// The transfer mode reported in `spi info` output, or `cached`, the mode applied by the most
+/// recent `set_transfer_mode()` or `set_idle_clock_high()`, if the output does not include the
+/// mode.
+fn reported_transfer_mode(info: &str, cached: TransferMode) -> TransferMode {
+    parse_transfer_mode(info).unwrap_or(cached)
 }
 
 impl Target for HyperdebugSpiTarget {
     fn get_transfer_mode(&self) -> Result<TransferMode> {
-        Ok(TransferMode::Mode0)
+        // Query the firmware, as it may not have honored the most recently requested mode.
+        let info = self
+            .inner
+            .cmd_one_line_output(&format!("spi info {}", self.target_idx))?;
+        Ok(reported_transfer_mode(
+            &info,
+            effective_transfer_mode(self.transfer_mode.get(), self.idle_clock_high.get()),
+        ))
     }
-    fn set_transfer_mode(&self, _mode: TransferMode) -> Result<()> {
-        todo!();
+    fn set_transfer_mode(&self, mode: TransferMode) -> Result<()> {
+        self.apply_transfer_mode(effective_transfer_mode(mode, self.idle_clock_high.get()))?;
+        self.transfer_mode.set(mode);
+        Ok(())
     }
 
     fn get_bits_per_word(&self) -> Result<u32> {
@@ -395,12 +851,54 @@ impl Target for HyperdebugSpiTarget {
         Ok(captures.get(3).unwrap().as_str().parse().unwrap())
     }
     fn set_max_speed(&self, frequency: u32) -> Result<()> {
-        self.inner
-            .cmd_no_output(&format!("spi set speed {} {}", &self.target_idx, frequency))
-            .or_else(|_| {
-                self.inner
-                    .cmd_no_output(&format!("spisetspeed {} {}", &self.target_idx, frequency))
-            })
+        set_speed_cached(&self.inner.spi_speeds, self.target_idx, frequency, || {
+            self.send_speed(frequency)
+        })
+    }
+
+    fn get_hardware_max_speed(&self) -> Result<u32> {
+        let info = self
+            .inner
+            .cmd_one_line_output(&format!("spi info {}", self.target_idx))?;
+        parse_hardware_max_speed(&info).ok_or_else(|| TransportError::UnsupportedOperation.into())
+    }
+
+    /// HyperDebug firmware always deasserts CS (high) between transactions, and has no command
+    /// to change that.
+    fn set_cs_idle_level(&self, high: bool) -> Result<()> {
+        ensure!(high, TransportError::UnsupportedOperation);
+        Ok(())
+    }
+
+    /// Only the explicit assertion and deassertion of CS, through `assert_cs()`, is inverted.
+    /// The firmware drives CS itself during transactions run without an `AssertChipSelect`
+    /// object held, always active low, so such transactions do not work with active high CS.
+    /// CS is driven to the deasserted level of the new polarity right away, and so the polarity
+    /// cannot be changed while CS is held asserted.
+    fn set_cs_polarity(&self, active_high: bool) -> Result<()> {
+        ensure!(
+            self.cs_asserted_count.get() == 0,
+            SpiError::InvalidOption("Cannot change CS polarity while CS is asserted".to_string())
+        );
+        let previous = self.cs_active_high.replace(active_high);
+        self.select_my_spi_bus()?;
+        if let Err(e) = self._do_assert_cs(false) {
+            self.cs_active_high.set(previous);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// HyperDebug firmware has no separate setting for the clock idle level, the requested
+    /// polarity is instead combined with the transfer mode, which must then be one supported by
+    /// the firmware.
+    fn set_idle_clock_high(&self, high: bool) -> Result<()> {
+        self.apply_transfer_mode(effective_transfer_mode(
+            self.transfer_mode.get(),
+            Some(high),
+        ))?;
+        self.idle_clock_high.set(Some(high));
+        Ok(())
     }
 
     fn get_max_transfer_count(&self) -> Result<usize> {
@@ -413,122 +911,373 @@ impl Target for HyperdebugSpiTarget {
         Ok(self.max_sizes)
     }
 
+    fn set_min_deselect_time(&self, duration: Duration) -> Result<()> {
+        self.deselect_timer.borrow_mut().min_deselect_time = duration;
+        Ok(())
+    }
+
+    /// Applies to each USB packet sent or received on the SPI interface, not to console commands
+    /// such as changing the speed.
+    fn set_transaction_timeout(&self, timeout: Duration) -> Result<()> {
+        self.packet_timeout.set(Some(timeout));
+        Ok(())
+    }
+
+    fn run_transaction_at_speed(&self, transaction: &mut [Transfer], max_speed: u32) -> Result<()> {
+        run_at_speed_cached(
+            &self.inner.spi_speeds,
+            self.target_idx,
+            max_speed,
+            || self.get_max_speed(),
+            |speed| self.send_speed(speed),
+            || self.run_transaction(transaction),
+        )
+    }
+
     fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
-        let mut idx: usize = 0;
+        // Unless CS is being held asserted, each transaction starts and ends with CS transitions.
+        if self.cs_asserted_count.get() > 0 {
+            return self.do_run_transaction(transaction);
+        }
+        self.deselect_timer.borrow().wait();
+        let result = self.do_run_transaction(transaction);
+        self.deselect_timer.borrow_mut().deselected();
+        result
+    }
+
+    fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+        self.do_assert_cs(true)?;
+        Ok(AssertChipSelect::new(self))
+    }
+}
+
+impl HyperdebugSpiTarget {
+    fn do_run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
         self.select_my_spi_bus()?;
+        if !self.inner.spi_trace_enabled.get() {
+            return dispatch_transaction(self, &self.max_sizes, transaction).map(|_| ());
+        }
+        let counting = CountingOps {
+            ops: self,
+            round_trips: Cell::new(0),
+        };
+        self.phase_timer.start();
+        *self.chunks.borrow_mut() = Some(TransferChunks::default());
+        let result = dispatch_transaction(&counting, &self.max_sizes, transaction);
+        let metrics = self.phase_timer.finish();
+        let chunks = self.chunks.borrow_mut().take().unwrap_or_default();
+        let path = result?;
+        self.inner.spi_traces.borrow_mut().insert(
+            self.target_idx,
+            TransactionTrace {
+                path,
+                round_trips: counting.round_trips.get(),
+                metrics,
+                chunks,
+            },
+        );
+        Ok(())
+    }
+}
 
-        // Simple cases involving using only a single USB command can be handled without explicit
-        // embracing commands to hold CS asserted across a sequence of transfers, use that for
-        // avoiding several USB roundtrips in the common cases.
-        match transaction {
-            [Transfer::Write(wbuf), Transfer::Read(rbuf)] => {
-                ensure!(
-                    wbuf.len() <= self.max_sizes.write,
-                    SpiError::InvalidDataLength(wbuf.len())
-                );
-                ensure!(
-                    rbuf.len() <= self.max_sizes.read,
-                    SpiError::InvalidDataLength(rbuf.len())
-                );
-                self.transmit(wbuf, rbuf.len())?;
-                self.receive(rbuf)?;
-                return Ok(());
-            }
-            [Transfer::Write(wbuf)] => {
-                ensure!(
-                    wbuf.len() <= self.max_sizes.write,
-                    SpiError::InvalidDataLength(wbuf.len())
-                );
-                self.transmit(wbuf, 0)?;
-                self.receive(&mut [])?;
-                return Ok(());
+/// Code path taken by `run_transaction()`, as recorded in a `TransactionTrace`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TransactionPath {
+    /// Single USB request for a write followed by a read.
+    WriteRead,
+    /// Single USB request for a write.
+    Write,
+    /// Single USB request for two writes, combined.
+    WriteWrite,
+    /// Single USB request for a read.
+    Read,
+    /// Single USB request for a full-duplex transfer.
+    Both,
+    /// General loop, holding CS asserted across a request per transfer.
+    General,
+}
+
+/// Record of how the most recent transaction on a SPI bus was carried out, for debugging
+/// performance.  Only collected after being enabled through the `SetSpiTrace` dispatch command.
+#[derive(Annotate, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TransactionTrace {
+    pub path: TransactionPath,
+    /// Number of requests awaiting a response from HyperDebug, including CS changes and speed
+    /// changes through the console.
+    pub round_trips: u32,
+    pub metrics: TransactionMetrics,
+    /// Split of the last request of the transaction into USB packets.
+    pub chunks: TransferChunks,
+}
+
+/// Data bytes carried by each USB packet of a request and its response, as `(offset, length)`
+/// within the write and read buffers.  Decoded from the packets as sent and received, for
+/// pinpointing errors at packet boundaries.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TransferChunks {
+    pub write: Vec<(usize, usize)>,
+    pub read: Vec<(usize, usize)>,
+}
+
+impl TransferChunks {
+    /// Records the data of a TRANSFER_START or TRANSFER_CONTINUE request packet.
+    fn record_write(&mut self, packet: &[u8]) {
+        self.write.extend(packet_chunk(
+            packet,
+            USB_SPI_PKT_ID_CMD_TRANSFER_START,
+            6,
+            USB_SPI_PKT_ID_CMD_TRANSFER_CONTINUE,
+        ));
+    }
+
+    /// Records the data of a TRANSFER_START or TRANSFER_CONTINUE response packet.
+    fn record_read(&mut self, packet: &[u8]) {
+        self.read.extend(packet_chunk(
+            packet,
+            USB_SPI_PKT_ID_RSP_TRANSFER_START,
+            4,
+            USB_SPI_PKT_ID_RSP_TRANSFER_CONTINUE,
+        ));
+    }
+}
+
+/// Offset and length of the data carried by `packet`, if it is of kind `start_id`, with a header
+/// of `start_header` bytes and data starting at offset zero, or of kind `continue_id`, with a
+/// four byte header giving the offset.
+fn packet_chunk(
+    packet: &[u8],
+    start_id: u16,
+    start_header: usize,
+    continue_id: u16,
+) -> Option<(usize, usize)> {
+    if packet.len() < 4 {
+        return None;
+    }
+    let word = |i: usize| u16::from_le_bytes([packet[i], packet[i + 1]]);
+    match word(0) {
+        id if id == start_id && packet.len() >= start_header => {
+            Some((0, packet.len() - start_header))
+        }
+        id if id == continue_id => Some((word(2) as usize, packet.len() - 4)),
+        _ => None,
+    }
+}
+
+/// Time spent in each phase of a transaction, for telling host overhead apart from time spent
+/// on USB transfers and in HyperDebug firmware.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TransactionMetrics {
+    /// Time spent sending USB packets.
+    pub usb_write: Duration,
+    /// Time spent waiting for the first USB packet of each response, while HyperDebug carries
+    /// out the request.
+    pub firmware: Duration,
+    /// Time spent receiving any further USB packets of each response.
+    pub usb_read: Duration,
+    /// Remainder of the transaction, spent on the host, or on console commands.
+    pub host: Duration,
+}
+
+/// Carries out `transaction`, returning which code path was taken.
+fn dispatch_transaction(
+    ops: &dyn TransferOps,
+    max_sizes: &MaxSizes,
+    transaction: &mut [Transfer],
+) -> Result<TransactionPath> {
+    // Simple cases involving using only a single USB command can be handled without explicit
+    // embracing commands to hold CS asserted across a sequence of transfers, use that for
+    // avoiding several USB roundtrips in the common cases.
+    // Transfers exceeding the maximum sizes are split up by the general loop below.
+    match transaction {
+        [Transfer::Write(wbuf), Transfer::Read(rbuf)]
+            if wbuf.len() <= max_sizes.write && rbuf.len() <= max_sizes.read =>
+        {
+            ops.transmit(wbuf, rbuf.len())?;
+            ops.receive(rbuf)?;
+            return Ok(TransactionPath::WriteRead);
+        }
+        [Transfer::Write(wbuf)] if wbuf.len() <= max_sizes.write => {
+            ops.transmit(wbuf, 0)?;
+            ops.receive(&mut [])?;
+            return Ok(TransactionPath::Write);
+        }
+        [Transfer::Write(wbuf1), Transfer::Write(wbuf2)] => {
+            if wbuf1.len() + wbuf2.len() <= max_sizes.write {
+                let mut combined_buf = vec![0u8; wbuf1.len() + wbuf2.len()];
+                combined_buf[..wbuf1.len()].clone_from_slice(wbuf1);
+                combined_buf[wbuf1.len()..].clone_from_slice(wbuf2);
+                ops.transmit(&combined_buf, 0)?;
+                ops.receive(&mut [])?;
+                return Ok(TransactionPath::WriteWrite);
             }
-            [Transfer::Write(wbuf1), Transfer::Write(wbuf2)] => {
-                if wbuf1.len() + wbuf2.len() <= self.max_sizes.write {
-                    let mut combined_buf = vec![0u8; wbuf1.len() + wbuf2.len()];
-                    combined_buf[..wbuf1.len()].clone_from_slice(&wbuf1);
-                    combined_buf[wbuf1.len()..].clone_from_slice(&wbuf2);
-                    self.transmit(&combined_buf, 0)?;
-                    self.receive(&mut [])?;
-                    return Ok(());
-                }
+        }
+        [Transfer::Read(rbuf)] if rbuf.len() <= max_sizes.read => {
+            ops.transmit(&[], rbuf.len())?;
+            ops.receive(rbuf)?;
+            return Ok(TransactionPath::Read);
+        }
+        [Transfer::Both(wbuf, rbuf)]
+            if wbuf.len() <= max_sizes.write && rbuf.len() <= max_sizes.read =>
+        {
+            ensure!(
+                rbuf.len() == wbuf.len(),
+                SpiError::MismatchedDataLength(wbuf.len(), rbuf.len())
+            );
+            ops.transmit(wbuf, FULL_DUPLEX)?;
+            ops.receive(rbuf)?;
+            return Ok(TransactionPath::Both);
+        }
+        _ => (),
+    }
+
+    // If control flow reaches this point, we have a more complicated sequence of operations,
+    // and have to explicitly tell HyperDebug to keep the CS asserted while we issue each
+    // command in turn.
+    run_transfers(ops, max_sizes, transaction)?;
+    Ok(TransactionPath::General)
+}
+
+/// Operations on a HyperDebug SPI bus used by `run_transfers()`.
+trait TransferOps {
+    fn transmit(&self, wbuf: &[u8], rbuf_len: usize) -> Result<()>;
+    fn receive(&self, rbuf: &mut [u8]) -> Result<()>;
+    fn assert_cs(&self, assert: bool) -> Result<()>;
+    fn set_speed(&self, speed: u32) -> Result<()>;
+}
+
+impl TransferOps for HyperdebugSpiTarget {
+    fn transmit(&self, wbuf: &[u8], rbuf_len: usize) -> Result<()> {
+        HyperdebugSpiTarget::transmit(self, wbuf, rbuf_len)
+    }
+    fn receive(&self, rbuf: &mut [u8]) -> Result<()> {
+        HyperdebugSpiTarget::receive(self, rbuf)
+    }
+    fn assert_cs(&self, assert: bool) -> Result<()> {
+        self.do_assert_cs(assert)
+    }
+    fn set_speed(&self, speed: u32) -> Result<()> {
+        self.set_max_speed(speed)
+    }
+}
+
+/// Forwards to another `TransferOps`, counting requests which await a response.
+struct CountingOps<'a> {
+    ops: &'a dyn TransferOps,
+    round_trips: Cell<u32>,
+}
+
+impl CountingOps<'_> {
+    fn count(&self) {
+        self.round_trips.set(self.round_trips.get() + 1);
+    }
+}
+
+impl TransferOps for CountingOps<'_> {
+    fn transmit(&self, wbuf: &[u8], rbuf_len: usize) -> Result<()> {
+        self.ops.transmit(wbuf, rbuf_len)
+    }
+    fn receive(&self, rbuf: &mut [u8]) -> Result<()> {
+        self.count();
+        self.ops.receive(rbuf)
+    }
+    fn assert_cs(&self, assert: bool) -> Result<()> {
+        self.count();
+        self.ops.assert_cs(assert)
+    }
+    fn set_speed(&self, speed: u32) -> Result<()> {
+        self.count();
+        self.ops.set_speed(speed)
+    }
+}
+
+/// Writes `wbuf` and then reads `rbuf`, split into as many requests as needed to stay within
+/// `max_sizes`.  The last part of the write and the first part of the read share a request, as
+/// HyperDebug can do SPI write followed by SPI read as a single USB request/reply.
+fn write_then_read(
+    ops: &dyn TransferOps,
+    max_sizes: &MaxSizes,
+    wbuf: &[u8],
+    rbuf: &mut [u8],
+) -> Result<()> {
+    ensure!(
+        max_sizes.write > 0 && max_sizes.read > 0,
+        SpiError::InvalidDataLength(0)
+    );
+    let mut wchunks = wbuf.chunks(max_sizes.write).peekable();
+    let mut last_wchunk: &[u8] = &[];
+    while let Some(wchunk) = wchunks.next() {
+        if wchunks.peek().is_none() {
+            last_wchunk = wchunk;
+            break;
+        }
+        ops.transmit(wchunk, 0)?;
+        ops.receive(&mut [])?;
+    }
+    let mut rchunks = rbuf.chunks_mut(max_sizes.read);
+    let first_rchunk = rchunks.next().unwrap_or_default();
+    ops.transmit(last_wchunk, first_rchunk.len())?;
+    ops.receive(first_rchunk)?;
+    for rchunk in rchunks {
+        ops.transmit(&[], rchunk.len())?;
+        ops.receive(rchunk)?;
+    }
+    Ok(())
+}
+
+/// Issues each transfer in turn, while holding CS asserted.  `Transfer::SetSpeed` is carried out
+/// through the console, which does not affect CS.  HyperDebug has separate COPI and CIPO lines,
+/// so `Transfer::Turnaround` is a no-op.  Transfers exceeding `max_sizes` are split into several
+/// requests.
+fn run_transfers(
+    ops: &dyn TransferOps,
+    max_sizes: &MaxSizes,
+    transaction: &mut [Transfer],
+) -> Result<()> {
+    let mut idx: usize = 0;
+    ops.assert_cs(true)?;
+    while idx < transaction.len() {
+        match &mut transaction[idx..] {
+            [Transfer::Write(wbuf), Transfer::Read(rbuf), ..]
+            | [Transfer::Write(wbuf), Transfer::Turnaround, Transfer::Read(rbuf), ..] => {
+                // Hyperdebug can do SPI write followed by SPI read as a single USB
+                // request/reply.  Take advantage of that by detecting pairs of
+                // Transfer::Write followed by Transfer::Read.
+                write_then_read(ops, max_sizes, wbuf, rbuf)?;
+                // Skip ahead past the items processed.
+                idx += match transaction[idx + 1] {
+                    Transfer::Turnaround => 3,
+                    _ => 2,
+                };
+                continue;
             }
-            [Transfer::Read(rbuf)] => {
+            [Transfer::Write(wbuf), ..] => write_then_read(ops, max_sizes, wbuf, &mut [])?,
+            [Transfer::Read(rbuf), ..] => write_then_read(ops, max_sizes, &[], rbuf)?,
+            [Transfer::Both(wbuf, rbuf), ..] => {
                 ensure!(
-                    rbuf.len() <= self.max_sizes.read,
-                    SpiError::InvalidDataLength(rbuf.len())
+                    rbuf.len() == wbuf.len(),
+                    SpiError::MismatchedDataLength(wbuf.len(), rbuf.len())
                 );
-                self.transmit(&[], rbuf.len())?;
-                self.receive(rbuf)?;
-                return Ok(());
-            }
-            _ => (),
-        }
-
-        // If control flow reaches this point, we have a more complicated sequence of operations,
-        // and have to explicitly tell HyperDebug to keep the CS asserted while we issue each
-        // command in turn.
-        self.do_assert_cs(true)?;
-        while idx < transaction.len() {
-            match &mut transaction[idx..] {
-                [Transfer::Write(wbuf), Transfer::Read(rbuf), ..] => {
-                    // Hyperdebug can do SPI write followed by SPI read as a single USB
-                    // request/reply.  Take advantage of that by detecting pairs of
-                    // Transfer::Write followed by Transfer::Read.
-                    ensure!(
-                        wbuf.len() <= self.max_sizes.write,
-                        SpiError::InvalidDataLength(wbuf.len())
-                    );
-                    ensure!(
-                        rbuf.len() <= self.max_sizes.read,
-                        SpiError::InvalidDataLength(rbuf.len())
-                    );
-                    self.transmit(wbuf, rbuf.len())?;
-                    self.receive(rbuf)?;
-                    // Skip two steps ahead, as two items were processed.
-                    idx += 2;
-                    continue;
-                }
-                [Transfer::Write(wbuf), ..] => {
-                    ensure!(
-                        wbuf.len() <= self.max_sizes.write,
-                        SpiError::InvalidDataLength(wbuf.len())
-                    );
-                    self.transmit(wbuf, 0)?;
-                    self.receive(&mut [])?;
-                }
-                [Transfer::Read(rbuf), ..] => {
-                    ensure!(
-                        rbuf.len() <= self.max_sizes.read,
-                        SpiError::InvalidDataLength(rbuf.len())
-                    );
-                    self.transmit(&[], rbuf.len())?;
-                    self.receive(rbuf)?;
+                let chunk_size = std::cmp::min(max_sizes.read, max_sizes.write);
+                ensure!(chunk_size > 0, SpiError::InvalidDataLength(wbuf.len()));
+                for (wchunk, rchunk) in wbuf.chunks(chunk_size).zip(rbuf.chunks_mut(chunk_size)) {
+                    ops.transmit(wchunk, FULL_DUPLEX)?;
+                    ops.receive(rchunk)?;
                 }
-                [Transfer::Both(wbuf, rbuf), ..] => {
-                    ensure!(
-                        rbuf.len() == wbuf.len(),
-                        SpiError::MismatchedDataLength(wbuf.len(), rbuf.len())
-                    );
-                    ensure!(
-                        wbuf.len() <= self.max_sizes.read && wbuf.len() <= self.max_sizes.write,
-                        SpiError::InvalidDataLength(wbuf.len())
-                    );
-                    self.transmit(wbuf, FULL_DUPLEX)?;
-                    self.receive(rbuf)?;
-                }
-                [] => (),
             }
-            idx += 1;
+            [Transfer::Fill { value, count }, ..] => {
+                fill_chunks(*value, *count, max_sizes.write, |chunk| {
+                    ops.transmit(chunk, 0)?;
+                    ops.receive(&mut [])
+                })?;
+            }
+            [Transfer::SetSpeed(speed), ..] => ops.set_speed(*speed)?,
+            [Transfer::Turnaround, ..] => (),
+            [] => (),
         }
-        self.do_assert_cs(false)?;
-        Ok(())
-    }
-
-    fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
-        self.do_assert_cs(true)?;
-        Ok(AssertChipSelect::new(self))
+        idx += 1;
     }
+    ops.assert_cs(false)?;
+    Ok(())
 }
 
 impl TargetChipDeassert for HyperdebugSpiTarget {
@@ -539,3 +1288,829 @@ impl TargetChipDeassert for HyperdebugSpiTarget {
             .expect("Error while deasserting CS");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::spi::SpiExt;
+    use crate::transport::hyperdebug::bulk_packet_size;
+    use std::collections::VecDeque;
+
+    fn packet(packet_id: u16, second_word: u16, data: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&packet_id.to_le_bytes());
+        packet.extend_from_slice(&second_word.to_le_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    /// Reads packets from `endpoint`, an empty endpoint results in a timeout.
+    fn read_from(endpoint: &mut VecDeque<Vec<u8>>, buf: &mut [u8]) -> Result<usize> {
+        let packet = endpoint
+            .pop_front()
+            .ok_or_else(|| anyhow::anyhow!("USB timeout"))?;
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Op {
+        Transmit(Vec<u8>, usize),
+        Receive(usize),
+        AssertCs(bool),
+        SetSpeed(u32),
+    }
+
+    /// Records operations requested by `run_transfers()`, reading zeros.
+    #[derive(Default)]
+    struct RecordingOps {
+        ops: RefCell<Vec<Op>>,
+    }
+
+    impl TransferOps for RecordingOps {
+        fn transmit(&self, wbuf: &[u8], rbuf_len: usize) -> Result<()> {
+            self.ops
+                .borrow_mut()
+                .push(Op::Transmit(wbuf.to_vec(), rbuf_len));
+            Ok(())
+        }
+        fn receive(&self, rbuf: &mut [u8]) -> Result<()> {
+            self.ops.borrow_mut().push(Op::Receive(rbuf.len()));
+            Ok(())
+        }
+        fn assert_cs(&self, assert: bool) -> Result<()> {
+            self.ops.borrow_mut().push(Op::AssertCs(assert));
+            Ok(())
+        }
+        fn set_speed(&self, speed: u32) -> Result<()> {
+            self.ops.borrow_mut().push(Op::SetSpeed(speed));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_flush_stale_packets() -> Result<()> {
+        // Two packets of a response left behind by a previous process, the second one partial.
+        let mut stale: VecDeque<Vec<u8>> = VecDeque::from([vec![0xaa; 64], vec![0xbb; 10]]);
+        let mut reads = 0;
+        let discarded = flush_stale_packets(
+            &mut |buf| {
+                reads += 1;
+                Ok(stale.pop_front().map(|packet| {
+                    buf[..packet.len()].copy_from_slice(&packet);
+                    packet.len()
+                }))
+            },
+            64,
+        )?;
+        assert_eq!(discarded, 74);
+        assert_eq!(reads, 3);
+
+        // Nothing stale, a single read times out.
+        assert_eq!(flush_stale_packets(&mut |_| Ok(None), 64)?, 0);
+
+        // A device which never stops sending.
+        assert!(flush_stale_packets(&mut |_| Ok(Some(64)), 64).is_err());
+        Ok(())
+    }
+
+    /// Bulk read of a device taking `delay` to respond, subject to `timeout` like `rusb`.
+    fn slow_read_bulk(delay: Duration, timeout: Duration) -> Result<usize> {
+        std::thread::sleep(delay.min(timeout));
+        if delay > timeout {
+            return Err(anyhow::Error::new(rusb::Error::Timeout).context("USB error"));
+        }
+        Ok(64)
+    }
+
+    #[test]
+    fn test_packet_timeout() {
+        let timeout = Duration::from_millis(20);
+        let result = map_packet_timeout(
+            Some(timeout),
+            slow_read_bulk(Duration::from_millis(50), timeout),
+        );
+        match result.unwrap_err().downcast_ref::<TransportError>() {
+            Some(TransportError::CommunicationError(msg)) => assert!(msg.contains("timeout")),
+            other => panic!("unexpected error {:?}", other),
+        }
+        // A packet within the timeout, and other errors, pass through.
+        assert_eq!(
+            map_packet_timeout(Some(timeout), slow_read_bulk(Duration::ZERO, timeout)).unwrap(),
+            64
+        );
+        let err =
+            map_packet_timeout::<usize>(Some(timeout), Err(rusb::Error::Pipe.into())).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(rusb::Error::Pipe)));
+    }
+
+    /// Bulk endpoints whose IN endpoint delivers the queued packets, `None` standing for a
+    /// packet which does not arrive in time.  Packets written to the OUT endpoint are recorded,
+    /// unless `stall_writes` is set.
+    #[derive(Default)]
+    struct MockBulk {
+        in_packets: RefCell<VecDeque<Option<Vec<u8>>>>,
+        out_packets: RefCell<Vec<Vec<u8>>>,
+        stall_writes: Cell<bool>,
+        timeouts: RefCell<Vec<Option<Duration>>>,
+    }
+
+    impl BulkIo for MockBulk {
+        fn bulk_write(&self, buf: &[u8], timeout: Option<Duration>) -> Result<()> {
+            self.timeouts.borrow_mut().push(timeout);
+            if self.stall_writes.get() {
+                return Err(anyhow::Error::new(rusb::Error::Timeout));
+            }
+            self.out_packets.borrow_mut().push(buf.to_vec());
+            Ok(())
+        }
+        fn bulk_read(&self, buf: &mut [u8], timeout: Option<Duration>) -> Result<usize> {
+            self.timeouts.borrow_mut().push(timeout);
+            match self.in_packets.borrow_mut().pop_front() {
+                Some(Some(packet)) => {
+                    buf[..packet.len()].copy_from_slice(&packet);
+                    Ok(packet.len())
+                }
+                _ => Err(anyhow::Error::new(rusb::Error::Timeout)),
+            }
+        }
+        fn packet_size(&self) -> usize {
+            64
+        }
+    }
+
+    #[test]
+    fn test_packet_timeout_flush() -> Result<()> {
+        let timeout = Some(Duration::from_millis(20));
+        let bulk = MockBulk::default();
+        let mut buf = [0u8; 64];
+
+        // A response within the timeout.
+        bulk.in_packets.borrow_mut().push_back(Some(vec![1; 8]));
+        assert_eq!(read_packet_timeout(&bulk, timeout, &mut buf)?, 8);
+        assert_eq!(*bulk.timeouts.borrow(), vec![timeout]);
+
+        // The response arrives late, and is discarded rather than taken for the next one.
+        bulk.timeouts.borrow_mut().clear();
+        bulk.in_packets
+            .borrow_mut()
+            .extend([None, Some(vec![2; 64]), Some(vec![2; 4])]);
+        let err = read_packet_timeout(&bulk, timeout, &mut buf).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransportError>(),
+            Some(TransportError::CommunicationError(_))
+        ));
+        assert!(bulk.in_packets.borrow().is_empty());
+        assert_eq!(
+            *bulk.timeouts.borrow(),
+            vec![
+                timeout,
+                Some(FLUSH_TIMEOUT),
+                Some(FLUSH_TIMEOUT),
+                Some(FLUSH_TIMEOUT)
+            ]
+        );
+        bulk.in_packets.borrow_mut().push_back(Some(vec![3; 8]));
+        assert_eq!(read_packet_timeout(&bulk, timeout, &mut buf)?, 8);
+        assert_eq!(buf[0], 3);
+
+        // Without a timeout set, nothing is flushed.
+        bulk.timeouts.borrow_mut().clear();
+        assert!(read_packet_timeout(&bulk, None, &mut buf).is_err());
+        assert_eq!(*bulk.timeouts.borrow(), vec![None]);
+
+        // A stalled OUT endpoint, the IN endpoint is flushed as well.
+        bulk.timeouts.borrow_mut().clear();
+        write_packet_timeout(&bulk, timeout, &[4; 8])?;
+        assert_eq!(*bulk.out_packets.borrow(), vec![vec![4; 8]]);
+        bulk.stall_writes.set(true);
+        bulk.in_packets.borrow_mut().push_back(Some(vec![5; 8]));
+        let err = write_packet_timeout(&bulk, timeout, &[4; 8]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransportError>(),
+            Some(TransportError::CommunicationError(_))
+        ));
+        assert!(bulk.in_packets.borrow().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cs_polarity() {
+        let flags = |assert, active_high| CmdChipSelect::new(assert, active_high).flags;
+        assert_eq!(flags(true, false), 1);
+        assert_eq!(flags(false, false), 0);
+        // Asserting active high CS asks the firmware to release it to the high level.
+        assert_eq!(flags(true, true), 0);
+        assert_eq!(flags(false, true), 1);
+        assert_eq!(
+            CmdChipSelect::new(true, true).packet_id,
+            USB_SPI_PKT_ID_CMD_CHIP_SELECT
+        );
+    }
+
+    #[test]
+    fn test_set_speed_within_transaction() -> Result<()> {
+        let ops = RecordingOps::default();
+        let max_sizes = MaxSizes {
+            read: 1024,
+            write: 1024,
+        };
+        let mut data = [0u8; 4];
+        run_transfers(
+            &ops,
+            &max_sizes,
+            &mut [
+                Transfer::SetSpeed(1_000_000),
+                Transfer::Write(&[0x0b, 0x00, 0x10, 0x00]),
+                Transfer::SetSpeed(20_000_000),
+                Transfer::Read(&mut data),
+            ],
+        )?;
+        assert_eq!(
+            ops.ops.into_inner(),
+            vec![
+                Op::AssertCs(true),
+                Op::SetSpeed(1_000_000),
+                Op::Transmit(vec![0x0b, 0x00, 0x10, 0x00], 0),
+                Op::Receive(0),
+                Op::SetSpeed(20_000_000),
+                Op::Transmit(vec![], 4),
+                Op::Receive(4),
+                Op::AssertCs(false),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_turnaround() -> Result<()> {
+        let ops = RecordingOps::default();
+        let max_sizes = MaxSizes {
+            read: 1024,
+            write: 1024,
+        };
+        let mut id = [0u8; 3];
+        let mut status = [0u8; 1];
+        run_transfers(
+            &ops,
+            &max_sizes,
+            &mut [
+                // Still combined into a single request/reply.
+                Transfer::Write(&[0x9f]),
+                Transfer::Turnaround,
+                Transfer::Read(&mut id),
+                Transfer::Write(&[0x05]),
+                Transfer::Turnaround,
+                Transfer::Turnaround,
+                Transfer::Read(&mut status),
+                Transfer::Turnaround,
+            ],
+        )?;
+        assert_eq!(
+            ops.ops.into_inner(),
+            vec![
+                Op::AssertCs(true),
+                Op::Transmit(vec![0x9f], 3),
+                Op::Receive(3),
+                Op::Transmit(vec![0x05], 0),
+                Op::Receive(0),
+                Op::Transmit(vec![], 1),
+                Op::Receive(1),
+                Op::AssertCs(false),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_oversized_transfers() -> Result<()> {
+        let max_sizes = MaxSizes { read: 8, write: 16 };
+        let wbuf = (0..40).collect::<Vec<u8>>();
+        let ops = RecordingOps::default();
+        let path = dispatch_transaction(&ops, &max_sizes, &mut [Transfer::Write(&wbuf)])?;
+        assert_eq!(path, TransactionPath::General);
+        let ops = ops.ops.into_inner();
+        // CS is asserted only once, around all the parts.
+        assert_eq!(ops.first(), Some(&Op::AssertCs(true)));
+        assert_eq!(ops.last(), Some(&Op::AssertCs(false)));
+        assert_eq!(
+            ops.iter()
+                .filter(|op| matches!(op, Op::AssertCs(_)))
+                .count(),
+            2
+        );
+        let mut written = Vec::new();
+        for op in &ops {
+            if let Op::Transmit(data, read_len) = op {
+                assert!(data.len() <= max_sizes.write);
+                assert_eq!(*read_len, 0);
+                written.extend_from_slice(data);
+            }
+        }
+        assert_eq!(written, wbuf);
+
+        // The last part of the write shares a request with the first part of the read.
+        let ops = RecordingOps::default();
+        let mut rbuf = [0u8; 20];
+        dispatch_transaction(
+            &ops,
+            &max_sizes,
+            &mut [Transfer::Write(&wbuf[..20]), Transfer::Read(&mut rbuf)],
+        )?;
+        assert_eq!(
+            ops.ops.into_inner(),
+            vec![
+                Op::AssertCs(true),
+                Op::Transmit(wbuf[..16].to_vec(), 0),
+                Op::Receive(0),
+                Op::Transmit(wbuf[16..20].to_vec(), 8),
+                Op::Receive(8),
+                Op::Transmit(vec![], 8),
+                Op::Receive(8),
+                Op::Transmit(vec![], 4),
+                Op::Receive(4),
+                Op::AssertCs(false),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_trace() -> Result<()> {
+        let max_sizes = MaxSizes {
+            read: 1024,
+            write: 1024,
+        };
+        let ops = RecordingOps::default();
+        let counting = CountingOps {
+            ops: &ops,
+            round_trips: Cell::new(0),
+        };
+        let mut data = [0u8; 4];
+        let path = dispatch_transaction(
+            &counting,
+            &max_sizes,
+            &mut [Transfer::Write(&[0x03, 0, 0, 0]), Transfer::Read(&mut data)],
+        )?;
+        assert_eq!(path, TransactionPath::WriteRead);
+        assert_eq!(counting.round_trips.get(), 1);
+
+        let counting = CountingOps {
+            ops: &ops,
+            round_trips: Cell::new(0),
+        };
+        let path = dispatch_transaction(
+            &counting,
+            &max_sizes,
+            &mut [
+                Transfer::Write(&[0x06]),
+                Transfer::Write(&[0x02, 0, 0, 0]),
+                Transfer::Write(&[0xff; 16]),
+            ],
+        )?;
+        assert_eq!(path, TransactionPath::General);
+        // Assert CS, three writes, deassert CS.
+        assert_eq!(counting.round_trips.get(), 5);
+        Ok(())
+    }
+
+    /// Runs transactions the way `HyperdebugSpiTarget` does, through `dispatch_transaction()`.
+    struct DispatchTarget<'a> {
+        ops: &'a dyn TransferOps,
+        max_sizes: MaxSizes,
+    }
+
+    impl Target for DispatchTarget<'_> {
+        fn get_transfer_mode(&self) -> Result<TransferMode> {
+            Ok(TransferMode::Mode0)
+        }
+        fn set_transfer_mode(&self, _mode: TransferMode) -> Result<()> {
+            Ok(())
+        }
+        fn get_bits_per_word(&self) -> Result<u32> {
+            Ok(8)
+        }
+        fn set_bits_per_word(&self, _bits_per_word: u32) -> Result<()> {
+            Ok(())
+        }
+        fn get_max_speed(&self) -> Result<u32> {
+            Ok(1_000_000)
+        }
+        fn set_max_speed(&self, _max_speed: u32) -> Result<()> {
+            Ok(())
+        }
+        fn get_max_transfer_count(&self) -> Result<usize> {
+            Ok(usize::MAX)
+        }
+        fn get_max_transfer_sizes(&self) -> Result<MaxSizes> {
+            Ok(self.max_sizes)
+        }
+        fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+            dispatch_transaction(self.ops, &self.max_sizes, transaction).map(|_| ())
+        }
+        fn assert_cs(self: Rc<Self>) -> Result<AssertChipSelect> {
+            Err(TransportError::UnsupportedOperation.into())
+        }
+    }
+
+    #[test]
+    fn test_read_registers_round_trips() -> Result<()> {
+        let ops = RecordingOps::default();
+        let counting = CountingOps {
+            ops: &ops,
+            round_trips: Cell::new(0),
+        };
+        let target = DispatchTarget {
+            ops: &counting,
+            max_sizes: MaxSizes { read: 4, write: 4 },
+        };
+        let commands: [&[u8]; 5] = [&[0x05], &[0x35], &[0x15], &[0x9f], &[0x4b]];
+        let read_lens = [2, 2, 3, 1, 4];
+        let values = target.read_registers(&commands, &read_lens)?;
+        assert_eq!(values.iter().map(Vec::len).collect::<Vec<_>>(), read_lens);
+        // A single write-then-read request per register, without any explicit CS commands.
+        let round_trips = counting.round_trips.get();
+        assert_eq!(round_trips, 5);
+        assert!(!ops
+            .ops
+            .borrow()
+            .iter()
+            .any(|op| matches!(op, Op::AssertCs(_))));
+
+        // Holding CS across all of the registers in one transaction takes the general path,
+        // with requests for asserting and deasserting CS on top.
+        let counting = CountingOps {
+            ops: &ops,
+            round_trips: Cell::new(0),
+        };
+        let mut values = read_lens.map(|len| vec![0u8; len]);
+        let mut transaction = Vec::new();
+        for (command, value) in commands.iter().zip(values.iter_mut()) {
+            transaction.push(Transfer::Write(command));
+            transaction.push(Transfer::Read(value));
+        }
+        dispatch_transaction(&counting, &target.max_sizes, &mut transaction)?;
+        assert!(round_trips < counting.round_trips.get());
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_full_duplex_transfer() -> Result<()> {
+        let max_sizes = MaxSizes {
+            read: 1024,
+            write: 1024,
+        };
+        let ops = RecordingOps::default();
+        let counting = CountingOps {
+            ops: &ops,
+            round_trips: Cell::new(0),
+        };
+        let mut data = [0u8; 4];
+        let path = dispatch_transaction(
+            &counting,
+            &max_sizes,
+            &mut [Transfer::Both(&[0x9f, 0, 0, 0], &mut data)],
+        )?;
+        assert_eq!(path, TransactionPath::Both);
+        assert_eq!(counting.round_trips.get(), 1);
+        // No explicit CS commands, the firmware frames the single transfer.
+        assert_eq!(
+            ops.ops.into_inner(),
+            vec![
+                Op::Transmit(vec![0x9f, 0, 0, 0], FULL_DUPLEX),
+                Op::Receive(4),
+            ]
+        );
+
+        let ops = RecordingOps::default();
+        let mut short = [0u8; 2];
+        assert!(dispatch_transaction(
+            &ops,
+            &max_sizes,
+            &mut [Transfer::Both(&[0x9f, 0, 0, 0], &mut short)],
+        )
+        .is_err());
+        assert!(ops.ops.into_inner().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reported_transfer_mode() {
+        // Mode 3 was requested, but the firmware applied mode 1.
+        assert_eq!(
+            reported_transfer_mode("  0 SPI2 1000000 mode 1", TransferMode::Mode3),
+            TransferMode::Mode1
+        );
+        // Older firmware does not report the mode.
+        assert_eq!(
+            reported_transfer_mode("  0 SPI2 1000000", TransferMode::Mode3),
+            TransferMode::Mode3
+        );
+        // In which case the clock idle level applied on top of the requested mode is reported.
+        assert_eq!(
+            reported_transfer_mode(
+                "  0 SPI2 1000000",
+                effective_transfer_mode(TransferMode::Mode1, Some(true))
+            ),
+            TransferMode::Mode3
+        );
+    }
+
+    #[test]
+    fn test_parse_hardware_max_speed() {
+        assert_eq!(
+            parse_hardware_max_speed("  0 SPI2 1000000 mode 0 max 24000000"),
+            Some(24_000_000)
+        );
+        // Older firmware does not report the maximum.
+        assert_eq!(parse_hardware_max_speed("  0 SPI2 1000000 mode 0"), None);
+        assert_eq!(parse_hardware_max_speed("  0 SPI2 1000000 max"), None);
+    }
+
+    #[test]
+    fn test_effective_transfer_mode() {
+        // Without an idle level override, the mode is applied as requested.
+        assert_eq!(
+            effective_transfer_mode(TransferMode::Mode1, None),
+            TransferMode::Mode1
+        );
+        // The idle level overrides the polarity, the phase is kept from the mode.
+        assert_eq!(
+            effective_transfer_mode(TransferMode::Mode0, Some(true)),
+            TransferMode::Mode2
+        );
+        assert_eq!(
+            effective_transfer_mode(TransferMode::Mode1, Some(true)),
+            TransferMode::Mode3
+        );
+        assert_eq!(
+            effective_transfer_mode(TransferMode::Mode3, Some(false)),
+            TransferMode::Mode1
+        );
+        assert_eq!(
+            effective_transfer_mode(TransferMode::Mode2, Some(true)),
+            TransferMode::Mode2
+        );
+    }
+
+    #[test]
+    fn test_deselect_timer() {
+        let min_deselect_time = Duration::from_millis(20);
+        let mut timer = DeselectTimer::default();
+        // No minimum configured, no waiting.
+        timer.deselected();
+        let t0 = Instant::now();
+        timer.wait();
+        assert!(t0.elapsed() < min_deselect_time);
+
+        // Two short back-to-back transactions.
+        timer.min_deselect_time = min_deselect_time;
+        timer.wait();
+        timer.deselected();
+        let end_of_first = Instant::now();
+        timer.wait();
+        assert!(end_of_first.elapsed() >= min_deselect_time);
+    }
+
+    #[test]
+    fn test_set_speed_cached() -> Result<()> {
+        let speeds = RefCell::new(HashMap::new());
+        let commands = Cell::new(0);
+        let set = || {
+            commands.set(commands.get() + 1);
+            Ok(())
+        };
+        set_speed_cached(&speeds, 0, 1_000_000, set)?;
+        assert_eq!(commands.get(), 1);
+        // Unchanged speed, no console command.
+        set_speed_cached(&speeds, 0, 1_000_000, set)?;
+        assert_eq!(commands.get(), 1);
+        // Other bus, or other speed.
+        set_speed_cached(&speeds, 1, 1_000_000, set)?;
+        set_speed_cached(&speeds, 0, 20_000_000, set)?;
+        assert_eq!(commands.get(), 3);
+
+        // A failed attempt is not remembered.
+        assert!(set_speed_cached(&speeds, 0, 1_000_000, || anyhow::bail!("Timeout")).is_err());
+        set_speed_cached(&speeds, 0, 20_000_000, set)?;
+        assert_eq!(commands.get(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_at_speed_cached() -> Result<()> {
+        let speeds = RefCell::new(HashMap::new());
+        let commands = RefCell::new(Vec::new());
+        let set = |speed| {
+            commands.borrow_mut().push(speed);
+            Ok(())
+        };
+        // The speed requested by the caller is restored, without querying the firmware.
+        set_speed_cached(&speeds, 0, 1_000_000, || set(1_000_000))?;
+        run_at_speed_cached(
+            &speeds,
+            0,
+            20_000_000,
+            || panic!("speed queried"),
+            set,
+            || Ok(()),
+        )?;
+        assert_eq!(commands.take(), vec![1_000_000, 20_000_000, 1_000_000]);
+        assert_eq!(speeds.borrow().get(&0), Some(&1_000_000));
+
+        // Also if the transaction fails.
+        assert!(run_at_speed_cached(
+            &speeds,
+            0,
+            20_000_000,
+            || Ok(0),
+            set,
+            || -> Result<()> { anyhow::bail!("failure") }
+        )
+        .is_err());
+        assert_eq!(commands.take(), vec![20_000_000, 1_000_000]);
+
+        // Without a requested speed, the current one is queried once.
+        let queries = Cell::new(0);
+        run_at_speed_cached(
+            &speeds,
+            1,
+            20_000_000,
+            || {
+                queries.set(queries.get() + 1);
+                Ok(999_000)
+            },
+            set,
+            || Ok(()),
+        )?;
+        assert_eq!(queries.get(), 1);
+        assert_eq!(commands.take(), vec![20_000_000, 999_000]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_high_speed_packets() -> Result<()> {
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        // Endpoint descriptors reporting 512 byte packets.
+        let packet_size = bulk_packet_size(512, 512);
+        let mut packets = Vec::new();
+        transmit_packets(
+            &mut |buf| {
+                packets.push(buf.to_vec());
+                Ok(())
+            },
+            packet_size,
+            &data,
+            0,
+        )?;
+        assert_eq!(
+            packets.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![512, 4 + 1000 - 506]
+        );
+
+        let mut endpoint = VecDeque::from(vec![
+            packet(USB_SPI_PKT_ID_RSP_TRANSFER_START, 0, &data[0..508]),
+            packet(USB_SPI_PKT_ID_RSP_TRANSFER_CONTINUE, 508, &data[508..1000]),
+        ]);
+        let mut rbuf = [0u8; 1000];
+        receive_packets(
+            &mut |buf| {
+                assert_eq!(buf.len(), 512);
+                read_from(&mut endpoint, buf)
+            },
+            packet_size,
+            &mut rbuf,
+        )?;
+        assert_eq!(rbuf.as_slice(), data.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer_chunks() -> Result<()> {
+        let data: Vec<u8> = (0..150).collect();
+        let mut chunks = TransferChunks::default();
+        // The first packet carries 64 - 6 bytes, the following ones 64 - 4.
+        transmit_packets(
+            &mut |buf| {
+                chunks.record_write(buf);
+                Ok(())
+            },
+            64,
+            &data,
+            150,
+        )?;
+        assert_eq!(chunks.write, vec![(0, 58), (58, 60), (118, 32)]);
+
+        let mut endpoint = VecDeque::from(vec![
+            packet(USB_SPI_PKT_ID_RSP_TRANSFER_START, 0, &data[0..60]),
+            packet(USB_SPI_PKT_ID_RSP_TRANSFER_CONTINUE, 60, &data[60..120]),
+            packet(USB_SPI_PKT_ID_RSP_TRANSFER_CONTINUE, 120, &data[120..150]),
+        ]);
+        let mut rbuf = [0u8; 150];
+        receive_packets(
+            &mut |buf| {
+                let len = read_from(&mut endpoint, buf)?;
+                chunks.record_read(&buf[..len]);
+                Ok(len)
+            },
+            64,
+            &mut rbuf,
+        )?;
+        assert_eq!(chunks.read, vec![(0, 60), (60, 60), (120, 30)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_phase_timer() -> Result<()> {
+        let now = Rc::new(Cell::new(Instant::now()));
+        let clock = Rc::clone(&now);
+        let timer = PhaseTimer::new(Box::new(move || clock.get()));
+        let advance = |millis| now.set(now.get() + Duration::from_millis(millis));
+        let data: Vec<u8> = (0..100).collect();
+
+        // Not measuring.
+        timed_transmit(
+            &timer,
+            &mut |_| {
+                advance(1);
+                Ok(())
+            },
+            64,
+            &data,
+            0,
+        )?;
+        assert_eq!(timer.finish(), TransactionMetrics::default());
+
+        timer.start();
+        advance(1);
+        // Two packets, 2ms each.
+        timed_transmit(
+            &timer,
+            &mut |_| {
+                advance(2);
+                Ok(())
+            },
+            64,
+            &data,
+            100,
+        )?;
+        advance(3);
+        // Firmware responds after 10ms, followed by a second packet after 1ms.
+        let mut endpoint = VecDeque::from(vec![
+            packet(USB_SPI_PKT_ID_RSP_TRANSFER_START, 0, &data[0..60]),
+            packet(USB_SPI_PKT_ID_RSP_TRANSFER_CONTINUE, 60, &data[60..100]),
+        ]);
+        let mut rbuf = [0u8; 100];
+        timed_receive(
+            &timer,
+            &mut |buf| {
+                advance(if endpoint.len() == 2 { 10 } else { 1 });
+                read_from(&mut endpoint, buf)
+            },
+            64,
+            &mut rbuf,
+        )?;
+        assert_eq!(rbuf.as_slice(), data.as_slice());
+        advance(1);
+        assert_eq!(
+            timer.finish(),
+            TransactionMetrics {
+                usb_write: Duration::from_millis(4),
+                firmware: Duration::from_millis(10),
+                usb_read: Duration::from_millis(1),
+                host: Duration::from_millis(5),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_receive_resync() -> Result<()> {
+        let data: Vec<u8> = (0..100).collect();
+        let mut endpoint = VecDeque::from(vec![
+            // Response with a bad data index, followed by remaining packets of the response.
+            packet(USB_SPI_PKT_ID_RSP_TRANSFER_START, 0, &data[0..60]),
+            packet(USB_SPI_PKT_ID_RSP_TRANSFER_CONTINUE, 99, &data[0..60]),
+            vec![0xAA; 64],
+            vec![0x55; 7],
+            // Well-formed response.
+            packet(USB_SPI_PKT_ID_RSP_TRANSFER_START, 0, &data[0..60]),
+            packet(USB_SPI_PKT_ID_RSP_TRANSFER_CONTINUE, 60, &data[60..100]),
+        ]);
+
+        let mut rbuf = [0u8; 100];
+        let err =
+            receive_packets(&mut |buf| read_from(&mut endpoint, buf), 64, &mut rbuf).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransportError>(),
+            Some(TransportError::CommunicationError(_))
+        ));
+        assert_eq!(endpoint.len(), 2);
+
+        let mut rbuf = [0u8; 100];
+        receive_packets(&mut |buf| read_from(&mut endpoint, buf), 64, &mut rbuf)?;
+        assert_eq!(rbuf.as_slice(), data.as_slice());
+        assert!(endpoint.is_empty());
+        Ok(())
+    }
+}
@@ -11,10 +11,11 @@ use std::rc::Rc;
 
 use crate::bootstrap::BootstrapOptions;
 use crate::io::emu::Emulator;
-use crate::io::gpio::{GpioMonitoring, GpioPin};
+use crate::io::gpio::{GpioMonitoring, GpioPatternGenerator, GpioPin, PinSnapshot};
 use crate::io::i2c::Bus;
 use crate::io::spi::Target;
 use crate::io::uart::Uart;
+use crate::util::usb::{UsbBackend, UsbStrings};
 
 pub mod common;
 pub mod cw310;
@@ -121,6 +122,10 @@ pub trait Transport {
     fn gpio_monitoring(&self) -> Result<Rc<dyn GpioMonitoring>> {
         Err(TransportError::InvalidInterface(TransportInterfaceType::GpioMonitoring).into())
     }
+    /// Returns a [`GpioPatternGenerator`] implementation, for precisely timed output.
+    fn gpio_pattern_generator(&self) -> Result<Rc<dyn GpioPatternGenerator>> {
+        Err(TransportError::InvalidInterface(TransportInterfaceType::GpioPatternGenerator).into())
+    }
     /// Returns a [`Emulator`] implementation.
     fn emulator(&self) -> Result<Rc<dyn Emulator>> {
         Err(TransportError::InvalidInterface(TransportInterfaceType::Emulator).into())
@@ -131,6 +136,22 @@ pub trait Transport {
         Err(TransportError::InvalidInterface(TransportInterfaceType::ProxyOps).into())
     }
 
+    /// Records the mode, level and pull of all GPIO pins in use, for later restoring using
+    /// `restore_pins()`.
+    fn snapshot_pins(&self) -> Result<PinSnapshot> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
+
+    /// Reapplies the pin state recorded by `snapshot_pins()`.
+    fn restore_pins(&self, snapshot: &PinSnapshot) -> Result<()> {
+        snapshot.restore(|name| self.gpio_pin(name))
+    }
+
+    /// Identification strings of the USB debugger device, for diagnostics.
+    fn description(&self) -> Result<UsbStrings> {
+        Err(TransportError::UnsupportedOperation.into())
+    }
+
     /// Invoke non-standard functionality of some Transport implementations.
     fn dispatch(&self, _action: &dyn Any) -> Result<Option<Box<dyn serde_annotate::Annotate>>> {
         Err(TransportError::UnsupportedOperation.into())
@@ -180,10 +201,102 @@ impl Transport for EmptyTransport {
     }
 }
 
+/// Debug boards which can be recognized by the USB vendor and product ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DetectedBoard {
+    Hyperdebug,
+    CW310,
+}
+
+impl DetectedBoard {
+    const ALL: [DetectedBoard; 2] = [DetectedBoard::Hyperdebug, DetectedBoard::CW310];
+
+    /// USB vendor and product ID presented by the board.
+    pub fn usb_ids(self) -> (u16, u16) {
+        match self {
+            DetectedBoard::Hyperdebug => (hyperdebug::VID_GOOGLE, hyperdebug::PID_HYPERDEBUG),
+            DetectedBoard::CW310 => (
+                cw310::usb::Backend::VID_NEWAE,
+                cw310::usb::Backend::PID_CW310,
+            ),
+        }
+    }
+
+    /// Select the single known board among the VID/PID pairs of the devices on the USB bus.
+    pub fn select(devices: &[(u16, u16)]) -> Result<Self> {
+        let found = devices
+            .iter()
+            .filter_map(|ids| Self::ALL.iter().copied().find(|b| b.usb_ids() == *ids))
+            .collect::<Vec<_>>();
+        match found.as_slice() {
+            [board] => Ok(*board),
+            [] => Err(TransportError::NoSupportedBoard(format!("{:?}", Self::ALL)).into()),
+            _ => Err(TransportError::MultipleBoards(format!("{:?}", found)).into()),
+        }
+    }
+
+    /// Open the transport for the board, with default options.
+    pub fn open(self) -> Result<Box<dyn Transport>> {
+        Ok(match self {
+            DetectedBoard::Hyperdebug => {
+                Box::new(hyperdebug::Hyperdebug::<hyperdebug::StandardFlavor>::open(
+                    None, None, None, None, None,
+                )?)
+            }
+            DetectedBoard::CW310 => Box::new(cw310::CW310::new(
+                None,
+                None,
+                None,
+                &[],
+                &Default::default(),
+            )?),
+        })
+    }
+}
+
+/// Enumerate USB devices, and open the transport for the single HyperDebug or CW310 board found.
+pub fn autodetect() -> Result<Box<dyn Transport>> {
+    let board = DetectedBoard::select(&UsbBackend::enumerate()?)?;
+    log::info!("Detected {:?}", board);
+    board.open()
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
 
+    #[test]
+    fn test_autodetect_select() -> anyhow::Result<()> {
+        const OTHER: (u16, u16) = (0x1d6b, 0x0002);
+        let hyperdebug = DetectedBoard::Hyperdebug.usb_ids();
+        let cw310 = DetectedBoard::CW310.usb_ids();
+        assert_eq!(
+            DetectedBoard::select(&[OTHER, cw310, OTHER])?,
+            DetectedBoard::CW310
+        );
+        assert_eq!(
+            DetectedBoard::select(&[hyperdebug])?,
+            DetectedBoard::Hyperdebug
+        );
+
+        let err = DetectedBoard::select(&[OTHER]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransportError>(),
+            Some(TransportError::NoSupportedBoard(_))
+        ));
+        assert!(DetectedBoard::select(&[]).is_err());
+
+        for devices in [[hyperdebug, cw310], [cw310, cw310]] {
+            let err = DetectedBoard::select(&devices).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<TransportError>(),
+                Some(TransportError::MultipleBoards(_))
+            ));
+            assert!(err.to_string().contains("CW310"));
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_capabilities_met() -> anyhow::Result<()> {
         let cap = Capabilities::new(Capability::UART | Capability::SPI);
@@ -6,7 +6,7 @@ use anyhow::{bail, Result};
 use std::rc::Rc;
 
 use super::ProxyError;
-use crate::io::gpio::{GpioPin, PinMode, PullMode};
+use crate::io::gpio::{GpioPin, GpioPinCapabilities, PinMode, PullMode};
 use crate::proxy::protocol::{GpioRequest, GpioResponse, Request, Response};
 use crate::transport::proxy::{Inner, Proxy};
 
@@ -66,4 +66,18 @@ impl GpioPin for ProxyGpioPin {
             _ => bail!(ProxyError::UnexpectedReply()),
         }
     }
+
+    fn get_mode(&self) -> Result<PinMode> {
+        match self.execute_command(GpioRequest::GetMode)? {
+            GpioResponse::GetMode { mode } => Ok(mode),
+            _ => bail!(ProxyError::UnexpectedReply()),
+        }
+    }
+
+    fn capabilities(&self) -> Result<GpioPinCapabilities> {
+        match self.execute_command(GpioRequest::GetCapabilities)? {
+            GpioResponse::GetCapabilities { capabilities } => Ok(capabilities),
+            _ => bail!(ProxyError::UnexpectedReply()),
+        }
+    }
 }
@@ -4,6 +4,7 @@
 
 use anyhow::{bail, ensure, Result};
 use std::rc::Rc;
+use std::time::Duration;
 
 use super::ProxyError;
 use crate::io::spi::{
@@ -82,6 +83,13 @@ impl Target for ProxySpi {
         }
     }
 
+    fn get_hardware_max_speed(&self) -> Result<u32> {
+        match self.execute_command(SpiRequest::GetHardwareMaxSpeed)? {
+            SpiResponse::GetHardwareMaxSpeed { speed } => Ok(speed),
+            _ => bail!(ProxyError::UnexpectedReply()),
+        }
+    }
+
     fn get_max_transfer_count(&self) -> Result<usize> {
         match self.execute_command(SpiRequest::GetMaxTransferCount)? {
             SpiResponse::GetMaxTransferCount { number } => Ok(number as usize),
@@ -110,6 +118,34 @@ impl Target for ProxySpi {
         }
     }
 
+    fn set_cs_idle_level(&self, high: bool) -> Result<()> {
+        match self.execute_command(SpiRequest::SetCsIdleLevel { high })? {
+            SpiResponse::SetCsIdleLevel => Ok(()),
+            _ => bail!(ProxyError::UnexpectedReply()),
+        }
+    }
+
+    fn set_cs_polarity(&self, active_high: bool) -> Result<()> {
+        match self.execute_command(SpiRequest::SetCsPolarity { active_high })? {
+            SpiResponse::SetCsPolarity => Ok(()),
+            _ => bail!(ProxyError::UnexpectedReply()),
+        }
+    }
+
+    fn set_min_deselect_time(&self, duration: Duration) -> Result<()> {
+        match self.execute_command(SpiRequest::SetMinDeselectTime { duration })? {
+            SpiResponse::SetMinDeselectTime => Ok(()),
+            _ => bail!(ProxyError::UnexpectedReply()),
+        }
+    }
+
+    fn set_transaction_timeout(&self, timeout: Duration) -> Result<()> {
+        match self.execute_command(SpiRequest::SetTransactionTimeout { timeout })? {
+            SpiResponse::SetTransactionTimeout => Ok(()),
+            _ => bail!(ProxyError::UnexpectedReply()),
+        }
+    }
+
     fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
         let mut req: Vec<SpiTransferRequest> = Vec::new();
         for transfer in transaction.iter() {
@@ -129,6 +165,14 @@ impl Target for ProxySpi {
                         data: wbuf.to_vec(),
                     })
                 }
+                Transfer::Fill { value, count } => req.push(SpiTransferRequest::Fill {
+                    value: *value,
+                    count: *count,
+                }),
+                Transfer::SetSpeed(speed) => {
+                    req.push(SpiTransferRequest::SetSpeed { speed: *speed })
+                }
+                Transfer::Turnaround => req.push(SpiTransferRequest::Turnaround),
             }
         }
         match self.execute_command(SpiRequest::RunTransaction { transaction: req })? {
@@ -143,7 +187,10 @@ impl Target for ProxySpi {
                         | (SpiTransferResponse::Both { data }, Transfer::Both(_, rbuf)) => {
                             rbuf.clone_from_slice(data);
                         }
-                        (SpiTransferResponse::Write, Transfer::Write(_)) => (),
+                        (SpiTransferResponse::Write, Transfer::Write(_))
+                        | (SpiTransferResponse::Write, Transfer::Fill { .. })
+                        | (SpiTransferResponse::Write, Transfer::SetSpeed(_))
+                        | (SpiTransferResponse::Write, Transfer::Turnaround) => (),
                         _ => bail!(ProxyError::UnexpectedReply()),
                     }
                 }
@@ -10,8 +10,9 @@ use std::collections::HashMap;
 use std::rc::Rc;
 
 use crate::collection;
-use crate::io::gpio::{GpioError, GpioPin, PinMode, PullMode};
+use crate::io::gpio::{GpioError, GpioPatternGenerator, GpioPin, PinMode, PullMode};
 use crate::transport::ultradebug::mpsse;
+use crate::transport::ultradebug::spi::UltradebugSpi;
 use crate::transport::ultradebug::Ultradebug;
 use crate::util::parse_int::ParseInt;
 
@@ -39,9 +40,11 @@ impl UltradebugGpio {
     }
 
     pub fn pin(&self, pinname: &str) -> Result<UltradebugGpioPin> {
+        let pin_id = self.pin_name_to_number(pinname)?;
         Ok(UltradebugGpioPin {
             device: self.device.clone(),
-            pin_id: self.pin_name_to_number(pinname)?,
+            pin_id,
+            pin_name: pin_id.to_string(),
         })
     }
 
@@ -68,6 +71,8 @@ impl UltradebugGpio {
 pub struct UltradebugGpioPin {
     device: Rc<RefCell<mpsse::Context>>,
     pin_id: u8,
+    /// The pin number as a string, used to identify the pin to `GpioPatternGenerator`.
+    pin_name: String,
 }
 
 impl GpioPin for UltradebugGpioPin {
@@ -103,6 +108,105 @@ impl GpioPin for UltradebugGpioPin {
     fn set_pull_mode(&self, mode: PullMode) -> Result<()> {
         Err(GpioError::UnsupportedPullMode(mode).into())
     }
+
+    fn get_internal_pin_name(&self) -> Option<&str> {
+        Some(&self.pin_name)
+    }
+}
+
+/// Emits patterns using the clocked data output of the MPSSE engine, which drives the SPI MOSI
+/// pin.  Chip select is left untouched, such that any SPI device will ignore the clock pulses.
+pub struct UltradebugGpioPatternGenerator {
+    device: Rc<RefCell<mpsse::Context>>,
+}
+
+impl UltradebugGpioPatternGenerator {
+    /// Size of the FTDI buffer, in bytes, limiting the length of a single write command.
+    const MAX_PATTERN_BYTES: usize = 65536;
+
+    pub fn open(ultradebug: &Ultradebug) -> Result<Self> {
+        Ok(UltradebugGpioPatternGenerator {
+            device: ultradebug.mpsse(ftdi::Interface::B)?,
+        })
+    }
+}
+
+/// Packs `pattern` most significant bit first into whole bytes, followed by the number of bits
+/// and left-aligned value of a partial final byte, if any.
+fn pack_pattern(pattern: &[bool]) -> (Vec<u8>, Option<(u8, u8)>) {
+    let pack = |bits: &[bool]| {
+        bits.iter()
+            .enumerate()
+            .fold(0u8, |acc, (i, &bit)| acc | ((bit as u8) << (7 - i)))
+    };
+    let chunks = pattern.chunks_exact(8);
+    let tail = chunks.remainder();
+    let bytes = chunks.map(pack).collect();
+    let tail = if tail.is_empty() {
+        None
+    } else {
+        Some((tail.len() as u8, pack(tail)))
+    };
+    (bytes, tail)
+}
+
+/// Builds the MPSSE commands shifting out the packed pattern, one bit per clock.
+fn pattern_commands(bytes: &[u8], tail: Option<(u8, u8)>) -> Vec<mpsse::Command<'_, '_>> {
+    let options = || mpsse::DataShiftOptions {
+        write_clock_edge: mpsse::ClockEdge::Falling,
+        write_data: true,
+        ..Default::default()
+    };
+    let mut commands = Vec::new();
+    if !bytes.is_empty() {
+        commands.push(mpsse::Command::WriteData(options(), bytes));
+    }
+    if let Some((count, bits)) = tail {
+        commands.push(mpsse::Command::WriteBits(options(), count, bits));
+    }
+    commands
+}
+
+impl GpioPatternGenerator for UltradebugGpioPatternGenerator {
+    fn max_pattern_length(&self) -> Result<usize> {
+        Ok(Self::MAX_PATTERN_BYTES * 8)
+    }
+
+    fn output_pattern(&self, pin: &dyn GpioPin, pattern: &[bool], clock_hz: u32) -> Result<()> {
+        let pin_name = pin.get_internal_pin_name().unwrap_or("");
+        ensure!(
+            pin_name == UltradebugSpi::PIN_MOSI.to_string(),
+            GpioError::UnsupportedPatternPin(pin_name.to_string())
+        );
+        let max_length = self.max_pattern_length()?;
+        ensure!(
+            pattern.len() <= max_length,
+            GpioError::PatternTooLong(pattern.len(), max_length)
+        );
+        let mut device = self.device.borrow_mut();
+        // The clock divisor is 16 bits wide.
+        let max_clock = device.max_clock_frequency;
+        let min_clock = max_clock / 65536 + 1;
+        ensure!(
+            (min_clock..=max_clock).contains(&clock_hz),
+            GpioError::UnsupportedPatternClock(clock_hz, min_clock, max_clock)
+        );
+        if pattern.is_empty() {
+            return Ok(());
+        }
+
+        let (bytes, tail) = pack_pattern(pattern);
+        let previous_clock = device.clock_frequency;
+        device.set_clock_frequency(clock_hz).context("FTDI error")?;
+        let result = device
+            .execute(&mut pattern_commands(&bytes, tail))
+            .context("FTDI error");
+        // Restore the clock used by SPI transfers, even if the pattern failed.
+        device
+            .set_clock_frequency(previous_clock)
+            .context("FTDI error")?;
+        result
+    }
 }
 
 lazy_static! {
@@ -113,3 +217,36 @@ lazy_static! {
         "TGT_RESET" => 7,
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_commands() -> Result<()> {
+        // 11 levels: one whole byte, followed by three bits.
+        let pattern = [
+            true, false, true, true, false, false, true, false, true, true, false,
+        ];
+        let (bytes, tail) = pack_pattern(&pattern);
+        assert_eq!(bytes, vec![0xb2]);
+        assert_eq!(tail, Some((3, 0xc0)));
+
+        let mut low_level_command = Vec::new();
+        for command in pattern_commands(&bytes, tail) {
+            command.extend(&mut low_level_command)?;
+        }
+        // Byte write of length 1 on falling edge, then bit write of length 3.
+        assert_eq!(&low_level_command, &[0x11, 0, 0, 0xb2, 0x13, 2, 0xc0]);
+
+        // Patterns shorter than a byte produce only a bit write.
+        let (bytes, tail) = pack_pattern(&[false, true]);
+        assert!(bytes.is_empty());
+        let mut low_level_command = Vec::new();
+        for command in pattern_commands(&bytes, tail) {
+            command.extend(&mut low_level_command)?;
+        }
+        assert_eq!(&low_level_command, &[0x13, 1, 0x40]);
+        Ok(())
+    }
+}
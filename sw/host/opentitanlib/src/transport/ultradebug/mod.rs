@@ -7,7 +7,7 @@ use safe_ftdi as ftdi;
 use std::cell::RefCell;
 use std::rc::Rc;
 
-use crate::io::gpio::GpioPin;
+use crate::io::gpio::{GpioPatternGenerator, GpioPin};
 use crate::io::spi::Target;
 use crate::io::uart::Uart;
 use crate::transport::{
@@ -140,6 +140,10 @@ impl Transport for Ultradebug {
         Ok(Rc::new(inner.gpio.as_ref().unwrap().pin(instance)?))
     }
 
+    fn gpio_pattern_generator(&self) -> Result<Rc<dyn GpioPatternGenerator>> {
+        Ok(Rc::new(gpio::UltradebugGpioPatternGenerator::open(self)?))
+    }
+
     fn spi(&self, instance: &str) -> Result<Rc<dyn Target>> {
         ensure!(
             instance == "0",
@@ -13,6 +13,7 @@ use crate::io::gpio::GpioError;
 use crate::io::spi::SpiError;
 
 pub const MPSSE_WRCLK_FALLING: u8 = 0x01;
+pub const MPSSE_BIT_MODE: u8 = 0x02;
 pub const MPSSE_RDCLK_FALLING: u8 = 0x04;
 pub const MPSSE_DIR_LSB_FIRST: u8 = 0x08;
 pub const MPSSE_WRITE_DATA: u8 = 0x10;
@@ -111,6 +112,9 @@ impl Default for DataShiftOptions {
 pub enum Command<'rd, 'wr> {
     ReadData(DataShiftOptions, &'rd mut [u8]),
     WriteData(DataShiftOptions, &'wr [u8]),
+    /// Shift out the given number (1-8) of bits from the byte, starting at the end given by the
+    /// bit direction.
+    WriteBits(DataShiftOptions, u8, u8),
     TransactData(DataShiftOptions, &'wr [u8], DataShiftOptions, &'rd mut [u8]),
     SetLowGpio(GpioDirection, u8),
     GetLowGpio(&'rd mut u8),
@@ -129,6 +133,7 @@ impl Command<'_, '_> {
             Command::TransactData(_, _, _, buf) => buf.len(),
             Command::GetLowGpio(_) => 1,
             Command::WriteData(_, _)
+            | Command::WriteBits(_, _, _)
             | Command::SetLowGpio(_, _)
             | Command::SetClockDivisor(_)
             | Command::DisableDivBy5
@@ -154,6 +159,14 @@ impl Command<'_, '_> {
                 buf.extend_from_slice(&((data.len() - 1) as u16).to_le_bytes());
                 buf.extend(data.iter());
             }
+            Command::WriteBits(options, count, bits) => {
+                if *count == 0 || *count > 8 {
+                    bail!(SpiError::InvalidDataLength(*count as usize));
+                }
+                buf.push(options.as_opcode() | MPSSE_BIT_MODE);
+                buf.push(count - 1);
+                buf.push(*bits);
+            }
             Command::TransactData(woptions, wdata, roptions, rdata) => {
                 if wdata.len() > Command::MAX_LENGTH {
                     bail!(SpiError::InvalidDataLength(wdata.len()));
@@ -498,6 +511,28 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    // Checks the construction of a WriteBits command.
+    fn test_command_write_bits() -> Result<()> {
+        let opt = DataShiftOptions {
+            write_clock_edge: ClockEdge::Falling,
+            write_data: true,
+            ..Default::default()
+        };
+
+        let command = Command::WriteBits(opt, 3, 0xa0);
+        assert_eq!(command.response_length(), 0);
+
+        let mut low_level_command = Vec::new();
+        command.extend(&mut low_level_command)?;
+        // opcode followed by (bit count-1) followed by data.
+        assert_eq!(&low_level_command, &[0x13, 2, 0xa0]);
+
+        let command = Command::WriteBits(DataShiftOptions::default(), 9, 0);
+        assert!(command.extend(&mut low_level_command).is_err());
+        Ok(())
+    }
+
     #[test]
     // Checks the construction of a SetLowGpio command.
     fn test_set_gpio() -> Result<()> {
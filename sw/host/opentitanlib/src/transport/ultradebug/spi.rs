@@ -2,7 +2,7 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
 use safe_ftdi as ftdi;
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -13,6 +13,7 @@ use crate::io::spi::{
 };
 use crate::transport::ultradebug::mpsse;
 use crate::transport::ultradebug::Ultradebug;
+use crate::transport::TransportError;
 
 struct Inner {
     mode: TransferMode,
@@ -32,6 +33,9 @@ impl UltradebugSpi {
     pub const PIN_CHIP_SELECT: u8 = 3;
     pub const MASK_CHIP_SELECT: u8 = 1u8 << Self::PIN_CHIP_SELECT;
     pub const PIN_SPI_ZB: u8 = 4;
+    /// Size of the FTDI read buffer, also used as the maximum write size.
+    const MAX_TRANSFER_SIZE: usize = 65536;
+
     pub fn open(ultradebug: &Ultradebug) -> Result<Self> {
         let mpsse = ultradebug.mpsse(ftdi::Interface::B)?;
         // Note: platforms ultradebugs tristate their SPI lines
@@ -108,12 +112,18 @@ impl Target for UltradebugSpi {
         // Size of the FTDI read buffer.  We can't perform a read larger than this;
         // the FTDI device simply won't read any more.
         Ok(MaxSizes {
-            read: 65536,
-            write: 65536,
+            read: Self::MAX_TRANSFER_SIZE,
+            write: Self::MAX_TRANSFER_SIZE,
         })
     }
 
     fn run_transaction(&self, transaction: &mut [Transfer]) -> Result<()> {
+        ensure!(
+            !transaction
+                .iter()
+                .any(|t| matches!(t, Transfer::SetSpeed(_))),
+            TransportError::UnsupportedOperation
+        );
         let (rdedge, wredge) = match self.inner.borrow().mode.polarity() {
             ClockPolarity::IdleLow => (mpsse::ClockEdge::Rising, mpsse::ClockEdge::Falling),
             ClockPolarity::IdleHigh => (mpsse::ClockEdge::Falling, mpsse::ClockEdge::Rising),
@@ -129,8 +139,38 @@ impl Target for UltradebugSpi {
                 device.gpio_value & !Self::MASK_CHIP_SELECT,
             ));
         }
+        // Buffers holding the data of `Transfer::Fill`, each written as many times as needed.
+        let fill_buffers = transaction
+            .iter()
+            .map(|transfer| match transfer {
+                Transfer::Fill { value, count } => {
+                    vec![*value; std::cmp::min(*count, Self::MAX_TRANSFER_SIZE)]
+                }
+                _ => Vec::new(),
+            })
+            .collect::<Vec<_>>();
         // Translate SPI Read/Write Transactions into MPSSE Commands.
-        for transfer in transaction.iter_mut() {
+        for (transfer, fill_buffer) in transaction.iter_mut().zip(&fill_buffers) {
+            if let Transfer::Fill { count, .. } = transfer {
+                let mut remaining = *count;
+                while remaining > 0 {
+                    let len = std::cmp::min(remaining, fill_buffer.len());
+                    command.push(mpsse::Command::WriteData(
+                        mpsse::DataShiftOptions {
+                            write_clock_edge: wredge,
+                            write_data: true,
+                            ..Default::default()
+                        },
+                        &fill_buffer[..len],
+                    ));
+                    remaining -= len;
+                }
+                continue;
+            }
+            if let Transfer::Turnaround = transfer {
+                // Separate MOSI and MISO lines, no turnaround needed.
+                continue;
+            }
             command.push(match transfer {
                 Transfer::Read(buf) => mpsse::Command::ReadData(
                     mpsse::DataShiftOptions {
@@ -162,6 +202,9 @@ impl Target for UltradebugSpi {
                     },
                     rbuf,
                 ),
+                Transfer::Fill { .. } | Transfer::SetSpeed(_) | Transfer::Turnaround => {
+                    unreachable!()
+                }
             });
         }
         if cs_not_already_asserted {
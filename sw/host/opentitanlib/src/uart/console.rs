@@ -7,6 +7,7 @@ use regex::{Captures, Regex};
 use std::fs::File;
 use std::io::{Read, Write};
 use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
 use std::time::{Duration, Instant, SystemTime};
 
 use crate::io::uart::{Uart, UartError};
@@ -22,6 +23,48 @@ pub struct UartConsole {
     pub timestamp: bool,
     pub buffer: String,
     pub newline: bool,
+    /// Line ending of the target, appended by `send_line()` and delimiting incoming lines for
+    /// the purpose of timestamps.
+    pub line_ending: LineEnding,
+}
+
+/// Line ending used by the console of a target.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Cr,
+    CrLf,
+}
+
+impl FromStr for LineEnding {
+    type Err = UartError;
+    fn from_str(s: &str) -> std::result::Result<LineEnding, Self::Err> {
+        match s {
+            "LF" | "lf" => Ok(LineEnding::Lf),
+            "CR" | "cr" => Ok(LineEnding::Cr),
+            "CRLF" | "crlf" => Ok(LineEnding::CrLf),
+            _ => Err(UartError::InvalidOption(s.to_string())),
+        }
+    }
+}
+
+impl LineEnding {
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::Cr => b"\r",
+            LineEnding::CrLf => b"\r\n",
+        }
+    }
+
+    /// The character ending each incoming line.
+    fn delimiter(&self) -> u8 {
+        match self {
+            LineEnding::Cr => b'\r',
+            LineEnding::Lf | LineEnding::CrLf => b'\n',
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -87,7 +130,7 @@ impl UartConsole {
                     .map_or(Ok(()), |out| out.write_fmt(format_args!("[{}]", t)))?;
                 self.newline = false;
             }
-            self.newline = buf[i] == b'\n';
+            self.newline = buf[i] == self.line_ending.delimiter();
             stdout.as_mut().map_or(Ok(()), |out| {
                 out.write_all(if self.newline {
                     b"\r\n"
@@ -106,6 +149,13 @@ impl UartConsole {
         Ok(())
     }
 
+    /// Sends `line` followed by the configured line ending.
+    pub fn send_line(&self, uart: &dyn Uart, line: &str) -> Result<()> {
+        let mut data = line.as_bytes().to_vec();
+        data.extend_from_slice(self.line_ending.as_bytes());
+        uart.write(&data)
+    }
+
     fn process_input(
         &self,
         uart: &dyn Uart,
@@ -202,3 +252,73 @@ impl UartConsole {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// UART recording the data written, and delivering scripted data when read.
+    #[derive(Default)]
+    struct WireUart {
+        tx: RefCell<Vec<u8>>,
+        rx: RefCell<Vec<u8>>,
+    }
+
+    impl Uart for WireUart {
+        fn get_baudrate(&self) -> Result<u32> {
+            Ok(115200)
+        }
+        fn set_baudrate(&self, _baudrate: u32) -> Result<()> {
+            Ok(())
+        }
+        fn read(&self, buf: &mut [u8]) -> Result<usize> {
+            self.read_timeout(buf, Duration::ZERO)
+        }
+        fn read_timeout(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize> {
+            let mut rx = self.rx.borrow_mut();
+            let len = std::cmp::min(buf.len(), rx.len());
+            buf[..len].copy_from_slice(&rx[..len]);
+            rx.drain(..len);
+            Ok(len)
+        }
+        fn write(&self, buf: &[u8]) -> Result<()> {
+            self.tx.borrow_mut().extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_send_line() -> Result<()> {
+        for (line_ending, wire) in [
+            (LineEnding::Lf, &b"help\n"[..]),
+            (LineEnding::Cr, b"help\r"),
+            (LineEnding::CrLf, b"help\r\n"),
+        ] {
+            let uart = WireUart::default();
+            let console = UartConsole {
+                line_ending,
+                ..Default::default()
+            };
+            console.send_line(&uart, "help")?;
+            assert_eq!(uart.tx.borrow().as_slice(), wire);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_incoming_line_ending() -> Result<()> {
+        let uart = WireUart::default();
+        uart.rx.borrow_mut().extend_from_slice(b"one\rtwo\r");
+        let mut console = UartConsole {
+            line_ending: LineEnding::Cr,
+            ..Default::default()
+        };
+        let mut stdout = Vec::new();
+        console.uart_read(&uart, Duration::ZERO, &mut Some(&mut stdout))?;
+        assert_eq!(stdout, b"one\r\ntwo\r\n");
+        assert!(console.newline);
+        assert_eq!(console.buffer, "one\rtwo\r");
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright lowRISC contributors.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::impl_serializable_error;
+
+/// Fixed preamble of the header of a Xilinx `.bit` file.
+const BIT_PREAMBLE: [u8; 13] = [
+    0x00, 0x09, 0x0f, 0xf0, 0x0f, 0xf0, 0x0f, 0xf0, 0x0f, 0xf0, 0x00, 0x00, 0x01,
+];
+/// Configuration sync word, preceding the first configuration packet.
+const SYNC_WORD: [u8; 4] = [0xaa, 0x99, 0x55, 0x66];
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum Error {
+    #[error("Bitstream header truncated at offset {0}")]
+    Truncated(usize),
+    #[error("Unexpected bitstream header field {0:#x} at offset {1}")]
+    UnexpectedField(u8, usize),
+    #[error("Sync word not found in bitstream")]
+    SyncWordNotFound,
+    #[error("Bitstream is for part {0}, expected {1}")]
+    PartMismatch(String, String),
+}
+impl_serializable_error!(Error);
+
+/// Information from the header of a Xilinx bitstream.  The header fields are `None` for raw
+/// `.bin` files, which consist only of configuration data.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BitstreamInfo {
+    /// Design name, typically followed by `;UserID=...;Version=...`.
+    pub design_name: Option<String>,
+    /// Part name, e.g. `7k410tfbg676`.
+    pub part_name: Option<String>,
+    pub date: Option<String>,
+    pub time: Option<String>,
+    /// Offset of the configuration data within the file.
+    pub data_offset: usize,
+    /// Offset of the sync word within the file.
+    pub sync_offset: usize,
+}
+
+impl BitstreamInfo {
+    /// Parses the header of a `.bit` file, or accepts a raw `.bin` file, and locates the sync
+    /// word in the configuration data.
+    pub fn parse(bitstream: &[u8]) -> Result<Self> {
+        let mut info = BitstreamInfo::default();
+        if bitstream.starts_with(&BIT_PREAMBLE) {
+            let mut pos = BIT_PREAMBLE.len();
+            loop {
+                let key = *bitstream.get(pos).ok_or(Error::Truncated(pos))?;
+                if key == b'e' {
+                    // Length of the configuration data is a 32-bit value.
+                    let len = Self::read_be(bitstream, pos + 1, 4)?;
+                    info.data_offset = pos + 5;
+                    if info.data_offset + len > bitstream.len() {
+                        return Err(Error::Truncated(bitstream.len()).into());
+                    }
+                    break;
+                }
+                let len = Self::read_be(bitstream, pos + 1, 2)?;
+                let start = pos + 3;
+                let value = bitstream
+                    .get(start..start + len)
+                    .ok_or(Error::Truncated(bitstream.len()))?;
+                let value = String::from_utf8_lossy(value)
+                    .trim_end_matches('\0')
+                    .to_string();
+                match key {
+                    b'a' => info.design_name = Some(value),
+                    b'b' => info.part_name = Some(value),
+                    b'c' => info.date = Some(value),
+                    b'd' => info.time = Some(value),
+                    _ => return Err(Error::UnexpectedField(key, pos).into()),
+                }
+                pos = start + len;
+            }
+        }
+        info.sync_offset = info.data_offset
+            + bitstream[info.data_offset..]
+                .windows(SYNC_WORD.len())
+                .position(|w| w == SYNC_WORD)
+                .ok_or(Error::SyncWordNotFound)?;
+        Ok(info)
+    }
+
+    /// Verifies that the bitstream is for the given part, if the header names a part.
+    pub fn check_part(&self, expected: &str) -> Result<()> {
+        match &self.part_name {
+            Some(part) if part != expected => {
+                Err(Error::PartMismatch(part.clone(), expected.to_string()).into())
+            }
+            Some(_) => Ok(()),
+            None => {
+                log::warn!("Bitstream has no header, cannot verify part {}", expected);
+                Ok(())
+            }
+        }
+    }
+
+    fn read_be(bitstream: &[u8], pos: usize, len: usize) -> Result<usize> {
+        let bytes = bitstream
+            .get(pos..pos + len)
+            .ok_or(Error::Truncated(bitstream.len()))?;
+        Ok(bytes.iter().fold(0, |acc, &b| acc << 8 | b as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configuration data as found at the start of a 7-series bitstream.
+    const DATA: [u8; 24] = [
+        0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0xbb, 0x11, 0x22, 0x00, 0x44, 0xff, 0xff, 0xff,
+        0xff, 0xaa, 0x99, 0x55, 0x66, 0x20, 0x00, 0x00, 0x00,
+    ];
+
+    fn field(key: u8, value: &str) -> Vec<u8> {
+        let mut field = vec![key];
+        field.extend_from_slice(&(value.len() as u16 + 1).to_be_bytes());
+        field.extend_from_slice(value.as_bytes());
+        field.push(0);
+        field
+    }
+
+    fn bit_file() -> Vec<u8> {
+        let mut bit = BIT_PREAMBLE.to_vec();
+        bit.extend(field(
+            b'a',
+            "chip_earlgrey_cw310;UserID=0XFFFFFFFF;Version=2021.1",
+        ));
+        bit.extend(field(b'b', "7k410tfbg676"));
+        bit.extend(field(b'c', "2022/11/02"));
+        bit.extend(field(b'd', "12:34:56"));
+        bit.push(b'e');
+        bit.extend_from_slice(&(DATA.len() as u32).to_be_bytes());
+        bit.extend_from_slice(&DATA);
+        bit
+    }
+
+    #[test]
+    fn test_parse_bit_header() -> Result<()> {
+        let bit = bit_file();
+        let info = BitstreamInfo::parse(&bit)?;
+        assert_eq!(
+            info.design_name.as_deref(),
+            Some("chip_earlgrey_cw310;UserID=0XFFFFFFFF;Version=2021.1")
+        );
+        assert_eq!(info.part_name.as_deref(), Some("7k410tfbg676"));
+        assert_eq!(info.date.as_deref(), Some("2022/11/02"));
+        assert_eq!(info.time.as_deref(), Some("12:34:56"));
+        assert_eq!(info.data_offset, bit.len() - DATA.len());
+        assert_eq!(info.sync_offset, info.data_offset + 16);
+
+        info.check_part("7k410tfbg676")?;
+        let err = info.check_part("7k160tfbg676").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::PartMismatch(_, _))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_bin() -> Result<()> {
+        let info = BitstreamInfo::parse(&DATA)?;
+        assert_eq!(info.part_name, None);
+        assert_eq!(info.data_offset, 0);
+        assert_eq!(info.sync_offset, 16);
+        // Without a header, the part cannot be verified.
+        info.check_part("7k410tfbg676")?;
+
+        let err = BitstreamInfo::parse(&DATA[..16]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::SyncWordNotFound)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let bit = bit_file();
+        for len in [BIT_PREAMBLE.len(), BIT_PREAMBLE.len() + 10, bit.len() - 1] {
+            let err = BitstreamInfo::parse(&bit[..len]).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref::<Error>(), Some(Error::Truncated(_))),
+                "{}: {}",
+                len,
+                err
+            );
+        }
+    }
+}
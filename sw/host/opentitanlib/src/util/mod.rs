@@ -4,6 +4,7 @@
 
 pub mod bigint;
 pub mod bitfield;
+pub mod bitstream;
 pub mod file;
 pub mod nix;
 pub mod num_de;
@@ -2,7 +2,7 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
@@ -10,6 +10,7 @@ use std::time::Duration;
 use std::time::Instant;
 use structopt::clap::arg_enum;
 
+use crate::app::TransportWrapper;
 use crate::io::uart::Uart;
 use crate::uart::console::{ExitStatus, UartConsole};
 use crate::util::usr_access::usr_access_get;
@@ -22,6 +23,59 @@ arg_enum! {
     }
 }
 
+/// Cause of a reset, as recorded in the rstmgr `RESET_INFO` register.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ResetCause {
+    /// Power-on-reset (`POR`).
+    PowerOn,
+    /// Exit from a low power state (`LOW_POWER_EXIT`).
+    LowPowerExit,
+    /// Software request through the `RESET_REQ` register (`SW_RESET`).
+    SoftwareRequest,
+    /// Reset request of `sysrst_ctrl_aon` (`HW_REQ` bit 3).
+    SysrstCtrl,
+    /// Watchdog of `aon_timer_aon` (`HW_REQ` bit 4).
+    Watchdog,
+    /// Main power glitch detected by `pwrmgr_aon` (`HW_REQ` bit 5).
+    PowerGlitch,
+    /// Escalation of `alert_handler` (`HW_REQ` bit 6).
+    Escalation,
+    /// Non-debug-module reset requested through `rv_dm` (`HW_REQ` bit 7).
+    NonDebugModule,
+    /// Bits of the register not defined above.
+    Unknown(u32),
+}
+
+impl ResetCause {
+    /// Causes in the order of their bits in `RESET_INFO`.
+    const BITS: [ResetCause; 8] = [
+        ResetCause::PowerOn,
+        ResetCause::LowPowerExit,
+        ResetCause::SoftwareRequest,
+        ResetCause::SysrstCtrl,
+        ResetCause::Watchdog,
+        ResetCause::PowerGlitch,
+        ResetCause::Escalation,
+        ResetCause::NonDebugModule,
+    ];
+
+    /// Decodes a value of the `RESET_INFO` register.  The bits accumulate until cleared by
+    /// software, so several causes may be reported, in the order of their bits.
+    pub fn from_reset_info(reset_info: u32) -> Vec<ResetCause> {
+        let mut causes: Vec<ResetCause> = Self::BITS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| reset_info & (1 << bit) != 0)
+            .map(|(_, cause)| *cause)
+            .collect();
+        let unknown = reset_info & !((1 << Self::BITS.len()) - 1);
+        if unknown != 0 {
+            causes.push(ResetCause::Unknown(unknown));
+        }
+        causes
+    }
+}
+
 pub struct RomDetect {
     kind: RomKind,
     usr_access: u32,
@@ -30,15 +84,24 @@ pub struct RomDetect {
 
 impl RomDetect {
     pub fn new(kind: RomKind, bitstream: &[u8], timeout: Option<Duration>) -> Result<RomDetect> {
-        Ok(RomDetect {
+        Ok(Self::with_usr_access(
+            kind,
+            usr_access_get(bitstream)?,
+            timeout,
+        ))
+    }
+
+    /// Detects the ROM of a bitstream whose USR_ACCESS value is already known.
+    pub fn with_usr_access(kind: RomKind, usr_access: u32, timeout: Option<Duration>) -> RomDetect {
+        RomDetect {
             kind,
-            usr_access: usr_access_get(bitstream)?,
+            usr_access,
             console: UartConsole {
                 timeout,
                 exit_success: Some(Regex::new(r"(\w*ROM):([^\r\n]+)[\r\n]").unwrap()),
                 ..Default::default()
             },
-        })
+        }
     }
 
     pub fn detect(&mut self, uart: &dyn Uart) -> Result<bool> {
@@ -66,3 +129,259 @@ impl RomDetect {
         Ok(false)
     }
 }
+
+/// How the ROM proceeded after reset, as reported on the console.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum BootMode {
+    /// The ROM entered bootstrap, as requested by the `ROM_BOOTSTRAP` strapping.
+    Bootstrap,
+    /// The ROM handed over to the ROM_EXT.
+    RomExt,
+    /// The ROM shut down with the given boot fault value, e.g. for lack of a valid ROM_EXT.
+    BootFault(u32),
+}
+
+/// Detects which `BootMode` the ROM reports entering, in order to confirm that the strapping
+/// pins were sampled as intended.
+pub struct BootModeDetect {
+    console: UartConsole,
+}
+
+impl BootModeDetect {
+    pub fn new(timeout: Option<Duration>) -> BootModeDetect {
+        BootModeDetect {
+            console: UartConsole {
+                timeout,
+                exit_success: Some(
+                    Regex::new(r"(bootstrap:1|BFV:([0-9a-fA-F]{8})|Starting ROM_EXT)\r?\n")
+                        .unwrap(),
+                ),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Watch `uart` for the message printed by the ROM after reset.
+    pub fn detect(&mut self, uart: &dyn Uart) -> Result<BootMode> {
+        let rc = self.console.interact(uart, None, None)?;
+        let cap = match self.console.captures(ExitStatus::ExitSuccess) {
+            Some(cap) => cap,
+            None => bail!("Did not detect the boot mode message ({:?})", rc),
+        };
+        if let Some(bfv) = cap.get(2) {
+            return Ok(BootMode::BootFault(u32::from_str_radix(bfv.as_str(), 16)?));
+        }
+        Ok(match cap.get(1).unwrap().as_str() {
+            "bootstrap:1" => BootMode::Bootstrap,
+            _ => BootMode::RomExt,
+        })
+    }
+
+    /// Apply or remove the `ROM_BOOTSTRAP` strapping, reset the target, and return the boot mode
+    /// reported by the ROM.  The strapping is left in place, callers entering bootstrap remove it
+    /// when done.
+    pub fn reset_with_bootstrap(
+        &mut self,
+        transport: &TransportWrapper,
+        uart: &dyn Uart,
+        bootstrap: bool,
+        reset_delay: Duration,
+    ) -> Result<BootMode> {
+        if bootstrap {
+            transport.apply_pin_strapping("ROM_BOOTSTRAP")?;
+        } else {
+            transport.remove_pin_strapping("ROM_BOOTSTRAP")?;
+        }
+        transport.reset_target(reset_delay, true)?;
+        let mode = self.detect(uart)?;
+        log::info!("ROM boot mode: {:?}", mode);
+        Ok(mode)
+    }
+}
+
+/// Detects whether a target is alive by watching for any console output at all, for use when
+/// there is no known banner to wait for (as `RomDetect` does).
+pub struct OutputDetect;
+
+impl OutputDetect {
+    /// Returns whether any bytes arrive on `uart` within `window`.  The received data is
+    /// discarded.
+    pub fn await_any_output(&self, uart: &dyn Uart, window: Duration) -> Result<bool> {
+        let deadline = Instant::now() + window;
+        let mut buf = [0u8; 256];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if uart.read_timeout(&mut buf, remaining)? > 0 {
+                return Ok(true);
+            }
+            if remaining.is_zero() {
+                log::info!("No console output within {:?}", window);
+                return Ok(false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    /// A UART which sends a single byte after `delay`, or stays silent if `delay` is `None`.
+    struct MockUart {
+        delay: Option<Duration>,
+        start: Instant,
+        sent: Cell<bool>,
+    }
+
+    impl MockUart {
+        fn new(delay: Option<Duration>) -> Self {
+            MockUart {
+                delay,
+                start: Instant::now(),
+                sent: Cell::new(false),
+            }
+        }
+    }
+
+    impl Uart for MockUart {
+        fn get_baudrate(&self) -> Result<u32> {
+            Ok(115200)
+        }
+        fn set_baudrate(&self, _baudrate: u32) -> Result<()> {
+            Ok(())
+        }
+        fn read(&self, _buf: &mut [u8]) -> Result<usize> {
+            unimplemented!();
+        }
+        fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+            let deadline = Instant::now() + timeout;
+            match self.delay {
+                Some(delay) if !self.sent.get() && self.start + delay <= deadline => {
+                    std::thread::sleep(
+                        (self.start + delay).saturating_duration_since(Instant::now()),
+                    );
+                    self.sent.set(true);
+                    buf[0] = b'\n';
+                    Ok(1)
+                }
+                _ => {
+                    std::thread::sleep(timeout);
+                    Ok(0)
+                }
+            }
+        }
+        fn write(&self, _buf: &[u8]) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_await_any_output_silent() -> Result<()> {
+        let uart = MockUart::new(None);
+        let t0 = Instant::now();
+        assert!(!OutputDetect.await_any_output(&uart, Duration::from_millis(50))?);
+        assert!(t0.elapsed() >= Duration::from_millis(50));
+        Ok(())
+    }
+
+    #[test]
+    fn test_await_any_output_active() -> Result<()> {
+        let uart = MockUart::new(Some(Duration::from_millis(10)));
+        assert!(OutputDetect.await_any_output(&uart, Duration::from_secs(5))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_await_any_output_too_late() -> Result<()> {
+        let uart = MockUart::new(Some(Duration::from_millis(200)));
+        assert!(!OutputDetect.await_any_output(&uart, Duration::from_millis(20))?);
+        Ok(())
+    }
+
+    /// A UART which sends the given output at once, then stays silent.
+    struct ScriptedUart(RefCell<Vec<u8>>);
+
+    impl Uart for ScriptedUart {
+        fn get_baudrate(&self) -> Result<u32> {
+            Ok(115200)
+        }
+        fn set_baudrate(&self, _baudrate: u32) -> Result<()> {
+            Ok(())
+        }
+        fn read(&self, _buf: &mut [u8]) -> Result<usize> {
+            unimplemented!();
+        }
+        fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+            let mut output = self.0.borrow_mut();
+            if output.is_empty() {
+                std::thread::sleep(timeout);
+                return Ok(0);
+            }
+            let len = std::cmp::min(buf.len(), output.len());
+            buf[..len].copy_from_slice(&output[..len]);
+            output.drain(..len);
+            Ok(len)
+        }
+        fn write(&self, _buf: &[u8]) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_boot_mode_detect() -> Result<()> {
+        let rom_banner = "ROM:0a1b2c3d\r\n";
+        for (output, expected) in [
+            ("bootstrap:1\r\n", BootMode::Bootstrap),
+            ("BFV:0142500d\r\n", BootMode::BootFault(0x0142500d)),
+            ("Starting ROM_EXT\r\n", BootMode::RomExt),
+        ] {
+            let uart = ScriptedUart(RefCell::new(
+                format!("{}{}", rom_banner, output).into_bytes(),
+            ));
+            let mut detect = BootModeDetect::new(Some(Duration::from_secs(5)));
+            assert_eq!(detect.detect(&uart)?, expected);
+        }
+
+        // Only the ROM banner, no boot mode.
+        let uart = ScriptedUart(RefCell::new(rom_banner.as_bytes().to_vec()));
+        let mut detect = BootModeDetect::new(Some(Duration::from_millis(50)));
+        assert!(detect.detect(&uart).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_cause_from_reset_info() {
+        assert_eq!(ResetCause::from_reset_info(0), vec![]);
+        assert_eq!(ResetCause::from_reset_info(0x1), vec![ResetCause::PowerOn]);
+        assert_eq!(
+            ResetCause::from_reset_info(0x2),
+            vec![ResetCause::LowPowerExit]
+        );
+        assert_eq!(
+            ResetCause::from_reset_info(0x4),
+            vec![ResetCause::SoftwareRequest]
+        );
+        assert_eq!(
+            ResetCause::from_reset_info(0x10),
+            vec![ResetCause::Watchdog]
+        );
+        assert_eq!(
+            ResetCause::from_reset_info(0x80),
+            vec![ResetCause::NonDebugModule]
+        );
+        // Causes accumulate until cleared.
+        assert_eq!(
+            ResetCause::from_reset_info(0x49),
+            vec![
+                ResetCause::PowerOn,
+                ResetCause::SysrstCtrl,
+                ResetCause::Escalation
+            ]
+        );
+        assert_eq!(
+            ResetCause::from_reset_info(0x120),
+            vec![ResetCause::PowerGlitch, ResetCause::Unknown(0x100)]
+        );
+    }
+}
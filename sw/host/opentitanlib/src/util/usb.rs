@@ -4,10 +4,76 @@
 
 use anyhow::{ensure, Context, Result};
 use rusb;
-use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_annotate::Annotate;
+use std::time::{Duration, Instant};
 
 use crate::transport::TransportError;
 
+/// Identification strings of a USB device, `None` for strings which the device does not
+/// provide, or which could not be read.
+#[derive(Annotate, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UsbStrings {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+impl UsbStrings {
+    /// Reads the strings at the given descriptor indices (as found in the device descriptor,
+    /// `None` if absent) using `read`.  Failure to read a string is logged and not reported.
+    pub fn read(
+        manufacturer: Option<u8>,
+        product: Option<u8>,
+        serial_number: Option<u8>,
+        read: impl Fn(u8) -> Result<String>,
+    ) -> Self {
+        let read_one = |idx: Option<u8>| {
+            idx.and_then(|idx| match read(idx) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    log::debug!("Could not read string descriptor {}: {}", idx, e);
+                    None
+                }
+            })
+        };
+        UsbStrings {
+            manufacturer: read_one(manufacturer),
+            product: read_one(product),
+            serial_number: read_one(serial_number),
+        }
+    }
+}
+
+/// Delay before the first retry of `retry_while_busy()`, doubled for each subsequent retry.
+const BUSY_INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Calls `claim` until it succeeds, or fails for any other reason than the USB interface `iface`
+/// being busy, for up to `timeout`.  The delay between attempts increases exponentially.  If the
+/// interface is still busy at the end, `TransportError::UsbInterfaceBusy` is returned.
+pub fn retry_while_busy(
+    iface: u8,
+    timeout: Duration,
+    mut claim: impl FnMut() -> Result<()>,
+) -> Result<()> {
+    let start = Instant::now();
+    let mut backoff = BUSY_INITIAL_BACKOFF;
+    loop {
+        match claim() {
+            Err(e) if e.downcast_ref::<rusb::Error>() == Some(&rusb::Error::Busy) => {
+                let remaining = timeout.saturating_sub(start.elapsed());
+                if remaining.is_zero() {
+                    return Err(TransportError::UsbInterfaceBusy(iface, timeout).into());
+                }
+                log::debug!("USB interface {} busy, retrying", iface);
+                std::thread::sleep(std::cmp::min(backoff, remaining));
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
 /// The `UsbBackend` provides low-level USB access to debugging devices.
 pub struct UsbBackend {
     device: rusb::Device<rusb::GlobalContext>,
@@ -92,6 +158,57 @@ impl UsbBackend {
         Ok(devices)
     }
 
+    /// List the VID/PID of every device on the USB bus, skipping devices whose descriptor
+    /// cannot be read.
+    pub fn enumerate() -> Result<Vec<(u16, u16)>> {
+        let mut ids = Vec::new();
+        for device in rusb::devices().context("USB error")?.iter() {
+            match device.device_descriptor() {
+                Ok(desc) => ids.push((desc.vendor_id(), desc.product_id())),
+                Err(e) => log::debug!(
+                    "Could not read device descriptor for device at bus={} address={}: {}",
+                    device.bus_number(),
+                    device.address(),
+                    e,
+                ),
+            }
+        }
+        Ok(ids)
+    }
+
+    /// List the devices matching VID/PID, without opening them.
+    pub fn matching_devices(
+        usb_vid: u16,
+        usb_pid: u16,
+    ) -> Result<Vec<rusb::Device<rusb::GlobalContext>>> {
+        let mut devices = Vec::new();
+        for device in rusb::devices().context("USB error")?.iter() {
+            match device.device_descriptor() {
+                Ok(desc) if desc.vendor_id() == usb_vid && desc.product_id() == usb_pid => {
+                    devices.push(device)
+                }
+                _ => (),
+            }
+        }
+        Ok(devices)
+    }
+
+    /// Open the given device, e.g. one returned by `matching_devices()`, without claiming any
+    /// interface.
+    pub fn from_device(device: rusb::Device<rusb::GlobalContext>) -> Result<Self> {
+        let descriptor = device.device_descriptor().context("USB error")?;
+        let handle = device.open().context("USB open error")?;
+        let serial_number = handle
+            .read_serial_number_string_ascii(&descriptor)
+            .context("USB error")?;
+        Ok(UsbBackend {
+            device,
+            handle,
+            serial_number,
+            timeout: Duration::from_millis(500),
+        })
+    }
+
     /// Create a new UsbBackend.
     pub fn new(usb_vid: u16, usb_pid: u16, usb_serial: Option<&str>) -> Result<Self> {
         let mut devices = UsbBackend::scan(usb_vid, usb_pid, usb_serial)?;
@@ -120,6 +237,17 @@ impl UsbBackend {
         self.serial_number.as_str()
     }
 
+    /// Reads the manufacturer, product and serial number strings of the device.
+    pub fn string_descriptors(&self) -> Result<UsbStrings> {
+        let descriptor = self.device.device_descriptor().context("USB error")?;
+        Ok(UsbStrings::read(
+            descriptor.manufacturer_string_index(),
+            descriptor.product_string_index(),
+            descriptor.serial_number_string_index(),
+            |idx| self.read_string_descriptor_ascii(idx),
+        ))
+    }
+
     //
     // Enumerating interfaces of the USB device.  The methods below leak rusb data structures,
     // and may have to be refactored, when we convert UsbDevice into a trait, and want to
@@ -130,6 +258,12 @@ impl UsbBackend {
         self.handle.claim_interface(iface).context("USB error")
     }
 
+    /// Claim the interface, waiting up to `timeout` for another process to release it, see
+    /// `retry_while_busy()`.
+    pub fn claim_interface_with_retry(&mut self, iface: u8, timeout: Duration) -> Result<()> {
+        retry_while_busy(iface, timeout, || self.claim_interface(iface))
+    }
+
     pub fn active_config_descriptor(&self) -> Result<rusb::ConfigDescriptor> {
         self.device.active_config_descriptor().context("USB error")
     }
@@ -182,19 +316,115 @@ impl UsbBackend {
 
     /// Read bulk data bytes to given USB endpoint.
     pub fn read_bulk(&self, endpoint: u8, data: &mut [u8]) -> Result<usize> {
+        self.read_bulk_timeout(endpoint, data, self.timeout)
+    }
+
+    /// Read bulk data bytes from given USB endpoint, waiting at most `timeout` rather than the
+    /// default timeout.  Expiry is reported as `rusb::Error::Timeout`.
+    pub fn read_bulk_timeout(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize> {
         let len = self
             .handle
-            .read_bulk(endpoint, data, self.timeout)
+            .read_bulk(endpoint, data, timeout)
             .context("USB error")?;
         Ok(len)
     }
 
     /// Write bulk data bytes to given USB endpoint.
     pub fn write_bulk(&self, endpoint: u8, data: &[u8]) -> Result<usize> {
+        self.write_bulk_timeout(endpoint, data, self.timeout)
+    }
+
+    /// Write bulk data bytes to given USB endpoint, waiting at most `timeout` rather than the
+    /// default timeout.  Expiry is reported as `rusb::Error::Timeout`.
+    pub fn write_bulk_timeout(
+        &self,
+        endpoint: u8,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<usize> {
         let len = self
             .handle
-            .write_bulk(endpoint, data, self.timeout)
+            .write_bulk(endpoint, data, timeout)
             .context("USB error")?;
         Ok(len)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+
+    #[test]
+    fn test_usb_strings() {
+        let read = |idx: u8| -> Result<String> {
+            match idx {
+                1 => Ok("NewAE Technology Inc.".to_string()),
+                2 => Ok("ChipWhisperer CW310".to_string()),
+                _ => bail!("USB error: Pipe error"),
+            }
+        };
+        assert_eq!(
+            UsbStrings::read(Some(1), Some(2), None, read),
+            UsbStrings {
+                manufacturer: Some("NewAE Technology Inc.".to_string()),
+                product: Some("ChipWhisperer CW310".to_string()),
+                serial_number: None,
+            }
+        );
+        // A string which cannot be read is treated as absent.
+        assert_eq!(
+            UsbStrings::read(None, Some(2), Some(3), read),
+            UsbStrings {
+                manufacturer: None,
+                product: Some("ChipWhisperer CW310".to_string()),
+                serial_number: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_retry_while_busy() -> Result<()> {
+        // Busy twice, then claimed.
+        let mut attempts = 0;
+        retry_while_busy(2, Duration::from_secs(5), || {
+            attempts += 1;
+            match attempts {
+                1 | 2 => Err(rusb::Error::Busy).context("USB error"),
+                _ => Ok(()),
+            }
+        })?;
+        assert_eq!(attempts, 3);
+
+        // Other errors are not retried.
+        let mut attempts = 0;
+        let err = retry_while_busy(2, Duration::from_secs(5), || {
+            attempts += 1;
+            Err(rusb::Error::Access).context("USB error")
+        })
+        .unwrap_err();
+        assert_eq!(attempts, 1);
+        assert_eq!(
+            err.downcast_ref::<rusb::Error>(),
+            Some(&rusb::Error::Access)
+        );
+
+        // Busy throughout.
+        let timeout = Duration::from_millis(50);
+        let t0 = Instant::now();
+        let err = retry_while_busy(2, timeout, || Err(rusb::Error::Busy).context("USB error"))
+            .unwrap_err();
+        assert!(t0.elapsed() >= timeout);
+        assert!(matches!(
+            err.downcast_ref::<TransportError>(),
+            Some(TransportError::UsbInterfaceBusy(2, t)) if *t == timeout
+        ));
+        assert!(err.to_string().contains("busy"));
+        Ok(())
+    }
+}
@@ -17,7 +17,7 @@ use opentitanlib::app::command::CommandDispatch;
 use opentitanlib::app::TransportWrapper;
 use opentitanlib::io::uart::UartParams;
 use opentitanlib::transport::Capability;
-use opentitanlib::uart::console::{ExitStatus, UartConsole};
+use opentitanlib::uart::console::{ExitStatus, LineEnding, UartConsole};
 
 #[derive(Debug, StructOpt)]
 pub struct Console {
@@ -43,6 +43,13 @@ pub struct Console {
     #[structopt(long, help = "Print a timestamp on each line of console output.")]
     timestamp: bool,
 
+    #[structopt(
+        long,
+        default_value = "LF",
+        help = "Line ending of the target: LF, CR or CRLF."
+    )]
+    line_ending: LineEnding,
+
     #[structopt(long, help = "Exit with success if the specified regex is matched.")]
     exit_success: Option<String>,
 
@@ -77,6 +84,7 @@ impl CommandDispatch for Console {
                 .transpose()?,
             timestamp: self.timestamp,
             newline: true,
+            line_ending: self.line_ending,
             ..Default::default()
         };
 
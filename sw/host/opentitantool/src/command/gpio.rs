@@ -17,7 +17,7 @@ use structopt::StructOpt;
 
 use opentitanlib::app::command::CommandDispatch;
 use opentitanlib::app::TransportWrapper;
-use opentitanlib::io::gpio::{ClockNature, Edge, GpioPin, PinMode, PullMode};
+use opentitanlib::io::gpio::{ClockNature, Edge, GpioPin, MonitoringSession, PinMode, PullMode};
 use opentitanlib::transport::Capability;
 use opentitanlib::util::file;
 use opentitanlib::util::voltage::Voltage;
@@ -357,13 +357,14 @@ impl CommandDispatch for GpioMonitoringRead {
             .ok()?;
         let gpio_monitoring = transport.gpio_monitoring()?;
         let gpio_pins = transport.gpio_pins(&self.pins)?;
-        let resp = gpio_monitoring.monitoring_read(
+        // Re-create the handle of the session established by a previous `start` command.
+        let session = MonitoringSession::from_pins(
             &gpio_pins
                 .iter()
                 .map(Rc::borrow)
                 .collect::<Vec<&dyn GpioPin>>(),
-            self.continue_monitoring,
         )?;
+        let resp = gpio_monitoring.monitoring_read(&session, self.continue_monitoring)?;
         Ok(Some(Box::new(GpioMonitoringReadResult {
             events: resp
                 .events
@@ -474,13 +475,7 @@ impl CommandDispatch for GpioMonitoringVcd {
         // printing them to the output file.
         let mut loop_count: usize = 0;
         'event_loop: loop {
-            let resp = gpio_monitoring.monitoring_read(
-                &gpio_pins
-                    .iter()
-                    .map(Rc::borrow)
-                    .collect::<Vec<&dyn GpioPin>>(),
-                true,
-            )?;
+            let resp = gpio_monitoring.monitoring_read(&initial.session, true)?;
             for event in &resp.events {
                 writeln!(&mut file, "#{}", event.timestamp)?;
                 writeln!(
@@ -512,13 +507,7 @@ impl CommandDispatch for GpioMonitoringVcd {
 
         // Make one final reading to fetch any events that may have happened just before user
         // requested to end monitoring.
-        let resp = gpio_monitoring.monitoring_read(
-            &gpio_pins
-                .iter()
-                .map(Rc::borrow)
-                .collect::<Vec<&dyn GpioPin>>(),
-            false,
-        )?;
+        let resp = gpio_monitoring.monitoring_read(&initial.session, false)?;
         for event in &resp.events {
             writeln!(&mut file, "#{}", event.timestamp)?;
             writeln!(
@@ -12,7 +12,7 @@ use structopt::StructOpt;
 
 use opentitanlib::app::command::CommandDispatch;
 use opentitanlib::app::{self, TransportWrapper};
-use opentitanlib::transport::common::fpga::FpgaProgram;
+use opentitanlib::transport::common::fpga::{FpgaProgram, FpgaProgramMethod};
 use opentitanlib::util::rom_detect::RomKind;
 
 /// Load a bitstream into the FPGA.
@@ -32,6 +32,30 @@ pub struct LoadBitstream {
     pub rom_reset_pulse: Duration,
     #[structopt(long, parse(try_from_str=humantime::parse_duration), default_value="2s", help = "Duration of ROM detection timeout")]
     pub rom_timeout: Duration,
+    #[structopt(
+        long,
+        possible_values = &FpgaProgramMethod::variants(),
+        case_insensitive = true,
+        default_value = "Spi",
+        help = "FPGA configuration interface"
+    )]
+    pub program_method: FpgaProgramMethod,
+    #[structopt(
+        long,
+        help = "Verify that the part named in the bitstream header matches the board"
+    )]
+    pub verify_part: bool,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Number of times to retry programming if it fails"
+    )]
+    pub program_retries: u32,
+    #[structopt(
+        long,
+        help = "Reset and detect the ROM even if this process has already loaded or detected the same bitstream"
+    )]
+    pub force: bool,
 }
 
 impl CommandDispatch for LoadBitstream {
@@ -41,16 +65,20 @@ impl CommandDispatch for LoadBitstream {
         transport: &TransportWrapper,
     ) -> Result<Option<Box<dyn Annotate>>> {
         log::info!("Loading bitstream: {:?}", self.filename);
-        let bitstream = fs::read(&self.filename)?;
-        let progress = app::progress_bar(bitstream.len() as u64);
+        let progress = app::progress_bar(fs::metadata(&self.filename)?.len());
         let pfunc = Box::new(move |_, chunk| {
             progress.inc(chunk as u64);
         });
         let operation = FpgaProgram {
-            bitstream,
+            bitstream: Vec::new(),
+            bitstream_path: Some(self.filename.clone()),
+            method: self.program_method,
             rom_kind: self.rom_kind,
             rom_reset_pulse: self.rom_reset_pulse,
             rom_timeout: self.rom_timeout,
+            verify_part: self.verify_part,
+            retries: self.program_retries,
+            force: self.force,
             progress: Some(pfunc),
         };
         transport.dispatch(&operation)
@@ -68,6 +68,20 @@ impl CommandDispatch for TransportUpdateFirmware {
     }
 }
 
+/// Show the manufacturer, product and serial number strings of the USB debugger device.
+#[derive(Debug, StructOpt)]
+pub struct TransportDescribe {}
+
+impl CommandDispatch for TransportDescribe {
+    fn run(
+        &self,
+        _context: &dyn Any,
+        transport: &TransportWrapper,
+    ) -> Result<Option<Box<dyn Annotate>>> {
+        Ok(Some(Box::new(transport.description()?)))
+    }
+}
+
 /// Watch verilator's stdout for a regex or until a timeout is reached.
 #[derive(Debug, StructOpt)]
 pub struct VerilatorWatch {
@@ -99,6 +113,7 @@ impl CommandDispatch for VerilatorWatch {
 #[derive(Debug, StructOpt, CommandDispatch)]
 pub enum TransportCommand {
     Init(TransportInit),
+    Describe(TransportDescribe),
     VerilatorWatch(VerilatorWatch),
     UpdateFirmware(TransportUpdateFirmware),
 }